@@ -19,59 +19,274 @@
 //! Utilities for handling a cargo instance
 
 use anyhow::Context;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tempfile::TempDir;
 
-use std::fs;
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+use std::ffi::{OsStr, OsString};
 use std::io::{self, BufRead, Read};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{env, fmt, fs};
 
+use crate::container::Container;
+use crate::gc;
 use crate::git::RepoRef;
-use crate::job::exec_or_stderr;
+use crate::job::{exec_or_stderr, run_captured, CaptureOptions, Captured};
+use crate::limits::Limits;
+use crate::sandbox::Sandbox;
+use crate::say;
+use crate::worker::Remote;
 
 /// Structure representing a cargo command
 pub struct Cargo<'a> {
-    exec: subprocess::Exec,
-    cwd: PathBuf,
+    command: String,
     version: String,
+    priority: Priority,
+    repo_dir: PathBuf,
+    cwd: PathBuf,
+    /// Environment variables set for every command
+    env: Vec<(OsString, OsString)>,
+    /// Directories outside the checkout which cargo uses
+    mounts: Vec<PathBuf>,
+    container: Option<Container>,
+    sandbox: Option<Sandbox>,
+    remote: Option<Remote>,
+    offline: bool,
+    log_file: Option<PathBuf>,
+    stream_prefix: Option<String>,
+    limits: Option<Limits>,
     _ref: RepoRef<'a>,
 }
 
 impl<'a> Cargo<'a> {
     /// Construct a new cargo instance
-    pub fn new(version: String, tmp_dir: &'a TempDir, cwd_ext: Option<&String>) -> Self {
+    ///
+    /// If `command` is given, it is run instead of `cargo`. It must accept
+    /// the same arguments as cargo, e.g. `cross`. If it is a path rather
+    /// than a command name, it is taken to be a specific toolchain rather
+    /// than a rustup proxy, so no `+<version>` argument is passed to it, and
+    /// `rustc` is looked for alongside it.
+    ///
+    /// Cargo is run with the given `priority`, by way of `nice` and `ionice`.
+    pub fn new(
+        command: Option<&String>,
+        version: String,
+        tmp_dir: &'a TempDir,
+        cwd_ext: Option<&String>,
+        priority: Priority,
+    ) -> Self {
         let mut cwd = tmp_dir.path().to_path_buf();
         if let Some(s) = cwd_ext {
             cwd.push(s);
         }
 
         Cargo {
-            exec: subprocess::Exec::cmd("cargo")
-                .arg(format!("+{}", version))
-                .stdin(subprocess::NullFile)
-                .cwd(&cwd),
-            cwd: cwd,
-            version: version,
+            command: command.map(String::as_str).unwrap_or("cargo").to_owned(),
+            version,
+            priority,
+            repo_dir: tmp_dir.path().to_path_buf(),
+            cwd,
+            env: vec![],
+            mounts: vec![],
+            container: None,
+            sandbox: None,
+            remote: None,
+            offline: false,
+            log_file: None,
+            stream_prefix: None,
+            limits: None,
             _ref: tmp_dir.into(),
         }
     }
 
-    /// Gets a parsed version of the toml file
-    pub fn toml(&self) -> anyhow::Result<CargoToml> {
-        let toml_path = self.cwd.join("Cargo.toml");
-        let toml_str = fs::read_to_string(&toml_path)
-            .with_context(|| format!("reading {}", toml_path.to_string_lossy()))?;
-        let toml: CargoToml = toml::from_str(&toml_str)
-            .with_context(|| format!("parsing {}", toml_path.to_string_lossy()))?;
-        Ok(toml)
+    /// Sets the directory that cargo puts build artifacts in
+    pub fn target_dir(mut self, dir: &Path) -> Self {
+        self.env.push(("CARGO_TARGET_DIR".into(), dir.into()));
+        self.mounts.push(dir.to_path_buf());
+        self
+    }
+
+    /// Sets the file to save the output of the next commands in
+    ///
+    /// When there is a log file, failures refer to it rather than including
+    /// the command's output.
+    pub fn log_file(mut self, path: PathBuf) -> Self {
+        self.log_file = Some(path);
+        self
+    }
+
+    /// Prints the output of the next commands as it is produced, with each
+    /// line prefixed by `prefix`
+    pub fn stream(mut self, prefix: String) -> Self {
+        self.stream_prefix = Some(prefix);
+        self
+    }
+
+    /// Sets the cargo home directory, where downloaded dependencies are kept
+    pub fn cargo_home(mut self, dir: &Path) -> Self {
+        self.env.push(("CARGO_HOME".into(), dir.into()));
+        self.mounts.push(dir.to_path_buf());
+        self
+    }
+
+    /// Limits the number of jobs cargo runs at once, and the number of
+    /// threads test binaries run tests on
+    ///
+    /// This is done through the environment so that it also applies to
+    /// cargo subcommands, and to the tests which cargo runs.
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.env
+            .push(("CARGO_BUILD_JOBS".into(), jobs.to_string().into()));
+        self.env
+            .push(("RUST_TEST_THREADS".into(), jobs.to_string().into()));
+        self
+    }
+
+    /// Limits the resources which the next commands may use
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = Some(limits);
+        self
+    }
+
+    /// Forbids cargo from accessing the network
+    ///
+    /// This is done through the environment rather than `--offline` so
+    /// that it also applies to cargo subcommands such as `cargo hfuzz`.
+    pub fn offline(mut self) -> Self {
+        self.env.push(("CARGO_NET_OFFLINE".into(), "true".into()));
+        self.offline = true;
+        self
+    }
+
+    /// Runs the next commands in a container
+    ///
+    /// The toolchain is whatever the container's image has, rather than
+    /// being chosen with a `+<version>` argument. Unless a cargo home is
+    /// set, one is created in the checkout, so that dependencies can be
+    /// downloaded; the container only has network access if cargo is not
+    /// offline.
+    pub fn container(mut self, container: Container) -> Self {
+        self.container = Some(container);
+        self
+    }
+
+    /// Runs the next commands in a sandbox
+    ///
+    /// The sandbox only has network access if cargo is not offline.
+    pub fn sandbox(mut self, sandbox: Sandbox) -> Self {
+        self.sandbox = Some(sandbox);
+        self
+    }
+
+    /// Runs the next commands on a worker, in a copy of the checkout there
+    ///
+    /// The worker's own toolchains and cargo home are used, so this should
+    /// not be combined with a target directory, cargo home, container or
+    /// sandbox, all of which are on this machine.
+    pub fn remote(mut self, remote: Remote) -> Self {
+        self.remote = Some(remote);
+        self
+    }
+
+    /// Constructs the command to run cargo with the given arguments
+    fn exec(&self, args: Args) -> subprocess::Exec {
+        self.exec_program(self.command.clone().into(), args)
+    }
+
+    /// Constructs the command to run rustc with the given arguments
+    fn rustc(&self, args: Args) -> subprocess::Exec {
+        let program = if self.command.contains('/') {
+            Path::new(&self.command).with_file_name("rustc").into()
+        } else {
+            "rustc".into()
+        };
+        self.exec_program(program, args)
+    }
+
+    /// Constructs the command to run cargo or rustc, on the host, in a
+    /// container, in a sandbox or on a worker
+    fn exec_program(&self, program: OsString, args: Args) -> subprocess::Exec {
+        let mut argv: Vec<OsString> = self
+            .priority
+            .wrapper()
+            .into_iter()
+            .map(Into::into)
+            .collect();
+        argv.push(program);
+        if !self.command.contains('/') && self.container.is_none() {
+            argv.push(format!("+{}", self.version).into());
+        }
+        argv.extend(args.args);
+        let mut env = self.env.clone();
+        env.extend(args.env);
+
+        if let Some(ref remote) = self.remote {
+            let cwd = self.cwd.strip_prefix(&self.repo_dir).unwrap_or(&self.cwd);
+            return remote.exec(&argv, &env, cwd);
+        }
+        let mut mounts = vec![self.repo_dir.clone()];
+        mounts.extend(self.mounts.iter().cloned());
+        if let Some(ref sandbox) = self.sandbox {
+            return sandbox.exec(&argv, &env, &self.cwd, &mounts, !self.offline);
+        }
+        match self.container {
+            Some(ref container) => {
+                if !env.iter().any(|(key, _)| key == "CARGO_HOME") {
+                    env.push((
+                        "CARGO_HOME".into(),
+                        self.repo_dir.join(".cargo-home").into(),
+                    ));
+                }
+                container.exec(
+                    &self.version,
+                    &argv,
+                    &env,
+                    &self.cwd,
+                    &mounts,
+                    !self.offline,
+                )
+            }
+            None => subprocess::Exec::cmd(&argv[0])
+                .args(&argv[1..])
+                .env_extend(&env)
+                .cwd(&self.cwd)
+                .stdin(subprocess::NullFile),
+        }
+    }
+
+    /// Gets the package and target information of the crate (or workspace)
+    /// from `cargo metadata`
+    pub fn metadata(&self) -> anyhow::Result<Metadata> {
+        let exec = self.exec(
+            Args::default()
+                .arg("metadata")
+                .arg("--format-version=1")
+                .arg("--no-deps"),
+        );
+        let invocation = exec.to_cmdline_lossy();
+        let capture = exec
+            .stdout(subprocess::Redirection::Pipe)
+            .stderr(subprocess::Redirection::Pipe)
+            .capture()
+            .with_context(|| format!("running {}", invocation))?;
+        if !capture.success() {
+            return Err(anyhow::Error::msg(format!(
+                "{}: exited with {:?}\nstderr:\n{}",
+                invocation,
+                capture.exit_status,
+                capture.stderr_str(),
+            )));
+        }
+        serde_json::from_slice(&capture.stdout)
+            .with_context(|| format!("parsing output of {}", invocation))
     }
 
     /// Gets the version string of the cargo instance
     pub fn version_string(&self) -> anyhow::Result<String> {
         let mut popen = self
-            .exec
-            .clone()
-            .arg("-V")
+            .exec(Args::default().arg("-V"))
             .stdout(subprocess::Redirection::Pipe)
             .stderr(subprocess::Redirection::Pipe)
             .popen()
@@ -93,10 +308,8 @@ impl<'a> Cargo<'a> {
 
     /// Gets the version string of the cargo instance
     pub fn rustc_version_string(&self) -> anyhow::Result<String> {
-        let exec = subprocess::Exec::cmd("rustc")
-            .arg(format!("+{}", self.version))
-            .stdin(subprocess::NullFile)
-            .arg("-V")
+        let exec = self
+            .rustc(Args::default().arg("-V"))
             .stdout(subprocess::Redirection::Pipe)
             .stderr(subprocess::Redirection::Pipe);
         let invocation = exec.to_cmdline_lossy();
@@ -117,23 +330,49 @@ impl<'a> Cargo<'a> {
     }
 
     fn pin_dep(&self, dep: &str, version: &str) {
-        let exec = self.exec.clone().arg("update");
-        println!("Version {}: pinning {} to {}. ", self.version, dep, version);
-        if let Err(e) = exec_or_stderr(exec.arg("-p").arg(dep).arg("--precise").arg(version)) {
-            println!(
+        let exec = self.exec(
+            Args::default()
+                .arg("update")
+                .arg("-p")
+                .arg(dep)
+                .arg("--precise")
+                .arg(version),
+        );
+        say!(
+            Verbose,
+            "Version {}: pinning {} to {}. ",
+            self.version,
+            dep,
+            version
+        );
+        if let Err(e) = exec_or_stderr(exec) {
+            say!(
+                Verbose,
                 "failed) Version {}: pinning {} to {}. Error {}",
-                self.version, dep, version, e
+                self.version,
+                dep,
+                version,
+                e
             );
         }
     }
 
-    /// Tries to execute the `cargo build` command
-    pub fn pin_deps(&self) -> anyhow::Result<()> {
+    /// Generates a lockfile and pins dependencies to versions that will
+    /// work with old compilers
+    ///
+    /// `extra_pins` are applied in addition to our hardcoded list, but
+    /// only for numbered (i.e. MSRV) toolchains, not `stable` etc.
+    ///
+    /// When offline, the lockfile is generated from the local copy of the
+    /// registry index, and is not updated.
+    pub fn pin_deps(&self, extra_pins: &[(String, String)]) -> anyhow::Result<()> {
         // Gate everything on generating the lockfile. Sometimes we
         // can't, e.g. if the project has `cargo vendor`ed a git repo.
         // In this case we can't pin deps anyway so don't try.
-        if exec_or_stderr(self.exec.clone().arg("generate-lockfile")).is_ok() {
-            exec_or_stderr(self.exec.clone().arg("update"))?;
+        if exec_or_stderr(self.exec(Args::default().arg("generate-lockfile"))).is_ok() {
+            if !self.offline {
+                exec_or_stderr(self.exec(Args::default().arg("update")))?;
+            }
             if &self.version[..] < "1.31.0" {
                 // Also don't report failure on any of these, since we don't
                 // know which deps are actually used
@@ -143,61 +382,694 @@ impl<'a> Cargo<'a> {
                 self.pin_dep("serde", "1.0.98");
                 self.pin_dep("serde_derive", "1.0.98");
             }
+            if self.version.starts_with(|c: char| c.is_ascii_digit()) {
+                for (dep, version) in extra_pins {
+                    self.pin_dep(dep, version);
+                }
+            }
         }
         Ok(())
     }
 
+    /// Downloads all dependencies in the lockfile
+    pub fn fetch(&self) -> anyhow::Result<()> {
+        exec_or_stderr(self.exec(Args::default().arg("fetch")))
+    }
+
     /// Tries to execute the `cargo build` command
-    pub fn build(&self, features: &[String]) -> anyhow::Result<()> {
-        exec_or_stderr(
-            self.exec
-                .clone()
-                .arg("build")
-                .arg(format!("--features={}", features.join(" "))),
+    pub fn build(&self, features: &[String]) -> anyhow::Result<Diagnostics> {
+        self.exec_diagnostics(
+            self.exec(
+                Args::default()
+                    .arg("build")
+                    .arg(format!("--features={}", features.join(" ")))
+                    .arg("--message-format=json"),
+            ),
         )
     }
 
     /// Tries to execute the `cargo test` command
-    pub fn test(&self, features: &[String]) -> anyhow::Result<()> {
-        exec_or_stderr(
-            self.exec
-                .clone()
-                .arg("test")
-                .arg(format!("--features={}", features.join(" "))),
+    pub fn test(&self, features: &[String]) -> anyhow::Result<Diagnostics> {
+        self.exec_diagnostics(
+            self.exec(
+                Args::default()
+                    .arg("test")
+                    .arg(format!("--features={}", features.join(" ")))
+                    .arg("--message-format=json"),
+            ),
         )
     }
 
     /// Tries to execute the `cargo run --example` command
-    pub fn example(&self, ex: &str) -> anyhow::Result<()> {
-        exec_or_stderr(self.exec.clone().arg("run").arg("--example").arg(ex))
+    pub fn example(
+        &self,
+        ex: &str,
+        features: &[String],
+        args: &[String],
+        env: &BTreeMap<String, String>,
+    ) -> anyhow::Result<Diagnostics> {
+        let mut cargo_args = Args::default()
+            .arg("run")
+            .arg("--example")
+            .arg(ex)
+            .arg("--message-format=json");
+        if !features.is_empty() {
+            cargo_args = cargo_args.arg(format!("--features={}", features.join(" ")));
+        }
+        if !args.is_empty() {
+            cargo_args = cargo_args.arg("--").args(args);
+        }
+        for (key, val) in env {
+            cargo_args = cargo_args.env(key, val);
+        }
+        self.exec_diagnostics(self.exec(cargo_args))
     }
 
-    /// Tries to execute the `cargo run --example` command
-    pub fn fuzz(&self, bin: &str, iters: usize) -> anyhow::Result<()> {
-        let exec = self
-            .exec
-            .clone()
-            .env("HFUZZ_BUILD_ARGS", "--features honggfuzz_fuzz")
-            .env(
-                "HFUZZ_RUN_ARGS",
-                format!("--exit_upon_crash -v -N{}", iters),
-            )
-            .arg("hfuzz")
-            .arg("run")
-            .arg(bin);
-        exec_or_stderr(exec)
+    /// Tries to execute the `cargo hfuzz run` or `cargo fuzz run` command
+    pub fn fuzz(&self, engine: FuzzEngine, bin: &str, iters: usize) -> anyhow::Result<()> {
+        let args = match engine {
+            FuzzEngine::Honggfuzz => Args::default()
+                .env("HFUZZ_BUILD_ARGS", "--features honggfuzz_fuzz")
+                .env(
+                    "HFUZZ_RUN_ARGS",
+                    format!("--exit_upon_crash -v -N{}", iters),
+                )
+                .arg("hfuzz")
+                .arg("run")
+                .arg(bin),
+            FuzzEngine::CargoFuzz => Args::default()
+                .arg("fuzz")
+                .arg("run")
+                .arg(bin)
+                .arg("--")
+                .arg(format!("-runs={}", iters)),
+        };
+        let exec = self.exec(args);
+        let captured = run_captured(exec, self.capture_options())?;
+        if captured.success() {
+            Ok(())
+        } else {
+            Err(anyhow::Error::msg(self.failure(&captured, "", "")))
+        }
+    }
+
+    /// Runs a cargo command given `--message-format=json`, collecting the
+    /// compiler's diagnostics
+    ///
+    /// If the command fails, the error is a [`Failure`], whose message
+    /// contains the rendered compiler errors.
+    fn exec_diagnostics(&self, exec: subprocess::Exec) -> anyhow::Result<Diagnostics> {
+        let captured = run_captured(exec, self.capture_options())?;
+        let diagnostics = Diagnostics::parse(&captured.stdout);
+        if captured.success() {
+            return Ok(diagnostics);
+        }
+
+        let summary = format!(
+            " ({} errors, {} warnings)",
+            diagnostics.errors, diagnostics.warnings
+        );
+        let message = self.failure(&captured, &summary, &diagnostics.rendered_errors.concat());
+        Err(Failure {
+            message,
+            diagnostics,
+        }
+        .into())
+    }
+
+    /// How to log and stream the output of commands
+    fn capture_options(&self) -> CaptureOptions<'_> {
+        CaptureOptions {
+            log: self.log_file.as_deref(),
+            stream_prefix: self.stream_prefix.as_deref(),
+            display: Some(display_line),
+            limits: self.limits.as_ref(),
+        }
+    }
+
+    /// Describes a failed command
+    ///
+    /// The command's output (other than cargo's JSON messages) is included,
+    /// unless it was saved to a log file, in which case that is referred to.
+    fn failure(&self, captured: &Captured, summary: &str, rendered: &str) -> String {
+        let output = match self.log_file {
+            Some(ref path) => format!("full output in {}", path.to_string_lossy()),
+            None => {
+                let other: Vec<&str> = captured
+                    .stdout
+                    .lines()
+                    .filter(|l| !l.starts_with('{'))
+                    .collect();
+                format!(
+                    "output:\n{}\nstderr:\n{}",
+                    other.join("\n"),
+                    captured.stderr
+                )
+            }
+        };
+        let exceeded = match captured.limit_exceeded {
+            Some(ref limit) => format!(" ({})", limit),
+            None => String::new(),
+        };
+        format!(
+            "{}: exited with {:?}{}{}\n{}{}",
+            captured.invocation, captured.status, exceeded, summary, rendered, output,
+        )
+    }
+}
+
+/// Arguments and environment variables for a single command
+///
+/// These are collected before the `subprocess::Exec` which runs the
+/// command is constructed, as in a container the environment has to be
+/// given in the container engine's arguments, ahead of the command itself.
+#[derive(Clone, Debug, Default)]
+struct Args {
+    args: Vec<OsString>,
+    env: Vec<(OsString, OsString)>,
+}
+
+impl Args {
+    /// Adds an argument
+    fn arg(mut self, arg: impl AsRef<OsStr>) -> Self {
+        self.args.push(arg.as_ref().to_owned());
+        self
+    }
+
+    /// Adds several arguments
+    fn args(mut self, args: &[impl AsRef<OsStr>]) -> Self {
+        self.args
+            .extend(args.iter().map(|arg| arg.as_ref().to_owned()));
+        self
+    }
+
+    /// Sets an environment variable
+    fn env(mut self, key: impl AsRef<OsStr>, val: impl AsRef<OsStr>) -> Self {
+        self.env
+            .push((key.as_ref().to_owned(), val.as_ref().to_owned()));
+        self
+    }
+}
+
+/// Scheduling priority to run cargo with
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Priority {
+    /// Niceness, from -20 (most favourable) to 19 (least)
+    pub nice: Option<i32>,
+    /// IO scheduling class
+    pub ionice: Option<IoPriority>,
+}
+
+impl Priority {
+    /// The commands to run cargo under to give it this priority
+    fn wrapper(&self) -> Vec<String> {
+        let mut ret = vec![];
+        if let Some(nice) = self.nice {
+            ret.extend(["nice".to_owned(), "-n".to_owned(), nice.to_string()]);
+        }
+        if let Some(ionice) = self.ionice {
+            let class = match ionice {
+                IoPriority::Realtime(_) => "1",
+                IoPriority::BestEffort(_) => "2",
+                IoPriority::Idle => "3",
+            };
+            ret.extend(["ionice".to_owned(), "-c".to_owned(), class.to_owned()]);
+            if let Some(level) = ionice.level() {
+                ret.extend(["-n".to_owned(), level.to_string()]);
+            }
+        }
+        ret
+    }
+}
+
+/// IO scheduling class, and priority within it, as set by `ionice`
+#[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum IoPriority {
+    /// Always given access to the disk first, at a level from 0 (highest)
+    /// to 7, if given
+    Realtime(Option<u8>),
+    /// The default, at a level from 0 (highest) to 7, if given
+    BestEffort(Option<u8>),
+    /// Only given access to the disk when no other process wants it
+    Idle,
+}
+
+impl IoPriority {
+    /// The level within the class, if any
+    fn level(&self) -> Option<u8> {
+        match *self {
+            IoPriority::Realtime(level) | IoPriority::BestEffort(level) => level,
+            IoPriority::Idle => None,
+        }
+    }
+}
+
+impl fmt::Display for IoPriority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IoPriority::Realtime(_) => f.write_str("realtime")?,
+            IoPriority::BestEffort(_) => f.write_str("best-effort")?,
+            IoPriority::Idle => f.write_str("idle")?,
+        }
+        match self.level() {
+            Some(level) => write!(f, ":{}", level),
+            None => Ok(()),
+        }
+    }
+}
+
+impl FromStr for IoPriority {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (class, level) = match s.split_once(':') {
+            Some((class, level)) => match u8::from_str(level) {
+                Ok(n) if n <= 7 => (class, Some(n)),
+                _ => return Err(format!("bad IO priority level {} in {}", level, s)),
+            },
+            None => (s, None),
+        };
+        match (class, level) {
+            ("realtime", _) => Ok(IoPriority::Realtime(level)),
+            ("best-effort", _) => Ok(IoPriority::BestEffort(level)),
+            ("idle", None) => Ok(IoPriority::Idle),
+            ("idle", Some(_)) => Err(format!("the idle IO class has no levels, in {}", s)),
+            _ => Err(format!(
+                "unknown IO class {} (expected realtime, best-effort or idle)",
+                class
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for IoPriority {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, String> {
+        IoPriority::from_str(&s)
+    }
+}
+
+impl From<IoPriority> for String {
+    fn from(prio: IoPriority) -> String {
+        prio.to_string()
+    }
+}
+
+/// Converts a line of cargo's output into something readable
+///
+/// JSON compiler messages are rendered as rustc would have printed them, and
+/// cargo's other JSON messages are dropped.
+fn display_line(line: &str) -> Option<String> {
+    if !line.starts_with('{') {
+        return Some(line.to_owned());
+    }
+    match serde_json::from_str::<CargoMessage>(line) {
+        Ok(CargoMessage {
+            message: Some(message),
+        }) => Some(message.rendered.unwrap_or(message.message)),
+        Ok(_) => None,
+        Err(_) => Some(line.to_owned()),
+    }
+}
+
+/// Compiler warnings and errors reported during a cargo invocation
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Diagnostics {
+    /// Number of distinct warnings
+    pub warnings: usize,
+    /// Number of distinct errors
+    pub errors: usize,
+    /// The errors, as rustc would have printed them
+    pub rendered_errors: Vec<String>,
+    /// The warnings and errors which point at a file in the crate
+    pub annotations: Vec<Annotation>,
+}
+
+/// A compiler diagnostic pointing at some lines of a file in the crate
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Annotation {
+    /// Path of the file, relative to the root of the workspace
+    pub path: String,
+    pub start_line: usize,
+    pub end_line: usize,
+    /// Either "warning" or "error"
+    pub level: String,
+    /// The diagnostic's headline, e.g. "mismatched types"
+    pub message: String,
+    /// The diagnostic as rustc would have printed it
+    pub rendered: String,
+}
+
+/// A cargo command which failed, with the diagnostics it produced
+#[derive(Debug)]
+pub struct Failure {
+    message: String,
+    pub diagnostics: Diagnostics,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Failure {}
+
+impl Diagnostics {
+    /// Collects the diagnostics from the output of a cargo command run with
+    /// `--message-format=json`
+    ///
+    /// Lines which are not JSON, such as the output of tests or examples,
+    /// are ignored. A diagnostic reported for several targets (e.g. for the
+    /// library and again for its unit tests) is only counted once.
+    pub fn parse(output: &str) -> Self {
+        let mut seen = BTreeSet::new();
+        let mut ret = Diagnostics::default();
+        for line in output.lines().filter(|l| l.starts_with('{')) {
+            let message = match serde_json::from_str::<CargoMessage>(line) {
+                Ok(CargoMessage {
+                    message: Some(message),
+                    ..
+                }) => message,
+                _ => continue,
+            };
+            let rendered = match message.rendered {
+                Some(ref rendered) => rendered.clone(),
+                None => message.message.clone(),
+            };
+            if !seen.insert((message.level.clone(), rendered.clone())) {
+                continue;
+            }
+            let level = match &message.level[..] {
+                "warning" => {
+                    ret.warnings += 1;
+                    "warning"
+                }
+                "error" | "error: internal compiler error" => {
+                    ret.errors += 1;
+                    ret.rendered_errors.push(rendered.clone());
+                    "error"
+                }
+                _ => continue,
+            };
+            // Spans in dependencies or the standard library have absolute
+            // paths, and cannot be pointed at
+            let span = message
+                .spans
+                .iter()
+                .find(|span| span.is_primary && !Path::new(&span.file_name).is_absolute());
+            if let Some(span) = span {
+                ret.annotations.push(Annotation {
+                    path: span.file_name.clone(),
+                    start_line: span.line_start,
+                    end_line: span.line_end,
+                    level: level.to_owned(),
+                    message: message.message,
+                    rendered,
+                });
+            }
+        }
+        ret
     }
 }
 
+/// A line of cargo's JSON output, restricted to the fields we use
+#[derive(Deserialize)]
+struct CargoMessage {
+    /// Only present for compiler messages
+    #[serde(default)]
+    message: Option<CompilerMessage>,
+}
+
+/// A compiler diagnostic in cargo's JSON output
 #[derive(Deserialize)]
-pub struct CargoToml {
+struct CompilerMessage {
+    level: String,
+    message: String,
     #[serde(default)]
-    pub bin: Vec<Example>,
+    rendered: Option<String>,
     #[serde(default)]
-    pub example: Vec<Example>,
+    spans: Vec<Span>,
 }
 
+/// A part of the source code which a compiler diagnostic refers to
 #[derive(Deserialize)]
-pub struct Example {
+struct Span {
+    file_name: String,
+    line_start: usize,
+    line_end: usize,
+    is_primary: bool,
+}
+
+/// Creates an empty cargo home directory for the duration of a run
+///
+/// The user's cargo configuration, if any, is copied in so that settings
+/// like registry mirrors still apply. So are their registry credentials,
+/// unless `credentials` is false, e.g. because jobs will be run in
+/// containers which should have nothing secret from the host.
+pub fn run_local_home(credentials: bool) -> anyhow::Result<TempDir> {
+    let dir = gc::temp_dir().context("creating temporary cargo home")?;
+    let user_home = match env::var_os("CARGO_HOME") {
+        Some(home) => Some(PathBuf::from(home)),
+        None => env::var_os("HOME").map(|home| PathBuf::from(home).join(".cargo")),
+    };
+    if let Some(user_home) = user_home {
+        for name in &["config", "config.toml", "credentials", "credentials.toml"] {
+            if !credentials && name.starts_with("credentials") {
+                continue;
+            }
+            let src = user_home.join(name);
+            if src.is_file() {
+                fs::copy(&src, dir.path().join(name))
+                    .with_context(|| format!("copying {}", src.to_string_lossy()))?;
+            }
+        }
+    }
+    Ok(dir)
+}
+
+/// Parses a list of dependency pins
+///
+/// Each line should either be a `cargo update -p <dep> --precise <version>`
+/// invocation, as found in the MSRV scripts of many projects, or simply
+/// `<dep> <version>`. Blank lines, comments and anything else (e.g. other
+/// shell commands) are ignored.
+pub fn parse_pins(text: &str) -> Vec<(String, String)> {
+    let mut ret = vec![];
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.first() == Some(&"cargo") {
+            let mut dep = None;
+            let mut version = None;
+            for pair in words.windows(2) {
+                match pair[0] {
+                    "-p" | "--package" => dep = Some(pair[1]),
+                    "--precise" => version = Some(pair[1]),
+                    _ => {}
+                }
+            }
+            if let (Some(dep), Some(version)) = (dep, version) {
+                ret.push((unquote(dep), unquote(version)));
+            }
+        } else if words.len() == 2 && words[1].starts_with(|c: char| c.is_ascii_digit()) {
+            ret.push((words[0].to_owned(), words[1].to_owned()));
+        }
+    }
+    ret
+}
+
+/// Strips shell quotes from a word
+fn unquote(s: &str) -> String {
+    s.trim_matches(|c| c == '"' || c == '\'').to_owned()
+}
+
+/// Fuzzing harness used to run fuzz targets
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FuzzEngine {
+    /// `cargo hfuzz`, with targets as `[[bin]]`s of the fuzz crate
+    Honggfuzz,
+    /// `cargo fuzz`, with targets in `fuzz_targets/`
+    CargoFuzz,
+}
+
+/// Output of `cargo metadata`, restricted to the fields we use
+#[derive(Deserialize)]
+pub struct Metadata {
+    pub packages: Vec<Package>,
+    pub workspace_members: Vec<String>,
+}
+
+impl Metadata {
+    /// The packages which are part of the workspace
+    pub fn members(&self) -> impl Iterator<Item = &Package> {
+        self.packages
+            .iter()
+            .filter(move |pkg| self.workspace_members.contains(&pkg.id))
+    }
+
+    /// All targets of a given kind (e.g. `example` or `bin`) across the
+    /// workspace
+    pub fn targets<'a>(&'a self, kind: &'a str) -> impl Iterator<Item = &'a Target> + 'a {
+        self.members()
+            .flat_map(|pkg| pkg.targets.iter())
+            .filter(move |target| target.kind.iter().any(|k| k == kind))
+    }
+
+    /// The fuzzing harness used by the workspace, assuming it is a fuzz crate
+    ///
+    /// Crates set up by `cargo fuzz` depend on `libfuzzer-sys`; anything else
+    /// is assumed to use honggfuzz.
+    pub fn fuzz_engine(&self) -> FuzzEngine {
+        let libfuzzer = self
+            .members()
+            .flat_map(|pkg| pkg.dependencies.iter())
+            .any(|dep| dep.name == "libfuzzer-sys");
+        if libfuzzer {
+            FuzzEngine::CargoFuzz
+        } else {
+            FuzzEngine::Honggfuzz
+        }
+    }
+
+    /// Whether a feature can be passed to `cargo --features`
+    ///
+    /// This is the case for features of any workspace member, its optional
+    /// dependencies, and `dep/feature` for any of its dependencies `dep`.
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.members().any(|pkg| pkg.has_feature(feature))
+    }
+}
+
+/// A single package in the output of `cargo metadata`
+#[derive(Deserialize)]
+pub struct Package {
+    pub id: String,
     pub name: String,
+    pub targets: Vec<Target>,
+    #[serde(default)]
+    pub features: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    pub dependencies: Vec<Dependency>,
+}
+
+impl Package {
+    fn has_feature(&self, feature: &str) -> bool {
+        let mut split = feature.splitn(2, '/');
+        let name = split.next().unwrap_or("");
+        if split.next().is_some() {
+            return name == self.name || self.dependencies.iter().any(|dep| dep.name() == name);
+        }
+        self.features.contains_key(name)
+            || self
+                .dependencies
+                .iter()
+                .any(|dep| dep.optional && dep.name() == name)
+    }
+}
+
+/// A build target (library, binary, example, ...) of a package
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Target {
+    pub name: String,
+    pub kind: Vec<String>,
+    /// Features which must be enabled for the target to be built
+    #[serde(default)]
+    pub required_features: Vec<String>,
+}
+
+/// A dependency of a package
+#[derive(Deserialize)]
+pub struct Dependency {
+    pub name: String,
+    #[serde(default)]
+    pub rename: Option<String>,
+    #[serde(default)]
+    pub optional: bool,
+}
+
+impl Dependency {
+    /// The name the dependency is known by within the package
+    fn name(&self) -> &str {
+        self.rename.as_ref().unwrap_or(&self.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diagnostics() {
+        let output = r#"{"reason":"compiler-message","package_id":"a","message":{"level":"warning","message":"function `f` is never used","rendered":"warning: function `f` is never used\n"}}
+{"reason":"compiler-message","package_id":"a","message":{"level":"warning","message":"function `f` is never used","rendered":"warning: function `f` is never used\n"}}
+{"reason":"compiler-artifact","package_id":"a"}
+running 1 test
+{"reason":"compiler-message","package_id":"a","message":{"level":"error","message":"mismatched types","rendered":"error[E0308]: mismatched types\n","spans":[{"file_name":"/rustc/lib.rs","line_start":1,"line_end":1,"is_primary":true},{"file_name":"src/lib.rs","line_start":1,"line_end":2,"is_primary":false},{"file_name":"src/lib.rs","line_start":3,"line_end":3,"is_primary":true}]}}
+{"reason":"compiler-message","package_id":"a","message":{"level":"failure-note","message":"For more information","rendered":"For more information\n"}}
+{"reason":"build-finished","success":false}
+"#;
+        let diags = Diagnostics::parse(output);
+        assert_eq!(diags.warnings, 1);
+        assert_eq!(diags.errors, 1);
+        assert_eq!(
+            diags.rendered_errors,
+            vec!["error[E0308]: mismatched types\n".to_owned()],
+        );
+        assert_eq!(
+            diags.annotations,
+            vec![Annotation {
+                path: "src/lib.rs".to_owned(),
+                start_line: 3,
+                end_line: 3,
+                level: "error".to_owned(),
+                message: "mismatched types".to_owned(),
+                rendered: "error[E0308]: mismatched types\n".to_owned(),
+            }],
+        );
+    }
+
+    #[test]
+    fn io_priority() {
+        for s in &["realtime", "best-effort:0", "best-effort:7", "idle"] {
+            assert_eq!(IoPriority::from_str(s).unwrap().to_string(), *s);
+        }
+        assert!(IoPriority::from_str("best-effort:8").is_err());
+        assert!(IoPriority::from_str("idle:1").is_err());
+        assert!(IoPriority::from_str("low").is_err());
+
+        let priority = Priority {
+            nice: Some(19),
+            ionice: Some(IoPriority::BestEffort(Some(7))),
+        };
+        assert_eq!(
+            priority.wrapper(),
+            vec!["nice", "-n", "19", "ionice", "-c", "2", "-n", "7"],
+        );
+    }
+
+    #[test]
+    fn pins() {
+        let pins = parse_pins(
+            "
+            #!/bin/sh
+            set -ex
+            # pins for MSRV
+            cargo update -p serde --precise 1.0.98
+            cargo update --package cc@1.0.90 --precise \"1.0.41\"
+            cargo update -p byteorder  # missing version
+
+            serde_json 1.0.39
+            ",
+        );
+        assert_eq!(
+            pins,
+            vec![
+                ("serde".to_owned(), "1.0.98".to_owned()),
+                ("cc@1.0.90".to_owned(), "1.0.41".to_owned()),
+                ("serde_json".to_owned(), "1.0.39".to_owned()),
+            ],
+        );
+    }
 }
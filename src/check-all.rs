@@ -0,0 +1,113 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+use std::env;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use structopt::StructOpt;
+
+use git_utils::config::Config;
+
+#[derive(StructOpt, Debug)]
+struct Opts {
+    /// Extra config file to read, after the global one (may be given
+    /// multiple times)
+    #[structopt(long, number_of_values = 1, parse(from_os_str))]
+    config: Vec<PathBuf>,
+    /// The check-pr binary to run; by default, the one alongside this one
+    #[structopt(long, parse(from_os_str))]
+    check_pr: Option<PathBuf>,
+    /// Only check the repositories with these names, rather than all of
+    /// those configured
+    #[structopt(name = "REPO")]
+    only: Vec<String>,
+}
+
+fn main() -> anyhow::Result<()> {
+    let opts = Opts::from_args();
+    let config = Config::load(&opts.config, None)?;
+    let repos: Vec<_> = config
+        .settings()
+        .serve
+        .iter()
+        .filter(|repo| opts.only.is_empty() || opts.only.contains(&repo.name))
+        .collect();
+    if repos.is_empty() {
+        return Err(anyhow::Error::msg(
+            "no repositories to check: add [[serve]] sections to the config",
+        ));
+    }
+    if let Some(name) = opts
+        .only
+        .iter()
+        .find(|name| !repos.iter().any(|repo| &&repo.name == name))
+    {
+        return Err(anyhow::Error::msg(format!(
+            "repository {} is not configured",
+            name
+        )));
+    }
+    let check_pr = match opts.check_pr {
+        Some(path) => path,
+        None => env::current_exe()
+            .context("finding our own binary")?
+            .with_file_name("check-pr"),
+    };
+
+    // Repositories and PRs are checked one at a time, so that each run has
+    // the build pool, and whatever target cache is configured, to itself.
+    // Every PR is checked, not just those which have changed, and check-pr
+    // skips the jobs which have already passed.
+    let mut n_failed = 0;
+    for repo in repos {
+        let updates = match repo.all_prs() {
+            Ok(updates) => updates,
+            Err(e) => {
+                eprintln!("Error fetching {}: {:?}", repo.name, e);
+                n_failed += 1;
+                continue;
+            }
+        };
+        println!("{}: {} PRs to check", repo.name, updates.len());
+        for update in updates {
+            let desc = format!("{}#{}", update.repo, update.number);
+            println!("Checking {}", desc);
+            match update.check(repo, &check_pr) {
+                Ok(true) => println!("Checks passed on {}", desc),
+                Ok(false) => {
+                    println!("Checks failed on {}", desc);
+                    n_failed += 1;
+                }
+                Err(e) => {
+                    eprintln!("Error checking {}: {:?}", desc, e);
+                    n_failed += 1;
+                }
+            }
+        }
+    }
+
+    if n_failed > 0 {
+        Err(anyhow::Error::msg(format!(
+            "{} PR(s) or repositories failed",
+            n_failed
+        )))
+    } else {
+        Ok(())
+    }
+}
@@ -16,55 +16,316 @@
 // Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
 //
 
-mod cargo;
-mod checks;
-mod git;
-mod job;
-mod pr;
-
-use std::collections::HashSet;
-use std::sync::mpsc;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
 use git2::Repository;
-use rayon::ThreadPoolBuilder;
 use structopt::StructOpt;
 
-use self::pr::PullRequest;
+use git_utils::checks::{
+    checks_from_tree, depends_on_commit, take_failures, Check, CommitPosition, RunOptions, Trailers,
+};
+use git_utils::config::{Config, Settings, Source};
+use git_utils::disk;
+use git_utils::gc;
+use git_utils::identity::Identity;
+use git_utils::job::{self, BuildPools, Semaphore};
+use git_utils::lock::RunLock;
+use git_utils::notes::{
+    fetch_notes, merge_entries, note_text, push_notes, read_entries, read_header, read_notes,
+    upgrade_entry, NoteEntry, NoteHeader, NotesMerge, NOTES_VERSION, SKIPPED_NOTE,
+};
+use git_utils::notify::RunReport;
+use git_utils::output::{
+    finished_jobs, report, use_color, Event, Format, Outcome, Summary, Verbosity,
+};
+use git_utils::pr::{BaseBranches, PullRequest};
+use git_utils::resume::{self, Journal};
+use git_utils::runs::{last_tips, record_run, superseded_commits};
+use git_utils::say;
+use git_utils::serve::{fetch, PrUpdate};
+use git_utils::tui::Dashboard;
 
 #[derive(StructOpt, Debug)]
 struct Opts {
     /// Repository to read
     #[structopt(short, long, default_value = ".")]
     repo: String,
-    /// The tip of the PR to check
-    #[structopt(short, long)]
-    tip: String,
-    /// The "master" branch the PR was forked from
-    #[structopt(short, long, default_value = "master")]
-    master: String,
-    /// Whether to accept PRs that have merge commits in them. We cannot
-    /// do rebase-testing of these.
+    /// Pick up the last run on the repository where it left off, after it
+    /// was interrupted or crashed, without rerunning any job which passed.
+    /// Every other option except --repo is taken from that run.
+    #[structopt(long)]
+    resume: bool,
+    /// If another check-pr is running on the repository, wait for it to
+    /// finish rather than failing straight away
+    #[structopt(long)]
+    wait_for_lock: bool,
+    /// The tip of the PR to check. May be given multiple times, or as a
+    /// glob of refs such as "pr/*/head", to check several PRs at once.
+    #[structopt(
+        short,
+        long,
+        number_of_values = 1,
+        required_unless_one = &["show-config", "validate-only", "pr", "range", "resume", "gc"]
+    )]
+    tip: Vec<String>,
+    /// Check every commit in this range (e.g. "v1.0..1.x"), as it is,
+    /// rather than the commits of a PR rebased onto master
+    #[structopt(long, conflicts_with_all = &["tip", "pr"])]
+    range: Option<String>,
+    /// Rather than running every check on every commit, find the first
+    /// commit on which the check with this number (as numbered in the
+    /// summary table, from 1) fails, assuming that it fails on the tip.
+    /// Only the PR's commits as rebased onto its base are searched, if it
+    /// was rebased, or else its own first-parent commits.
+    #[structopt(long, conflicts_with = "test-merge")]
+    bisect: Option<usize>,
+    /// Also run every check on the merge of each PR into master, as it
+    /// would be merged, to catch conflicts which neither shows on its own
+    #[structopt(long, conflicts_with = "range")]
+    test_merge: bool,
+    /// The "master" branch the PR was forked from. May be given several
+    /// times (e.g. for release branches), in which case the PR is taken to
+    /// be based on whichever it forked from. With --pr, the name of the
+    /// branch on the remote, if there is no forge configured to look it up
+    /// on. By default, the branch --remote's HEAD points to, or else
+    /// init.defaultBranch, or else master.
+    #[structopt(short, long, number_of_values = 1)]
+    master: Vec<String>,
+    /// With several --master branches, rebase-test PRs onto every one of
+    /// them, rather than just the one each was forked from
+    #[structopt(long, conflicts_with = "no-rebase")]
+    rebase_onto_all: bool,
+    /// Only check PRs' own commits, not their commits rebased onto master
+    #[structopt(long, conflicts_with_all = &["rebase-only", "range"])]
+    no_rebase: bool,
+    /// Only check PRs' commits rebased onto master, not their own commits,
+    /// unless they cannot be rebased
+    #[structopt(long, conflicts_with = "range")]
+    rebase_only: bool,
+    /// Fetch PR number <pr> and the branch it targets from --remote, then
+    /// check it, in place of giving --tip
+    #[structopt(long, conflicts_with = "tip")]
+    pr: Option<usize>,
+    /// Remote to fetch --pr from
+    #[structopt(long, default_value = "origin")]
+    remote: String,
+    /// Ref on the remote from which to fetch --pr, with `*` standing for
+    /// its number. Defaults to the configured forge's convention, e.g.
+    /// "refs/merge-requests/*/head" for GitLab, or else "refs/pull/*/head".
+    #[structopt(long)]
+    pr_ref: Option<String>,
+    /// Before checking anything, fetch the heads of every PR (from
+    /// --pr-ref) and the --master branches from this remote, along with
+    /// its configured refspecs, and take the --master branches from it
+    #[structopt(long, conflicts_with = "pr")]
+    fetch: Option<String>,
+    /// Whether to accept PRs that have merge commits in them. Their merges
+    /// are redone when rebase-testing, but PRs with octopus merges are
+    /// only checked as they are.
     #[structopt(long)]
     allow_merges: bool,
-    /// The actual check to do
+    /// Skip commits whose message contains this marker (may be given
+    /// multiple times; defaults to "[skip ci]" and "[no ci]")
+    #[structopt(long = "skip-marker")]
+    skip_markers: Vec<String>,
+    /// Also run the checks listed in each commit's own .rsgit/checks.json
+    /// or .rsgit/checks.toml, in addition to those given on the command
+    /// line. These may not set cargo-command or raise their priority, and
+    /// those which run the crate's code need a container or sandbox.
+    #[structopt(long)]
+    tree_config: bool,
+    /// Maximum number of commits to have temporary repos for at once.
+    /// Checks on further commits wait until earlier ones finish.
+    #[structopt(long)]
+    max_concurrent_commits: Option<usize>,
+    /// Extra config file to read, after the global and per-repo ones (may
+    /// be given multiple times)
+    #[structopt(long, parse(from_os_str))]
+    config: Vec<PathBuf>,
+    /// Number of threads in the build pool, i.e. jobs run at once. By
+    /// default, enough to give each a few CPUs and enough memory.
+    #[structopt(long, alias = "build-jobs")]
+    build_threads: Option<usize>,
+    /// Number of the build pool's threads kept for light jobs (builds), so
+    /// that they are not held up behind heavy ones (tests, examples and
+    /// fuzzing). By default, a quarter of them.
+    #[structopt(long)]
+    light_threads: Option<usize>,
+    /// Number of jobs each cargo invocation may run at once (cargo's -j),
+    /// and threads each test binary may use. By default, the CPUs divided
+    /// by --build-threads.
+    #[structopt(short, long)]
+    jobs: Option<usize>,
+    /// Memory each job may use, e.g. 4G, so that one job cannot run the
+    /// machine out of memory
+    #[structopt(long)]
+    memory_limit: Option<String>,
+    /// CPU time, in seconds, each process of a job may use
+    #[structopt(long)]
+    cpu_time_limit: Option<u64>,
+    /// Cgroup (v2) under which to create one for each job, to limit its
+    /// memory. By default, check-pr's own cgroup if possible; otherwise
+    /// each process's address space is limited instead
+    #[structopt(long)]
+    cgroup: Option<String>,
+    /// Ref under which to record check results
+    #[structopt(long)]
+    notes_ref: Option<String>,
+    /// Ref under which to also record check results on each commit's tree,
+    /// so that a commit with the same tree as one already checked, as after
+    /// a rebase, is not checked again
+    #[structopt(long)]
+    tree_notes_ref: Option<String>,
+    /// What to do about jobs which an earlier run recorded as failing:
+    /// "retry" them (the default), "skip" them, counting them as failing
+    /// again, or "retry-after(<hours>)" skip them until that long after
+    /// they failed
+    #[structopt(long)]
+    failure_policy: Option<String>,
+    /// GPG key with which to sign the notes commits, so that whoever reads
+    /// the notes can verify where they came from
+    #[structopt(long)]
+    signing_key: Option<String>,
+    /// Remote to fetch notes from before the run, merging them into ours so
+    /// that jobs another machine has already run are not run again
+    #[structopt(long)]
+    fetch_notes: Option<String>,
+    /// Remote to push the notes to once the run is over, merging in any
+    /// notes pushed there by others in the meantime
+    #[structopt(long)]
+    push_notes: Option<String>,
+    /// Directory in which to keep cargo target directories shared between
+    /// commits, one per toolchain and feature set, so that dependencies are
+    /// not rebuilt for every job
+    #[structopt(long)]
+    target_cache: Option<String>,
+    /// Directory in which to create temporary checkouts, e.g. one on a
+    /// tmpfs, rather than the system's temporary directory
+    #[structopt(long)]
+    temp_dir: Option<String>,
+    /// Disk space which temporary checkouts and the target cache may use,
+    /// e.g. 50G. Jobs wait to start while more is used, and build
+    /// directories are deleted as soon as no job needs them.
+    #[structopt(long)]
+    disk_budget: Option<String>,
+    /// Have temporary checkouts borrow the repository's objects through
+    /// alternates rather than linking or copying them. Cannot be used with
+    /// containers, sandboxes or workers.
+    #[structopt(long)]
+    share_objects: bool,
+    /// Directory in which to keep clones of submodules fetched to check out
+    /// commits, rather than in the repository's git directory
+    #[structopt(long)]
+    submodule_cache: Option<String>,
+    /// Check out the contents of Git LFS files, rather than pointers to
+    /// them. Needs git-lfs.
+    #[structopt(long)]
+    lfs: bool,
+    /// Directory in which to keep Git LFS objects, rather than in the
+    /// repository's own LFS storage
+    #[structopt(long)]
+    lfs_cache: Option<String>,
+    /// Fetch every commit's dependencies into a fresh cargo home before
+    /// running its jobs, then run them offline
+    #[structopt(long)]
+    prefetch: bool,
+    /// SQLite database in which to record the result of every job
+    #[structopt(long)]
+    results_db: Option<String>,
+    /// Run cargo without network access, using only dependencies which are
+    /// already in the local cache
+    #[structopt(long)]
+    offline: bool,
+    /// Save the full output of every job in a file in this directory, named
+    /// after the commit, toolchain, job and features
+    #[structopt(long, parse(from_os_str))]
+    log_dir: Option<PathBuf>,
+    /// Print the output of every job as it is produced, with each line
+    /// prefixed by the commit, toolchain and job it comes from
+    #[structopt(long)]
+    stream: bool,
+    /// Show a live dashboard of the jobs being run, rather than printing
+    /// progress messages
+    #[structopt(long, conflicts_with = "stream")]
+    tui: bool,
+    /// Output format: "human", or "json-lines" to print each event as a
+    /// line of JSON for other programs to consume
+    #[structopt(
+        long,
+        default_value = "human",
+        conflicts_with_all = &["stream", "tui"]
+    )]
+    output: Format,
+    /// Write a JUnit XML report of every job run to this file
+    #[structopt(long, parse(from_os_str))]
+    junit: Option<PathBuf>,
+    /// Write a Markdown table of the results to this file, suitable for
+    /// posting as a PR comment
+    #[structopt(long, parse(from_os_str))]
+    markdown_summary: Option<PathBuf>,
+    /// Serve Prometheus metrics about the jobs being run over HTTP on this
+    /// address (e.g. 127.0.0.1:9184) for as long as the run lasts
+    #[structopt(long)]
+    metrics_addr: Option<String>,
+    /// Only print the result of each check on each commit
+    #[structopt(short, long)]
+    quiet: bool,
+    /// Print more about what is going on (may be given twice, to also
+    /// print every command that is run)
+    #[structopt(short, long, parse(from_occurrences))]
+    verbose: u64,
+    /// Print the effective configuration, and where each setting came
+    /// from, then exit
+    #[structopt(long)]
+    show_config: bool,
+    /// Remove the temporary worktrees and directories left behind by runs
+    /// which crashed or were killed, then exit. This is also done at the
+    /// start of every run.
+    #[structopt(long)]
+    gc: bool,
+    /// Check the configuration for mistakes and print every job it would
+    /// run, then exit without running anything. If --tip is given, the
+    /// checks are also validated against the crate at that commit.
+    #[structopt(long)]
+    validate_only: bool,
+    /// The checks to do, as a JSON list. Overrides any checks given in the
+    /// config files.
     #[structopt(name = "CHECK")]
-    check: String,
+    check: Option<String>,
 }
 
 struct ThreadData {
-    rx: mpsc::Receiver<anyhow::Result<Vec<String>>>,
+    rx: mpsc::Receiver<(anyhow::Result<Vec<String>>, Duration)>,
     commit: git2::Oid,
+    /// Row of the summary table for the commit
+    row: usize,
     desc: String,
+    /// Description of the check as configured, before trailers are applied
+    column: String,
+    /// Whether the check failing should not fail the run
+    allow_failure: bool,
 }
 
 /// Wrapper for the functionality of main to get the ability to spawn scoped threads
 fn real_main<'s>(
     s: &rayon::Scope<'s>,
-    check_list: &'s [self::checks::Check],
+    settings: &'s Settings,
     opts: &Opts,
-    build_pool: &'s rayon::ThreadPool,
+    tips: &[String],
+    build_pools: &'s BuildPools,
+    run_options: &'s RunOptions,
+    summary: &mut Summary,
 ) -> anyhow::Result<()> {
+    let check_list = &settings.check[..];
+    let notes_ref = settings.notes_ref();
+    let tree_notes_ref = settings.tree_notes_ref();
+
     // 0. Open repo.
     let repo = Repository::open_ext(
         &opts.repo,
@@ -72,48 +333,568 @@ fn real_main<'s>(
         Option::<String>::None,
     )
     .with_context(|| format!("Opening repo {}", opts.repo))?;
+    let identity = identity(settings, &repo);
+    identity.check_signing_key(&repo)?;
+    if let Some(remote) = settings.fetch_notes() {
+        // Without them, the run just repeats some work
+        for notes_ref in std::iter::once(notes_ref).chain(tree_notes_ref) {
+            match fetch_notes(&repo, &identity, remote, notes_ref) {
+                Ok(true) => say!(Normal, "Merged {} from {}", notes_ref, remote),
+                Ok(false) => {}
+                Err(e) => eprintln!("Failed to fetch {} from {}: {:?}", notes_ref, remote, e),
+            }
+        }
+    }
 
-    // 1. Compute first-parent history of master to determine where
-    //    the fork point of the PR was
-    let mut parent_commits = HashSet::new();
-    let rf = repo
-        .revparse_single(&opts.master)
-        .with_context(|| format!("looking up master ref {}", opts.master))?;
-
-    let master_id = rf.id();
-    let master_tip = repo
-        .find_commit(master_id)
-        .with_context(|| format!("reading master oid {} as a commit", master_id))?;
-    let mut parent = Ok(master_tip.clone());
-    while let Ok(parent_commit) = parent {
-        parent_commits.insert(parent_commit.id());
-        parent = parent_commit.parent(0);
+    // The PRs' tips, to notice any force-pushed since they were last checked
+    let mut tip_ids = vec![];
+    if opts.range.is_none() {
+        let last_tips = last_tips(&repo);
+        for tip in tips {
+            // Any error is reported once we go looking for the PR's commits
+            let id = match repo.revparse_single(tip) {
+                Ok(obj) => obj.id(),
+                Err(_) => continue,
+            };
+            if let Some(&old) = last_tips.get(tip) {
+                let superseded = superseded_commits(&repo, old, id)?;
+                if !superseded.is_empty() {
+                    say!(
+                        Normal,
+                        "Note: {} was force-pushed from {} to {} since it was last checked; \
+                         the results of its {} dropped commits are superseded.",
+                        tip,
+                        old,
+                        id,
+                        superseded.len()
+                    );
+                }
+            }
+            tip_ids.push((tip.clone(), id));
+        }
     }
-    println!(
-        "Found {} parent commits starting from master {}",
-        parent_commits.len(),
-        master_id
+    let run_id = record_run(
+        &repo,
+        &identity,
+        check_list,
+        &tip_ids,
+        &match opts.range {
+            Some(ref range) => format!("check-pr run on range {}", range),
+            None => format!(
+                "check-pr run on {} (master {})",
+                tips.join(", "),
+                opts.master.join(", ")
+            ),
+        },
+    )?;
+    say!(Normal, "Recorded check configuration as run {}", run_id);
+
+    let mut result = Ok(());
+    let mut pr_commit_set = HashMap::new();
+    // Merges of PRs into master made for --test-merge, on which every check
+    // is run
+    let mut merge_commits = HashSet::new();
+    // Commits made by us, by rebasing or merging, and the PR commits they
+    // were made from, whose metadata checks such as signatures look at
+    let mut sources = HashMap::new();
+    // The commits `--bisect` searches, from the base
+    let mut series = vec![];
+    if let Some(ref range) = opts.range {
+        // 1-4. Check every commit of the range as it is, in order
+        for (id, pos) in range_commits(&repo, range, opts.allow_merges)? {
+            pr_commit_set.insert(id, (pos.index, pos));
+        }
+        series = first_parent_commits(&repo, range)?;
+    } else {
+        // 1. Look up each master, from which the PRs were forked
+        let mut masters = vec![];
+        for name in &opts.master {
+            let master = Master::new(&repo, name)?;
+            say!(Verbose, "Found master {} at {}", name, master.tip.id());
+            masters.push(master);
+        }
+        let base_branches = BaseBranches::new(masters.iter().map(|m| m.tip.id()).collect());
+
+        // 2-4. Get the commits of each PR. A commit shared by several PRs is
+        //      only checked once, in the first of them; the PRs' commits
+        //      are listed one after the other in the summary.
+        let mut n_rows = 0;
+        for tip in tips {
+            if tips.len() > 1 {
+                say!(Normal, "Finding commits of {}", tip);
+            }
+            let PrCommits {
+                base,
+                commits,
+                sources: pr_sources,
+                series: pr_series,
+            } = match pr_commits(&repo, &identity, opts, tip, &masters, &base_branches) {
+                Ok(commits) => commits,
+                // Carry on with the other PRs rather than giving up on all of them
+                Err(e) if tips.len() > 1 => {
+                    say!(Quiet, "Not checking {}: {:#}", tip, e);
+                    result = Err(e.context(format!("finding commits of {}", tip)));
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            sources.extend(pr_sources);
+            series = pr_series;
+            let offset = n_rows;
+            for (id, pos) in commits {
+                if let Entry::Vacant(entry) = pr_commit_set.entry(id) {
+                    entry.insert((n_rows, pos));
+                    n_rows += 1;
+                }
+            }
+
+            // Listed after the PR's own commits, as its new tip
+            if opts.test_merge {
+                match merge_commit(&repo, &identity, tip, &masters[base].tip) {
+                    Ok(Some(id)) => {
+                        let index = n_rows - offset;
+                        let pos = CommitPosition {
+                            index,
+                            n_commits: index + 1,
+                        };
+                        if let Entry::Vacant(entry) = pr_commit_set.entry(id) {
+                            entry.insert((n_rows, pos));
+                            n_rows += 1;
+                        }
+                        merge_commits.insert(id);
+                        // Whose second parent is the PR's tip
+                        match repo.find_commit(id).and_then(|merge| merge.parent_id(1)) {
+                            Ok(pr_tip) => {
+                                sources.insert(id, pr_tip);
+                            }
+                            Err(e) => result = Err(e).context("looking up merge commit"),
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        say!(Quiet, "Cannot test the merge of {}: {:#}", tip, e);
+                        result = Err(e.context(format!("merging {} into master", tip)));
+                    }
+                }
+            }
+        }
+    }
+
+    if let Some(number) = opts.bisect {
+        let check = &check_list[number - 1];
+        return bisect(
+            &repo,
+            &identity,
+            notes_ref,
+            tree_notes_ref,
+            check,
+            &series,
+            build_pools,
+            run_options,
+            summary,
+        );
+    }
+
+    // 5. Spawn new repos for all of our checks and execute them
+
+    let mut exec_threads = vec![];
+    if opts.max_concurrent_commits == Some(0) {
+        return Err(anyhow::Error::msg(
+            "--max-concurrent-commits must be at least 1",
+        ));
+    }
+    let commit_slots = Semaphore::new(opts.max_concurrent_commits.unwrap_or(usize::MAX));
+
+    let default_markers = ["[skip ci]".to_owned(), "[no ci]".to_owned()];
+    let skip_markers = if opts.skip_markers.is_empty() {
+        &default_markers[..]
+    } else {
+        &opts.skip_markers[..]
+    };
+
+    // Jobs are run in the order they are spawned in, so start with the
+    // commits nearest each PR's tip, which give the most useful signal
+    // soonest, and the cheapest checks on each. Columns of the summary stay
+    // in the order the checks are configured in.
+    let mut pr_commit_set: Vec<_> = pr_commit_set.into_iter().collect();
+    pr_commit_set.sort_by_key(|&(_, (row, pos))| (pos.n_commits - pos.index, row));
+    for check in check_list {
+        summary.add_check(&check.to_string());
+    }
+
+    // Checkouts are reused from commit to commit rather than created afresh
+    // for each. Commits with the same tree, as after a rebase which dropped
+    // a change, can reuse each other's build output too.
+    let mut tree_counts: HashMap<git2::Oid, usize> = HashMap::new();
+    for &(id, _) in &pr_commit_set {
+        let commit = repo
+            .find_commit(id)
+            .with_context(|| format!("finding commit {}", id))?;
+        *tree_counts.entry(commit.tree_id()).or_insert(0) += 1;
+    }
+    let _pool = git_utils::git::checkout_pool(
+        tree_counts
+            .into_iter()
+            .filter(|&(_, count)| count > 1)
+            .map(|(tree, _)| tree),
     );
 
-    // 2. Get set of commits in the PR (you can use label-pr to assign
+    // Limits on checks' jobs, shared by every commit they are run on
+    let mut limits: HashMap<Check, Semaphore> = HashMap::new();
+    for (id, (row, pos)) in pr_commit_set {
+        if job::cancelled() {
+            break;
+        }
+        if has_skip_note(&repo, notes_ref, id) {
+            say!(
+                Normal,
+                "Skipping all checks on commit {} (previously skipped)",
+                id
+            );
+            for check in check_list {
+                summary.record(row, id, &check.to_string(), Outcome::Skipped);
+            }
+            continue;
+        } else if let Some(marker) = skip_marker(&repo, id, skip_markers)? {
+            say!(
+                Normal,
+                "Skipping all checks on commit {} (message contains {})",
+                id,
+                marker
+            );
+            write_note(&repo, &identity, notes_ref, id, &[SKIPPED_NOTE.to_owned()])?;
+            for check in check_list {
+                summary.record(row, id, &check.to_string(), Outcome::Skipped);
+            }
+            continue;
+        }
+
+        // Held by every check thread of this commit, and released once
+        // they have all finished and dropped their temp repos
+        let commit_permit = Arc::new(commit_slots.acquire());
+        let commit = match repo.find_commit(id) {
+            Ok(commit) => commit,
+            Err(e) => {
+                result = Err(e).with_context(|| format!("looking up commit {}", id));
+                break;
+            }
+        };
+        let trailers = Trailers::from_message(commit.message().unwrap_or(""));
+        if !trailers.is_empty() {
+            say!(
+                Normal,
+                "Commit {} adds checks {:?} and skips checks {:?}",
+                id,
+                trailers.add,
+                trailers.skip,
+            );
+        }
+
+        let mut commit_checks = check_list.to_vec();
+        if opts.tree_config {
+            match checks_from_tree(&repo, &commit) {
+                Ok(Some(tree_checks)) => {
+                    say!(
+                        Normal,
+                        "Commit {} specifies {} checks of its own",
+                        id,
+                        tree_checks.len()
+                    );
+                    if let Some(e) = tree_checks
+                        .iter()
+                        .find_map(|check| settings.validate_tree_check(check).err())
+                    {
+                        result = Err(e.context(format!("reading checks of commit {}", id)));
+                        break;
+                    }
+                    for check in tree_checks {
+                        if !commit_checks.contains(&check) {
+                            commit_checks.push(check);
+                        }
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        commit_checks.sort_by_key(Check::class);
+
+        for check in &commit_checks {
+            let selected = match check.commits().selects(&repo, id, pos) {
+                Ok(selected) => selected || merge_commits.contains(&id),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            };
+            let column = check.to_string();
+            let limit = check.max_parallel().map(|permits| {
+                limits
+                    .entry(check.clone())
+                    .or_insert_with(|| Semaphore::new(permits))
+                    .clone()
+            });
+            let check = match check.for_commit(selected, &trailers) {
+                Some(check) => check,
+                None => {
+                    say!(
+                        Normal,
+                        "Skipping check {} on commit {} (commits: {})",
+                        check,
+                        id,
+                        check.commits()
+                    );
+                    summary.record(row, id, &column, Outcome::Skipped);
+                    continue;
+                }
+            };
+
+            // Checks of a commit's metadata, rather than its code, look at
+            // the PR's own commit rather than one we made from it, which is
+            // neither signed nor carries the PR's trailers as they were
+            let checked = match sources.get(&id) {
+                Some(&source) if !check.runs_code() => source,
+                _ => id,
+            };
+            let fresh_repo = match git_utils::git::pooled_repo(&repo, checked, "commit")
+                .with_context(|| format!("creating temporary repo for {}", checked))
+            {
+                Ok(repo) => repo,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            };
+            summary.record_hash(&column, &check.config_hash());
+            let (tx, rx) = mpsc::channel();
+            let commit_permit = commit_permit.clone();
+            let desc = check.to_string();
+            let allow_failure = check.allow_failure();
+            let existing_notes = known_notes(&repo, notes_ref, tree_notes_ref, id);
+            s.spawn(move |_| {
+                let start = Instant::now();
+                let result = check
+                    .execute(
+                        &fresh_repo,
+                        existing_notes,
+                        build_pools,
+                        run_options,
+                        limit.as_ref(),
+                    )
+                    .with_context(|| format!("executing check {} on commit {}", check, id));
+                // Back to the pool before the next commit can start
+                drop(fresh_repo);
+                drop(commit_permit);
+                tx.send((result, start.elapsed()))
+                    .expect("main still alive")
+            });
+            exec_threads.push(ThreadData {
+                rx,
+                commit: id,
+                row,
+                desc,
+                column,
+                allow_failure,
+            });
+        }
+    }
+
+    // Once interrupted, every job still running has its subprocesses killed,
+    // so this does not wait long; the notes of those which completed are
+    // still written
+    for handle in exec_threads {
+        let (check_result, elapsed) = handle.rx.recv().expect("execution thread to not panic");
+        match check_result
+            .with_context(|| format!("subthread: commit {}, check {}", handle.commit, handle.desc))
+        {
+            Ok(ref notes) => {
+                let outcome = if notes.is_empty() {
+                    Outcome::Cached
+                } else {
+                    Outcome::Pass(elapsed)
+                };
+                summary.record(handle.row, handle.commit, &handle.column, outcome);
+                // Jobs which passed before the run was resumed were not run
+                // again, so their notes come from the journal
+                let mut notes = notes.clone();
+                notes.extend(resume::notes(handle.commit));
+                // Likewise for those which passed on another commit with
+                // the same tree
+                notes.extend(tree_notes(&repo, tree_notes_ref, handle.commit));
+                // Returning early would leave the jobs still running with
+                // nobody to report to, so the run just fails at the end
+                let recorded = write_note(&repo, &identity, notes_ref, handle.commit, &notes)
+                    .and_then(|note_oid| {
+                        if let Some(tree_notes_ref) = tree_notes_ref {
+                            let all_notes = read_notes(&repo, notes_ref, handle.commit);
+                            write_tree_note(
+                                &repo,
+                                &identity,
+                                tree_notes_ref,
+                                handle.commit,
+                                &all_notes,
+                            )?;
+                        }
+                        Ok(note_oid)
+                    });
+                match recorded {
+                    Ok(note_oid) => {
+                        let warnings: usize = read_entries(&repo, notes_ref, handle.commit)
+                            .iter()
+                            .filter_map(|entry| entry.warnings)
+                            .sum();
+                        say!(
+                            Quiet,
+                            "Success on {} ({} compiler warnings). Recorded notes in ref {}",
+                            handle.commit,
+                            warnings,
+                            note_oid
+                        );
+                    }
+                    Err(e) => {
+                        say!(Quiet, "Failed to record notes on {}", handle.commit);
+                        result = Err(e);
+                    }
+                }
+            }
+            Err(_) if job::cancelled() => {
+                say!(
+                    Normal,
+                    "Cancelled check {} on {}",
+                    handle.desc,
+                    handle.commit
+                );
+                summary.record(handle.row, handle.commit, &handle.column, Outcome::Skipped);
+            }
+            Err(e) => {
+                let failures = take_failures(handle.commit);
+                if !failures.is_empty() {
+                    if let Err(e) =
+                        write_note(&repo, &identity, notes_ref, handle.commit, &failures)
+                    {
+                        say!(
+                            Quiet,
+                            "Failed to record failure on {}: {:?}",
+                            handle.commit,
+                            e
+                        );
+                    }
+                }
+                say!(
+                    Quiet,
+                    "Failure on {} (check {}{})",
+                    handle.commit,
+                    handle.desc,
+                    if handle.allow_failure {
+                        ", allowed to fail"
+                    } else {
+                        ""
+                    },
+                );
+                summary.record(
+                    handle.row,
+                    handle.commit,
+                    &handle.column,
+                    Outcome::Fail(elapsed),
+                );
+                if handle.allow_failure {
+                    say!(Normal, "{:?}", e);
+                } else {
+                    result = Err(e);
+                }
+            }
+        }
+    }
+
+    if job::cancelled() {
+        return Err(interrupted());
+    }
+    result
+}
+
+/// The identity with which to write notes, with any signing key from the
+/// settings
+fn identity(settings: &Settings, repo: &Repository) -> Identity {
+    let mut identity = Identity::from_repo(repo, "PR Checker", "prcheck@wpsoftware.net");
+    if let Some(key) = settings.signing_key() {
+        identity.signing_key = Some(key.to_owned());
+    }
+    identity
+}
+
+/// Error for a run cut short by Ctrl-C
+fn interrupted() -> anyhow::Error {
+    anyhow::Error::msg("interrupted")
+}
+
+/// The commits of a PR to be checked, as found by `pr_commits`
+struct PrCommits {
+    /// Index of the master branch the PR was forked from
+    base: usize,
+    /// The rebased commits, then the PR's own, each in order from the base
+    commits: Vec<(git2::Oid, CommitPosition)>,
+    /// The PR commit each rebased commit was made from
+    sources: HashMap<git2::Oid, git2::Oid>,
+    /// The commits for `--bisect` to search, as one first-parent history in
+    /// order from the base: those rebased onto the PR's base branch if it
+    /// was rebased onto it, or else its own
+    series: Vec<git2::Oid>,
+}
+
+/// Finds the commits of the PR with the given tip which are to be checked,
+/// rebasing them onto master if need be
+fn pr_commits(
+    repo: &Repository,
+    identity: &Identity,
+    opts: &Opts,
+    tip: &str,
+    masters: &[Master],
+    base_branches: &BaseBranches,
+) -> anyhow::Result<PrCommits> {
+    // Get set of commits in the PR (you can use label-pr to assign
     //    some sort of ordering to them, but for our purposes here we
     //    just test them all and don't care about the order).
     let rf = repo
-        .revparse_single(&opts.tip)
-        .with_context(|| format!("looking up PR tip ref {}", opts.tip))?;
+        .revparse_single(tip)
+        .with_context(|| format!("looking up PR tip ref {}", tip))?;
     let pr_id = rf.id();
     let pr_tip = repo
         .find_commit(pr_id)
         .with_context(|| format!("reading PR tip oid {} as commit", rf.id()))?;
+    // The commits of the PR which are not on any master, or `None` if it
+    // has no history in common with any of them
+    let branch_commits = base_branches
+        .branch_commits(repo, pr_id)
+        .with_context(|| format!("finding the commits of {} not on master", tip))?;
+    let in_pr: HashSet<_> = branch_commits.iter().flatten().copied().collect();
 
     let mut pr_linear_commits = vec![];
     let mut has_merges = false;
+    // Merges of more than two branches, which we cannot rebase
+    let mut has_octopus = false;
     let mut needs_rebase = true;
+    let mut base = 0;
+    // The PR has a root commit or grafted history
+    let is_orphan = branch_commits.is_none();
     let mut parent = Ok(pr_tip.clone());
     while let Ok(parent_commit) = parent {
         let id = parent_commit.id();
-        if parent_commits.contains(&id) {
-            if id == master_id {
+        if !is_orphan && !in_pr.contains(&id) {
+            // Prefer a master the PR is based on the tip of
+            base = match masters.iter().position(|master| master.tip.id() == id) {
+                Some(base) => base,
+                None => masters
+                    .iter()
+                    .position(|master| {
+                        repo.graph_descendant_of(master.tip.id(), id)
+                            .unwrap_or(false)
+                    })
+                    .with_context(|| format!("fork point {} of {} is on no master", id, tip))?,
+            };
+            if id == masters[base].tip.id() {
                 needs_rebase = false;
             }
             break;
@@ -121,7 +902,11 @@ fn real_main<'s>(
 
         if parent_commit.parent_count() > 1 {
             has_merges = true;
-            println!("Note: commit {} is a merge commit.", id);
+            has_octopus |= parent_commit.parent_count() > 2;
+            say!(Normal, "Note: commit {} is a merge commit.", id);
+        }
+        if parent_commit.parent_count() == 0 {
+            say!(Normal, "Note: commit {} is a root commit.", id);
         }
         parent = parent_commit.parent(0);
         pr_linear_commits.push(parent_commit);
@@ -129,11 +914,25 @@ fn real_main<'s>(
     pr_linear_commits.reverse();
 
     // Alert user about merge/rebaseability story
+    if is_orphan {
+        say!(
+            Normal,
+            "Note: PR has no common ancestor with master (root commit or grafted history). \
+             Skipping rebase-testing and testing its commits as-is."
+        );
+        needs_rebase = false;
+    }
+    if masters.len() > 1 && !is_orphan {
+        say!(Normal, "PR was forked from {}", masters[base].name);
+    }
     if needs_rebase {
-        println!("Note: PR is not based on master.");
+        say!(Normal, "Note: PR is not based on master.");
+    }
+    if opts.no_rebase {
+        needs_rebase = false;
     }
-    if needs_rebase && has_merges {
-        println!("Note: PR is not based on master, but we cannot do rebase-testing as it contains merges.");
+    if needs_rebase && has_octopus {
+        say!(Normal, "Note: PR is not based on master, but we cannot do rebase-testing as it contains octopus merges.");
     }
     if !opts.allow_merges && has_merges {
         return Err(anyhow::Error::msg(
@@ -142,187 +941,1303 @@ fn real_main<'s>(
     }
 
     if !has_merges {
-        println!("Found linear history");
+        say!(Normal, "Found linear history");
         for commit in &pr_linear_commits {
-            println!("    {}", commit.id());
+            say!(Verbose, "    {}", commit.id());
         }
     }
 
-    // 3. Construct rebase commits, if needed and possible
-    let mut pr_commit_set = HashSet::with_capacity(2 * pr_linear_commits.len());
-    if needs_rebase && !has_merges {
-        let worktree = self::git::TempWorktree::new(&repo, None)
-            .context("creating temporary worktree to do rebase in")?;
-        let wt_repo = worktree
-            .repo()
-            .context("getting temporary worktree as repo")?;
+    // Construct rebase commits, if needed and possible
+    let mut pr_commit_set = Vec::with_capacity(2 * pr_linear_commits.len());
+    let mut rebased_onto_base = false;
+    let mut sources = HashMap::new();
+    let mut series: Vec<_> = pr_linear_commits.iter().map(|commit| commit.id()).collect();
+    if !is_orphan && !has_octopus && !opts.no_rebase {
+        for (index, master) in masters.iter().enumerate() {
+            let onto_base = index == base;
+            if (onto_base && !needs_rebase) || (!onto_base && !opts.rebase_onto_all) {
+                continue;
+            }
+            if masters.len() > 1 {
+                say!(Normal, "Rebasing onto {}", master.name);
+            }
+            let rebased_commits = match rebase(repo, identity, &pr_linear_commits, &master.tip) {
+                Ok(commits) => commits,
+                // A PR need not apply to branches other than its own
+                Err(e) if !onto_base => {
+                    say!(Normal, "Skipping {}: {:#}", master.name, e);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            if onto_base {
+                rebased_onto_base = true;
+                series = rebased_commits.iter().map(|&(id, _)| id).collect();
+            }
+            let n_commits = rebased_commits.len();
+            for (index, (id, source)) in rebased_commits.into_iter().enumerate() {
+                pr_commit_set.push((id, CommitPosition { index, n_commits }));
+                sources.insert(id, source);
+            }
+        }
+    }
+    if opts.rebase_only {
+        if rebased_onto_base {
+            return Ok(PrCommits {
+                base,
+                commits: pr_commit_set,
+                sources,
+                series,
+            });
+        }
+        say!(
+            Normal,
+            "Note: PR was not rebased, so checking its own commits."
+        );
+    }
+
+    // Put original commits into our set. If the PR is unrelated to
+    //    master, walking all its ancestors would pull in its entire
+    //    history, so just take the first-parent commits we found above.
+    let original: Vec<_> = match branch_commits {
+        Some(commits) => commits,
+        None => pr_linear_commits.iter().map(|commit| commit.id()).collect(),
+    };
+    let n_commits = original.len();
+    for (index, id) in original.into_iter().enumerate() {
+        pr_commit_set.push((id, CommitPosition { index, n_commits }));
+    }
+
+    Ok(PrCommits {
+        base,
+        commits: pr_commit_set,
+        sources,
+        series,
+    })
+}
+
+/// Cherry-picks a PR's first-parent commits onto a master branch, redoing
+/// any merges among them, returning the IDs of the new commits, each with
+/// that of the commit it was made from
+fn rebase(
+    repo: &Repository,
+    identity: &Identity,
+    commits: &[git2::Commit],
+    onto: &git2::Commit,
+) -> anyhow::Result<Vec<(git2::Oid, git2::Oid)>> {
+    let mut rebased_commits = vec![];
+    let worktree = git_utils::git::TempWorktree::new(repo, None)
+        .context("creating temporary worktree to do rebase in")?;
+    let wt_repo = worktree
+        .repo()
+        .context("getting temporary worktree as repo")?;
+
+    wt_repo
+        .set_head_detached(onto.id())
+        .with_context(|| format!("setting rebase worktree to master {}", onto.id()))?;
+    wt_repo
+        .checkout_head(None)
+        .context("checking out HEAD in rebase worktree")?;
+
+    for commit in commits {
+        let current_head = wt_repo.head().context("getting HEAD")?.target().unwrap();
+        let current_commit = wt_repo
+            .find_commit(current_head)
+            .with_context(|| format!("looking up tip of temp worktree {}", current_head))?;
 
+        let mut merge_opts = git2::MergeOptions::new();
+        merge_opts.fail_on_conflict(true);
+        if commit.parent_count() > 1 {
+            if let Some(new_head) =
+                remerge(&wt_repo, identity, commit, &current_commit, &mut merge_opts)?
+            {
+                rebased_commits.push((new_head, commit.id()));
+            }
+            continue;
+        }
         wt_repo
-            .set_head_detached(master_tip.id())
-            .with_context(|| format!("setting rebase worktree to master {}", master_tip.id()))?;
+            .cherrypick(
+                commit,
+                Some(git2::CherrypickOptions::new().merge_opts(merge_opts)),
+            )
+            .with_context(|| format!("cherry-picking {} onto {}", commit.id(), current_head))?;
+
+        let mut index = wt_repo.index().context("getting index")?;
+        let tree_oid = index.write_tree().context("writing index to tree")?;
+        let tree = wt_repo
+            .find_tree(tree_oid)
+            .context("looking up tree we just created")?;
+        let message = git_utils::git::add_trailer(
+            commit.message().unwrap_or(""),
+            "Cherry-picked-from",
+            &commit.id().to_string(),
+        );
+        // Keep the original commit time so that rebasing the same PR onto
+        // the same master gives the same commit IDs, and existing notes
+        // for them are still found.
+        let committer = identity.signature(Some(&commit.committer().when()))?;
         wt_repo
-            .checkout_head(None)
-            .context("checking out HEAD in rebase worktree")?;
+            .commit(
+                Some("HEAD"),
+                &commit.author(),
+                &committer,
+                &message,
+                &tree,
+                &[&current_commit],
+            )
+            .context("committing cherry-pick")?;
 
-        for commit in &pr_linear_commits {
-            let current_head = wt_repo.head().context("getting HEAD")?.target().unwrap();
-            let current_commit = wt_repo
-                .find_commit(current_head)
-                .with_context(|| format!("looking up tip of temp worktree {}", current_head))?;
-
-            let mut merge_opts = git2::MergeOptions::new();
-            merge_opts.fail_on_conflict(true);
-            wt_repo
-                .cherrypick(
-                    commit,
-                    Some(git2::CherrypickOptions::new().merge_opts(merge_opts)),
-                )
-                .with_context(|| format!("cherry-picking {} onto {}", commit.id(), current_head))?;
-
-            let mut index = wt_repo.index().context("getting index")?;
-            let tree_oid = index.write_tree().context("writing index to tree")?;
-            let tree = wt_repo
-                .find_tree(tree_oid)
-                .context("looking up tree we just created")?;
-            let message = format!(
-                "{}\nCherry-picked from {}\n",
-                commit.message().unwrap_or(""),
-                commit.id()
+        let new_head = wt_repo.head().context("getting HEAD")?.target().unwrap();
+        if new_head == current_head {
+            say!(
+                Verbose,
+                "Skipping cherry-pick of {} onto {} (no change).",
+                commit.id(),
+                new_head
             );
-            wt_repo
-                .commit(
-                    Some("HEAD"),
-                    &commit.author(),
-                    &commit.committer(),
-                    &message,
-                    &tree,
-                    &[&current_commit],
-                )
-                .context("committing cherry-pick")?;
-
-            let new_head = wt_repo.head().context("getting HEAD")?.target().unwrap();
-            if new_head == current_head {
-                println!(
-                    "Skipping cherry-pick of {} onto {} (no change).",
-                    commit.id(),
-                    new_head
-                );
-            } else {
-                pr_commit_set.insert(new_head);
-                println!(
-                    "Cherry-picked {} onto {} as {}.",
-                    commit.id(),
-                    current_head,
-                    new_head
-                );
+        } else {
+            rebased_commits.push((new_head, commit.id()));
+            say!(
+                Verbose,
+                "Cherry-picked {} onto {} as {}.",
+                commit.id(),
+                current_head,
+                new_head
+            );
+        }
+    }
+    Ok(rebased_commits)
+}
+
+/// Redoes a PR's merge commit on top of the rebased commits before it, by
+/// merging its second parent into them afresh, as `git rebase
+/// --rebase-merges` would. Returns the new commit, or `None` if the merged
+/// branch is already in the rebased history (e.g. an upstream merge of
+/// master, rebased onto a newer master).
+fn remerge(
+    wt_repo: &Repository,
+    identity: &Identity,
+    commit: &git2::Commit,
+    current_commit: &git2::Commit,
+    merge_opts: &mut git2::MergeOptions,
+) -> anyhow::Result<Option<git2::Oid>> {
+    let current_head = current_commit.id();
+    let side = commit
+        .parent(1)
+        .with_context(|| format!("looking up second parent of merge {}", commit.id()))?;
+    if side.id() == current_head
+        || wt_repo
+            .graph_descendant_of(current_head, side.id())
+            .context("checking ancestry of merged branch")?
+    {
+        say!(
+            Verbose,
+            "Skipping merge {} onto {} (already merged).",
+            commit.id(),
+            current_head
+        );
+        return Ok(None);
+    }
+
+    let mut index = wt_repo
+        .merge_commits(current_commit, &side, Some(merge_opts))
+        .with_context(|| format!("re-merging {} onto {}", commit.id(), current_head))?;
+    let tree_oid = index
+        .write_tree_to(wt_repo)
+        .context("writing merge to tree")?;
+    let tree = wt_repo
+        .find_tree(tree_oid)
+        .context("looking up tree we just created")?;
+    let message = git_utils::git::add_trailer(
+        commit.message().unwrap_or(""),
+        "Rebased-from",
+        &commit.id().to_string(),
+    );
+    let committer = identity.signature(Some(&commit.committer().when()))?;
+    let new_head = wt_repo
+        .commit(
+            Some("HEAD"),
+            &commit.author(),
+            &committer,
+            &message,
+            &tree,
+            &[current_commit, &side],
+        )
+        .context("committing re-merge")?;
+    // Bring the worktree up to date for the cherry-picks which follow
+    wt_repo
+        .checkout_head(Some(git2::build::CheckoutBuilder::new().force()))
+        .context("checking out HEAD in rebase worktree")?;
+    say!(
+        Verbose,
+        "Re-merged {} onto {} as {}.",
+        commit.id(),
+        current_head,
+        new_head
+    );
+    Ok(Some(new_head))
+}
+
+/// A branch which PRs may be based on
+struct Master<'repo> {
+    name: String,
+    tip: git2::Commit<'repo>,
+}
+
+impl<'repo> Master<'repo> {
+    fn new(repo: &'repo Repository, name: &str) -> anyhow::Result<Self> {
+        let rf = repo
+            .revparse_single(name)
+            .with_context(|| format!("looking up master ref {}", name))?;
+        let master_id = rf.id();
+        let tip = repo
+            .find_commit(master_id)
+            .with_context(|| format!("reading master oid {} as a commit", master_id))?;
+        Ok(Master {
+            name: name.to_owned(),
+            tip,
+        })
+    }
+}
+
+/// Implements --bisect: finds the first of a series of commits on which a
+/// check fails, assuming that it passes on their base and fails on the last
+/// of them
+#[allow(clippy::too_many_arguments)]
+fn bisect(
+    repo: &Repository,
+    identity: &Identity,
+    notes_ref: &str,
+    tree_notes_ref: Option<&str>,
+    check: &Check,
+    commits: &[git2::Oid],
+    build_pools: &BuildPools,
+    run_options: &RunOptions,
+    summary: &mut Summary,
+) -> anyhow::Result<()> {
+    let column = check.to_string();
+    // Every probe runs all of the check's jobs, whatever the commit
+    // selector and trailers say
+    let to_run = check
+        .for_commit(true, &Trailers::default())
+        .ok_or_else(|| {
+            anyhow::Error::msg(format!("check {} has no jobs to bisect with", column))
+        })?;
+    let limit = to_run.max_parallel().map(Semaphore::new);
+    let probe = |index: usize| -> anyhow::Result<bool> {
+        let id = commits[index];
+        say!(
+            Normal,
+            "Bisecting: trying commit {} ({} of {})",
+            id,
+            index + 1,
+            commits.len()
+        );
+        let fresh_repo = git_utils::git::temp_repo(repo, id)
+            .with_context(|| format!("creating temporary repo for {}", id))?;
+        summary.record_hash(&column, &to_run.config_hash());
+        let start = Instant::now();
+        match to_run.execute(
+            &fresh_repo,
+            known_notes(repo, notes_ref, tree_notes_ref, id),
+            build_pools,
+            run_options,
+            limit.as_ref(),
+        ) {
+            Ok(notes) => {
+                let outcome = if notes.is_empty() {
+                    Outcome::Cached
+                } else {
+                    Outcome::Pass(start.elapsed())
+                };
+                summary.record(index, id, &column, outcome);
+                let mut notes = notes;
+                notes.extend(tree_notes(repo, tree_notes_ref, id));
+                write_note(repo, identity, notes_ref, id, &notes)?;
+                if let Some(tree_notes_ref) = tree_notes_ref {
+                    let all_notes = read_notes(repo, notes_ref, id);
+                    write_tree_note(repo, identity, tree_notes_ref, id, &all_notes)?;
+                }
+                say!(Quiet, "Check passes on {}", id);
+                Ok(true)
+            }
+            // Not a failure of the check, so must not steer the search
+            Err(_) if job::cancelled() => Err(interrupted()),
+            Err(e) => {
+                let failures = take_failures(id);
+                if !failures.is_empty() {
+                    write_note(repo, identity, notes_ref, id, &failures)?;
+                }
+                summary.record(index, id, &column, Outcome::Fail(start.elapsed()));
+                say!(Quiet, "Check fails on {}", id);
+                say!(Verbose, "{:?}", e);
+                Ok(false)
             }
         }
+    };
+
+    match first_failure(commits.len(), probe)? {
+        Some(index) => say!(
+            Quiet,
+            "Check {} first fails on commit {} ({} of {})",
+            column,
+            commits[index],
+            index + 1,
+            commits.len()
+        ),
+        None => say!(
+            Quiet,
+            "Check {} passes on the tip; nothing to bisect",
+            column
+        ),
+    }
+    Ok(())
+}
+
+/// Binary-searches `n_commits` commits, in order from the base, for the
+/// first on which `probe` fails, given that it passes on the base; returns
+/// `None` if it passes on the last of them
+fn first_failure<F>(n_commits: usize, mut probe: F) -> anyhow::Result<Option<usize>>
+where
+    F: FnMut(usize) -> anyhow::Result<bool>,
+{
+    let tip = match n_commits.checked_sub(1) {
+        Some(tip) => tip,
+        None => return Ok(None),
+    };
+    if probe(tip)? {
+        return Ok(None);
+    }
+    // The first failing commit is always in lo..=hi
+    let (mut lo, mut hi) = (0, tip);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        if probe(mid)? {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    Ok(Some(hi))
+}
+
+/// Creates the commit merging a PR into master, as GitHub's merge ref would
+/// have it, returning `None` if the PR is already based on master so that
+/// the merge would be the same as its tip
+fn merge_commit(
+    repo: &Repository,
+    identity: &Identity,
+    tip: &str,
+    master_tip: &git2::Commit,
+) -> anyhow::Result<Option<git2::Oid>> {
+    let pr_tip = repo
+        .revparse_single(tip)
+        .and_then(|obj| obj.peel_to_commit())
+        .with_context(|| format!("looking up PR tip {}", tip))?;
+    if pr_tip.id() == master_tip.id()
+        || repo
+            .graph_descendant_of(pr_tip.id(), master_tip.id())
+            .context("comparing PR tip with master")?
+    {
+        say!(
+            Normal,
+            "Not testing the merge of {}, which is based on master",
+            tip
+        );
+        return Ok(None);
     }
 
-    // 4. Put original commits into our set
-    PullRequest {
-        number: 0, // irrelevant for us
-        id: pr_id,
+    let mut index = repo
+        .merge_commits(master_tip, &pr_tip, None)
+        .with_context(|| format!("merging {} into master {}", pr_tip.id(), master_tip.id()))?;
+    if index.has_conflicts() {
+        let paths: Vec<String> = index
+            .conflicts()
+            .context("listing merge conflicts")?
+            .filter_map(|conflict| conflict.ok())
+            .filter_map(|conflict| conflict.our.or(conflict.their))
+            .map(|entry| String::from_utf8_lossy(&entry.path).into_owned())
+            .collect();
+        return Err(anyhow::Error::msg(format!(
+            "PR conflicts with master in {}",
+            paths.join(", ")
+        )));
     }
-    .for_each_commit(&repo, &parent_commits, |id, _, _| {
-        pr_commit_set.insert(id);
+    let tree_oid = index
+        .write_tree_to(repo)
+        .context("writing merged index to tree")?;
+    let tree = repo.find_tree(tree_oid).context("looking up merged tree")?;
+    // As for rebased commits, use the PR tip's commit time so that merging
+    // the same PR into the same master gives the same commit, and existing
+    // notes for it are still found.
+    let signature = identity.signature(Some(&pr_tip.committer().when()))?;
+    let message = format!(
+        "Merge {} into master\n\nMerged {} into {}\n",
+        tip,
+        pr_tip.id(),
+        master_tip.id()
+    );
+    let id = repo
+        .commit(
+            None,
+            &signature,
+            &signature,
+            &message,
+            &tree,
+            &[master_tip, &pr_tip],
+        )
+        .context("committing merge")?;
+    say!(Normal, "Merged {} into master as {}", tip, id);
+    Ok(Some(id))
+}
+
+/// Finds the commits of a range `A..B` which are to be checked, ordered from
+/// the oldest
+fn range_commits(
+    repo: &Repository,
+    range: &str,
+    allow_merges: bool,
+) -> anyhow::Result<HashMap<git2::Oid, CommitPosition>> {
+    let mut walk = repo.revwalk().context("walking commit range")?;
+    walk.push_range(range)
+        .with_context(|| format!("looking up commit range {}", range))?;
+    walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)
+        .context("sorting commit range")?;
+    let ids = walk
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("listing commits in range {}", range))?;
+    if ids.is_empty() {
+        return Err(anyhow::Error::msg(format!(
+            "range {} contains no commits",
+            range
+        )));
+    }
+    for &id in &ids {
+        let commit = repo
+            .find_commit(id)
+            .with_context(|| format!("looking up commit {}", id))?;
+        if commit.parent_count() > 1 {
+            if !allow_merges {
+                return Err(anyhow::Error::msg(format!(
+                    "Refusing to check a range with merges (commit {}). Use --allow-merges to allow.",
+                    id
+                )));
+            }
+            say!(Normal, "Note: commit {} is a merge commit.", id);
+        }
+    }
+    say!(Normal, "Found {} commits in range {}", ids.len(), range);
+
+    let n_commits = ids.len();
+    Ok(ids
+        .into_iter()
+        .enumerate()
+        .map(|(index, id)| (id, CommitPosition { index, n_commits }))
+        .collect())
+}
+
+/// Finds the first-parent history of a range `A..B`, in order from the
+/// oldest, leaving out the commits of any branches merged into it
+fn first_parent_commits(repo: &Repository, range: &str) -> anyhow::Result<Vec<git2::Oid>> {
+    let mut walk = repo.revwalk().context("walking commit range")?;
+    walk.push_range(range)
+        .with_context(|| format!("looking up commit range {}", range))?;
+    walk.simplify_first_parent()
+        .context("walking first parents of commit range")?;
+    walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)
+        .context("sorting commit range")?;
+    walk.collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("listing commits in range {}", range))
+}
+
+/// Returns the first skip marker found in a commit's message, if any
+fn skip_marker<'m>(
+    repo: &Repository,
+    id: git2::Oid,
+    markers: &'m [String],
+) -> anyhow::Result<Option<&'m str>> {
+    let commit = repo
+        .find_commit(id)
+        .with_context(|| format!("looking up commit {}", id))?;
+    let message = commit.message().unwrap_or("");
+    Ok(markers
+        .iter()
+        .find(|marker| !marker.is_empty() && message.contains(&marker[..]))
+        .map(|marker| &marker[..]))
+}
+
+/// Whether an earlier run recorded that a commit was skipped
+fn has_skip_note(repo: &Repository, notes_ref: &str, id: git2::Oid) -> bool {
+    read_notes(repo, notes_ref, id)
+        .iter()
+        .any(|line| line == SKIPPED_NOTE)
+}
+
+/// Notes of the jobs already known to have passed on a commit, from its git
+/// notes, those on its tree and the journal of a resumed run
+fn known_notes(
+    repo: &Repository,
+    notes_ref: &str,
+    tree_notes_ref: Option<&str>,
+    commit: git2::Oid,
+) -> Vec<String> {
+    let mut notes = read_notes(repo, notes_ref, commit);
+    notes.extend(tree_notes(repo, tree_notes_ref, commit));
+    notes.extend(resume::notes(commit));
+    notes
+}
+
+/// Notes of the jobs which passed on other commits with the same tree as a
+/// commit, if they are recorded
+fn tree_notes(repo: &Repository, tree_notes_ref: Option<&str>, commit: git2::Oid) -> Vec<String> {
+    match (tree_notes_ref, repo.find_commit(commit)) {
+        (Some(tree_notes_ref), Ok(commit)) => read_notes(repo, tree_notes_ref, commit.tree_id()),
+        _ => vec![],
+    }
+}
+
+/// Where the journal of runs on the repository at `path` is kept
+fn journal_path(path: &str) -> anyhow::Result<PathBuf> {
+    let repo = Repository::open_ext(
+        path,
+        git2::RepositoryOpenFlags::empty(),
+        Option::<String>::None,
+    )
+    .with_context(|| format!("opening repo {}", path))?;
+    Ok(Journal::path(&repo))
+}
+
+/// Removes what earlier runs left behind, returning the number of worktrees
+/// and of temporary directories removed
+///
+/// Must be called while holding the repository's lock.
+fn collect_garbage(path: &str) -> anyhow::Result<(usize, usize)> {
+    let repo = Repository::open_ext(
+        path,
+        git2::RepositoryOpenFlags::empty(),
+        Option::<String>::None,
+    )
+    .with_context(|| format!("opening repo {}", path))?;
+    let worktrees = gc::prune_worktrees(&repo)?;
+    let dirs = gc::remove_temp_dirs()?;
+    Ok((worktrees, dirs))
+}
+
+fn lock_path(path: &str) -> anyhow::Result<PathBuf> {
+    let repo = Repository::open_ext(
+        path,
+        git2::RepositoryOpenFlags::empty(),
+        Option::<String>::None,
+    )
+    .with_context(|| format!("opening repo {}", path))?;
+    Ok(RunLock::path(&repo))
+}
+
+/// Adds entries to the notes on a commit, updating its timestamp
+fn write_note(
+    repo: &Repository,
+    identity: &Identity,
+    notes_ref: &str,
+    commit: git2::Oid,
+    notes: &[String],
+) -> anyhow::Result<git2::Oid> {
+    let note = add_notes(repo, identity, notes_ref, commit, notes)
+        .with_context(|| format!("Adding notes to {}", commit))?;
+    report(Event::NoteWritten {
+        commit,
+        notes_ref,
+        note,
+        lines: notes,
     });
+    Ok(note)
+}
 
-    // 5. Spawn new repos for all of our checks and execute them
+/// Records the notes of the jobs which passed on a commit on its tree too, so
+/// that they are known to pass on any other commit with the same tree
+fn write_tree_note(
+    repo: &Repository,
+    identity: &Identity,
+    tree_notes_ref: &str,
+    commit: git2::Oid,
+    notes: &[String],
+) -> anyhow::Result<()> {
+    let tree = repo
+        .find_commit(commit)
+        .with_context(|| format!("finding commit {}", commit))?
+        .tree_id();
+    // A failure may be down to a flaky test, so is kept to the one commit
+    let passed: Vec<String> = notes
+        .iter()
+        .filter(|note| {
+            NoteEntry::parse(note)
+                .is_none_or(|entry| entry.passed() && !depends_on_commit(&entry.job))
+        })
+        .cloned()
+        .collect();
+    add_notes(repo, identity, tree_notes_ref, tree, &passed)
+        .with_context(|| format!("Adding notes to tree {} of {}", tree, commit))?;
+    Ok(())
+}
 
-    let mut result = Ok(());
-    let mut exec_threads = vec![];
+/// Adds entries to the notes on an object, without duplicating any it
+/// already has
+///
+/// Each job keeps one entry: a pass is replaced by an entry for the same
+/// job which is at least as new, and a failure by any entry for it. The
+/// notes are rewritten in the current version of the format, so notes in a
+/// newer version are an error rather than being mangled.
+fn add_notes(
+    repo: &Repository,
+    identity: &Identity,
+    notes_ref: &str,
+    id: git2::Oid,
+    notes: &[String],
+) -> anyhow::Result<git2::Oid> {
+    if let Some(header) = read_header(repo, notes_ref, id).filter(|header| !header.is_known()) {
+        return Err(anyhow::Error::msg(format!(
+            "notes on {} under {} are in version {} of the format, but this version \
+             of rsgit only knows up to version {}",
+            id, notes_ref, header.version, NOTES_VERSION
+        )));
+    }
+    let mut all_notes: Vec<_> = read_notes(repo, notes_ref, id)
+        .iter()
+        .map(|line| upgrade_entry(line))
+        .collect();
+    merge_entries(&mut all_notes, notes);
+    let note_str = note_text(&NoteHeader::now(), &all_notes);
 
-    for id in pr_commit_set {
-        for check in check_list {
-            let fresh_repo = match self::git::temp_repo(&repo, id)
-                .with_context(|| format!("creating temporary repo for {}", id))
-            {
-                Ok(repo) => repo,
-                Err(e) => {
-                    result = Err(e);
-                    break;
-                }
-            };
-            let (tx, rx) = mpsc::channel();
-            s.spawn(move |_| {
-                tx.send(
-                    check
-                        .execute(fresh_repo, build_pool)
-                        .with_context(|| format!("executing check {} on commit {}", check, id)),
-                )
-                .expect("main still alive")
-            });
-            exec_threads.push(ThreadData {
-                rx: rx,
-                commit: id,
-                desc: check.to_string(),
-            });
+    let sig = identity
+        .signature(None)
+        .context("creating git signature for new note")?;
+    let note = repo.note(&sig, &sig, Some(notes_ref), id, &note_str, true)?;
+    identity
+        .sign_ref(repo, notes_ref)
+        .with_context(|| format!("signing {}", notes_ref))?;
+    Ok(note)
+}
+
+/// Implements --validate-only: print the expansion of every check, and fail
+/// if any of them have problems
+fn validate_only(settings: &Settings, opts: &Opts, tip: Option<&String>) -> anyhow::Result<()> {
+    let repo = Repository::open_ext(
+        &opts.repo,
+        git2::RepositoryOpenFlags::empty(),
+        Option::<String>::None,
+    )
+    .with_context(|| format!("opening repo {}", opts.repo))?;
+    // Check out the tip so that cargo can tell us about the crate
+    let checkout = match tip {
+        Some(tip) => {
+            let commit = repo
+                .revparse_single(tip)
+                .and_then(|obj| obj.peel_to_commit())
+                .with_context(|| format!("looking up commit {}", tip))?;
+            Some(git_utils::git::temp_repo(&repo, commit.id())?)
+        }
+        None => None,
+    };
+
+    let mut n_problems = 0;
+    for check in &settings.check {
+        let validation = check.validate(checkout.as_ref().map(|tmp| &tmp.dir));
+        println!("{}", check);
+        for job in &validation.jobs {
+            println!("    {}", job);
+        }
+        for problem in &validation.problems {
+            println!("    error: {}", problem);
         }
+        n_problems += validation.problems.len();
     }
 
-    for handle in exec_threads {
-        // FIXME should catch ctrl-C here signal everything to stop waiting
-        match handle
-            .rx
-            .recv()
-            .expect("execution thread to not panic")
-            .with_context(|| format!("subthread: commit {}, check {}", handle.commit, handle.desc))
-        {
-            Ok(ref notes) => {
-                let mut note_str = format!("{}\n", time::now_utc().rfc3339());
-                for note in notes {
-                    note_str.push_str(note);
-                    note_str.push_str("\n");
-                }
+    if n_problems > 0 {
+        Err(anyhow::Error::msg(format!(
+            "found {} problem(s) in check configuration",
+            n_problems
+        )))
+    } else {
+        Ok(())
+    }
+}
 
-                let sig = git2::Signature::now("PR Checker", "prcheck@wpsoftware.net")
-                    .context("creating git signature for new note")?;
-                let note_oid = repo
-                    .note(
-                        &sig,
-                        &sig,
-                        Some("refs/notes/check-commit"),
-                        handle.commit,
-                        &note_str,
-                        true,
-                    )
-                    .with_context(|| format!("Adding notes to {}", handle.commit))?;
-                println!(
-                    "Success on {}. Recorded notes in ref {}",
-                    handle.commit, note_oid
-                );
+/// Expands every `--tip` which is a glob into the refs it matches, leaving
+/// the rest to be looked up later. Globs not starting with `refs/` are
+/// matched against both local branches and remote-tracking refs, so that
+/// e.g. `pr/*/head` finds every PR fetched as for `label-pr`.
+fn resolve_tips(repo: &str, tips: &[String]) -> anyhow::Result<Vec<String>> {
+    let is_glob = |tip: &String| tip.contains(['*', '?', '[']);
+    if !tips.iter().any(is_glob) {
+        return Ok(tips.to_vec());
+    }
+
+    let repo = Repository::open_ext(
+        repo,
+        git2::RepositoryOpenFlags::empty(),
+        Option::<String>::None,
+    )
+    .with_context(|| format!("opening repo {}", repo))?;
+    let mut ret = vec![];
+    for tip in tips {
+        if !is_glob(tip) {
+            ret.push(tip.clone());
+            continue;
+        }
+        let globs = if tip.starts_with("refs/") {
+            vec![tip.clone()]
+        } else {
+            vec![
+                format!("refs/heads/{}", tip),
+                format!("refs/remotes/{}", tip),
+            ]
+        };
+        let mut matched = vec![];
+        for glob in &globs {
+            for reference in repo
+                .references_glob(glob)
+                .with_context(|| format!("listing refs matching {}", glob))?
+            {
+                let reference =
+                    reference.with_context(|| format!("reading refs matching {}", glob))?;
+                if let Some(name) = reference.shorthand() {
+                    matched.push(name.to_owned());
+                }
             }
-            Err(e) => result = Err(e),
         }
+        // PRs in numerical order, rather than pr/10 before pr/9
+        matched.sort_by(|a, b| {
+            (PullRequest::number_from_ref(a), a).cmp(&(PullRequest::number_from_ref(b), b))
+        });
+        matched.dedup();
+        if matched.is_empty() {
+            return Err(anyhow::Error::msg(format!("no refs match {}", tip)));
+        }
+        say!(Normal, "Checking {} refs matching {}", matched.len(), tip);
+        ret.extend(matched);
     }
+    Ok(ret)
+}
 
-    result
+/// Fetches a PR and the branch it targets, returning the refs to use as
+/// `--tip` and `--master`
+fn fetch_pr(settings: &Settings, opts: &Opts, number: usize) -> anyhow::Result<(String, String)> {
+    // Without a forge to ask, assume GitHub's conventions
+    let forge = settings.forges().into_iter().next();
+    let base = match forge {
+        Some(forge) => forge
+            .pr_base(number)
+            .with_context(|| format!("looking up PR #{} on {}", number, forge.name()))?,
+        None => opts.master[0].clone(),
+    };
+    let update = PrUpdate {
+        repo: opts.repo.clone(),
+        number,
+        head_ref: pr_ref(settings, opts).replace('*', &number.to_string()),
+        base,
+    };
+    say!(
+        Normal,
+        "Fetching PR #{} and {} from {}",
+        number,
+        update.base,
+        opts.remote
+    );
+    update.fetch(Path::new(&opts.repo), &opts.remote)
+}
+
+/// The ref on the remote holding each PR's head, with `*` for its number
+fn pr_ref<'a>(settings: &'a Settings, opts: &'a Opts) -> &'a str {
+    match (&opts.pr_ref, settings.forges().into_iter().next()) {
+        (Some(pr_ref), _) => pr_ref.as_str(),
+        (None, Some(forge)) => forge.pr_ref(),
+        (None, None) => "refs/pull/*/head",
+    }
+}
+
+/// Fetches the heads of every PR, the `--master` branches and whatever
+/// else the remote is configured to fetch, returning the `--master`
+/// branches to use in place of the local ones
+fn fetch_all(settings: &Settings, opts: &Opts, remote: &str) -> anyhow::Result<Vec<String>> {
+    let repo = Repository::open_ext(
+        &opts.repo,
+        git2::RepositoryOpenFlags::empty(),
+        Option::<String>::None,
+    )
+    .with_context(|| format!("opening repo {}", opts.repo))?;
+    let configured = repo
+        .find_remote(remote)
+        .with_context(|| format!("looking up remote {}", remote))?
+        .fetch_refspecs()
+        .with_context(|| format!("reading refspecs of remote {}", remote))?;
+    let mut refspecs: Vec<String> = configured.iter().flatten().map(str::to_owned).collect();
+    refspecs.push(format!(
+        "+{}:refs/remotes/pr/*/head",
+        pr_ref(settings, opts)
+    ));
+    let mut masters = vec![];
+    for master in &opts.master {
+        // A master which already names a remote-tracking ref is left alone
+        let branch = master.strip_prefix("refs/heads/").unwrap_or(master);
+        if repo.find_branch(branch, git2::BranchType::Local).is_err()
+            && repo.revparse_single(master).is_ok()
+        {
+            masters.push(master.clone());
+            continue;
+        }
+        refspecs.push(format!(
+            "+refs/heads/{}:refs/remotes/{}/{}",
+            branch, remote, branch
+        ));
+        masters.push(format!("{}/{}", remote, branch));
+    }
+    say!(
+        Normal,
+        "Fetching PRs and {} from {}",
+        masters.join(", "),
+        remote
+    );
+    fetch(Path::new(&opts.repo), remote, &refspecs)?;
+    Ok(masters)
 }
 
 fn main() -> anyhow::Result<()> {
     // Construct variables that need to outlive every thread
-    let opts = Opts::from_args();
+    let mut opts = Opts::from_args();
+    let journal = if opts.resume {
+        let path = journal_path(&opts.repo)?;
+        if !path.exists() {
+            return Err(anyhow::Error::msg(format!(
+                "no interrupted run to resume in {}",
+                opts.repo
+            )));
+        }
+        let journal = Journal::load(&path)?;
+        std::env::set_current_dir(&journal.cwd).with_context(|| {
+            format!(
+                "changing to directory {} the run was started in",
+                journal.cwd.to_string_lossy()
+            )
+        })?;
+        opts = Opts::from_iter(&journal.args);
+        journal
+    } else {
+        let cwd = std::env::current_dir().context("getting current directory")?;
+        Journal::new(cwd, std::env::args().collect())
+    };
+    if opts.tui {
+        // Anything printed would only be drawn over by the dashboard
+        Verbosity::Quiet.set();
+    } else {
+        Verbosity::from_flags(opts.quiet, opts.verbose).set();
+    }
+    opts.output.set();
+
+    // Look up the repo only to find its config file and default branch;
+    // it is reopened in real_main since it cannot be shared across threads
+    let repo = Repository::open_ext(
+        &opts.repo,
+        git2::RepositoryOpenFlags::empty(),
+        Option::<String>::None,
+    )
+    .ok();
+    let repo_dir = repo
+        .as_ref()
+        .map(|repo| git_utils::git::repo_dir(repo).to_path_buf());
+    // Failing to find it is only an error if it is needed, further down
+    let default_master = match (opts.master.is_empty(), repo.as_ref()) {
+        (false, _) => None,
+        (true, None) => Some(Ok("master".to_owned())),
+        (true, Some(repo)) => {
+            let remote = opts.fetch.as_ref().unwrap_or(&opts.remote);
+            // --pr and --fetch look the branch up on the remote by its name
+            let on_remote = opts.pr.is_some() || opts.fetch.is_some();
+            Some(git_utils::git::default_branch(repo, remote).map(|branch| {
+                if on_remote {
+                    branch.name
+                } else {
+                    branch.local
+                }
+            }))
+        }
+    };
+    drop(repo);
+    let mut config = Config::load(&opts.config, repo_dir.as_deref())?;
+    let mut cli_settings = Settings {
+        build_threads: opts.build_threads,
+        light_threads: opts.light_threads,
+        jobs: opts.jobs,
+        memory_limit: opts.memory_limit.clone(),
+        cpu_time_limit: opts.cpu_time_limit,
+        cgroup: opts.cgroup.clone(),
+        notes_ref: opts.notes_ref.clone(),
+        tree_notes_ref: opts.tree_notes_ref.clone(),
+        failure_policy: opts.failure_policy.clone(),
+        signing_key: opts.signing_key.clone(),
+        fetch_notes: opts.fetch_notes.clone(),
+        push_notes: opts.push_notes.clone(),
+        target_cache: opts.target_cache.clone(),
+        temp_dir: opts.temp_dir.clone(),
+        disk_budget: opts.disk_budget.clone(),
+        share_objects: if opts.share_objects { Some(true) } else { None },
+        submodule_cache: opts.submodule_cache.clone(),
+        lfs: if opts.lfs { Some(true) } else { None },
+        lfs_cache: opts.lfs_cache.clone(),
+        prefetch: if opts.prefetch { Some(true) } else { None },
+        results_db: opts.results_db.clone(),
+        ..Default::default()
+    };
+    if let Some(ref check) = opts.check {
+        cli_settings.check = serde_json::from_str(check).context("parsing check list JSON")?;
+    }
+    config.add_layer(cli_settings, Source::CommandLine);
+    config.validate()?;
+    if opts.show_config {
+        print!("{}", config.describe());
+        return Ok(());
+    }
+    let settings = config.settings();
+    if let Some(dir) = settings.temp_dir() {
+        gc::set_temp_root(dir)?;
+    }
+    if let Some(bytes) = settings.disk_budget()? {
+        disk::set_budget(bytes, settings.target_cache());
+    }
+    git_utils::git::set_share_objects(settings.share_objects());
+    if let Some(dir) = settings.submodule_cache() {
+        git_utils::git::set_submodule_cache(dir);
+    }
+    if settings.lfs() {
+        git_utils::git::set_lfs(settings.lfs_cache());
+    }
+    // Held for the whole run, which includes fetching the PR
+    let lock = if opts.validate_only {
+        None
+    } else {
+        Some(RunLock::acquire(
+            &lock_path(&opts.repo)?,
+            opts.wait_for_lock,
+        )?)
+    };
+    if opts.gc {
+        let (worktrees, dirs) = collect_garbage(&opts.repo)?;
+        println!(
+            "Removed {} leaked worktrees and {} temporary directories",
+            worktrees, dirs
+        );
+        return Ok(());
+    }
+    if lock.is_some() {
+        match collect_garbage(&opts.repo) {
+            Ok((0, 0)) => {}
+            Ok((worktrees, dirs)) => say!(
+                Normal,
+                "Removed {} leaked worktrees and {} temporary directories from earlier runs",
+                worktrees,
+                dirs
+            ),
+            Err(e) => eprintln!("WARNING: failed to clean up after earlier runs: {:?}", e),
+        }
+    }
+    if let Some(master) = default_master.filter(|_| opts.range.is_none()) {
+        opts.master = vec![master?];
+    }
+    if let Some(number) = opts.pr {
+        let (tip, master) = fetch_pr(settings, &opts, number)?;
+        opts.tip = vec![tip];
+        opts.master = vec![master];
+    }
+    if let Some(remote) = opts.fetch.clone() {
+        opts.master = fetch_all(settings, &opts, &remote)?;
+    }
+    let tips = resolve_tips(&opts.repo, &opts.tip)?;
+    if let Some(number) = opts.bisect {
+        if tips.len() > 1 {
+            return Err(anyhow::Error::msg(
+                "--bisect can only be used on one PR or range at a time",
+            ));
+        }
+        if number == 0 || number > settings.check.len() {
+            return Err(anyhow::Error::msg(format!(
+                "--bisect {}: there are only {} checks, numbered from 1",
+                number,
+                settings.check.len()
+            )));
+        }
+    }
+    if opts.validate_only {
+        return validate_only(settings, &opts, tips.first());
+    }
 
-    let check_list: Vec<self::checks::Check> =
-        serde_json::from_str(&opts.check).context("parsing check list JSON")?;
+    if settings.check.is_empty() && !opts.tree_config {
+        return Err(anyhow::Error::msg(
+            "No checks to do. Give them on the command line or in a config file.",
+        ));
+    }
 
     // cargo can get jammed if you spawn too many instances at once, and anyway
     // it launches many rustcs at once, which are all themselves multithreaded,
     // so limit the size of the builder pool to something fairly small.
-    let build_pool = ThreadPoolBuilder::new()
-        .num_threads(8)
-        .build()
-        .context("setting up thread pool")?;
+    // Some threads are kept for light jobs, so that they are not held up
+    // behind a pile of heavy ones. Jobs on workers only wait on this machine,
+    // so the pools have a thread for each of their slots too.
+    let build_pools = BuildPools::new(
+        settings.build_threads() + settings.worker_slots(),
+        settings.light_threads(),
+    )?;
+    git_utils::worker::start(settings.build_threads(), &settings.worker);
+    if opts.offline && settings.prefetch() {
+        return Err(anyhow::Error::msg(
+            "--offline cannot be used with prefetch, which needs the network",
+        ));
+    }
+    if let Some(ref dir) = opts.log_dir {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("creating log directory {}", dir.to_string_lossy()))?;
+    }
+    // Kept alive until the end of the run
+    // Open the results database up front, so that a bad path is reported
+    // before rather than after running everything
+    #[cfg(feature = "sqlite")]
+    let (results_db, run_time) = (
+        settings
+            .results_db()
+            .map(|path| git_utils::results::ResultsDb::open(&path))
+            .transpose()?,
+        time::now_utc().rfc3339().to_string(),
+    );
+    #[cfg(not(feature = "sqlite"))]
+    if settings.results_db().is_some() {
+        return Err(anyhow::Error::msg(
+            "results-db is set, but check-pr was built without the sqlite feature",
+        ));
+    }
+    // Jobs in containers have a cargo home of their own, so only have the
+    // dependencies in the local cache if they are prefetched into one
+    if opts.offline && settings.container.is_some() {
+        return Err(anyhow::Error::msg(
+            "--offline cannot be used with a container; set prefetch instead, so that \
+             jobs are run offline with their dependencies fetched beforehand",
+        ));
+    }
+    let cargo_home = if settings.prefetch() {
+        Some(git_utils::cargo::run_local_home(
+            settings.container.is_none() && settings.sandbox.is_none(),
+        )?)
+    } else {
+        None
+    };
+    let run_options = RunOptions {
+        target_cache: settings.target_cache(),
+        cargo_home: cargo_home.as_ref().map(|dir| dir.path().to_path_buf()),
+        jobs: Some(settings.jobs()),
+        limits: settings.limits()?,
+        container: settings.container.clone(),
+        sandbox: settings.sandbox.clone(),
+        offline: opts.offline,
+        log_dir: opts.log_dir.clone(),
+        stream: opts.stream,
+        failure_policy: settings.failure_policy()?,
+    };
+
+    if let Some(ref addr) = opts.metrics_addr {
+        git_utils::metrics::serve(addr)?;
+    }
+
+    job::cancel_on_interrupt()?;
+    resume::start(journal_path(&opts.repo)?, journal)?;
 
     // Create a scoped-thread scope and actually execute main
     let (tx, rx) = mpsc::channel();
+    let mut summary = Summary::default();
+    let dashboard = if opts.tui {
+        Some(Dashboard::start())
+    } else {
+        None
+    };
     rayon::scope(|s| {
         let tx = tx; // force move into by-ref closure
-        tx.send(real_main(s, &check_list, &opts, &build_pool))
-            .expect("main alive");
+        tx.send(real_main(
+            s,
+            settings,
+            &opts,
+            &tips,
+            &build_pools,
+            &run_options,
+            &mut summary,
+        ))
+        .expect("main alive");
     });
+    drop(dashboard);
+    // An interrupted run may be resumed; any other is over
+    if !job::cancelled() {
+        if let Err(e) = resume::finish() {
+            eprintln!("Failed to remove journal: {:?}", e);
+        }
+    }
 
     // Get real_main's return value and return it
-    rx.recv().expect("main alive")
+    let result = rx.recv().expect("main alive");
+    let error = result.as_ref().err().map(|e| format!("{:?}", e));
+    let jobs = finished_jobs();
+    let repo_name = match repo_dir {
+        Some(ref dir) => dir.to_string_lossy().into_owned(),
+        None => opts.repo.clone(),
+    };
+    #[cfg(feature = "sqlite")]
+    if let Some(mut db) = results_db {
+        db.record(&run_time, &repo_name, &jobs)?;
+    }
+    if let Some(ref path) = opts.junit {
+        fs::write(path, git_utils::report::junit(&jobs))
+            .with_context(|| format!("writing JUnit report {}", path.to_string_lossy()))?;
+    }
+    if let Some(ref path) = opts.markdown_summary {
+        fs::write(path, git_utils::report::markdown(&summary, &jobs))
+            .with_context(|| format!("writing Markdown summary {}", path.to_string_lossy()))?;
+    }
+    match opts.output {
+        Format::Human => {
+            if !summary.is_empty() {
+                print!("\n{}", summary.render(use_color()));
+            }
+        }
+        Format::JsonLines => report(Event::RunFinished {
+            summary: &summary,
+            error: error.as_deref(),
+        }),
+    }
+    if let Some(remote) = settings.push_notes() {
+        // Failing to push should not hide the result of the run either
+        let repo = Repository::open_ext(
+            &opts.repo,
+            git2::RepositoryOpenFlags::empty(),
+            Option::<String>::None,
+        )
+        .with_context(|| format!("opening repo {}", opts.repo))?;
+        let identity = identity(settings, &repo);
+        for notes_ref in std::iter::once(settings.notes_ref()).chain(settings.tree_notes_ref()) {
+            if let Err(e) = push_notes(&repo, &identity, remote, notes_ref, NotesMerge::Entries) {
+                eprintln!("Failed to push {} to {}: {:?}", notes_ref, remote, e);
+            }
+        }
+    }
+    // Partial results would only mislead anyone reading them on the forge,
+    // or being notified of them
+    if job::cancelled() {
+        return result;
+    }
+    for forge in settings.forges() {
+        // Failing to post should not hide the result of the run
+        if forge.wants_statuses() {
+            if let Err(e) = forge.post_statuses(&summary, &jobs) {
+                eprintln!("Failed to post statuses to {}: {:?}", forge.name(), e);
+            }
+        }
+        if forge.wants_comment() {
+            // The summary covers every PR checked, so is no good as a
+            // comment on any one of them
+            let tip = match tips[..] {
+                [ref tip] => tip,
+                [] => {
+                    eprintln!("Not commenting: no PR was checked");
+                    continue;
+                }
+                _ => {
+                    eprintln!("Not commenting: {} PRs were checked at once", tips.len());
+                    continue;
+                }
+            };
+            match PullRequest::number_from_ref(tip) {
+                Some(pr) => {
+                    if let Err(e) = forge.post_comment(pr, &summary, &jobs) {
+                        eprintln!(
+                            "Failed to comment on PR #{} on {}: {:?}",
+                            pr,
+                            forge.name(),
+                            e
+                        );
+                    }
+                }
+                None => eprintln!("Not commenting: cannot tell which PR {} belongs to", tip),
+            }
+        }
+    }
+    let report_tip = match (&opts.range, &tips[..]) {
+        (Some(range), _) => range.clone(),
+        (None, [tip]) => tip.clone(),
+        (None, _) => format!("{} PRs", tips.len()),
+    };
+    let run_report = RunReport {
+        repo: &repo_name,
+        tip: &report_tip,
+        summary: &summary,
+        jobs: &jobs,
+        error: error.as_deref(),
+    };
+    for notifier in &settings.notify {
+        // A notification going astray should not hide the result of the run
+        if let Err(e) = notifier.send(&run_report) {
+            eprintln!("Failed to send notification: {:?}", e);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bisect_rebased() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let sig = git2::Signature::now("A", "a@b").unwrap();
+        // Commits adding a file to the tree of `parent`
+        let commit = |parent: Option<git2::Oid>, file: &str| -> git2::Oid {
+            let parent = parent.map(|id| repo.find_commit(id).unwrap());
+            let mut builder = repo
+                .treebuilder(parent.as_ref().map(|p| p.tree().unwrap()).as_ref())
+                .unwrap();
+            let blob = repo.blob(file.as_bytes()).unwrap();
+            builder.insert(file, blob, 0o100644).unwrap();
+            let tree = repo.find_tree(builder.write().unwrap()).unwrap();
+            let parents: Vec<_> = parent.iter().collect();
+            repo.commit(None, &sig, &sig, file, &tree, &parents)
+                .unwrap()
+        };
+        let base = commit(None, "base");
+        let master = commit(Some(base), "master");
+        repo.reference("refs/heads/master", master, true, "")
+            .unwrap();
+        let p1 = commit(Some(base), "p1");
+        let p2 = commit(Some(p1), "broken");
+        let p3 = commit(Some(p2), "p3");
+        repo.reference("refs/heads/pr", p3, true, "").unwrap();
+
+        let opts = Opts::from_iter(&["check-pr", "--tip", "pr"]);
+        let identity = Identity::from_repo(&repo, "A", "a@b");
+        let masters = vec![Master::new(&repo, "master").unwrap()];
+        let base_branches = BaseBranches::new(vec![master]);
+        let commits = pr_commits(&repo, &identity, &opts, "pr", &masters, &base_branches).unwrap();
+
+        // Only the rebased series, not the PR's own commits after it
+        assert_eq!(commits.series.len(), 3);
+        let originals: Vec<_> = commits
+            .series
+            .iter()
+            .map(|id| commits.sources[id])
+            .collect();
+        assert_eq!(originals, vec![p1, p2, p3]);
+        let broken = |index: usize| -> anyhow::Result<bool> {
+            let tree = repo.find_commit(commits.series[index])?.tree()?;
+            let fixed = tree.get_name("broken").is_none();
+            Ok(fixed)
+        };
+        assert_eq!(
+            first_failure(commits.series.len(), broken).unwrap(),
+            Some(1)
+        );
+        assert_eq!(first_failure(3, |_| Ok(true)).unwrap(), None);
+        assert_eq!(first_failure(0, |_| Ok(false)).unwrap(), None);
+    }
 }
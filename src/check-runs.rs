@@ -0,0 +1,548 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+use anyhow::Context;
+use git2::Repository;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+use git_utils::config::{Config, Settings};
+use git_utils::coverage::{Coverage, JobStatus};
+use git_utils::html;
+use git_utils::identity::Identity;
+use git_utils::notes::{migrate_notes, prune_notes, read_notes, NoteHeader, NOTES_VERSION};
+use git_utils::output::FinishedJob;
+use git_utils::pr::{BaseBranches, PullRequest};
+use git_utils::runs::{diff_runs, find_run, run_tips, superseded_commits, RUNS_REF};
+
+/// Ref under which label-pr writes its notes
+const LABEL_NOTES_REF: &str = "refs/notes/label-pr";
+
+/// Refs whose history `clean-notes` keeps the notes on by default
+const DEFAULT_KEEP: &[&str] = &["refs/heads/*", "refs/remotes/*", "refs/tags/*"];
+
+#[derive(StructOpt, Debug)]
+struct Opts {
+    /// Repository to read
+    #[structopt(short, long, default_value = ".")]
+    repo: String,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// List recorded check-pr runs, most recent first
+    List {
+        /// Maximum number of runs to list
+        #[structopt(short = "n", long, default_value = "20")]
+        max_count: usize,
+    },
+    /// Show how the check configuration changed between two runs. Runs
+    /// are git revisions, or numbers counting back from the latest run.
+    Diff {
+        /// The older run
+        #[structopt(default_value = "1")]
+        old: String,
+        /// The newer run
+        #[structopt(default_value = "0")]
+        new: String,
+    },
+    /// Write a static HTML report of the results recorded in the notes (and
+    /// in the results database, if there is one) to a directory
+    Html {
+        /// Directory to write the report to
+        #[structopt(short, long, parse(from_os_str))]
+        out: PathBuf,
+        /// Branches to show the latest commits of, by default the one
+        /// PRs are based on
+        #[structopt(short, long = "branch")]
+        branches: Vec<String>,
+        /// Number of commits to show for each branch
+        #[structopt(short = "n", long, default_value = "50")]
+        max_count: usize,
+        /// Show PRs fetched to refs/remotes/<prs>/<number>/head
+        #[structopt(long, default_value = "pr")]
+        prs: String,
+        /// Branch which PRs are based on. By default, the branch origin's
+        /// HEAD points to, or else init.defaultBranch, or else master.
+        #[structopt(long)]
+        master: Option<String>,
+    },
+    /// Show which jobs of the configured checks are recorded in the notes
+    /// as having passed on some commits, and which are missing or failed.
+    /// Fails unless every job passed on every commit.
+    Status {
+        /// Commits to look at: a range such as master..release-1.0, or
+        /// else a branch, of which the latest commits are looked at
+        revisions: String,
+        /// Number of commits of a branch to look at
+        #[structopt(short = "n", long, default_value = "50")]
+        max_count: usize,
+        /// JSON check list to use rather than the configured one
+        #[structopt(long)]
+        check: Option<String>,
+        /// Only show the commits which are missing jobs
+        #[structopt(short, long)]
+        quiet: bool,
+    },
+    /// Rewrite the notes written by older versions of check-pr in the
+    /// current version of the format, in one commit per notes ref
+    MigrateNotes {
+        /// Notes refs to migrate, by default those check-pr is configured
+        /// to write to
+        #[structopt(long = "notes-ref")]
+        notes_refs: Vec<String>,
+        /// Only count the notes which would be migrated
+        #[structopt(long)]
+        dry_run: bool,
+    },
+    /// Remove the notes on commits which are no longer reachable from any
+    /// branch, tag or PR, and on their trees, so that the notes do not grow
+    /// without bound
+    CleanNotes {
+        /// Notes refs to clean, by default those check-pr is configured to
+        /// write to and refs/notes/label-pr
+        #[structopt(long = "notes-ref")]
+        notes_refs: Vec<String>,
+        /// Globs of the refs whose history to keep the notes on, by default
+        /// every branch, remote-tracking ref (including fetched PRs) and tag
+        #[structopt(long)]
+        keep: Vec<String>,
+        /// Also remove the notes written more than this many days ago, or
+        /// on commits made that long ago if the notes do not say when they
+        /// were written
+        #[structopt(long)]
+        max_age: Option<u64>,
+        /// Only count the notes which would be removed
+        #[structopt(long)]
+        dry_run: bool,
+    },
+}
+
+/// Source of the results to show in an HTML report
+struct Results<'a> {
+    repo: &'a Repository,
+    notes_ref: String,
+    #[cfg(feature = "sqlite")]
+    db: Option<git_utils::results::ResultsDb>,
+    out: &'a Path,
+}
+
+impl Results<'_> {
+    /// Jobs recorded in the results database as having run on a commit
+    #[cfg(feature = "sqlite")]
+    fn jobs(&self, commit: git2::Oid) -> anyhow::Result<Vec<FinishedJob>> {
+        match self.db {
+            Some(ref db) => db.jobs_for_commit(commit),
+            None => Ok(vec![]),
+        }
+    }
+
+    #[cfg(not(feature = "sqlite"))]
+    fn jobs(&self, _: git2::Oid) -> anyhow::Result<Vec<FinishedJob>> {
+        Ok(vec![])
+    }
+
+    /// Copies a job's log file into the report, returning a link to it
+    fn copy_log(&self, job: &FinishedJob) -> Option<String> {
+        let log = job.log.as_ref()?;
+        let name = log.file_name()?.to_string_lossy().into_owned();
+        fs::copy(log, self.out.join("logs").join(&name)).ok()?;
+        Some(format!("logs/{}", name))
+    }
+
+    /// Looks up the results of the given commits
+    fn rows(&self, commits: &[git2::Oid]) -> anyhow::Result<Vec<html::Row>> {
+        let mut rows = vec![];
+        for &commit in commits {
+            let notes = read_notes(self.repo, &self.notes_ref, commit);
+            let jobs = self.jobs(commit)?;
+            let summary = self
+                .repo
+                .find_commit(commit)
+                .with_context(|| format!("looking up commit {}", commit))?
+                .summary()
+                .unwrap_or("")
+                .to_owned();
+            rows.push(html::Row {
+                commit,
+                summary,
+                results: html::commit_results(&notes, &jobs, |job| self.copy_log(job)),
+            });
+        }
+        Ok(rows)
+    }
+
+    /// Writes a page of the report
+    fn write(&self, name: &str, title: &str, body: &str) -> anyhow::Result<()> {
+        let path = self.out.join(name);
+        fs::write(&path, html::page(title, body))
+            .with_context(|| format!("writing {}", path.to_string_lossy()))
+    }
+}
+
+/// The identity with which to rewrite notes, with any signing key from the
+/// settings
+fn identity(repo: &Repository, settings: &Settings) -> anyhow::Result<Identity> {
+    let mut identity = Identity::from_repo(repo, "PR Checker", "prcheck@wpsoftware.net");
+    if let Some(key) = settings.signing_key() {
+        identity.signing_key = Some(key.to_owned());
+    }
+    identity.check_signing_key(repo)?;
+    Ok(identity)
+}
+
+/// Replaces any characters which do not belong in file names
+fn file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+fn main() -> anyhow::Result<()> {
+    let opts = Opts::from_args();
+    let repo = Repository::open_ext(&opts.repo, git2::RepositoryOpenFlags::empty(), Some("/"))
+        .with_context(|| format!("Opening repo {}", opts.repo))?;
+
+    match opts.command {
+        Command::List { max_count } => {
+            let mut run = Some(find_run(&repo, "0")?);
+            let mut n = 0;
+            // The tip each PR had when next checked, after the run being listed
+            let mut later_tips = HashMap::new();
+            while let Some(commit) = run {
+                if n == max_count {
+                    break;
+                }
+                let time = commit.time();
+                println!(
+                    "{:>4} {} {} {}",
+                    n,
+                    commit.id(),
+                    time::at_utc(time::Timespec::new(time.seconds(), 0)).rfc3339(),
+                    commit.summary().unwrap_or(""),
+                );
+                for (name, id) in run_tips(&repo, &commit) {
+                    let superseded_by = match later_tips.insert(name.clone(), id) {
+                        Some(new) if !superseded_commits(&repo, id, new)?.is_empty() => Some(new),
+                        _ => None,
+                    };
+                    match superseded_by {
+                        Some(new) => println!(
+                            "       {} at {} (superseded by force-push to {})",
+                            name, id, new
+                        ),
+                        None => println!("       {} at {}", name, id),
+                    }
+                }
+                run = commit.parent(0).ok();
+                n += 1;
+            }
+            if n == 0 {
+                println!("No runs recorded in {}", RUNS_REF);
+            }
+        }
+        Command::Diff { old, new } => {
+            let diff = diff_runs(&repo, &old, &new)?;
+            if diff.is_empty() {
+                println!(
+                    "No changes to check configuration between {} and {}",
+                    old, new
+                );
+            } else {
+                print!("{}", diff);
+            }
+        }
+        Command::Status {
+            revisions,
+            max_count,
+            check,
+            quiet,
+        } => {
+            let config = Config::load(&[], Some(git_utils::git::repo_dir(&repo)))?;
+            let settings = config.settings();
+            let checks = match check {
+                Some(ref json) => serde_json::from_str(json).context("parsing check list JSON")?,
+                None => settings.check.clone(),
+            };
+            if checks.is_empty() {
+                return Err(anyhow::Error::msg(
+                    "No checks to look for. Give them with --check or in a config file.",
+                ));
+            }
+
+            let mut walk = repo.revwalk().context("walking history")?;
+            let commits = if revisions.contains("..") {
+                walk.push_range(&revisions)
+                    .with_context(|| format!("looking up range {}", revisions))?;
+                walk.collect::<Result<Vec<_>, _>>()
+            } else {
+                walk.simplify_first_parent().context("walking history")?;
+                let tip = repo
+                    .revparse_single(&revisions)
+                    .with_context(|| format!("looking up {}", revisions))?;
+                walk.push(tip.id()).context("walking history")?;
+                walk.take(max_count).collect::<Result<Vec<_>, _>>()
+            }
+            .with_context(|| format!("walking history of {}", revisions))?;
+
+            let mut incomplete = 0;
+            for &commit in &commits {
+                let coverage = Coverage::of(
+                    &repo,
+                    settings.notes_ref(),
+                    settings.tree_notes_ref(),
+                    &checks,
+                    commit,
+                )?;
+                let short = &commit.to_string()[..7];
+                if coverage.skipped {
+                    if !quiet {
+                        println!("{} skipped", short);
+                    }
+                    continue;
+                }
+                if coverage.is_complete() {
+                    if !quiet {
+                        println!("{} complete ({} jobs)", short, coverage.jobs.len());
+                    }
+                    continue;
+                }
+                let missing = coverage
+                    .jobs
+                    .iter()
+                    .filter(|&&(_, _, status)| status != JobStatus::Passed)
+                    .count();
+                incomplete += 1;
+                println!(
+                    "{} incomplete ({} of {} jobs not passed)",
+                    short,
+                    missing,
+                    coverage.jobs.len()
+                );
+                for (check, job, status) in &coverage.jobs {
+                    let status = match status {
+                        JobStatus::Passed => continue,
+                        JobStatus::Failed => "failed",
+                        JobStatus::Missing => "missing",
+                    };
+                    println!("    {}: {} ({})", check, job, status);
+                }
+            }
+
+            println!(
+                "{} of {} commits fully checked",
+                commits.len() - incomplete,
+                commits.len()
+            );
+            if incomplete > 0 {
+                return Err(anyhow::Error::msg(format!(
+                    "{} commits are not fully checked",
+                    incomplete
+                )));
+            }
+        }
+        Command::MigrateNotes {
+            notes_refs,
+            dry_run,
+        } => {
+            let config = Config::load(&[], Some(git_utils::git::repo_dir(&repo)))?;
+            let settings = config.settings();
+            let notes_refs = if notes_refs.is_empty() {
+                std::iter::once(settings.notes_ref())
+                    .chain(settings.tree_notes_ref())
+                    .map(str::to_owned)
+                    .collect()
+            } else {
+                notes_refs
+            };
+            let identity = identity(&repo, settings)?;
+            for notes_ref in &notes_refs {
+                let n = migrate_notes(&repo, &identity, notes_ref, dry_run)?;
+                println!(
+                    "{} {} notes under {} to version {}",
+                    if dry_run { "Would migrate" } else { "Migrated" },
+                    n,
+                    notes_ref,
+                    NOTES_VERSION
+                );
+            }
+        }
+        Command::CleanNotes {
+            notes_refs,
+            keep,
+            max_age,
+            dry_run,
+        } => {
+            let config = Config::load(&[], Some(git_utils::git::repo_dir(&repo)))?;
+            let settings = config.settings();
+            let notes_refs = if notes_refs.is_empty() {
+                std::iter::once(settings.notes_ref())
+                    .chain(settings.tree_notes_ref())
+                    .chain(std::iter::once(LABEL_NOTES_REF))
+                    .map(str::to_owned)
+                    .collect()
+            } else {
+                notes_refs
+            };
+            let keep = if keep.is_empty() {
+                DEFAULT_KEEP.iter().map(|&glob| glob.to_owned()).collect()
+            } else {
+                keep
+            };
+
+            // Notes are kept on every reachable commit and on its tree
+            let mut walk = repo.revwalk().context("walking history")?;
+            for glob in &keep {
+                walk.push_glob(glob)
+                    .with_context(|| format!("looking up refs {}", glob))?;
+            }
+            let mut reachable = HashSet::new();
+            for id in walk {
+                let commit = repo
+                    .find_commit(id.context("walking history")?)
+                    .context("walking history")?;
+                reachable.insert(commit.id());
+                reachable.insert(commit.tree_id());
+            }
+            let cutoff = max_age.map(|days| time::get_time().sec - days as i64 * 24 * 60 * 60);
+
+            let identity = identity(&repo, settings)?;
+            for notes_ref in &notes_refs {
+                let (removed, total) =
+                    prune_notes(&repo, &identity, notes_ref, dry_run, |id, text| {
+                        if !reachable.contains(&id) {
+                            return false;
+                        }
+                        let cutoff = match cutoff {
+                            Some(cutoff) => cutoff,
+                            None => return true,
+                        };
+                        let written = NoteHeader::parse(text.lines().next().unwrap_or(""))
+                            .time()
+                            .map(|time| time.sec)
+                            .or_else(|| Some(repo.find_commit(id).ok()?.time().seconds()));
+                        written.is_none_or(|written| written >= cutoff)
+                    })?;
+                println!(
+                    "{} {} of {} notes under {}",
+                    if dry_run { "Would remove" } else { "Removed" },
+                    removed,
+                    total,
+                    notes_ref
+                );
+            }
+        }
+        Command::Html {
+            out,
+            branches,
+            max_count,
+            prs,
+            master,
+        } => {
+            let master = match master {
+                Some(master) => master,
+                None => git_utils::git::default_branch(&repo, "origin")?.local,
+            };
+            let branches = if branches.is_empty() {
+                vec![master.clone()]
+            } else {
+                branches
+            };
+            let config = Config::load(&[], Some(git_utils::git::repo_dir(&repo)))?;
+            let settings = config.settings();
+            fs::create_dir_all(out.join("logs"))
+                .with_context(|| format!("creating directory {}", out.to_string_lossy()))?;
+            let results = Results {
+                repo: &repo,
+                notes_ref: settings.notes_ref().to_owned(),
+                #[cfg(feature = "sqlite")]
+                db: settings
+                    .results_db()
+                    .map(|path| git_utils::results::ResultsDb::open(&path))
+                    .transpose()?,
+                out: &out,
+            };
+
+            let mut branch_links = vec![];
+            for branch in &branches {
+                let mut walk = repo.revwalk().context("walking history")?;
+                walk.simplify_first_parent().context("walking history")?;
+                let tip = repo
+                    .revparse_single(branch)
+                    .with_context(|| format!("looking up branch {}", branch))?;
+                walk.push(tip.id()).context("walking history")?;
+                let commits = walk
+                    .take(max_count)
+                    .collect::<Result<Vec<_>, _>>()
+                    .with_context(|| format!("walking history of {}", branch))?;
+
+                let name = format!("branch-{}.html", file_name(branch));
+                let body = html::matrix(&results.rows(&commits)?);
+                results.write(&name, &format!("Branch {}", branch), &body)?;
+                branch_links.push((branch.clone(), name));
+            }
+
+            let master_id = repo
+                .revparse_single(&master)
+                .with_context(|| format!("looking up master branch {}", master))?
+                .id();
+            let masters = BaseBranches::new(vec![master_id]);
+
+            let mut prs = PullRequest::find_all(&repo, &prs).context("looking up PRs")?;
+            prs.sort_by_key(|pr| std::cmp::Reverse(pr.number));
+            let mut pr_links = vec![];
+            for pr in &prs {
+                let mut commits = match masters
+                    .branch_commits(&repo, pr.id)
+                    .with_context(|| format!("finding commits of PR #{}", pr.number))?
+                {
+                    Some(commits) => commits,
+                    None => continue,
+                };
+                // Newest first, as for branches
+                commits.reverse();
+
+                let name = format!("pr-{}.html", pr.number);
+                let body = html::matrix(&results.rows(&commits)?);
+                results.write(&name, &format!("PR #{}", pr.number), &body)?;
+                pr_links.push((format!("PR #{}", pr.number), name));
+            }
+
+            let body = format!(
+                "<h2>Branches</h2>\n{}<h2>Pull requests</h2>\n{}",
+                html::link_list(&branch_links),
+                html::link_list(&pr_links),
+            );
+            results.write("index.html", "Check results", &body)?;
+            println!(
+                "Wrote report of {} branches and {} PRs to {}",
+                branch_links.len(),
+                pr_links.len(),
+                out.to_string_lossy()
+            );
+        }
+    }
+    Ok(())
+}
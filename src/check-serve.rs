@@ -0,0 +1,249 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+use std::io::BufReader;
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{env, thread};
+
+use anyhow::Context;
+use structopt::StructOpt;
+
+use git_utils::config::{Config, Settings};
+use git_utils::http::{respond, Incoming};
+use git_utils::serve::{Queue, ServedRepo};
+use git_utils::webhook::{self, Delivery};
+
+#[derive(StructOpt, Debug)]
+struct Opts {
+    /// Address to listen for webhook deliveries on, e.g. 127.0.0.1:8080
+    #[structopt(short, long, required_unless_one = &["poll", "poll-once"])]
+    listen: Option<String>,
+    /// Also fetch every PR of every repository this often, in seconds, and
+    /// check those which are new or have changed
+    #[structopt(long)]
+    poll: Option<u64>,
+    /// Fetch every PR of every repository, queue those which are new or
+    /// have changed in the queue database for a running check-serve to
+    /// check, and exit
+    #[structopt(long, conflicts_with_all = &["listen", "poll"])]
+    poll_once: bool,
+    /// Extra config file to read, after the global one (may be given
+    /// multiple times)
+    #[structopt(long, parse(from_os_str))]
+    config: Vec<PathBuf>,
+    /// The check-pr binary to run; by default, the one alongside this one
+    #[structopt(long, parse(from_os_str))]
+    check_pr: Option<PathBuf>,
+}
+
+/// Polls every repository for new and updated PRs, forever
+fn poll(interval: Duration, repos: &[ServedRepo], queue: &Queue) -> ! {
+    loop {
+        poll_once(repos, queue);
+        thread::sleep(interval);
+    }
+}
+
+/// Polls every repository for new and updated PRs, queueing them
+fn poll_once(repos: &[ServedRepo], queue: &Queue) {
+    for repo in repos {
+        match repo.poll() {
+            Ok(updates) => {
+                for update in updates {
+                    let desc = format!("{}#{}", update.repo, update.number);
+                    match queue.push(update) {
+                        Ok(true) => println!("Queued {}", desc),
+                        Ok(false) => {}
+                        Err(e) => eprintln!("Error queueing {}: {:?}", desc, e),
+                    }
+                }
+            }
+            Err(e) => eprintln!("Error polling {}: {:?}", repo.name, e),
+        }
+    }
+}
+
+/// Opens the queue database, if there is one, or creates a queue in memory
+fn open_queue(settings: &Settings) -> anyhow::Result<Queue> {
+    match settings.queue_db() {
+        #[cfg(feature = "sqlite")]
+        Some(path) => Queue::open(&path),
+        #[cfg(not(feature = "sqlite"))]
+        Some(_) => Err(anyhow::Error::msg(
+            "queue-db is set, but check-serve was built without the sqlite feature",
+        )),
+        None => Ok(Queue::new()),
+    }
+}
+
+/// Answers a webhook delivery, queueing the PR it is about if it needs
+/// checking
+fn handle(stream: TcpStream, repos: &[ServedRepo], queue: &Queue) -> anyhow::Result<()> {
+    stream
+        .set_read_timeout(Some(Duration::from_secs(30)))
+        .context("setting read timeout")?;
+    let mut writer = stream.try_clone().context("cloning stream")?;
+    let request = Incoming::read(&mut BufReader::new(stream))?;
+    if request.method != "POST" {
+        respond(
+            &mut writer,
+            "405 Method Not Allowed",
+            "text/plain",
+            "only POST is supported\n",
+        )?;
+        return Ok(());
+    }
+
+    match webhook::parse(&request, repos) {
+        Ok(Delivery::Check(update)) => {
+            let desc = format!("{}#{}", update.repo, update.number);
+            match queue.push(update) {
+                Ok(added) => {
+                    let body = if added {
+                        println!("Queued {}", desc);
+                        format!("queued {}\n", desc)
+                    } else {
+                        format!("{} is already queued\n", desc)
+                    };
+                    respond(&mut writer, "202 Accepted", "text/plain", &body)?;
+                }
+                Err(e) => {
+                    eprintln!("Error queueing {}: {:?}", desc, e);
+                    respond(
+                        &mut writer,
+                        "500 Internal Server Error",
+                        "text/plain",
+                        &format!("failed to queue {}\n", desc),
+                    )?;
+                }
+            }
+        }
+        Ok(Delivery::Ignore(reason)) => {
+            respond(
+                &mut writer,
+                "200 OK",
+                "text/plain",
+                &format!("ignored: {}\n", reason),
+            )?;
+        }
+        Err(e) => {
+            eprintln!("Rejected webhook delivery: {:#}", e);
+            respond(
+                &mut writer,
+                "400 Bad Request",
+                "text/plain",
+                &format!("{:#}\n", e),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    let opts = Opts::from_args();
+    let config = Config::load(&opts.config, None)?;
+    let repos = config.settings().serve.clone();
+    if repos.is_empty() {
+        return Err(anyhow::Error::msg(
+            "no repositories to serve: add [[serve]] sections to the config",
+        ));
+    }
+    let check_pr = match opts.check_pr.clone() {
+        Some(path) => path,
+        None => env::current_exe()
+            .context("finding our own binary")?
+            .with_file_name("check-pr"),
+    };
+
+    let queue = Arc::new(open_queue(config.settings())?);
+    if opts.poll_once {
+        if config.settings().queue_db().is_none() {
+            return Err(anyhow::Error::msg(
+                "--poll-once needs queue-db to be set, so that a running check-serve \
+                 can check the PRs it queues",
+            ));
+        }
+        poll_once(&repos, &queue);
+        return Ok(());
+    }
+    let requeued = queue.requeue_started()?;
+    if requeued > 0 {
+        println!("Requeued {} PRs which were being checked", requeued);
+    }
+
+    // Check PRs one at a time, in the background
+    let worker_queue = Arc::clone(&queue);
+    let worker_repos = repos.clone();
+    thread::spawn(move || loop {
+        let update = match worker_queue.pop() {
+            Ok(update) => update,
+            Err(e) => {
+                eprintln!("Error reading queue: {:?}", e);
+                thread::sleep(Duration::from_secs(60));
+                continue;
+            }
+        };
+        let desc = format!("{}#{}", update.repo, update.number);
+        let repo = worker_repos
+            .iter()
+            .find(|repo| repo.name == update.repo)
+            .expect("only served repositories are queued");
+        println!("Checking {}", desc);
+        match update.check(repo, &check_pr) {
+            Ok(true) => println!("Checks passed on {}", desc),
+            Ok(false) => println!("Checks failed on {}", desc),
+            Err(e) => eprintln!("Error checking {}: {:?}", desc, e),
+        }
+        if let Err(e) = worker_queue.done(&update) {
+            eprintln!("Error removing {} from queue: {:?}", desc, e);
+        }
+    });
+
+    let listen = match opts.listen {
+        Some(ref listen) => listen,
+        None => {
+            let interval = Duration::from_secs(opts.poll.expect("--poll is given"));
+            println!("Polling {} repositories", repos.len());
+            poll(interval, &repos, &queue);
+        }
+    };
+    if let Some(secs) = opts.poll {
+        let poll_queue = Arc::clone(&queue);
+        let poll_repos = repos.clone();
+        thread::spawn(move || poll(Duration::from_secs(secs), &poll_repos, &poll_queue));
+    }
+    let listener = TcpListener::bind(listen)
+        .with_context(|| format!("listening for webhooks on {}", listen))?;
+    println!(
+        "Listening for webhooks on {}, for {} repositories",
+        listen,
+        repos.len()
+    );
+    for stream in listener.incoming() {
+        let result = stream
+            .context("accepting connection")
+            .and_then(|stream| handle(stream, &repos, &queue));
+        if let Err(e) = result {
+            eprintln!("Error handling webhook delivery: {:?}", e);
+        }
+    }
+    Ok(())
+}
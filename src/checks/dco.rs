@@ -0,0 +1,261 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+//! Checks that commits carry the trailers a project requires, such as a
+//! `Signed-off-by` by their author for the Developer Certificate of Origin
+
+use anyhow::Context;
+use git2::{Oid, Repository};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use tempfile::TempDir;
+
+use super::{Trailers, Validation};
+use crate::git::TempRepo;
+
+/// Start of the description of the check's job
+pub(super) const JOB: &str = "dco";
+
+/// The trailer by which the author certifies the DCO
+const SIGN_OFF: &str = "Signed-off-by";
+
+fn default_required() -> Vec<String> {
+    vec![SIGN_OFF.to_owned()]
+}
+
+fn default_match_author() -> bool {
+    true
+}
+
+/// A check that every commit carries some trailers, by default a
+/// `Signed-off-by` naming its author
+#[derive(Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "kebab-case")]
+pub struct DcoCheck {
+    /// Trailers every commit must have, matched case-insensitively
+    #[serde(
+        default = "default_required",
+        deserialize_with = "super::single_or_seq"
+    )]
+    required: Vec<String>,
+    /// Whether one of the `Signed-off-by` trailers must give the commit's
+    /// author, as `Name <email>`
+    #[serde(default = "default_match_author")]
+    match_author: bool,
+    #[serde(default)]
+    pub(super) commits: super::CommitSelector,
+    /// Whether the check failing should not fail the run
+    #[serde(default)]
+    pub(super) allow_failure: bool,
+}
+
+impl fmt::Display for DcoCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{{ dco {:?} }}", self.required)
+    }
+}
+
+impl DcoCheck {
+    /// See `Check::for_commit`
+    ///
+    /// As for signatures, a commit cannot skip this check with its own
+    /// trailers.
+    pub(super) fn for_commit(&self, selected: bool, trailers: &Trailers) -> Option<Self> {
+        if selected || trailers.adds(JOB) {
+            Some(self.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Description of the check's only job, as recorded in the notes
+    fn job_description(&self) -> String {
+        format!(
+            "{} '--required={}'{}",
+            JOB,
+            self.required.join(","),
+            if self.match_author {
+                " '--match-author'"
+            } else {
+                ""
+            },
+        )
+    }
+
+    /// See `Check::validate`
+    pub(super) fn validate(&self, _: Option<&TempDir>) -> Validation {
+        let mut ret = Validation::default();
+        if self.required.is_empty() && !self.match_author {
+            ret.problems
+                .push("no trailers required and match-author is false".to_owned());
+        }
+        for trailer in &self.required {
+            if trailer.is_empty() || trailer.contains(|c: char| c == ':' || c.is_whitespace()) {
+                ret.problems
+                    .push(format!("`{}` is not a trailer name", trailer));
+            }
+        }
+        ret.jobs.push(self.job_description());
+        ret
+    }
+
+    /// Checks the trailers of the commit checked out in `repo`
+    pub fn execute(
+        &self,
+        repo: &TempRepo,
+        _existing_notes: Vec<String>,
+        check_hash: &str,
+    ) -> anyhow::Result<Vec<String>> {
+        let head = repo.repo.head().context("getting HEAD")?.target().unwrap();
+        super::run_commit_job(
+            head,
+            check_hash,
+            &self.job_description(),
+            "DCO check",
+            || {
+                self.verify(&repo.repo, head)
+                    .map(|()| "has every required trailer".to_owned())
+            },
+        )
+    }
+
+    /// Checks the trailers of a commit, failing with everything missing
+    fn verify(&self, repo: &Repository, commit: Oid) -> anyhow::Result<()> {
+        let commit_obj = repo
+            .find_commit(commit)
+            .with_context(|| format!("looking up commit {}", commit))?;
+        let author = commit_obj.author();
+        let author = format!(
+            "{} <{}>",
+            author.name().unwrap_or(""),
+            author.email().unwrap_or("")
+        );
+        let problems = self.problems(commit_obj.message().unwrap_or(""), &author);
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::Error::msg(format!(
+                "commit {} {}",
+                commit,
+                problems.join(", and ")
+            )))
+        }
+    }
+
+    /// Everything wrong with the trailers of a commit message, given the
+    /// commit's author as `Name <email>`
+    fn problems(&self, message: &str, author: &str) -> Vec<String> {
+        let trailers: Vec<(String, String)> = match git2::message_trailers_strs(message) {
+            Ok(trailers) => trailers
+                .iter()
+                .map(|(key, value)| (key.to_owned(), value.trim().to_owned()))
+                .collect(),
+            Err(_) => vec![],
+        };
+        let values = |name: &str| -> Vec<&str> {
+            trailers
+                .iter()
+                .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| &value[..])
+                .collect()
+        };
+
+        let mut ret = vec![];
+        for name in &self.required {
+            if values(name).is_empty() {
+                ret.push(format!("has no {} trailer", name));
+            }
+        }
+        let sign_offs = values(SIGN_OFF);
+        if self.match_author && !sign_offs.iter().any(|&value| same_person(value, author)) {
+            if sign_offs.is_empty() {
+                if !self
+                    .required
+                    .iter()
+                    .any(|r| r.eq_ignore_ascii_case(SIGN_OFF))
+                {
+                    ret.push(format!("has no {} trailer", SIGN_OFF));
+                }
+            } else {
+                ret.push(format!(
+                    "is not signed off by its author {} (only by {})",
+                    author,
+                    sign_offs.join(", ")
+                ));
+            }
+        }
+        ret
+    }
+}
+
+/// Whether two `Name <email>` identities are the same, taking email
+/// addresses to be case-insensitive
+fn same_person(a: &str, b: &str) -> bool {
+    let split = |s: &str| match s.rsplit_once('<') {
+        Some((name, email)) => (
+            name.trim().to_owned(),
+            email.trim_end_matches('>').trim().to_ascii_lowercase(),
+        ),
+        None => (s.trim().to_owned(), String::new()),
+    };
+    split(a) == split(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn problems() {
+        let check: DcoCheck = serde_json::from_str("{}").unwrap();
+        let author = "Some One <some@example.com>";
+
+        let ok = "Fix\n\nSigned-off-by: Some One <Some@Example.com>\n";
+        assert!(check.problems(ok, author).is_empty());
+        assert_eq!(
+            check.problems("Fix\n", author),
+            vec!["has no Signed-off-by trailer"]
+        );
+        let other = "Fix\n\nSigned-off-by: Other <other@example.com>\n";
+        assert_eq!(
+            check.problems(other, author),
+            vec![
+                "is not signed off by its author Some One <some@example.com> \
+                 (only by Other <other@example.com>)"
+            ]
+        );
+        // As rebased by check-pr
+        let rebased = crate::git::add_trailer(ok, "Cherry-picked-from", "abc");
+        assert!(check.problems(&rebased, author).is_empty());
+        // Only a trailer block counts, not a line in the body
+        let body = "Fix\n\nSigned-off-by: Some One <some@example.com> was here.\n\nMore\n";
+        assert_eq!(check.problems(body, author).len(), 1);
+
+        let check: DcoCheck =
+            serde_json::from_str(r#"{ "required": ["Reviewed-by"], "match-author": false }"#)
+                .unwrap();
+        assert_eq!(
+            check.problems(ok, author),
+            vec!["has no Reviewed-by trailer"]
+        );
+        assert!(check
+            .problems("Fix\n\nreviewed-by: X <x@y>\n", author)
+            .is_empty());
+    }
+}
@@ -16,14 +16,31 @@
 // Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
 //
 
+mod dco;
 mod rust;
+mod signatures;
 
-use rayon::ThreadPool;
+use anyhow::Context;
 use serde::{de, Deserialize, Deserializer, Serialize};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::fmt;
 use std::marker::PhantomData;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Instant;
+use tempfile::TempDir;
 
+use crate::container::Container;
 use crate::git::TempRepo;
+use crate::job::{BuildPools, JobClass, Semaphore};
+use crate::limits::Limits;
+use crate::notes::{NoteEntry, NoteOutcome};
+use crate::output::{report, Event, Outcome};
+use crate::resume;
+use crate::sandbox::Sandbox;
+use crate::say;
 
 /// serde helper from https://stackoverflow.com/a/43627388/14495533
 /// to decode strings as single-element vecs of strings. Modified to
@@ -62,16 +79,612 @@ where
     deserializer.deserialize_any(StringOrVec(PhantomData))
 }
 
+/// serde helper decoding a check's `commits`, or its deprecated alias
+/// `only-tip`, a bool which stands for `commits = "tip"` when true
+fn commits_or_only_tip<'de, D>(deserializer: D) -> Result<CommitSelector, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct SelectorOrBool;
+
+    impl<'de> de::Visitor<'de> for SelectorOrBool {
+        type Value = CommitSelector;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("commit selector, or bool for only-tip")
+        }
+
+        fn visit_bool<E>(self, only_tip: bool) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            if only_tip {
+                Ok(CommitSelector::Tip)
+            } else {
+                Ok(CommitSelector::All)
+            }
+        }
+
+        fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            CommitSelector::from_str(value).map_err(E::custom)
+        }
+    }
+
+    deserializer.deserialize_any(SelectorOrBool)
+}
+
+/// Position of a commit within the series of PR commits being checked
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CommitPosition {
+    /// Index of the commit, counting from 0 at the base of the PR
+    pub index: usize,
+    /// Total number of commits in the series
+    pub n_commits: usize,
+}
+
+impl CommitPosition {
+    /// Whether this is the tip of the PR
+    pub fn is_tip(&self) -> bool {
+        self.index + 1 == self.n_commits
+    }
+}
+
+/// Which commits of a PR a check should be run on
+#[derive(Clone, Debug, Default, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum CommitSelector {
+    /// Every commit
+    #[default]
+    All,
+    /// Only the tip
+    Tip,
+    /// The tip and the first commit of the PR
+    TipAndBase,
+    /// Every nth commit, counting from the base, as well as the tip
+    EveryNth(usize),
+    /// Commits which change a file whose path matches the given glob
+    ChangedPaths(String),
+}
+
+impl CommitSelector {
+    /// Whether a given commit is selected
+    pub fn selects(
+        &self,
+        repo: &git2::Repository,
+        id: git2::Oid,
+        pos: CommitPosition,
+    ) -> anyhow::Result<bool> {
+        match *self {
+            CommitSelector::All => Ok(true),
+            CommitSelector::Tip => Ok(pos.is_tip()),
+            CommitSelector::TipAndBase => Ok(pos.is_tip() || pos.index == 0),
+            CommitSelector::EveryNth(n) => Ok(pos.is_tip() || (pos.index + 1).is_multiple_of(n)),
+            CommitSelector::ChangedPaths(ref glob) => {
+                let pattern =
+                    glob::Pattern::new(glob).with_context(|| format!("parsing glob {}", glob))?;
+                let commit = repo
+                    .find_commit(id)
+                    .with_context(|| format!("looking up commit {}", id))?;
+                let tree = commit.tree().context("getting commit tree")?;
+                let parent_tree = match commit.parent(0) {
+                    Ok(parent) => Some(parent.tree().context("getting parent tree")?),
+                    Err(_) => None,
+                };
+                let diff = repo
+                    .diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), None)
+                    .with_context(|| format!("diffing commit {} against its parent", id))?;
+                Ok(diff.deltas().any(|delta| {
+                    [delta.old_file().path(), delta.new_file().path()]
+                        .iter()
+                        .flatten()
+                        .any(|path| pattern.matches_path(path))
+                }))
+            }
+        }
+    }
+}
+
+impl fmt::Display for CommitSelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CommitSelector::All => f.write_str("all"),
+            CommitSelector::Tip => f.write_str("tip"),
+            CommitSelector::TipAndBase => f.write_str("tip-and-base"),
+            CommitSelector::EveryNth(n) => write!(f, "every-nth({})", n),
+            CommitSelector::ChangedPaths(ref glob) => write!(f, "changed-paths({})", glob),
+        }
+    }
+}
+
+impl FromStr for CommitSelector {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "all" => return Ok(CommitSelector::All),
+            "tip" => return Ok(CommitSelector::Tip),
+            "tip-and-base" => return Ok(CommitSelector::TipAndBase),
+            _ => {}
+        }
+
+        if let Some(n) = s
+            .strip_prefix("every-nth(")
+            .and_then(|s| s.strip_suffix(')'))
+        {
+            return match usize::from_str(n) {
+                Ok(0) | Err(_) => Err(format!("bad commit count {} in {}", n, s)),
+                Ok(n) => Ok(CommitSelector::EveryNth(n)),
+            };
+        }
+        if let Some(glob) = s
+            .strip_prefix("changed-paths(")
+            .and_then(|s| s.strip_suffix(')'))
+        {
+            return match glob::Pattern::new(glob) {
+                Ok(_) => Ok(CommitSelector::ChangedPaths(glob.to_owned())),
+                Err(e) => Err(format!("bad glob {}: {}", glob, e)),
+            };
+        }
+        Err(format!(
+            "unknown commit selector {} (expected all, tip, tip-and-base, every-nth(n) or changed-paths(glob))",
+            s
+        ))
+    }
+}
+
+impl TryFrom<String> for CommitSelector {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, String> {
+        CommitSelector::from_str(&s)
+    }
+}
+
+impl From<CommitSelector> for String {
+    fn from(sel: CommitSelector) -> String {
+        sel.to_string()
+    }
+}
+
+/// What network access a check's jobs have
+///
+/// Only a container or sandbox can actually cut jobs off from the network;
+/// otherwise, all that can be done is to run cargo offline.
+#[derive(
+    Copy, Clone, Debug, Default, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Network {
+    /// None: every dependency must already be in the local cache
+    Off,
+    /// None, but dependencies are fetched beforehand, by `cargo fetch` on
+    /// the host, which does not run any of the crate's code
+    RegistryOnly,
+    /// Full access
+    #[default]
+    Full,
+}
+
+/// Per-commit adjustments to the checks, given by trailers in the commit
+/// message such as `Rsgit-Check: fuzz` or `Rsgit-Skip: examples, test`
+///
+/// Names are either check types (e.g. `rust`), which refer to whole
+/// checks, or job names (e.g. `examples`). `Rsgit-Skip` removes the named
+/// checks or jobs; `Rsgit-Check` forces them to run even if the check's
+/// `commits` selector would skip this commit. Neither can add a job that
+/// is not in the configuration. `signatures` and `dco` checks cannot be
+/// skipped.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Trailers {
+    /// Checks or jobs to run regardless of the commit selector
+    pub add: Vec<String>,
+    /// Checks or jobs not to run
+    pub skip: Vec<String>,
+}
+
+impl Trailers {
+    /// Extracts the trailers from the trailer block of a commit message
+    pub fn from_message(message: &str) -> Self {
+        let mut ret = Trailers::default();
+        let trailers = match git2::message_trailers_strs(message) {
+            Ok(trailers) => trailers,
+            Err(_) => return ret,
+        };
+        for (key, values) in trailers.iter() {
+            let list = match &key.to_ascii_lowercase()[..] {
+                "rsgit-check" => &mut ret.add,
+                "rsgit-skip" => &mut ret.skip,
+                _ => continue,
+            };
+            list.extend(
+                values
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|s| !s.is_empty())
+                    .map(str::to_ascii_lowercase),
+            );
+        }
+        ret
+    }
+
+    /// Whether there are no trailers
+    pub fn is_empty(&self) -> bool {
+        self.add.is_empty() && self.skip.is_empty()
+    }
+
+    fn adds(&self, name: &str) -> bool {
+        self.add.iter().any(|s| s == name)
+    }
+
+    fn skips(&self, name: &str) -> bool {
+        self.skip.iter().any(|s| s == name)
+    }
+}
+
+/// Whether the result of a job depends on more than the tree of the commit
+/// it was run on, e.g. on its signature, so must not be recorded on the tree
+pub fn depends_on_commit(job: &str) -> bool {
+    job.starts_with(signatures::JOB) || job.starts_with(dco::JOB)
+}
+
+/// Paths, within a commit's tree, at which it may specify its own checks
+pub const TREE_CONFIG_PATHS: [&str; 2] = [".rsgit/checks.json", ".rsgit/checks.toml"];
+
+/// Layout of the TOML form of a check list, which cannot be a bare array
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlCheckList {
+    #[serde(default)]
+    check: Vec<Check>,
+}
+
+/// Reads the check list stored in a commit's tree, if any
+///
+/// JSON files contain a list of checks, in the same format as check-pr's
+/// command-line argument; TOML files contain a `[[check]]` table per check.
+pub fn checks_from_tree(
+    repo: &git2::Repository,
+    commit: &git2::Commit,
+) -> anyhow::Result<Option<Vec<Check>>> {
+    let tree = commit
+        .tree()
+        .with_context(|| format!("getting tree of {}", commit.id()))?;
+    for path in &TREE_CONFIG_PATHS {
+        let entry = match tree.get_path(std::path::Path::new(path)) {
+            Ok(entry) => entry,
+            Err(_) => continue,
+        };
+        let blob = repo
+            .find_blob(entry.id())
+            .with_context(|| format!("reading {} in commit {}", path, commit.id()))?;
+        let text = std::str::from_utf8(blob.content())
+            .with_context(|| format!("decoding {} in commit {}", path, commit.id()))?;
+        let checks = if path.ends_with(".toml") {
+            toml::from_str::<TomlCheckList>(text)
+                .map(|list| list.check)
+                .map_err(anyhow::Error::from)
+        } else {
+            serde_json::from_str(text).map_err(anyhow::Error::from)
+        };
+        return checks
+            .map(Some)
+            .with_context(|| format!("parsing {} in commit {}", path, commit.id()));
+    }
+    Ok(None)
+}
+
+/// Entries for failed jobs not yet written to the notes, by commit
+static FAILURES: Mutex<BTreeMap<git2::Oid, Vec<String>>> = Mutex::new(BTreeMap::new());
+
+/// What is shown as the toolchain of jobs run by `run_commit_job`
+const COMMIT_JOB_TOOLCHAIN: &str = "git";
+
+/// Runs a job which only looks at the commit itself, such as its signature,
+/// rather than building anything, returning its note
+///
+/// `job` returns what it found, which is printed after the commit ID if it
+/// passed. Such jobs are cheap, so are always run afresh. Each failure is
+/// printed as it happens, prefixed by `name`, as the run's error only names
+/// the first.
+fn run_commit_job<F>(
+    head: git2::Oid,
+    check_hash: &str,
+    job: &str,
+    name: &str,
+    run: F,
+) -> anyhow::Result<Vec<String>>
+where
+    F: FnOnce() -> anyhow::Result<String>,
+{
+    report(Event::Started {
+        commit: head,
+        check: check_hash,
+        toolchain: COMMIT_JOB_TOOLCHAIN,
+        job,
+    });
+    let start = Instant::now();
+    let result = run();
+    let (outcome, error) = match result {
+        Ok(ref found) => {
+            say!(Normal, "Commit {} {}", head, found);
+            (Outcome::Pass(start.elapsed()), None)
+        }
+        Err(ref e) => {
+            say!(Quiet, "{} failed: {:#}", name, e);
+            (Outcome::Fail(start.elapsed()), Some(format!("{:#}", e)))
+        }
+    };
+    report(Event::Finished {
+        commit: head,
+        check: check_hash,
+        toolchain: COMMIT_JOB_TOOLCHAIN,
+        job,
+        outcome,
+        error: error.as_deref(),
+        log: None,
+        annotations: &[],
+    });
+
+    let outcome = match result {
+        Ok(..) => NoteOutcome::Pass,
+        Err(..) => NoteOutcome::Fail,
+    };
+    let mut entry = NoteEntry::finished(job, outcome, start.elapsed());
+    entry.check = Some(check_hash.to_owned()).filter(|hash| !hash.is_empty());
+    if result.is_err() {
+        record_failure(head, &entry);
+    }
+    result?;
+    let note = entry.to_string();
+    resume::record(head, &note);
+    Ok(vec![note])
+}
+
+/// Records the entry of a job which has failed on a commit, to be written
+/// to its notes with [`take_failures`]
+pub fn record_failure(commit: git2::Oid, entry: &NoteEntry) {
+    FAILURES
+        .lock()
+        .unwrap()
+        .entry(commit)
+        .or_default()
+        .push(entry.to_string());
+}
+
+/// Entries for the jobs which have failed on a commit since this was last
+/// called for it
+pub fn take_failures(commit: git2::Oid) -> Vec<String> {
+    FAILURES.lock().unwrap().remove(&commit).unwrap_or_default()
+}
+
+/// What to do about a job which an earlier run recorded as failing
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum FailurePolicy {
+    /// Run it again
+    #[default]
+    Retry,
+    /// Count it as failing again, without running it
+    Skip,
+    /// Run it again once this many hours have passed since it failed
+    RetryAfter(u64),
+}
+
+impl FailurePolicy {
+    /// Whether a job which failed at the given time is to be counted as
+    /// failing again, rather than run
+    pub fn skips(&self, failed_at: time::Timespec) -> bool {
+        match *self {
+            FailurePolicy::Retry => false,
+            FailurePolicy::Skip => true,
+            FailurePolicy::RetryAfter(hours) => {
+                let hours = i64::try_from(hours).unwrap_or(i64::MAX / 3600);
+                time::get_time().sec - failed_at.sec < hours * 3600
+            }
+        }
+    }
+}
+
+impl fmt::Display for FailurePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FailurePolicy::Retry => f.write_str("retry"),
+            FailurePolicy::Skip => f.write_str("skip"),
+            FailurePolicy::RetryAfter(hours) => write!(f, "retry-after({})", hours),
+        }
+    }
+}
+
+impl FromStr for FailurePolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "retry" => return Ok(FailurePolicy::Retry),
+            "skip" => return Ok(FailurePolicy::Skip),
+            _ => {}
+        }
+
+        if let Some(hours) = s
+            .strip_prefix("retry-after(")
+            .and_then(|s| s.strip_suffix(')'))
+        {
+            return match u64::from_str(hours) {
+                Ok(hours) => Ok(FailurePolicy::RetryAfter(hours)),
+                Err(_) => Err(format!("bad number of hours {} in {}", hours, s)),
+            };
+        }
+        Err(format!(
+            "unknown failure policy {} (expected retry, skip or retry-after(<hours>))",
+            s
+        ))
+    }
+}
+
+/// Result of validating a check
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Validation {
+    /// Descriptions of every job the check would run
+    pub jobs: Vec<String>,
+    /// Mistakes found in the check's configuration
+    pub problems: Vec<String>,
+}
+
+/// Settings which apply to every check in a run
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RunOptions {
+    /// Directory in which to keep cargo target directories shared between
+    /// jobs, one per toolchain and feature set
+    pub target_cache: Option<PathBuf>,
+    /// Cargo home directory to fetch all dependencies into before running
+    /// any jobs, which are then run offline
+    pub cargo_home: Option<PathBuf>,
+    /// Number of jobs each cargo invocation may run at once, if limited
+    pub jobs: Option<usize>,
+    /// Limits on the resources each job may use
+    pub limits: Limits,
+    /// How to run jobs in containers, if at all
+    pub container: Option<Container>,
+    /// How to sandbox jobs, if at all
+    pub sandbox: Option<Sandbox>,
+    /// Whether to run cargo without network access, using only the
+    /// dependencies already in the local cache
+    pub offline: bool,
+    /// Directory in which to save the output of every job
+    pub log_dir: Option<PathBuf>,
+    /// Whether to print the output of every job as it is produced
+    pub stream: bool,
+    /// What to do about jobs which an earlier run recorded as failing
+    pub failure_policy: FailurePolicy,
+}
+
 #[derive(Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case", tag = "type")]
 pub enum Check {
     Rust(self::rust::RustCheck),
+    Signatures(self::signatures::SignaturesCheck),
+    Dco(self::dco::DcoCheck),
 }
 
 impl Check {
-    pub fn execute(&self, repo: TempRepo, build_pool: &ThreadPool) -> anyhow::Result<Vec<String>> {
+    /// Runs the check on the commit checked out in `repo`, skipping any
+    /// jobs that are already recorded in `existing_notes`
+    ///
+    /// If `limit` is given, each job holds one of its permits while it runs.
+    ///
+    /// Returns the notes for the jobs which were newly run.
+    pub fn execute(
+        &self,
+        repo: &TempRepo,
+        existing_notes: Vec<String>,
+        build_pools: &BuildPools,
+        options: &RunOptions,
+        limit: Option<&Semaphore>,
+    ) -> anyhow::Result<Vec<String>> {
+        let hash = self.config_hash();
+        match *self {
+            Check::Rust(ref sub) => {
+                sub.execute(repo, existing_notes, build_pools, options, &hash, limit)
+            }
+            Check::Signatures(ref sub) => sub.execute(repo, existing_notes, &hash),
+            Check::Dco(ref sub) => sub.execute(repo, existing_notes, &hash),
+        }
+    }
+
+    /// Hash identifying the check's configuration, which is the git blob ID
+    /// of its JSON encoding
+    pub fn config_hash(&self) -> String {
+        let json = serde_json::to_string(self).expect("checks can be serialized");
+        git2::Oid::hash_object(git2::ObjectType::Blob, json.as_bytes())
+            .expect("hashing in memory")
+            .to_string()
+    }
+
+    /// Checks the configuration for mistakes, and expands the full list of
+    /// jobs that it would run, without running anything
+    ///
+    /// If a checkout of the crate is given, the configuration is also
+    /// checked against it.
+    pub fn validate(&self, checkout: Option<&TempDir>) -> Validation {
+        match *self {
+            Check::Rust(ref sub) => sub.validate(checkout),
+            Check::Signatures(ref sub) => sub.validate(checkout),
+            Check::Dco(ref sub) => sub.validate(checkout),
+        }
+    }
+
+    /// What is wrong with the check if it comes from a commit's own tree,
+    /// which anyone opening a PR can write, beyond what `validate` finds:
+    /// anything which would change how its jobs are run on this machine
+    pub fn untrusted_problems(&self) -> Vec<String> {
+        match *self {
+            Check::Rust(ref sub) => sub.untrusted_problems(),
+            Check::Signatures(_) | Check::Dco(_) => vec![],
+        }
+    }
+
+    /// Whether the check runs any of the crate's code, as opposed to only
+    /// looking at the commit itself
+    pub fn runs_code(&self) -> bool {
         match *self {
-            Check::Rust(ref sub) => sub.execute(repo, build_pool),
+            Check::Rust(_) => true,
+            Check::Signatures(_) | Check::Dco(_) => false,
+        }
+    }
+
+    /// Most of the check's jobs to run at once, across every commit, if
+    /// limited
+    pub fn max_parallel(&self) -> Option<usize> {
+        match *self {
+            Check::Rust(ref sub) => sub.max_parallel,
+            Check::Signatures(_) | Check::Dco(_) => None,
+        }
+    }
+
+    /// The class of the check's heaviest job, as a rough measure of how
+    /// long it will take
+    pub fn class(&self) -> JobClass {
+        match *self {
+            Check::Rust(ref sub) => sub.class(),
+            Check::Signatures(_) | Check::Dco(_) => JobClass::Light,
+        }
+    }
+
+    /// Whether the check failing should not fail the run, e.g. for
+    /// experimental checks on nightly
+    pub fn allow_failure(&self) -> bool {
+        match *self {
+            Check::Rust(ref sub) => sub.allow_failure,
+            Check::Signatures(ref sub) => sub.allow_failure,
+            Check::Dco(ref sub) => sub.allow_failure,
+        }
+    }
+
+    /// What network access the check's jobs have
+    pub fn network(&self) -> Network {
+        match *self {
+            Check::Rust(ref sub) => sub.network,
+            // Nothing of the crate's is run, so there is nothing to limit
+            Check::Signatures(_) | Check::Dco(_) => Network::Full,
+        }
+    }
+
+    /// Which commits of the PR this check should be run on
+    pub fn commits(&self) -> &CommitSelector {
+        match *self {
+            Check::Rust(ref sub) => &sub.commits,
+            Check::Signatures(ref sub) => &sub.commits,
+            Check::Dco(ref sub) => &sub.commits,
+        }
+    }
+
+    /// Determines the check to actually run on a commit, given whether the
+    /// commit selector picked it and the commit's trailers
+    ///
+    /// Returns `None` if nothing should be run.
+    pub fn for_commit(&self, selected: bool, trailers: &Trailers) -> Option<Check> {
+        match *self {
+            Check::Rust(ref sub) => sub.for_commit(selected, trailers).map(Check::Rust),
+            Check::Signatures(ref sub) => sub.for_commit(selected, trailers).map(Check::Signatures),
+            Check::Dco(ref sub) => sub.for_commit(selected, trailers).map(Check::Dco),
         }
     }
 }
@@ -80,6 +693,8 @@ impl fmt::Display for Check {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Check::Rust(ref sub) => sub.fmt(f),
+            Check::Signatures(ref sub) => sub.fmt(f),
+            Check::Dco(ref sub) => sub.fmt(f),
         }
     }
 }
@@ -87,7 +702,106 @@ impl fmt::Display for Check {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use serde_json;
+
+    #[test]
+    fn commit_selector() {
+        for s in &[
+            "all",
+            "tip",
+            "tip-and-base",
+            "every-nth(5)",
+            "changed-paths(src/**/*.rs)",
+        ] {
+            let sel = CommitSelector::from_str(s).expect("parsing");
+            assert_eq!(sel.to_string(), *s);
+        }
+        assert!(CommitSelector::from_str("every-nth(0)").is_err());
+        assert!(CommitSelector::from_str("every-nth(x)").is_err());
+        assert!(CommitSelector::from_str("changed-paths([)").is_err());
+
+        let pos = |index| CommitPosition {
+            index,
+            n_commits: 7,
+        };
+        let dir = tempfile::tempdir().expect("creating tempdir");
+        let repo = git2::Repository::init(dir.path()).expect("creating repo");
+        let selected = |sel: CommitSelector| -> Vec<usize> {
+            (0..7)
+                .filter(|&i| sel.selects(&repo, git2::Oid::zero(), pos(i)).unwrap())
+                .collect()
+        };
+        assert_eq!(selected(CommitSelector::All), vec![0, 1, 2, 3, 4, 5, 6]);
+        assert_eq!(selected(CommitSelector::Tip), vec![6]);
+        assert_eq!(selected(CommitSelector::TipAndBase), vec![0, 6]);
+        let every_3rd = selected(CommitSelector::EveryNth(3));
+        assert_eq!(every_3rd, vec![2, 5, 6]);
+    }
+
+    #[test]
+    fn failure_policy() {
+        for s in &["retry", "skip", "retry-after(24)"] {
+            let policy = FailurePolicy::from_str(s).expect("parsing");
+            assert_eq!(policy.to_string(), *s);
+        }
+        assert!(FailurePolicy::from_str("retry-after(x)").is_err());
+        assert!(FailurePolicy::from_str("never").is_err());
+
+        let at = time::get_time();
+        assert!(!FailurePolicy::Retry.skips(at));
+        assert!(FailurePolicy::Skip.skips(at));
+        assert!(FailurePolicy::RetryAfter(1).skips(at));
+        let earlier = time::Timespec::new(at.sec - 7200, 0);
+        assert!(!FailurePolicy::RetryAfter(1).skips(earlier));
+        assert!(FailurePolicy::RetryAfter(3).skips(earlier));
+    }
+
+    #[test]
+    fn trailers() {
+        let trailers = Trailers::from_message(
+            "Add a new fuzz target\n\
+             \n\
+             This isn't ready yet.\n\
+             \n\
+             Rsgit-Skip: examples, Test\n\
+             rsgit-check: fuzz\n\
+             Signed-off-by: Someone <someone@example.com>\n",
+        );
+        assert_eq!(trailers.add, vec!["fuzz"]);
+        assert_eq!(trailers.skip, vec!["examples", "test"]);
+
+        // Only the trailer block counts, not lines in the body which look
+        // like trailers
+        let quoted = Trailers::from_message(
+            "Revert \"Skip the tests\"\n\
+             \n\
+             This reverts a commit which said\n\
+             Rsgit-Skip: rust\n\
+             \n\
+             Signed-off-by: Someone <someone@example.com>\n",
+        );
+        assert!(quoted.is_empty());
+        assert!(Trailers::from_message("Rsgit-Skip: rust\n").is_empty());
+
+        let check: Check = serde_json::from_str(
+            "{ \"type\": \"rust\", \"jobs\": [\"build\", \"examples\", { \"fuzz\": {} }] }",
+        )
+        .expect("decoding");
+        let adjusted = check.for_commit(true, &trailers).expect("still a check");
+        assert_eq!(
+            adjusted.to_string(),
+            "{ rust [] [Build, Fuzz { iters: 100000 }] }"
+        );
+        let adjusted = check.for_commit(false, &trailers).expect("still a check");
+        assert_eq!(adjusted.to_string(), "{ rust [] [Fuzz { iters: 100000 }] }");
+
+        let none = Trailers::default();
+        assert_eq!(check.for_commit(true, &none), Some(check.clone()));
+        assert_eq!(check.for_commit(false, &none), None);
+        let skip_all = Trailers::from_message("Skip it\n\nRsgit-Skip: rust\n");
+        assert_eq!(check.for_commit(true, &skip_all), None);
+        let add_all = Trailers::from_message("Check it\n\nRsgit-Check: rust\n");
+        assert_eq!(check.for_commit(false, &add_all), Some(check.clone()));
+    }
 
     #[test]
     fn decode_rust() {
@@ -128,4 +842,109 @@ mod tests {
         )
         .expect("decoding");
     }
+
+    #[test]
+    fn decode_rust_options() {
+        let _ck: Check = serde_json::from_str(
+            "
+            {
+                \"type\": \"rust\",
+                \"version\": [\"1.41.1\", \"stable\"],
+                \"pins-file\": \"contrib/msrv-pins.sh\",
+                \"example-args\": {
+                    \"sign\": { \"args\": [\"--key\", \"00ff\"], \"env\": { \"RUST_LOG\": \"debug\" } },
+                    \"verify\": { \"args\": \"sig.bin\" }
+                }
+            }
+       ",
+        )
+        .expect("decoding");
+
+        let _ck: Check = serde_json::from_str(
+            "
+            {
+                \"type\": \"rust\",
+                \"commits\": \"tip\",
+                \"version\": \"nightly\",
+                \"working-dir\": \"fuzz\",
+                \"jobs\": [ \"test\", { \"fuzz\": { \"iters\": 1000000 } } ],
+                \"max-parallel\": 2,
+                \"allow-failure\": true,
+                \"nice\": 19,
+                \"ionice\": \"best-effort:7\",
+                \"network\": \"registry-only\"
+            }
+       ",
+        )
+        .expect("decoding");
+
+        // `only-tip` is the deprecated spelling of `commits = "tip"`
+        let commits = |json: &str| {
+            let ck: Check = serde_json::from_str(json).expect("decoding");
+            ck.commits().clone()
+        };
+        assert_eq!(
+            commits("{ \"type\": \"rust\", \"commits\": \"tip-and-base\" }"),
+            CommitSelector::TipAndBase
+        );
+        assert_eq!(
+            commits("{ \"type\": \"rust\", \"only-tip\": true }"),
+            CommitSelector::Tip
+        );
+        assert_eq!(
+            commits("{ \"type\": \"rust\", \"only-tip\": false }"),
+            CommitSelector::All
+        );
+        assert!(serde_json::from_str::<Check>(
+            "{ \"type\": \"rust\", \"commits\": \"tip\", \"only-tip\": true }"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn validate() {
+        let ck: Check = serde_json::from_str(
+            "
+            {
+                \"type\": \"rust\",
+                \"version\": [\"1.41.1\", \"nightly-2021-03-01\", \"stable-x86_64-unknown-linux-gnu\"],
+                \"jobs\": [ \"build\", \"test\" ]
+            }
+       ",
+        )
+        .expect("decoding");
+        let validation = ck.validate(None);
+        assert_eq!(validation.problems, Vec::<String>::new());
+        assert_eq!(validation.jobs.len(), 6);
+
+        let ck: Check = serde_json::from_str(
+            "
+            {
+                \"type\": \"rust\",
+                \"version\": [\"stabel\", \"1.41-\"],
+                \"jobs\": [ { \"fuzz\": { \"iters\": 0 } } ]
+            }
+       ",
+        )
+        .expect("decoding");
+        assert_eq!(ck.validate(None).problems.len(), 3);
+
+        let ck: Check =
+            serde_json::from_str("{ \"type\": \"rust\", \"max-parallel\": 0 }").expect("decoding");
+        assert_eq!(
+            ck.validate(None).problems,
+            vec!["max-parallel is 0, so no job could run".to_owned()],
+        );
+
+        let ck: Check =
+            serde_json::from_str("{ \"type\": \"rust\", \"nice\": 20 }").expect("decoding");
+        assert_eq!(
+            ck.validate(None).problems,
+            vec!["nice is 20, but must be from -20 to 19".to_owned()],
+        );
+        assert!(
+            serde_json::from_str::<Check>("{ \"type\": \"rust\", \"ionice\": \"idle:3\" }")
+                .is_err()
+        );
+    }
 }
@@ -20,15 +20,34 @@
 
 use anyhow::Context;
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
-use rayon::ThreadPool;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use std::{fmt, mem};
+use std::time::{Duration, Instant};
+use std::{fmt, fs};
 use tempfile::TempDir;
 
-use crate::cargo::Cargo;
-use crate::git::{temp_repo, TempRepo};
-use crate::job::JobHandle;
+use super::{Network, RunOptions, Trailers, Validation};
+use crate::cargo::{
+    parse_pins, Annotation, Cargo, Diagnostics, Failure, FuzzEngine, IoPriority, Priority, Target,
+};
+use crate::disk;
+use crate::git::{pooled_repo, TempRepo};
+use crate::job::{self, BuildPools, JobClass, JobHandle, Semaphore};
+use crate::notes::{NoteEntry, NoteOutcome};
+use crate::output::{report, Event, Outcome};
+use crate::resume;
+use crate::say;
+use crate::worker::{self, Remote, Slot};
+
+/// Toolchains which rustup updates in place, so that a job which passed
+/// with one may not pass with it now
+const UPDATED_TOOLCHAINS: &[&str] = &["nightly", "beta"];
+
+/// Hashes of the versions of toolchains, by cargo command and toolchain,
+/// found at most once per run
+static TOOLCHAIN_HASHES: Mutex<BTreeMap<String, Option<String>>> = Mutex::new(BTreeMap::new());
 
 fn default_rust_jobs() -> Vec<RustJob> {
     vec![RustJob::Build, RustJob::Test, RustJob::Examples]
@@ -51,54 +70,320 @@ pub enum RustJob {
     },
 }
 
+impl RustJob {
+    /// Which pool the job is run in: anything which runs the crate's code,
+    /// rather than just building it, may take a long time
+    fn class(&self) -> JobClass {
+        match *self {
+            RustJob::Build => JobClass::Light,
+            RustJob::Examples | RustJob::Test | RustJob::Fuzz { .. } => JobClass::Heavy,
+        }
+    }
+
+    /// Name of the job, as used in the configuration and in commit trailers
+    fn name(&self) -> &'static str {
+        match *self {
+            RustJob::Build => "build",
+            RustJob::Examples => "examples",
+            RustJob::Test => "test",
+            RustJob::Fuzz { .. } => "fuzz",
+        }
+    }
+}
+
+/// Command-line arguments and environment needed to run an example
+#[derive(Clone, Debug, Default, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "kebab-case")]
+pub struct ExampleArgs {
+    #[serde(default, deserialize_with = "super::single_or_seq")]
+    args: Vec<String>,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+}
+
+impl ExampleArgs {
+    /// Whether there is nothing to pass to the example
+    fn is_empty(&self) -> bool {
+        self.args.is_empty() && self.env.is_empty()
+    }
+}
+
+/// Whether a string is a plausible rustup toolchain name, e.g. `stable`,
+/// `nightly-2021-03-01`, `1.41` or `1.41.1-x86_64-unknown-linux-gnu`
+fn is_valid_toolchain(s: &str) -> bool {
+    let mut parts = s.splitn(2, '-');
+    let channel = parts.next().unwrap_or("");
+    let rest = parts.next();
+
+    let is_num = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    let numbered = {
+        let nums: Vec<&str> = channel.split('.').collect();
+        (nums.len() == 2 || nums.len() == 3) && nums.iter().all(|n| is_num(n))
+    };
+    if !numbered && !["stable", "beta", "nightly"].contains(&channel) {
+        return false;
+    }
+    match rest {
+        None => true,
+        // Either a date, a host triple, or a date followed by a host triple
+        Some(rest) => {
+            let date: Vec<&str> = rest.splitn(4, '-').collect();
+            let has_date =
+                date.len() >= 3 && date[..3].iter().all(|n| is_num(n)) && date[0].len() == 4;
+            match (has_date, date.len()) {
+                (true, 3) => true,
+                (true, _) => !date[3].is_empty(),
+                (false, _) => rest.contains('-'),
+            }
+        }
+    }
+}
+
+/// The directory, relative to the repository root, that fuzz jobs run in
+///
+/// This is the `fuzz/` crate in the working directory, if there is one, and
+/// otherwise the working directory itself.
+fn fuzz_dir(root: &Path, working_dir: Option<&String>) -> Option<String> {
+    let fuzz = match working_dir {
+        Some(dir) => format!("{}/fuzz", dir),
+        None => "fuzz".to_owned(),
+    };
+    if root.join(&fuzz).join("Cargo.toml").is_file() {
+        Some(fuzz)
+    } else {
+        working_dir.cloned()
+    }
+}
+
+/// Whether a path from the tree under test may lead outside the repository
+fn escapes_repo(path: &str) -> bool {
+    Path::new(path)
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+/// Whether to fetch a crate's dependencies before running its jobs
+///
+/// This makes them fail fast when offline, and lets them run offline when
+/// prefetching or when they may not use the network.
+fn fetch_first(options: &RunOptions, network: Network) -> bool {
+    options.offline || options.cargo_home.is_some() || network != Network::Full
+}
+
+/// For an example target, its name followed by the features it requires
+fn example_ext(target: &Target) -> Vec<String> {
+    let mut ret = vec![target.name.clone()];
+    ret.extend(target.required_features.iter().cloned());
+    ret
+}
+
+/// Describes a single cargo invocation; used as its entry in the notes
+///
+/// For examples, `ext` is the name of the example followed by any features
+/// it requires. For fuzz targets it is the name of the target, and for
+/// anything else, the features to enable.
+fn job_description(
+    cargo_ver: &str,
+    cargo_cmd: &str,
+    job: RustJob,
+    ext: &[String],
+    example_args: &ExampleArgs,
+    fuzz_engine: FuzzEngine,
+) -> String {
+    match job {
+        RustJob::Build => format!(
+            "{} {} build '--features={}'",
+            cargo_ver,
+            cargo_cmd,
+            ext.join(" ")
+        ),
+        RustJob::Test => format!(
+            "{} {} test '--features={}'",
+            cargo_ver,
+            cargo_cmd,
+            ext.join(" ")
+        ),
+        RustJob::Examples if ext.len() > 1 => {
+            let without_features = job_description(
+                cargo_ver,
+                cargo_cmd,
+                job,
+                &ext[..1],
+                example_args,
+                fuzz_engine,
+            );
+            format!("{} # features '{}'", without_features, ext[1..].join(" "))
+        }
+        RustJob::Examples if example_args.is_empty() => {
+            format!("{} {} run '--example {}'", cargo_ver, cargo_cmd, ext[0],)
+        }
+        RustJob::Examples => {
+            let env: Vec<String> = example_args
+                .env
+                .iter()
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect();
+            format!(
+                "{} {} run '--example {}' -- '{}' # env '{}'",
+                cargo_ver,
+                cargo_cmd,
+                ext[0],
+                example_args.args.join(" "),
+                env.join(" "),
+            )
+        }
+        RustJob::Fuzz { iters } => {
+            let cmd = match fuzz_engine {
+                FuzzEngine::Honggfuzz => "hfuzz",
+                FuzzEngine::CargoFuzz => "fuzz",
+            };
+            format!(
+                "{} {} {} run {} # iters {}",
+                cargo_ver, cargo_cmd, cmd, ext[0], iters
+            )
+        }
+    }
+}
+
 /// A single check (i.e. cargo invocation)
-struct SingleCheck<'a, 'b, 'c> {
+struct SingleCheck<'a, 'b, 'c, 'd> {
+    cargo_cmd: Option<&'b String>,
     cargo_ver: String,
     repo: &'a TempDir,
     path_ext: Option<&'b String>,
     job: RustJob,
     ext: &'c [String],
+    example_args: ExampleArgs,
+    fuzz_engine: FuzzEngine,
+    options: &'d RunOptions,
+    /// Hash of the configuration of the check this job is part of
+    check_hash: &'d str,
+    /// Limit on how many of the check's jobs run at once, if any
+    limit: Option<&'d Semaphore>,
+    /// Scheduling priority to run the job with
+    priority: Priority,
+    /// What network access the job has
+    network: Network,
 }
 
-impl<'a, 'b, 'c> SingleCheck<'a, 'b, 'c> {
+impl<'a, 'b, 'c, 'd> SingleCheck<'a, 'b, 'c, 'd> {
     fn new(
+        cargo_cmd: Option<&'b String>,
         cargo_ver: String,
         repo: &'a TempDir,
         path_ext: Option<&'b String>,
         job: RustJob,
         ext: &'c [String],
+        options: &'d RunOptions,
     ) -> Self {
         SingleCheck {
-            cargo_ver: cargo_ver,
-            repo: repo,
-            path_ext: path_ext,
-            job: job,
-            ext: ext,
+            cargo_cmd,
+            cargo_ver,
+            repo,
+            path_ext,
+            job,
+            ext,
+            example_args: ExampleArgs::default(),
+            fuzz_engine: FuzzEngine::Honggfuzz,
+            options,
+            check_hash: "",
+            limit: None,
+            priority: Priority::default(),
+            network: Network::default(),
         }
     }
 
-    fn notes_str(&self) -> String {
+    /// The shared target directory to build in, if any
+    ///
+    /// There is one per toolchain and set of features, so that jobs which
+    /// build the same dependencies with the same flags share them. Cargo
+    /// locks the directory while building in it, so jobs that share one
+    /// are serialized rather than clobbering each other.
+    fn target_dir(&self) -> Option<PathBuf> {
+        let features = match self.job {
+            RustJob::Build | RustJob::Test => self.ext,
+            RustJob::Examples => &self.ext[1..],
+            // cargo hfuzz and cargo fuzz build with their own instrumentation
+            // flags, which would only thrash a shared directory
+            RustJob::Fuzz { .. } => return None,
+        };
+        let key = if features.is_empty() {
+            "no-features".to_owned()
+        } else {
+            features.join(",").replace('/', "_")
+        };
+        self.options
+            .target_cache
+            .as_ref()
+            .map(|dir| dir.join(self.cargo_ver.replace('/', "_")).join(key))
+    }
+
+    /// The directories which the job's build output may go in
+    fn build_dirs(&self) -> Vec<PathBuf> {
+        if let Some(dir) = self.target_dir() {
+            return vec![dir];
+        }
+        let mut crate_dir = self.repo.path().to_path_buf();
+        if let Some(ext) = self.path_ext {
+            crate_dir.push(ext);
+        }
         match self.job {
-            RustJob::Build => format!(
-                "{} cargo build '--features={}'",
-                self.cargo_ver,
-                self.ext.join(" "),
-            ),
-            RustJob::Test => format!(
-                "{} cargo test '--features={}'",
-                self.cargo_ver,
-                self.ext.join(" "),
-            ),
-            RustJob::Examples => {
-                format!("{} cargo run '--example {}'", self.cargo_ver, self.ext[0],)
+            RustJob::Fuzz { .. } => vec![crate_dir.join("target"), crate_dir.join("hfuzz_target")],
+            _ => {
+                let mut dirs = vec![self.repo.path().join("target")];
+                if self.path_ext.is_some() {
+                    dirs.push(crate_dir.join("target"));
+                }
+                dirs
             }
-            RustJob::Fuzz { iters } => format!(
-                "{} cargo hfuzz run {} # iters {}",
-                self.cargo_ver, self.ext[0], iters,
-            ),
         }
     }
 
+    /// Name of the file to save this job's output in, within the log
+    /// directory
+    fn log_name(&self, head: git2::Oid) -> String {
+        let what = if self.ext.is_empty() {
+            "no-features".to_owned()
+        } else {
+            self.ext.join(",")
+        };
+        let name = format!(
+            "{:.7}-{}-{}-{}",
+            head,
+            self.cargo_ver,
+            self.job.name(),
+            what
+        );
+        let name: String = name
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' | ',' => c,
+                _ => '_',
+            })
+            .collect();
+        format!("{}.log", name)
+    }
+
+    /// File to save this job's output in, if there is a log directory
+    fn log_file(&self, head: git2::Oid) -> Option<PathBuf> {
+        self.options
+            .log_dir
+            .as_ref()
+            .map(|dir| dir.join(self.log_name(head)))
+    }
+
+    fn notes_str(&self) -> String {
+        job_description(
+            &self.cargo_ver,
+            self.cargo_cmd.map(String::as_str).unwrap_or("cargo"),
+            self.job,
+            self.ext,
+            &self.example_args,
+            self.fuzz_engine,
+        )
+    }
+
     fn run(
         self,
         head: git2::Oid,
@@ -106,52 +391,298 @@ impl<'a, 'b, 'c> SingleCheck<'a, 'b, 'c> {
         new_notes: &Mutex<Vec<String>>,
     ) -> anyhow::Result<()> {
         let my_note = self.notes_str();
-        for note in existing_notes {
+        let entries: Vec<NoteEntry> = existing_notes
+            .iter()
+            .filter_map(|note| NoteEntry::parse(note))
+            .filter(|entry| entry.job == my_note)
+            .collect();
+        for entry in entries.iter().filter(|entry| entry.passed()) {
+            if self.toolchain_changed(entry) {
+                say!(
+                    Normal,
+                    "Toolchain {} has changed since {} passed on {}; running it again",
+                    self.cargo_ver,
+                    my_note,
+                    head
+                );
+                continue;
+            }
             // Already done
-            if note == &my_note {
-                return Ok(());
+            report(Event::Finished {
+                commit: head,
+                check: self.check_hash,
+                toolchain: &self.cargo_ver,
+                job: &my_note,
+                outcome: Outcome::Cached,
+                error: None,
+                log: None,
+                annotations: &[],
+            });
+            return Ok(());
+        }
+        for entry in entries.iter().filter(|entry| !entry.passed()) {
+            match entry.at {
+                Some(at) if self.options.failure_policy.skips(at) => {
+                    let error = format!(
+                        "job {} failed on commit {} at {} and is not retried (failure-policy {})",
+                        my_note,
+                        head,
+                        time::at_utc(at).rfc3339(),
+                        self.options.failure_policy,
+                    );
+                    report(Event::Finished {
+                        commit: head,
+                        check: self.check_hash,
+                        toolchain: &self.cargo_ver,
+                        job: &my_note,
+                        outcome: Outcome::Fail(Duration::from_secs(0)),
+                        error: Some(&error),
+                        log: None,
+                        annotations: &[],
+                    });
+                    return Err(anyhow::Error::msg(error));
+                }
+                _ => {}
+            }
+        }
+
+        // Held until the job is done
+        let _permit = self.limit.map(Semaphore::acquire);
+        let _disk = disk::start(self.build_dirs());
+        report(Event::Started {
+            commit: head,
+            check: self.check_hash,
+            toolchain: &self.cargo_ver,
+            job: &my_note,
+        });
+        let start = Instant::now();
+        let result = self.run_uncached(head);
+        let (outcome, error) = match result {
+            Ok(..) => (Outcome::Pass(start.elapsed()), None),
+            Err(ref e) => (Outcome::Fail(start.elapsed()), Some(format!("{:?}", e))),
+        };
+        let annotations = match result {
+            Ok(Some(ref diagnostics)) => self.repo_annotations(&diagnostics.annotations),
+            Ok(None) => vec![],
+            Err(ref e) => match e.downcast_ref::<Failure>() {
+                Some(failure) => self.repo_annotations(&failure.diagnostics.annotations),
+                None => vec![],
+            },
+        };
+        report(Event::Finished {
+            commit: head,
+            check: self.check_hash,
+            toolchain: &self.cargo_ver,
+            job: &my_note,
+            outcome,
+            error: error.as_deref(),
+            log: self.log_file(head).as_deref(),
+            annotations: &annotations,
+        });
+
+        let outcome = match result {
+            Ok(..) => NoteOutcome::Pass,
+            Err(..) => NoteOutcome::Fail,
+        };
+        let mut entry = NoteEntry::finished(&my_note, outcome, start.elapsed());
+        entry.check = Some(self.check_hash.to_owned()).filter(|hash| !hash.is_empty());
+        entry.toolchain = self.toolchain_hash();
+        if result.is_err() && !job::cancelled() {
+            super::record_failure(head, &entry);
+        }
+        entry.warnings = result?.map(|diagnostics| diagnostics.warnings);
+        let my_note = entry.to_string();
+        resume::record(head, &my_note);
+        new_notes.lock().unwrap().push(my_note);
+        Ok(())
+    }
+
+    /// Hash of the version of the job's toolchain, as `rustc -V` reports it
+    /// on this machine, if it can be found
+    fn toolchain_hash(&self) -> Option<String> {
+        let key = format!(
+            "{} {}",
+            self.cargo_cmd.map(String::as_str).unwrap_or("cargo"),
+            self.cargo_ver
+        );
+        let mut hashes = TOOLCHAIN_HASHES.lock().unwrap();
+        if let Some(hash) = hashes.get(&key) {
+            return hash.clone();
+        }
+        let cargo = Cargo::new(
+            self.cargo_cmd,
+            self.cargo_ver.clone(),
+            self.repo,
+            self.path_ext,
+            self.priority,
+        );
+        let hash = cargo.rustc_version_string().ok().and_then(|version| {
+            git2::Oid::hash_object(git2::ObjectType::Blob, version.as_bytes())
+                .ok()
+                .map(|oid| oid.to_string()[..12].to_owned())
+        });
+        hashes.insert(key, hash.clone());
+        hash
+    }
+
+    /// Whether a pass recorded in the notes was with an earlier build of a
+    /// toolchain which is updated in place, such as `nightly`, than the one
+    /// the job would now run with
+    fn toolchain_changed(&self, entry: &NoteEntry) -> bool {
+        if !UPDATED_TOOLCHAINS.contains(&self.cargo_ver.as_str()) {
+            return false;
+        }
+        match (&entry.toolchain, self.toolchain_hash()) {
+            (Some(recorded), Some(current)) => *recorded != current,
+            _ => false,
+        }
+    }
+
+    /// Makes the paths of compiler annotations relative to the root of the
+    /// repository
+    ///
+    /// Cargo gives paths relative to the root of the workspace, which may
+    /// be the directory of a crate in a subdirectory of the repository.
+    fn repo_annotations(&self, annotations: &[Annotation]) -> Vec<Annotation> {
+        let mut ret = annotations.to_vec();
+        if let Some(ext) = self.path_ext {
+            for ann in &mut ret {
+                let in_ext = Path::new(ext).join(&ann.path);
+                if self.repo.path().join(&in_ext).exists() {
+                    ann.path = in_ext.to_string_lossy().into_owned();
+                }
             }
         }
+        ret
+    }
 
+    /// Runs the job, without checking whether it has already been done
+    fn run_uncached(&self, head: git2::Oid) -> anyhow::Result<Option<Diagnostics>> {
         // Need a new cargo as the old one internally has stdout/err
         // `File`s that cannot be shared across threads
-        let cargo = Cargo::new(self.cargo_ver, self.repo, self.path_ext);
+        let mut cargo = Cargo::new(
+            self.cargo_cmd,
+            self.cargo_ver.clone(),
+            self.repo,
+            self.path_ext,
+            self.priority,
+        );
+        // Held until the job is done
+        let slot = worker::acquire();
+        match slot.as_ref().and_then(Slot::worker) {
+            Some(worker) => {
+                let mut excludes = vec![];
+                for dir in &["target", "hfuzz_target", "hfuzz_workspace"] {
+                    excludes.push(dir.to_string());
+                    if let Some(ext) = self.path_ext {
+                        excludes.push(format!("{}/{}", ext, dir));
+                    }
+                }
+                let remote = Remote::ship(worker, self.repo.path(), &excludes)
+                    .with_context(|| format!("starting job on commit {}", head))?;
+                cargo = cargo.remote(remote);
+                // The worker downloads dependencies for itself
+                if self.options.offline {
+                    cargo = cargo.offline();
+                }
+            }
+            None => {
+                if let Some(dir) = self.target_dir() {
+                    cargo = cargo.target_dir(&dir);
+                }
+                if let Some(ref home) = self.options.cargo_home {
+                    cargo = cargo.cargo_home(home);
+                }
+                if let Some(jobs) = self.options.jobs {
+                    cargo = cargo.jobs(jobs);
+                }
+                cargo = cargo.limits(self.options.limits.clone());
+                if let Some(ref container) = self.options.container {
+                    cargo = cargo.container(container.clone());
+                }
+                if let Some(ref sandbox) = self.options.sandbox {
+                    cargo = cargo.sandbox(sandbox.clone());
+                }
+                // If prefetching, or if the job may not use the network,
+                // everything was already fetched before any jobs were started
+                if self.options.offline
+                    || self.options.cargo_home.is_some()
+                    || self.network != Network::Full
+                {
+                    cargo = cargo.offline();
+                }
+            }
+        }
+        if let Some(log) = self.log_file(head) {
+            cargo = cargo.log_file(log);
+        }
+        if self.options.stream {
+            let mut prefix = format!("[{:.7} {} {}", head, self.cargo_ver, self.job.name());
+            if !self.ext.is_empty() {
+                prefix.push(' ');
+                prefix.push_str(&self.ext.join(","));
+            }
+            prefix.push(']');
+            cargo = cargo.stream(prefix);
+        }
         let c_ver = cargo.version_string()?;
-        let r_ver = cargo.rustc_version_string()?;
-        let result = match self.job {
+        let mut r_ver = cargo.rustc_version_string()?;
+        if let Some(worker) = slot.as_ref().and_then(Slot::worker) {
+            r_ver = format!("{} on {}", r_ver, worker.host);
+        }
+        Ok(match self.job {
             RustJob::Build => {
-                println!(
+                say!(
+                    Normal,
                     "Building {} (features {:?}) ({} / {})",
-                    head, self.ext, c_ver, r_ver
+                    head,
+                    self.ext,
+                    c_ver,
+                    r_ver
                 );
-                cargo.build(&self.ext)
+                Some(cargo.build(self.ext)?)
             }
             RustJob::Test => {
-                println!(
+                say!(
+                    Normal,
                     "Testing {} (features {:?}) ({} / {})",
-                    head, self.ext, c_ver, r_ver
+                    head,
+                    self.ext,
+                    c_ver,
+                    r_ver
                 );
-                cargo.test(&self.ext)
+                Some(cargo.test(self.ext)?)
             }
             RustJob::Examples => {
-                assert_eq!(self.ext.len(), 1);
-                println!(
+                say!(
+                    Normal,
                     "Running example {} on {} ({} / {})",
-                    &self.ext[0], head, c_ver, r_ver,
+                    &self.ext[0],
+                    head,
+                    c_ver,
+                    r_ver,
                 );
-                cargo.example(&self.ext[0])
+                Some(cargo.example(
+                    &self.ext[0],
+                    &self.ext[1..],
+                    &self.example_args.args,
+                    &self.example_args.env,
+                )?)
             }
             RustJob::Fuzz { iters } => {
                 assert_eq!(self.ext.len(), 1);
-                println!(
+                say!(
+                    Normal,
                     "Fuzzing {} on {} ({} / {})",
-                    &self.ext[0], head, c_ver, r_ver,
+                    &self.ext[0],
+                    head,
+                    c_ver,
+                    r_ver,
                 );
-                cargo.fuzz(&self.ext[0], iters)
+                cargo.fuzz(self.fuzz_engine, &self.ext[0], iters)?;
+                None
             }
-        }?;
-        new_notes.lock().unwrap().push(my_note);
-        Ok(result)
+        })
     }
 }
 
@@ -169,10 +700,45 @@ pub struct RustCheck {
         deserialize_with = "super::single_or_seq"
     )]
     jobs: Vec<RustJob>,
-    #[serde(default)]
-    only_tip: bool,
+    /// Which commits to run on; `only-tip = true` is accepted for `"tip"`
+    #[serde(
+        default,
+        alias = "only-tip",
+        deserialize_with = "super::commits_or_only_tip"
+    )]
+    pub(super) commits: super::CommitSelector,
     #[serde(default)]
     working_dir: Option<String>,
+    /// File, relative to the root of the repo under test, listing the
+    /// dependency pins needed for MSRV builds (see `cargo::parse_pins`).
+    /// It is read separately from each commit that we check.
+    #[serde(default, alias = "pin-script")]
+    pins_file: Option<String>,
+    /// Arguments and environment variables to run specific examples with,
+    /// keyed by example name
+    #[serde(default)]
+    example_args: BTreeMap<String, ExampleArgs>,
+    /// Command to run instead of `cargo`, e.g. `cross` or the path to a
+    /// cargo binary not managed by rustup
+    #[serde(default)]
+    cargo_command: Option<String>,
+    /// Most of the check's jobs to run at once, across every commit being
+    /// checked, e.g. for fuzzers which are multithreaded themselves
+    #[serde(default)]
+    pub(super) max_parallel: Option<usize>,
+    /// Whether the check failing should not fail the run
+    #[serde(default)]
+    pub(super) allow_failure: bool,
+    /// Niceness to run the check's jobs with, e.g. 19 for long fuzz runs
+    /// which should not slow down other work on the machine
+    #[serde(default)]
+    nice: Option<i32>,
+    /// IO scheduling class to run the check's jobs with, e.g. `idle`
+    #[serde(default)]
+    ionice: Option<IoPriority>,
+    /// What network access the check's jobs have
+    #[serde(default)]
+    pub(super) network: Network,
 }
 
 impl fmt::Display for RustCheck {
@@ -182,14 +748,81 @@ impl fmt::Display for RustCheck {
 }
 
 impl RustCheck {
-    pub fn execute(&self, repo: TempRepo, build_pool: &ThreadPool) -> anyhow::Result<Vec<String>> {
-        let default_versions = vec!["stable".to_owned()];
-        let versions = if self.version.is_empty() {
-            default_versions
+    /// See `Check::for_commit`
+    pub(super) fn for_commit(&self, selected: bool, trailers: &Trailers) -> Option<RustCheck> {
+        if trailers.skips("rust") {
+            return None;
+        }
+        let whole = selected || trailers.adds("rust");
+        let jobs: Vec<RustJob> = self
+            .jobs
+            .iter()
+            .copied()
+            .filter(|job| !trailers.skips(job.name()) && (whole || trailers.adds(job.name())))
+            .collect();
+        if jobs.is_empty() {
+            None
+        } else {
+            Some(RustCheck {
+                jobs,
+                ..self.clone()
+            })
+        }
+    }
+
+    /// See `Check::untrusted_problems`
+    pub(super) fn untrusted_problems(&self) -> Vec<String> {
+        let mut ret = vec![];
+        if let Some(ref cmd) = self.cargo_command {
+            ret.push(format!(
+                "cargo-command is {}, but only the configuration may set it",
+                cmd
+            ));
+        }
+        if self.nice.is_some_and(|nice| nice < 0) {
+            ret.push("nice is negative, which would raise the jobs' priority".to_owned());
+        }
+        if let Some(IoPriority::Realtime(_)) = self.ionice {
+            ret.push("ionice is realtime, which would raise the jobs' priority".to_owned());
+        }
+        if self.working_dir.as_deref().is_some_and(escapes_repo) {
+            ret.push("working-dir must be a path within the repository".to_owned());
+        }
+        if self.pins_file.as_deref().is_some_and(escapes_repo) {
+            ret.push("pins-file must be a path within the repository".to_owned());
+        }
+        ret
+    }
+
+    /// See `Check::class`
+    pub(super) fn class(&self) -> JobClass {
+        self.jobs
+            .iter()
+            .map(RustJob::class)
+            .max()
+            .unwrap_or(JobClass::Light)
+    }
+
+    /// Scheduling priority to run the check's jobs with
+    fn priority(&self) -> Priority {
+        Priority {
+            nice: self.nice,
+            ionice: self.ionice,
+        }
+    }
+
+    /// The toolchains to check with
+    fn versions(&self) -> Vec<String> {
+        if self.version.is_empty() {
+            vec!["stable".to_owned()]
         } else {
             self.version.clone()
-        };
+        }
+    }
 
+    /// The sets of features to build and test with: none, all, and each one
+    /// individually
+    fn feature_matrix(&self) -> Vec<Vec<String>> {
         let mut feature_matrix = vec![vec![]];
         if !self.features.is_empty() {
             feature_matrix.push(self.features.clone());
@@ -197,83 +830,354 @@ impl RustCheck {
         for feat in &self.features {
             feature_matrix.push(vec![feat.clone()]);
         }
+        feature_matrix
+    }
 
-        let head = repo.repo.head().context("getting HEAD")?.target().unwrap();
-        let existing_notes = repo
-            .repo
-            .find_note(Some("refs/notes/check-commit"), head)
-            .ok()
+    /// See `Check::validate`
+    pub(super) fn validate(&self, checkout: Option<&TempDir>) -> Validation {
+        let mut ret = Validation::default();
+
+        // A cargo given by path is not run through rustup, so its "version"
+        // is only a label
+        let rustup = !self
+            .cargo_command
             .as_ref()
-            .and_then(|note| note.message())
-            .map(|text| text.split('\n').map(|s| s.to_owned()).collect())
-            .unwrap_or(vec![]);
+            .is_some_and(|cmd| cmd.contains('/'));
+        for ver in &self.version {
+            if rustup && !is_valid_toolchain(ver) {
+                ret.problems
+                    .push(format!("`{}` is not a toolchain name", ver));
+            }
+        }
+        if self.jobs.is_empty() {
+            ret.problems.push("no jobs given".to_owned());
+        }
+        for job in &self.jobs {
+            if let RustJob::Fuzz { iters: 0 } = *job {
+                ret.problems.push("fuzz job has 0 iterations".to_owned());
+            }
+        }
+        if self.max_parallel == Some(0) {
+            ret.problems
+                .push("max-parallel is 0, so no job could run".to_owned());
+        }
+        if let Some(nice) = self.nice {
+            if !(-20..=19).contains(&nice) {
+                ret.problems
+                    .push(format!("nice is {}, but must be from -20 to 19", nice));
+            }
+        }
+
+        // Ask cargo about the crate to check features and examples, and to
+        // find out what examples and fuzz targets we would run
+        let metadata = checkout.and_then(|dir| {
+            let ver = self.versions().swap_remove(0);
+            match Cargo::new(
+                self.cargo_command.as_ref(),
+                ver,
+                dir,
+                self.working_dir.as_ref(),
+                Priority::default(),
+            )
+            .metadata()
+            {
+                Ok(metadata) => Some(metadata),
+                Err(e) => {
+                    ret.problems.push(format!("{:#}", e));
+                    None
+                }
+            }
+        });
+        if let Some(ref metadata) = metadata {
+            for feat in &self.features {
+                if !metadata.has_feature(feat) {
+                    ret.problems
+                        .push(format!("feature `{}` is not defined by the crate", feat));
+                }
+            }
+            for ex in self.example_args.keys() {
+                if !metadata.targets("example").any(|target| &target.name == ex) {
+                    ret.problems
+                        .push(format!("example-args given for unknown example `{}`", ex));
+                }
+            }
+        }
+
+        // Fuzz targets live in their own crate, if there is one
+        let fuzz_metadata = checkout.and_then(|dir| {
+            if !self
+                .jobs
+                .iter()
+                .any(|job| matches!(job, RustJob::Fuzz { .. }))
+            {
+                return None;
+            }
+            let ver = self.versions().swap_remove(0);
+            let fuzz_dir = fuzz_dir(dir.path(), self.working_dir.as_ref());
+            match Cargo::new(
+                self.cargo_command.as_ref(),
+                ver,
+                dir,
+                fuzz_dir.as_ref(),
+                Priority::default(),
+            )
+            .metadata()
+            {
+                Ok(metadata) => Some(metadata),
+                Err(e) => {
+                    ret.problems.push(format!("{:#}", e));
+                    None
+                }
+            }
+        });
+        let fuzz_engine = fuzz_metadata
+            .as_ref()
+            .map(|metadata| metadata.fuzz_engine())
+            .unwrap_or(FuzzEngine::Honggfuzz);
+
+        let no_args = ExampleArgs::default();
+        let unknown = ["<unknown>".to_owned()];
+        for ver in self.versions() {
+            for job in &self.jobs {
+                let exts: Vec<Vec<String>> = match (*job, metadata.as_ref()) {
+                    (RustJob::Build, _) | (RustJob::Test, _) => self.feature_matrix(),
+                    (RustJob::Examples, Some(metadata)) => {
+                        metadata.targets("example").map(example_ext).collect()
+                    }
+                    (RustJob::Examples, None) => vec![unknown.to_vec()],
+                    (RustJob::Fuzz { .. }, _) => match fuzz_metadata {
+                        Some(ref metadata) => metadata
+                            .targets("bin")
+                            .map(|bin| vec![bin.name.clone()])
+                            .collect(),
+                        None => vec![unknown.to_vec()],
+                    },
+                };
+                for ext in exts {
+                    let args = match *job {
+                        RustJob::Examples => self.example_args.get(&ext[0]).unwrap_or(&no_args),
+                        _ => &no_args,
+                    };
+                    ret.jobs.push(job_description(
+                        &ver,
+                        self.cargo_command.as_deref().unwrap_or("cargo"),
+                        *job,
+                        &ext,
+                        args,
+                        fuzz_engine,
+                    ));
+                }
+            }
+        }
+        ret
+    }
+
+    pub fn execute(
+        &self,
+        repo: &TempRepo,
+        existing_notes: Vec<String>,
+        build_pools: &BuildPools,
+        options: &RunOptions,
+        check_hash: &str,
+        limit: Option<&Semaphore>,
+    ) -> anyhow::Result<Vec<String>> {
+        let versions = self.versions();
+        let feature_matrix = self.feature_matrix();
+
+        let head = repo.repo.head().context("getting HEAD")?.target().unwrap();
         let existing_notes = Arc::new(existing_notes);
 
         let mut handles = vec![];
         for ver in versions {
-            let fresh_repo = temp_repo(&repo.repo, head)
-                .with_context(|| format!("creating temporary repo for {}", head))?;
+            // Each class of jobs gets a checkout of its own, so that light jobs
+            // do not wait on cargo's lock on the target directory of heavy ones
+            for &class in &[JobClass::Light, JobClass::Heavy] {
+                // In the order of `RustJob`'s variants (build, examples, test,
+                // fuzz), roughly cheapest first, for the earliest signal
+                let mut jobs: Vec<RustJob> = self
+                    .jobs
+                    .iter()
+                    .copied()
+                    .filter(|job| job.class() == class)
+                    .collect();
+                jobs.sort();
+                if jobs.is_empty() {
+                    continue;
+                }
+                let ver = ver.clone();
+                let purpose = format!("{} {:?} jobs", ver, class);
+                let fresh_repo = pooled_repo(&repo.repo, head, &purpose)
+                    .with_context(|| format!("creating temporary repo for {}", head))?;
 
-            let data = JobData {
-                version: ver.clone(),
-                commit: head,
-                new_notes: Arc::new(Mutex::new(vec![])),
-            };
+                let data = JobData {
+                    version: ver.clone(),
+                    commit: head,
+                    new_notes: Arc::new(Mutex::new(vec![])),
+                };
 
-            let jobs = self.jobs.clone();
-            let path_ext = self.working_dir.clone();
-            let feature_matrix = feature_matrix.clone();
-            let notes = existing_notes.clone();
-            let new_notes = data.new_notes.clone();
-            handles.push(JobHandle::spawn(build_pool, data, move || {
-                let repo_dir = &fresh_repo.dir;
-
-                let cargo = Cargo::new(ver.clone(), &repo_dir, path_ext.as_ref());
-                cargo.pin_deps().context("pinning dependencies")?;
-
-                let toml = cargo.toml()?;
-                for job in &jobs {
-                    match *job {
-                        RustJob::Build | RustJob::Test => {
-                            feature_matrix.par_iter().try_for_each(|feats| {
-                                SingleCheck::new(
-                                    ver.clone(),
-                                    &repo_dir,
-                                    path_ext.as_ref(),
-                                    *job,
-                                    feats,
-                                )
-                                .run(head, &*notes, &*new_notes)
-                            })?;
+                let path_ext = self.working_dir.clone();
+                let pins_file = self.pins_file.clone();
+                let example_args = self.example_args.clone();
+                let cargo_cmd = self.cargo_command.clone();
+                let options = options.clone();
+                let check_hash = check_hash.to_owned();
+                let limit = limit.cloned();
+                let priority = self.priority();
+                let network = self.network;
+                let feature_matrix = feature_matrix.clone();
+                let notes = existing_notes.clone();
+                let new_notes = data.new_notes.clone();
+                handles.push(JobHandle::spawn(build_pools, class, data, move || {
+                    let repo_dir = &fresh_repo.dir;
+
+                    let pins = match pins_file {
+                        Some(ref file) => {
+                            let path = repo_dir.path().join(file);
+                            match fs::read_to_string(&path) {
+                                Ok(text) => parse_pins(&text),
+                                Err(e) => {
+                                    say!(
+                                        Normal,
+                                        "Commit {} has no pins file {}; not pinning ({})",
+                                        head,
+                                        path.to_string_lossy(),
+                                        e,
+                                    );
+                                    vec![]
+                                }
+                            }
                         }
-                        RustJob::Examples => {
-                            toml.example.par_iter().try_for_each(|ex| {
-                                SingleCheck::new(
-                                    ver.clone(),
-                                    &repo_dir,
-                                    path_ext.as_ref(),
-                                    *job,
-                                    &[ex.name.clone()],
-                                )
-                                .run(head, &*notes, &*new_notes)
-                            })?;
+                        None => vec![],
+                    };
+
+                    let new_cargo = |dir: Option<&String>| {
+                        let mut cargo = Cargo::new(
+                            cargo_cmd.as_ref(),
+                            ver.clone(),
+                            repo_dir,
+                            dir,
+                            Priority::default(),
+                        );
+                        if let Some(ref home) = options.cargo_home {
+                            cargo = cargo.cargo_home(home);
+                        }
+                        if options.offline || network == Network::Off {
+                            cargo = cargo.offline();
                         }
-                        RustJob::Fuzz { .. } => {
-                            toml.bin.par_iter().try_for_each(|fuzz| {
-                                SingleCheck::new(
-                                    ver.clone(),
-                                    &repo_dir,
-                                    path_ext.as_ref(),
-                                    *job,
-                                    &[fuzz.name.clone()],
+                        cargo
+                    };
+                    let fetch = |cargo: &Cargo| {
+                        cargo.fetch().with_context(|| {
+                            if options.offline {
+                                format!(
+                                    "not all dependencies of commit {} are in the local cache; \
+                                     run once without --offline to download them",
+                                    head,
                                 )
-                                .run(head, &*notes, &*new_notes)
-                            })?;
+                            } else if network == Network::Off {
+                                format!(
+                                    "not all dependencies of commit {} are in the local cache, \
+                                     and the check has network = \"off\"",
+                                    head,
+                                )
+                            } else {
+                                format!("fetching dependencies of commit {}", head)
+                            }
+                        })
+                    };
+                    let cargo = new_cargo(path_ext.as_ref());
+                    cargo.pin_deps(&pins).context("pinning dependencies")?;
+                    if fetch_first(&options, network) {
+                        fetch(&cargo)?;
+                    }
+
+                    let queued = |count| {
+                        report(Event::Queued {
+                            commit: head,
+                            toolchain: &ver,
+                            count,
+                        })
+                    };
+                    let metadata = cargo.metadata()?;
+                    let examples: Vec<Vec<String>> =
+                        metadata.targets("example").map(example_ext).collect();
+                    for job in &jobs {
+                        match *job {
+                            RustJob::Build | RustJob::Test => {
+                                queued(feature_matrix.len());
+                                feature_matrix.par_iter().try_for_each(|feats| {
+                                    let mut check = SingleCheck::new(
+                                        cargo_cmd.as_ref(),
+                                        ver.clone(),
+                                        repo_dir,
+                                        path_ext.as_ref(),
+                                        *job,
+                                        feats,
+                                        &options,
+                                    );
+                                    check.check_hash = &check_hash;
+                                    check.limit = limit.as_ref();
+                                    check.priority = priority;
+                                    check.network = network;
+                                    check.run(head, &notes, &new_notes)
+                                })?;
+                            }
+                            RustJob::Examples => {
+                                queued(examples.len());
+                                examples.par_iter().try_for_each(|ext| {
+                                    let mut check = SingleCheck::new(
+                                        cargo_cmd.as_ref(),
+                                        ver.clone(),
+                                        repo_dir,
+                                        path_ext.as_ref(),
+                                        *job,
+                                        ext,
+                                        &options,
+                                    );
+                                    check.check_hash = &check_hash;
+                                    check.limit = limit.as_ref();
+                                    check.priority = priority;
+                                    check.network = network;
+                                    if let Some(args) = example_args.get(&ext[0]) {
+                                        check.example_args = args.clone();
+                                    }
+                                    check.run(head, &notes, &new_notes)
+                                })?;
+                            }
+                            RustJob::Fuzz { .. } => {
+                                let fuzz_dir = fuzz_dir(repo_dir.path(), path_ext.as_ref());
+                                let fuzz_cargo = new_cargo(fuzz_dir.as_ref());
+                                if fuzz_dir != path_ext && fetch_first(&options, network) {
+                                    fetch(&fuzz_cargo)?;
+                                }
+                                let fuzz_metadata =
+                                    fuzz_cargo.metadata().context("looking up fuzz targets")?;
+                                let engine = fuzz_metadata.fuzz_engine();
+                                let targets: Vec<&Target> = fuzz_metadata.targets("bin").collect();
+                                queued(targets.len());
+                                targets.par_iter().try_for_each(|fuzz| {
+                                    let mut check = SingleCheck::new(
+                                        cargo_cmd.as_ref(),
+                                        ver.clone(),
+                                        repo_dir,
+                                        fuzz_dir.as_ref(),
+                                        *job,
+                                        std::slice::from_ref(&fuzz.name),
+                                        &options,
+                                    );
+                                    check.check_hash = &check_hash;
+                                    check.limit = limit.as_ref();
+                                    check.priority = priority;
+                                    check.network = network;
+                                    check.fuzz_engine = engine;
+                                    check.run(head, &notes, &new_notes)
+                                })?;
+                            }
                         }
                     }
-                }
-                Ok(())
-            }));
+                    Ok(())
+                }));
+            }
         }
 
         let mut result = Ok(vec![]);
@@ -287,17 +1191,18 @@ impl RustCheck {
             match new_res {
                 Ok(()) => {
                     if let Ok(ref mut ret_notes) = result {
-                        let new_notes =
-                            mem::replace(&mut *h.data.new_notes.lock().unwrap(), vec![]);
+                        let new_notes = std::mem::take(&mut *h.data.new_notes.lock().unwrap());
                         ret_notes.extend(new_notes);
                     }
                 }
                 Err(e) => result = Err(e),
             }
 
-            println!(
+            say!(
+                Verbose,
                 "Completed all checks (commit {}, cargo {}",
-                h.data.commit, h.data.version,
+                h.data.commit,
+                h.data.version,
             );
         }
 
@@ -310,3 +1215,29 @@ struct JobData {
     commit: git2::Oid,
     new_notes: Arc<Mutex<Vec<String>>>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fetch_fuzz_crate() {
+        let repo_dir = tempfile::tempdir().expect("creating tempdir");
+        fs::create_dir(repo_dir.path().join("fuzz")).unwrap();
+        fs::write(repo_dir.path().join("fuzz/Cargo.toml"), "").unwrap();
+        let fuzz_dir = fuzz_dir(repo_dir.path(), None);
+        assert_eq!(fuzz_dir.as_deref(), Some("fuzz"));
+
+        // A separate fuzz crate's dependencies are fetched whenever the
+        // main crate's are, including when the network is limited
+        let options = RunOptions::default();
+        assert!(fetch_first(&options, Network::RegistryOnly));
+        assert!(fetch_first(&options, Network::Off));
+        assert!(!fetch_first(&options, Network::Full));
+        let options = RunOptions {
+            offline: true,
+            ..Default::default()
+        };
+        assert!(fetch_first(&options, Network::Full));
+    }
+}
@@ -0,0 +1,304 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+//! Checks that commits are signed by known keys
+
+use anyhow::Context;
+use git2::{Oid, Repository};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::Write;
+use std::path::Path;
+use tempfile::{NamedTempFile, TempDir};
+
+use super::{Trailers, Validation};
+use crate::config::expand_home;
+use crate::git::TempRepo;
+
+/// Start of the description of the check's job, and its name in trailers
+pub(super) const JOB: &str = "verify-signature";
+
+/// First line of an SSH signature, as made by `ssh-keygen -Y sign`
+const SSH_SIGNATURE: &str = "-----BEGIN SSH SIGNATURE-----";
+
+/// A check that every commit is signed, with GPG by a key in a keyring or
+/// with SSH by a key in an allowed-signers file
+#[derive(Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "kebab-case")]
+pub struct SignaturesCheck {
+    /// GPG keyring (as exported by `gpg --export`) of the keys commits may
+    /// be signed with
+    #[serde(default)]
+    keyring: Option<String>,
+    /// File listing the SSH keys commits may be signed with, in the format
+    /// of git's `gpg.ssh.allowedSignersFile`
+    #[serde(default)]
+    allowed_signers: Option<String>,
+    #[serde(default)]
+    pub(super) commits: super::CommitSelector,
+    /// Whether the check failing should not fail the run
+    #[serde(default)]
+    pub(super) allow_failure: bool,
+}
+
+impl fmt::Display for SignaturesCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("{ signatures }")
+    }
+}
+
+impl SignaturesCheck {
+    /// See `Check::for_commit`
+    ///
+    /// Unlike other checks, this one cannot be skipped by a commit's
+    /// trailers, as the commit may not be the signer's.
+    pub(super) fn for_commit(&self, selected: bool, trailers: &Trailers) -> Option<Self> {
+        if selected || trailers.adds("signatures") {
+            Some(self.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Description of the check's only job, as recorded in the notes
+    fn job_description(&self) -> String {
+        format!(
+            "{} '--keyring={}' '--allowed-signers={}'",
+            JOB,
+            self.keyring.as_deref().unwrap_or(""),
+            self.allowed_signers.as_deref().unwrap_or(""),
+        )
+    }
+
+    /// See `Check::validate`
+    pub(super) fn validate(&self, _: Option<&TempDir>) -> Validation {
+        let mut ret = Validation::default();
+        if self.keyring.is_none() && self.allowed_signers.is_none() {
+            ret.problems
+                .push("neither keyring nor allowed-signers given".to_owned());
+        }
+        for (name, path) in &[
+            ("keyring", &self.keyring),
+            ("allowed-signers", &self.allowed_signers),
+        ] {
+            if let Some(path) = path {
+                if !expand_home(path).is_file() {
+                    ret.problems
+                        .push(format!("{} {} does not exist", name, path));
+                }
+            }
+        }
+        ret.jobs.push(self.job_description());
+        ret
+    }
+
+    /// Verifies the signature of the commit checked out in `repo`
+    ///
+    /// Signatures are always verified afresh, as it is cheap and a key may
+    /// have been removed since they were last checked, so `existing_notes`
+    /// are not looked at.
+    pub fn execute(
+        &self,
+        repo: &TempRepo,
+        _existing_notes: Vec<String>,
+        check_hash: &str,
+    ) -> anyhow::Result<Vec<String>> {
+        let head = repo.repo.head().context("getting HEAD")?.target().unwrap();
+        super::run_commit_job(
+            head,
+            check_hash,
+            &self.job_description(),
+            "Signature check",
+            || {
+                self.verify(&repo.repo, head)
+                    .map(|signer| format!("is signed by {}", signer))
+            },
+        )
+    }
+
+    /// Verifies the signature of a commit, returning who it is signed by
+    fn verify(&self, repo: &Repository, commit: Oid) -> anyhow::Result<String> {
+        let (signature, data) = match repo.extract_signature(&commit, None) {
+            Ok(extracted) => extracted,
+            Err(e) if e.code() == git2::ErrorCode::NotFound => {
+                return Err(anyhow::Error::msg(format!(
+                    "commit {} is not signed",
+                    commit
+                )));
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("reading signature of commit {}", commit))
+            }
+        };
+        let signature = String::from_utf8_lossy(&signature).into_owned();
+        let mut sig_file = NamedTempFile::new().context("creating signature file")?;
+        sig_file
+            .write_all(signature.as_bytes())
+            .context("writing signature file")?;
+
+        if signature.starts_with(SSH_SIGNATURE) {
+            let allowed_signers = self.allowed_signers.as_ref().ok_or_else(|| {
+                anyhow::Error::msg(format!(
+                    "commit {} is signed with SSH, but no allowed-signers file is given",
+                    commit
+                ))
+            })?;
+            verify_ssh(&expand_home(allowed_signers), &sig_file, &data)
+        } else {
+            let keyring = self.keyring.as_ref().ok_or_else(|| {
+                anyhow::Error::msg(format!(
+                    "commit {} is signed with GPG, but no keyring is given",
+                    commit
+                ))
+            })?;
+            verify_gpg(repo, &expand_home(keyring), &sig_file, &data)
+        }
+        .with_context(|| format!("verifying signature of commit {}", commit))
+    }
+}
+
+/// Verifies a GPG signature against the keys in a keyring, returning the
+/// fingerprint of the key which made it
+fn verify_gpg(
+    repo: &Repository,
+    keyring: &Path,
+    signature: &NamedTempFile,
+    data: &[u8],
+) -> anyhow::Result<String> {
+    // gpg looks for a keyring given by a relative path in its home directory
+    let keyring = keyring
+        .canonicalize()
+        .with_context(|| format!("looking up keyring {}", keyring.to_string_lossy()))?;
+    let program = repo
+        .config()
+        .and_then(|config| config.get_string("gpg.program"))
+        .unwrap_or_else(|_| "gpg".to_owned());
+    let capture = subprocess::Exec::cmd(&program)
+        .args(&["--batch", "--no-default-keyring", "--keyring"])
+        .arg(&keyring)
+        .args(&["--status-fd", "1", "--verify"])
+        .arg(signature.path())
+        .arg("-")
+        .stdin(data.to_vec())
+        .stdout(subprocess::Redirection::Pipe)
+        .stderr(subprocess::Redirection::Pipe)
+        .capture()
+        .with_context(|| format!("running {}", program))?;
+
+    // See doc/DETAILS in the GnuPG source for the status lines
+    let status = capture.stdout_str();
+    let mut good = false;
+    let mut fingerprint = None;
+    for line in status.lines() {
+        let mut words = line.split(' ').skip_while(|&word| word == "[GNUPG:]");
+        match (words.next(), words.next()) {
+            (Some("GOODSIG"), _) => good = true,
+            (Some("VALIDSIG"), Some(fpr)) => fingerprint = Some(fpr.to_owned()),
+            (Some("NO_PUBKEY"), Some(key)) => {
+                return Err(anyhow::Error::msg(format!("signed by unknown key {}", key)))
+            }
+            (Some("BADSIG"), Some(key)) => {
+                return Err(anyhow::Error::msg(format!("bad signature by key {}", key)))
+            }
+            (Some("EXPKEYSIG"), Some(key)) => {
+                return Err(anyhow::Error::msg(format!("signed by expired key {}", key)))
+            }
+            (Some("REVKEYSIG"), Some(key)) => {
+                return Err(anyhow::Error::msg(format!("signed by revoked key {}", key)))
+            }
+            _ => {}
+        }
+    }
+    match fingerprint {
+        Some(fpr) if good && capture.success() => Ok(fpr),
+        _ => Err(anyhow::Error::msg(format!(
+            "signature could not be verified: {}",
+            capture.stderr_str().trim()
+        ))),
+    }
+}
+
+/// Verifies an SSH signature against the keys in an allowed-signers file,
+/// returning the principal whose key made it
+fn verify_ssh(
+    allowed_signers: &Path,
+    signature: &NamedTempFile,
+    data: &[u8],
+) -> anyhow::Result<String> {
+    let capture = subprocess::Exec::cmd("ssh-keygen")
+        .args(&["-Y", "find-principals", "-f"])
+        .arg(allowed_signers)
+        .arg("-s")
+        .arg(signature.path())
+        .stdout(subprocess::Redirection::Pipe)
+        .stderr(subprocess::Redirection::Pipe)
+        .capture()
+        .context("running ssh-keygen")?;
+    let principal = match capture.stdout_str().lines().next() {
+        Some(principal) if capture.success() => principal.to_owned(),
+        _ => {
+            return Err(anyhow::Error::msg(format!(
+                "signed by an SSH key not in {}",
+                allowed_signers.to_string_lossy()
+            )))
+        }
+    };
+
+    let capture = subprocess::Exec::cmd("ssh-keygen")
+        .args(&["-Y", "verify", "-n", "git", "-f"])
+        .arg(allowed_signers)
+        .arg("-I")
+        .arg(&principal)
+        .arg("-s")
+        .arg(signature.path())
+        .stdin(data.to_vec())
+        .stdout(subprocess::Redirection::Pipe)
+        .stderr(subprocess::Redirection::Pipe)
+        .capture()
+        .context("running ssh-keygen")?;
+    if capture.success() {
+        Ok(principal)
+    } else {
+        Err(anyhow::Error::msg(format!(
+            "bad signature by {}: {}",
+            principal,
+            capture.stderr_str().trim()
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trailers_cannot_skip() {
+        let check = SignaturesCheck {
+            keyring: Some("keys.gpg".to_owned()),
+            allowed_signers: None,
+            commits: Default::default(),
+            allow_failure: false,
+        };
+        let trailers = Trailers::from_message("Fix\n\nRsgit-Skip: signatures\n");
+        assert!(check.for_commit(true, &trailers).is_some());
+        assert!(check.for_commit(false, &trailers).is_none());
+        let trailers = Trailers::from_message("Fix\n\nRsgit-Check: signatures\n");
+        assert!(check.for_commit(false, &trailers).is_some());
+    }
+}
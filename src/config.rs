@@ -0,0 +1,952 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+//! Configuration
+//!
+//! The configuration is built from several layers, each overriding the
+//! settings of the ones before it:
+//!
+//!  1. built-in defaults;
+//!  2. the global file, `$XDG_CONFIG_HOME/rsgit/config.toml` (usually
+//!     `~/.config/rsgit/config.toml`);
+//!  3. the per-repository file, `.rsgit.toml` at the root of the repo;
+//!  4. any files given on the command line with `--config`, in order;
+//!  5. command-line flags.
+//!
+//! Every file may contain `[repo."<path>"]` sections. Their settings apply
+//! only to the repository at that path, and override the rest of the file
+//! they appear in. The whole configuration is validated as soon as it has
+//! been assembled, so that mistakes are reported before any work is done.
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::{env, fmt, fs};
+
+use crate::checks::{Check, FailurePolicy, Network};
+use crate::container::Container;
+use crate::forge::Forge;
+use crate::gerrit::Gerrit;
+use crate::gitea::Gitea;
+use crate::github::GitHub;
+use crate::gitlab::GitLab;
+use crate::limits::{parse_size, Limits};
+use crate::notify::Notifier;
+use crate::sandbox::Sandbox;
+use crate::serve::ServedRepo;
+use crate::worker::Worker;
+
+/// Name of the per-repository configuration file
+pub const REPO_CONFIG: &str = ".rsgit.toml";
+
+/// Most threads in the build pool by default, however big the machine
+pub const DEFAULT_BUILD_THREADS: usize = 8;
+
+/// CPUs to allow for each job in the build pool, when sizing it by default
+const CPUS_PER_BUILD: usize = 4;
+
+/// Memory to allow for each job in the build pool, when sizing it by
+/// default: a big crate's tests can easily take this much to link
+const MEMORY_PER_BUILD: u64 = 2 << 30;
+
+/// Default ref under which check results are recorded
+pub const DEFAULT_NOTES_REF: &str = "refs/notes/check-commit";
+
+/// A set of settings, as read from a configuration file
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Settings {
+    /// The checks to run
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub check: Vec<Check>,
+    /// Number of threads in the build pool
+    #[serde(alias = "build-jobs", skip_serializing_if = "Option::is_none")]
+    pub build_threads: Option<usize>,
+    /// Number of the build pool's threads kept for light jobs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub light_threads: Option<usize>,
+    /// Number of jobs each cargo invocation may run at once
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jobs: Option<usize>,
+    /// Memory each job may use, e.g. `4G`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_limit: Option<String>,
+    /// CPU time, in seconds, each process of a job may use
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_time_limit: Option<u64>,
+    /// Cgroup (v2) under which to create one for each job, to limit its
+    /// memory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cgroup: Option<String>,
+    /// Ref under which to record check results
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notes_ref: Option<String>,
+    /// Ref under which to also record check results on each commit's tree,
+    /// if at all
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tree_notes_ref: Option<String>,
+    /// What to do about jobs which an earlier run recorded as failing:
+    /// `retry`, `skip` or `retry-after(<hours>)`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failure_policy: Option<String>,
+    /// GPG key with which to sign the notes commits, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signing_key: Option<String>,
+    /// Remote to fetch and merge notes from before the run, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fetch_notes: Option<String>,
+    /// Remote to push the notes to once the run is over, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub push_notes: Option<String>,
+    /// Directory in which to keep cargo target directories shared between
+    /// jobs, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_cache: Option<String>,
+    /// Directory in which to create temporary checkouts, rather than the
+    /// system's temporary directory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temp_dir: Option<String>,
+    /// Disk space which temporary checkouts and the target cache may use,
+    /// e.g. `50G`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disk_budget: Option<String>,
+    /// Whether temporary checkouts borrow the repository's objects through
+    /// alternates rather than having their own
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub share_objects: Option<bool>,
+    /// Directory in which to keep clones of submodules fetched to check out
+    /// commits, rather than in the repository's git directory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub submodule_cache: Option<String>,
+    /// Whether to check out the contents of Git LFS files, rather than
+    /// pointers to them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lfs: Option<bool>,
+    /// Directory in which to keep Git LFS objects, rather than in the
+    /// repository's own LFS storage
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lfs_cache: Option<String>,
+    /// Whether to fetch all dependencies into a fresh cargo home before
+    /// running any jobs, then run them offline
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prefetch: Option<bool>,
+    /// How to run jobs in containers, if at all
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub container: Option<Container>,
+    /// How to sandbox jobs, if at all
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sandbox: Option<Sandbox>,
+    /// Other machines to run jobs on, over SSH
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub worker: Vec<Worker>,
+    /// SQLite database in which to record the result of every job, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub results_db: Option<String>,
+    /// SQLite database in which `check-serve` keeps the PRs waiting to be
+    /// checked, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_db: Option<String>,
+    /// Where to send notifications when a run completes
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub notify: Vec<Notifier>,
+    /// How to report results to GitHub, if at all
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub github: Option<GitHub>,
+    /// How to report results to GitLab, if at all
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gitlab: Option<GitLab>,
+    /// How to report results to Gitea or Forgejo, if at all
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gitea: Option<Gitea>,
+    /// Where `label-pr` looks up the changes of commits reviewed on Gerrit,
+    /// if anywhere
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gerrit: Option<Gerrit>,
+    /// Repositories whose PRs `check-serve` checks
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub serve: Vec<ServedRepo>,
+    /// Overrides for specific repositories, keyed by path
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub repo: BTreeMap<String, Settings>,
+}
+
+impl Settings {
+    /// Reads settings from a TOML file
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("reading config file {}", path.to_string_lossy()))?;
+        toml::from_str(&text)
+            .with_context(|| format!("parsing config file {}", path.to_string_lossy()))
+    }
+
+    /// Number of threads in the build pool, or the default, which allows
+    /// each job a few CPUs and enough memory
+    pub fn build_threads(&self) -> usize {
+        self.build_threads.unwrap_or_else(|| {
+            let by_cpus = available_cpus() / CPUS_PER_BUILD;
+            let by_memory = available_memory()
+                .map(|bytes| (bytes / MEMORY_PER_BUILD) as usize)
+                .unwrap_or(usize::MAX);
+            by_cpus.min(by_memory).clamp(1, DEFAULT_BUILD_THREADS)
+        })
+    }
+
+    /// Number of the build pool's threads kept for light jobs, or the
+    /// default, which is a quarter of them
+    pub fn light_threads(&self) -> usize {
+        self.light_threads
+            .unwrap_or_else(|| (self.build_threads() / 4).max(1))
+    }
+
+    /// Number of jobs which may run on workers at once
+    pub fn worker_slots(&self) -> usize {
+        self.worker.iter().map(|w| w.slots).sum()
+    }
+
+    /// Number of jobs each cargo invocation may run at once, or the default,
+    /// which shares the CPUs out between the threads of the build pool
+    pub fn jobs(&self) -> usize {
+        self.jobs
+            .unwrap_or_else(|| (available_cpus() / self.build_threads().max(1)).max(1))
+    }
+
+    /// Limits on the resources each job may use
+    pub fn limits(&self) -> anyhow::Result<Limits> {
+        let memory = match self.memory_limit {
+            Some(ref size) => Some(parse_size(size).context("parsing memory-limit")?),
+            None => None,
+        };
+        Limits::new(
+            memory,
+            self.cpu_time_limit,
+            self.cgroup.as_deref().map(expand_home),
+        )
+    }
+
+    /// Ref under which to record check results, or the default
+    pub fn notes_ref(&self) -> &str {
+        self.notes_ref.as_deref().unwrap_or(DEFAULT_NOTES_REF)
+    }
+
+    /// The ref under which to record check results on trees, if any
+    pub fn tree_notes_ref(&self) -> Option<&str> {
+        self.tree_notes_ref.as_deref()
+    }
+
+    /// What to do about jobs which an earlier run recorded as failing
+    pub fn failure_policy(&self) -> anyhow::Result<FailurePolicy> {
+        match self.failure_policy {
+            Some(ref policy) => policy
+                .parse()
+                .map_err(anyhow::Error::msg)
+                .context("parsing failure-policy"),
+            None => Ok(FailurePolicy::default()),
+        }
+    }
+
+    /// GPG key with which to sign the notes commits, if any
+    pub fn signing_key(&self) -> Option<&str> {
+        self.signing_key.as_deref()
+    }
+
+    /// Remote to fetch and merge notes from before the run, if any
+    pub fn fetch_notes(&self) -> Option<&str> {
+        self.fetch_notes.as_deref()
+    }
+
+    /// Remote to push the notes to once the run is over, if any
+    pub fn push_notes(&self) -> Option<&str> {
+        self.push_notes.as_deref()
+    }
+
+    /// Directory in which to keep shared cargo target directories, if any
+    pub fn target_cache(&self) -> Option<PathBuf> {
+        self.target_cache.as_deref().map(expand_home)
+    }
+
+    /// Directory in which to create temporary checkouts, if not the system's
+    /// temporary directory
+    pub fn temp_dir(&self) -> Option<PathBuf> {
+        self.temp_dir.as_deref().map(expand_home)
+    }
+
+    /// Disk space, in bytes, which temporary checkouts and the target cache
+    /// may use, if limited
+    pub fn disk_budget(&self) -> anyhow::Result<Option<u64>> {
+        match self.disk_budget {
+            Some(ref size) => Ok(Some(parse_size(size).context("parsing disk-budget")?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether temporary checkouts borrow the repository's objects
+    pub fn share_objects(&self) -> bool {
+        self.share_objects.unwrap_or(false)
+    }
+
+    /// Directory in which to keep clones of submodules, if not the default
+    pub fn submodule_cache(&self) -> Option<PathBuf> {
+        self.submodule_cache.as_deref().map(expand_home)
+    }
+
+    /// Whether to check out the contents of Git LFS files
+    pub fn lfs(&self) -> bool {
+        self.lfs.unwrap_or(false)
+    }
+
+    /// Directory in which to keep Git LFS objects, if not the default
+    pub fn lfs_cache(&self) -> Option<PathBuf> {
+        self.lfs_cache.as_deref().map(expand_home)
+    }
+
+    /// Whether to fetch dependencies before running any jobs
+    pub fn prefetch(&self) -> bool {
+        self.prefetch.unwrap_or(false)
+    }
+
+    /// Checks that a check read from a commit's own tree, which anyone
+    /// opening a PR can write, makes sense and keeps to the policy of these
+    /// settings: it may not choose how its jobs are run, and, if it runs
+    /// the crate's code, must run in the container or sandbox
+    pub fn validate_tree_check(&self, check: &Check) -> anyhow::Result<()> {
+        let mut problems = check.validate(None).problems;
+        problems.extend(check.untrusted_problems());
+        if check.runs_code() {
+            let confined = self.container.is_some() || self.sandbox.is_some();
+            match check.network() {
+                Network::Full if !confined => problems.push(
+                    "has full network access, which needs a container or sandbox to confine"
+                        .to_owned(),
+                ),
+                Network::Full => {}
+                _ if !confined => problems.push(
+                    "limits network access, which needs a container or sandbox to enforce"
+                        .to_owned(),
+                ),
+                _ if self.container.is_some() && !self.prefetch() => problems.push(
+                    "limits network access, so needs prefetch to be set to give jobs in \
+                     containers their dependencies"
+                        .to_owned(),
+                ),
+                _ => {}
+            }
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::Error::msg(format!(
+                "check {} in the commit's tree: {}",
+                check,
+                problems.join("; "),
+            )))
+        }
+    }
+
+    /// SQLite database in which to record results, if any
+    pub fn results_db(&self) -> Option<PathBuf> {
+        self.results_db.as_deref().map(expand_home)
+    }
+
+    /// SQLite database in which `check-serve` keeps its queue, if any
+    pub fn queue_db(&self) -> Option<PathBuf> {
+        self.queue_db.as_deref().map(expand_home)
+    }
+
+    /// Every forge to which results should be reported
+    pub fn forges(&self) -> Vec<&dyn Forge> {
+        let mut ret: Vec<&dyn Forge> = vec![];
+        if let Some(ref github) = self.github {
+            ret.push(github);
+        }
+        if let Some(ref gitlab) = self.gitlab {
+            ret.push(gitlab);
+        }
+        if let Some(ref gitea) = self.gitea {
+            ret.push(gitea);
+        }
+        ret
+    }
+}
+
+/// Where a setting came from
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Source {
+    /// Built-in default
+    #[default]
+    Default,
+    /// The top level of a configuration file
+    File(PathBuf),
+    /// A `[repo."<path>"]` section of a configuration file
+    RepoSection(PathBuf, String),
+    /// A command-line flag
+    CommandLine,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Source::Default => f.write_str("default"),
+            Source::File(ref path) => write!(f, "{}", path.to_string_lossy()),
+            Source::RepoSection(ref path, ref repo) => {
+                write!(f, "{} [repo.\"{}\"]", path.to_string_lossy(), repo)
+            }
+            Source::CommandLine => f.write_str("command line"),
+        }
+    }
+}
+
+/// The effective configuration, remembering where each setting came from
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    settings: Settings,
+    sources: Sources,
+}
+
+/// Defines how each field of `Settings` is layered and described, given as
+/// `field: kind` in the order `describe` shows them
+///
+/// The kinds are `value`, shown only if it is set; `effective`, shown
+/// always, as returned by the method of `Settings` of the same name;
+/// `table`, shown as JSON if it is set; and `list(heading)` and
+/// `always_list(heading)`, lists shown as JSON, one per line, either only
+/// if not empty or always.
+macro_rules! settings {
+    ($($field:ident: $kind:ident $(($heading:literal))?,)*) => {
+        /// Where each setting of a `Config` came from
+        #[derive(Clone, Debug, Default)]
+        struct Sources {
+            $($field: Source,)*
+        }
+
+        impl Config {
+            /// Adds a new layer, replacing every setting which it sets
+            pub fn add_layer(&mut self, layer: Settings, source: Source) {
+                $(
+                    if settings!(@is_set $kind layer.$field) {
+                        self.settings.$field = layer.$field;
+                        self.sources.$field = source.clone();
+                    }
+                )*
+            }
+
+            /// Describes the effective settings, and where they came from
+            pub fn describe(&self) -> String {
+                let mut ret = String::new();
+                $(
+                    settings!(@describe self ret $field $kind $(($heading))?);
+                )*
+                ret
+            }
+        }
+    };
+
+    (@is_set list $setting:expr) => { !$setting.is_empty() };
+    (@is_set always_list $setting:expr) => { !$setting.is_empty() };
+    (@is_set $kind:ident $setting:expr) => { $setting.is_some() };
+
+    (@describe $self:ident $ret:ident $field:ident value) => {
+        if let Some(ref value) = $self.settings.$field {
+            $ret.push_str(&format!(
+                "{} = {}  # {}\n",
+                setting_name(stringify!($field)),
+                to_json(value),
+                $self.sources.$field,
+            ));
+        }
+    };
+    (@describe $self:ident $ret:ident $field:ident effective) => {
+        $ret.push_str(&format!(
+            "{} = {}  # {}\n",
+            setting_name(stringify!($field)),
+            to_json(&$self.settings.$field()),
+            $self.sources.$field,
+        ));
+    };
+    (@describe $self:ident $ret:ident $field:ident table) => {
+        if let Some(ref value) = $self.settings.$field {
+            $ret.push_str(&format!(
+                "# {} from {}:\n{}\n",
+                setting_name(stringify!($field)),
+                $self.sources.$field,
+                to_json(value),
+            ));
+        }
+    };
+    (@describe $self:ident $ret:ident $field:ident list($heading:literal)) => {
+        if !$self.settings.$field.is_empty() {
+            settings!(@describe $self $ret $field always_list($heading));
+        }
+    };
+    (@describe $self:ident $ret:ident $field:ident always_list($heading:literal)) => {
+        $ret.push_str(&format!("# {} from {}:\n", $heading, $self.sources.$field));
+        for value in &$self.settings.$field {
+            $ret.push_str(&to_json(value));
+            $ret.push('\n');
+        }
+    };
+}
+
+settings! {
+    build_threads: effective,
+    light_threads: effective,
+    jobs: effective,
+    notes_ref: effective,
+    memory_limit: value,
+    cpu_time_limit: value,
+    cgroup: value,
+    tree_notes_ref: value,
+    failure_policy: value,
+    signing_key: value,
+    fetch_notes: value,
+    push_notes: value,
+    target_cache: value,
+    temp_dir: value,
+    disk_budget: value,
+    share_objects: effective,
+    submodule_cache: value,
+    lfs: effective,
+    lfs_cache: value,
+    prefetch: effective,
+    container: table,
+    sandbox: table,
+    worker: list("workers"),
+    results_db: value,
+    queue_db: value,
+    notify: list("notifiers"),
+    github: table,
+    gitlab: table,
+    gitea: table,
+    gerrit: table,
+    serve: list("served repositories"),
+    check: always_list("checks"),
+}
+
+/// The name of a setting in configuration files, from its field
+fn setting_name(field: &str) -> String {
+    field.replace('_', "-")
+}
+
+/// A setting as JSON, or the error encoding it
+fn to_json<T: Serialize>(value: &T) -> String {
+    serde_json::to_string(value).unwrap_or_else(|e| e.to_string())
+}
+
+impl Config {
+    /// Loads the global and per-repository files, then any explicitly
+    /// given ones
+    ///
+    /// Does not validate the result, since command-line flags may still
+    /// need to be layered on top.
+    pub fn load(explicit: &[PathBuf], repo_dir: Option<&Path>) -> anyhow::Result<Self> {
+        let implicit = global_config_path()
+            .into_iter()
+            .chain(repo_dir.map(|dir| dir.join(REPO_CONFIG)))
+            .filter(|path| path.exists());
+
+        let mut ret = Config::default();
+        for file in implicit.chain(explicit.iter().cloned()) {
+            ret.add_file(&file, repo_dir)?;
+        }
+        Ok(ret)
+    }
+
+    /// Adds a configuration file as a new layer, followed by any of its
+    /// `[repo]` sections which apply to the given repository
+    pub fn add_file(&mut self, path: &Path, repo_dir: Option<&Path>) -> anyhow::Result<()> {
+        let mut settings = Settings::from_file(path)?;
+        let sections = std::mem::take(&mut settings.repo);
+        self.add_layer(settings, Source::File(path.to_path_buf()));
+
+        let repo_dir = repo_dir.and_then(|dir| dir.canonicalize().ok());
+        for (repo, section) in sections {
+            if !section.repo.is_empty() {
+                return Err(anyhow::Error::msg(format!(
+                    "{}: [repo.\"{}\"] sections cannot themselves contain [repo] sections",
+                    path.to_string_lossy(),
+                    repo,
+                )));
+            }
+            let section_dir = expand_home(&repo).canonicalize().ok();
+            if section_dir.is_some() && section_dir == repo_dir {
+                self.add_layer(section, Source::RepoSection(path.to_path_buf(), repo));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the effective settings make sense
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.settings.build_threads() == 0 {
+            return Err(anyhow::Error::msg(format!(
+                "build-threads (set by {}) must be at least 1",
+                self.sources.build_threads,
+            )));
+        }
+        if self.settings.light_threads() == 0 {
+            return Err(anyhow::Error::msg(format!(
+                "light-threads (set by {}) must be at least 1",
+                self.sources.light_threads,
+            )));
+        }
+        if self.settings.jobs() == 0 {
+            return Err(anyhow::Error::msg(format!(
+                "jobs (set by {}) must be at least 1",
+                self.sources.jobs,
+            )));
+        }
+        if let Some(ref size) = self.settings.memory_limit {
+            match parse_size(size) {
+                Ok(0) => {
+                    return Err(anyhow::Error::msg(format!(
+                        "memory-limit (set by {}) must be more than 0",
+                        self.sources.memory_limit,
+                    )))
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    return Err(e.context(format!(
+                        "memory-limit (set by {}) must be a size such as 4G",
+                        self.sources.memory_limit,
+                    )))
+                }
+            }
+        }
+        if let Some(ref size) = self.settings.disk_budget {
+            match parse_size(size) {
+                Ok(0) => {
+                    return Err(anyhow::Error::msg(format!(
+                        "disk-budget (set by {}) must be more than 0",
+                        self.sources.disk_budget,
+                    )))
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    return Err(e.context(format!(
+                        "disk-budget (set by {}) must be a size such as 50G",
+                        self.sources.disk_budget,
+                    )))
+                }
+            }
+        }
+        if self.settings.cpu_time_limit == Some(0) {
+            return Err(anyhow::Error::msg(format!(
+                "cpu-time-limit (set by {}) must be at least 1",
+                self.sources.cpu_time_limit,
+            )));
+        }
+        if self.settings.container.is_some()
+            && (self.settings.memory_limit.is_some() || self.settings.cpu_time_limit.is_some())
+        {
+            return Err(anyhow::Error::msg(format!(
+                "memory-limit and cpu-time-limit (set by {} and {}) cannot be used with a \
+                 container (set by {}), whose engine's own options, such as --memory, \
+                 should be given in its args instead",
+                self.sources.memory_limit, self.sources.cpu_time_limit, self.sources.container,
+            )));
+        }
+        if self.settings.sandbox.is_some() {
+            if self.settings.container.is_some() {
+                return Err(anyhow::Error::msg(format!(
+                    "a sandbox (set by {}) and a container (set by {}) cannot both be used",
+                    self.sources.sandbox, self.sources.container,
+                )));
+            }
+            if self.settings.memory_limit.is_some() || self.settings.cpu_time_limit.is_some() {
+                return Err(anyhow::Error::msg(format!(
+                    "memory-limit and cpu-time-limit (set by {} and {}) cannot be used with a \
+                     sandbox (set by {}), as they would only apply to the sandboxing program",
+                    self.sources.memory_limit, self.sources.cpu_time_limit, self.sources.sandbox,
+                )));
+            }
+        }
+        if let Some(worker) = self.settings.worker.iter().find(|w| w.slots == 0) {
+            return Err(anyhow::Error::msg(format!(
+                "worker {} (set by {}) must have at least 1 slot",
+                worker.host, self.sources.worker,
+            )));
+        }
+        if !self.settings.worker.is_empty()
+            && (self.settings.container.is_some() || self.settings.sandbox.is_some())
+        {
+            return Err(anyhow::Error::msg(format!(
+                "workers (set by {}) cannot be used with a container or sandbox (set by {} \
+                 and {}), which only exist on this machine",
+                self.sources.worker, self.sources.container, self.sources.sandbox,
+            )));
+        }
+        if self.settings.share_objects()
+            && (self.settings.container.is_some()
+                || self.settings.sandbox.is_some()
+                || !self.settings.worker.is_empty())
+        {
+            return Err(anyhow::Error::msg(format!(
+                "share-objects (set by {}) cannot be used with a container, sandbox or workers \
+                 (set by {}, {} and {}), whose jobs cannot see the repository's objects",
+                self.sources.share_objects,
+                self.sources.container,
+                self.sources.sandbox,
+                self.sources.worker,
+            )));
+        }
+        if let Some(check) = self
+            .settings
+            .check
+            .iter()
+            .find(|check| check.network() != Network::Full)
+        {
+            if self.settings.container.is_none() && self.settings.sandbox.is_none() {
+                return Err(anyhow::Error::msg(format!(
+                    "check {} (set by {}) limits network access, which needs a container \
+                     or sandbox to enforce",
+                    check, self.sources.check,
+                )));
+            }
+            if self.settings.container.is_some() && !self.settings.prefetch() {
+                return Err(anyhow::Error::msg(format!(
+                    "check {} (set by {}) limits network access, so needs prefetch to be set \
+                     to give jobs in containers their dependencies",
+                    check, self.sources.check,
+                )));
+            }
+        }
+        if let Err(e) = self.settings.limits() {
+            return Err(e.context(format!(
+                "setting up resource limits (set by {} and {})",
+                self.sources.memory_limit, self.sources.cgroup,
+            )));
+        }
+
+        let notes_ref = self.settings.notes_ref();
+        if !notes_ref.starts_with("refs/notes/") || !git2::Reference::is_valid_name(notes_ref) {
+            return Err(anyhow::Error::msg(format!(
+                "notes-ref {} (set by {}) must be a valid ref name under refs/notes/",
+                notes_ref, self.sources.notes_ref,
+            )));
+        }
+        if let Some(tree_notes_ref) = self.settings.tree_notes_ref() {
+            if !tree_notes_ref.starts_with("refs/notes/")
+                || !git2::Reference::is_valid_name(tree_notes_ref)
+                || tree_notes_ref == notes_ref
+            {
+                return Err(anyhow::Error::msg(format!(
+                    "tree-notes-ref {} (set by {}) must be a valid ref name under refs/notes/, \
+                     other than notes-ref",
+                    tree_notes_ref, self.sources.tree_notes_ref,
+                )));
+            }
+        }
+        if let Err(e) = self.settings.failure_policy() {
+            return Err(e.context(format!(
+                "failure-policy (set by {}) must be retry, skip or retry-after(<hours>)",
+                self.sources.failure_policy,
+            )));
+        }
+        Ok(())
+    }
+
+    /// The effective settings
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+}
+
+/// Location of the global configuration file
+pub fn global_config_path() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("rsgit").join("config.toml"))
+}
+
+/// Expands a leading `~/` in a path to the user's home directory
+pub(crate) fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Number of CPUs we may use
+fn available_cpus() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// Memory available for new processes, in bytes, if it can be found out
+/// (which it only can on Linux)
+fn available_memory() -> Option<u64> {
+    parse_meminfo(&fs::read_to_string("/proc/meminfo").ok()?)
+}
+
+/// Reads the memory available from the contents of `/proc/meminfo`
+fn parse_meminfo(meminfo: &str) -> Option<u64> {
+    meminfo.lines().find_map(|line| {
+        let kib = line
+            .strip_prefix("MemAvailable:")?
+            .trim()
+            .strip_suffix("kB")?;
+        kib.trim().parse::<u64>().ok().map(|kib| kib << 10)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn meminfo() {
+        let meminfo = "MemTotal:       16318508 kB\n\
+                       MemFree:         1011448 kB\n\
+                       MemAvailable:    9437184 kB\n";
+        assert_eq!(parse_meminfo(meminfo), Some(9 << 30));
+        assert_eq!(parse_meminfo("MemTotal: 16318508 kB\n"), None);
+    }
+
+    #[test]
+    fn layers() {
+        let repo_dir = tempfile::tempdir().expect("creating tempdir");
+        let config_dir = tempfile::tempdir().expect("creating tempdir");
+        let config_path = config_dir.path().join("config.toml");
+        fs::write(
+            &config_path,
+            format!(
+                "
+                build-threads = 4
+
+                [[check]]
+                type = \"rust\"
+
+                [repo.\"{}\"]
+                notes-ref = \"refs/notes/other\"
+
+                [repo.\"/nonexistent\"]
+                build-threads = 100
+                ",
+                repo_dir.path().to_string_lossy(),
+            ),
+        )
+        .expect("writing config");
+
+        let mut config = Config::default();
+        config
+            .add_file(&config_path, Some(repo_dir.path()))
+            .expect("loading");
+        config.validate().expect("valid");
+        assert_eq!(config.settings().check.len(), 1);
+        assert_eq!(config.settings().build_threads(), 4);
+        assert_eq!(config.settings().notes_ref(), "refs/notes/other");
+        assert_eq!(
+            config.sources.notes_ref,
+            Source::RepoSection(
+                config_path.clone(),
+                repo_dir.path().to_string_lossy().into()
+            ),
+        );
+
+        let mut config = Config::default();
+        config.add_file(&config_path, None).expect("loading");
+        assert_eq!(config.settings().notes_ref(), DEFAULT_NOTES_REF);
+        assert_eq!(config.sources.notes_ref, Source::Default);
+
+        config.add_layer(
+            Settings {
+                build_threads: Some(0),
+                ..Default::default()
+            },
+            Source::CommandLine,
+        );
+        let err = config.validate().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "build-threads (set by command line) must be at least 1"
+        );
+
+        config.add_layer(
+            Settings {
+                build_threads: Some(2),
+                notes_ref: Some("refs/heads/master".into()),
+                ..Default::default()
+            },
+            Source::CommandLine,
+        );
+        assert!(config.validate().is_err());
+
+        config.add_layer(
+            Settings {
+                notes_ref: Some(DEFAULT_NOTES_REF.into()),
+                tree_notes_ref: Some(DEFAULT_NOTES_REF.into()),
+                ..Default::default()
+            },
+            Source::CommandLine,
+        );
+        assert!(config.validate().is_err());
+        config.add_layer(
+            Settings {
+                tree_notes_ref: Some("refs/notes/check-tree".into()),
+                ..Default::default()
+            },
+            Source::CommandLine,
+        );
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn tree_checks() {
+        let check = |json: &str| -> Check { serde_json::from_str(json).expect("decoding") };
+        let full = check("{ \"type\": \"rust\", \"network\": \"full\" }");
+        let off = check("{ \"type\": \"rust\", \"network\": \"off\" }");
+        let dco = check("{ \"type\": \"dco\" }");
+
+        let unconfined = Settings::default();
+        let err = unconfined.validate_tree_check(&full).unwrap_err();
+        assert!(err.to_string().contains("full network access"));
+        assert!(unconfined.validate_tree_check(&off).is_err());
+        unconfined.validate_tree_check(&dco).unwrap();
+
+        let sandboxed = Settings {
+            sandbox: Some(Sandbox::default()),
+            ..Default::default()
+        };
+        sandboxed.validate_tree_check(&full).unwrap();
+        sandboxed.validate_tree_check(&off).unwrap();
+        for json in &[
+            "{ \"type\": \"rust\", \"cargo-command\": \"/bin/sh\" }",
+            "{ \"type\": \"rust\", \"nice\": -5 }",
+            "{ \"type\": \"rust\", \"ionice\": \"realtime\" }",
+            "{ \"type\": \"rust\", \"working-dir\": \"../..\" }",
+            "{ \"type\": \"rust\", \"pins-file\": \"/etc/passwd\" }",
+            "{ \"type\": \"rust\", \"pins-file\": \"../../../etc/passwd\" }",
+            "{ \"type\": \"rust\", \"jobs\": [] }",
+        ] {
+            assert!(
+                sandboxed.validate_tree_check(&check(json)).is_err(),
+                "{}",
+                json
+            );
+        }
+        sandboxed
+            .validate_tree_check(&check("{ \"type\": \"rust\", \"nice\": 19 }"))
+            .unwrap();
+        sandboxed
+            .validate_tree_check(&check(
+                "{ \"type\": \"rust\", \"pins-file\": \"./ci/pins.txt\" }",
+            ))
+            .unwrap();
+    }
+}
@@ -0,0 +1,152 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+//! Running jobs in containers
+//!
+//! Checking a PR runs its build scripts and tests, i.e. arbitrary code from
+//! whoever wrote it. With a `[container]` section configured, every build,
+//! test, example and fuzz job is run in a throwaway container instead, with
+//! nothing from the host but the job's checkout and the directories cargo
+//! needs bind-mounted into it. Git operations, and cargo commands which do
+//! not run the crate's code (fetching, `cargo metadata` and so on), are
+//! still run on the host.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+fn default_engine() -> String {
+    "podman".to_owned()
+}
+
+/// How to run jobs in containers
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Container {
+    /// Container engine to run, which must accept docker's options
+    #[serde(default = "default_engine")]
+    pub engine: String,
+    /// Image to run jobs in, with `{toolchain}` replaced by the toolchain
+    /// being checked with
+    pub image: String,
+    /// Images for specific toolchains, overriding `image`
+    #[serde(default)]
+    pub images: BTreeMap<String, String>,
+    /// Extra options to the engine's `run` command, e.g. `--memory=4g`
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+impl Container {
+    /// The image to check with a given toolchain in
+    pub fn image(&self, toolchain: &str) -> String {
+        match self.images.get(toolchain) {
+            Some(image) => image.clone(),
+            None => self.image.replace("{toolchain}", toolchain),
+        }
+    }
+
+    /// Constructs the command to run a program in a new container
+    ///
+    /// Each of `mounts` is bind-mounted at the same path in the container,
+    /// so that paths in `argv`, `env` and `cwd` mean the same there. The
+    /// environment is not inherited from the host, only `env` is set. The
+    /// container only has network access if `network` is set.
+    pub fn exec(
+        &self,
+        toolchain: &str,
+        argv: &[OsString],
+        env: &[(OsString, OsString)],
+        cwd: &Path,
+        mounts: &[PathBuf],
+        network: bool,
+    ) -> subprocess::Exec {
+        let mut exec = subprocess::Exec::cmd(&self.engine)
+            .arg("run")
+            .arg("--rm")
+            .arg("--init");
+        // Files the job creates in its checkout must belong to us, so that
+        // they can be cleaned up afterwards
+        exec = if Path::new(&self.engine).file_name() == Some(OsStr::new("podman")) {
+            exec.arg("--userns=keep-id")
+        } else {
+            let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+            exec.arg(format!("--user={}:{}", uid, gid))
+        };
+        if !network {
+            exec = exec.arg("--network=none");
+        }
+        for dir in mounts {
+            let mut volume = dir.as_os_str().to_owned();
+            volume.push(":");
+            volume.push(dir);
+            exec = exec.arg("--volume").arg(volume);
+        }
+        exec = exec.arg("--workdir").arg(cwd);
+        for (key, val) in env {
+            let mut var = key.clone();
+            var.push("=");
+            var.push(val);
+            exec = exec.arg("--env").arg(var);
+        }
+        exec.args(&self.args)
+            .arg(self.image(toolchain))
+            .args(argv)
+            .stdin(subprocess::NullFile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exec() {
+        let container: Container = toml::from_str(
+            "
+            image = \"docker.io/library/rust:{toolchain}\"
+            images = { nightly = \"docker.io/rustlang/rust:nightly\" }
+            args = [\"--memory=4g\"]
+            ",
+        )
+        .expect("decoding");
+        assert_eq!(container.engine, "podman");
+        assert_eq!(container.image("1.41.1"), "docker.io/library/rust:1.41.1");
+        assert_eq!(
+            container.image("nightly"),
+            "docker.io/rustlang/rust:nightly"
+        );
+
+        let exec = container.exec(
+            "1.41.1",
+            &["cargo".into(), "test".into()],
+            &[("CARGO_NET_OFFLINE".into(), "true".into())],
+            Path::new("/tmp/repo/sub"),
+            &[PathBuf::from("/tmp/repo")],
+            false,
+        );
+        assert_eq!(
+            exec.to_cmdline_lossy(),
+            "podman run --rm --init '--userns=keep-id' '--network=none' \
+             --volume '/tmp/repo:/tmp/repo' --workdir /tmp/repo/sub \
+             --env 'CARGO_NET_OFFLINE=true' '--memory=4g' \
+             'docker.io/library/rust:1.41.1' cargo test",
+        );
+    }
+}
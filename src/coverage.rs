@@ -0,0 +1,161 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+//! Which of the jobs a check configuration would run on a commit are
+//! recorded in the notes, without running anything
+
+use anyhow::Context;
+use git2::Repository;
+
+use crate::checks::{Check, Trailers};
+use crate::git::TempRepo;
+use crate::notes::{read_notes, NoteEntry, SKIPPED_NOTE};
+
+/// Placeholder in job descriptions for targets which can only be found by
+/// asking cargo about a checkout of the crate
+const UNKNOWN_TARGET: &str = "<unknown>";
+
+/// What the notes say about a job
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum JobStatus {
+    Passed,
+    Failed,
+    Missing,
+}
+
+impl JobStatus {
+    /// Looks up a job among the entries of a commit's notes
+    ///
+    /// As when deciding whether to run a job, a pass counts for more than
+    /// a failure.
+    pub fn of(job: &str, entries: &[NoteEntry]) -> Self {
+        let mut matching = entries.iter().filter(|entry| entry.job == job);
+        match matching.next() {
+            None => JobStatus::Missing,
+            Some(entry) if entry.passed() || matching.any(NoteEntry::passed) => JobStatus::Passed,
+            Some(_) => JobStatus::Failed,
+        }
+    }
+}
+
+/// The jobs expected on a commit, and what the notes say about each
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Coverage {
+    pub commit: git2::Oid,
+    /// Whether check-pr skipped the commit due to a skip marker
+    pub skipped: bool,
+    /// Each job, as the check it belongs to, its description and status
+    pub jobs: Vec<(String, String, JobStatus)>,
+}
+
+impl Coverage {
+    /// Works out the coverage of a commit by the notes under `notes_ref`,
+    /// and those on its tree under `tree_notes_ref`
+    ///
+    /// The commit is only checked out if some job's description depends on
+    /// the crate's examples or fuzz targets.
+    pub fn of(
+        repo: &Repository,
+        notes_ref: &str,
+        tree_notes_ref: Option<&str>,
+        checks: &[Check],
+        commit: git2::Oid,
+    ) -> anyhow::Result<Self> {
+        let commit_obj = repo
+            .find_commit(commit)
+            .with_context(|| format!("looking up commit {}", commit))?;
+        let mut notes = read_notes(repo, notes_ref, commit);
+        if let Some(tree_notes_ref) = tree_notes_ref {
+            notes.extend(read_notes(repo, tree_notes_ref, commit_obj.tree_id()));
+        }
+        if notes.iter().any(|line| line == SKIPPED_NOTE) {
+            return Ok(Coverage {
+                commit,
+                skipped: true,
+                jobs: vec![],
+            });
+        }
+        let entries: Vec<_> = notes
+            .iter()
+            .filter_map(|line| NoteEntry::parse(line))
+            .collect();
+
+        let trailers = Trailers::from_message(commit_obj.message().unwrap_or(""));
+        let mut checkout: Option<TempRepo> = None;
+        let mut jobs = vec![];
+        for check in checks {
+            // Every commit counts as selected, as selectors only make sense
+            // within a PR
+            let check = match check.for_commit(true, &trailers) {
+                Some(check) => check,
+                None => continue,
+            };
+            let mut validation = check.validate(None);
+            if validation
+                .jobs
+                .iter()
+                .any(|job| job.contains(UNKNOWN_TARGET))
+            {
+                if checkout.is_none() {
+                    checkout = Some(crate::git::temp_repo(repo, commit)?);
+                }
+                validation = check.validate(checkout.as_ref().map(|tmp| &tmp.dir));
+            }
+            let name = check.to_string();
+            for job in validation.jobs {
+                let status = JobStatus::of(&job, &entries);
+                jobs.push((name.clone(), job, status));
+            }
+        }
+        Ok(Coverage {
+            commit,
+            skipped: false,
+            jobs,
+        })
+    }
+
+    /// Whether every expected job passed
+    pub fn is_complete(&self) -> bool {
+        self.jobs
+            .iter()
+            .all(|&(_, _, status)| status == JobStatus::Passed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notes::NoteOutcome;
+
+    #[test]
+    fn job_status() {
+        let build = "stable cargo build '--features='";
+        let test = "stable cargo test '--features='";
+        let entries = [
+            NoteEntry::new(build, NoteOutcome::Fail),
+            NoteEntry::new(build, NoteOutcome::Pass),
+            NoteEntry::new(test, NoteOutcome::Fail),
+        ];
+        assert_eq!(JobStatus::of(build, &entries), JobStatus::Passed);
+        assert_eq!(JobStatus::of(test, &entries), JobStatus::Failed);
+        assert_eq!(
+            JobStatus::of("nightly cargo build '--features='", &entries),
+            JobStatus::Missing
+        );
+    }
+}
@@ -0,0 +1,163 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+//! Keeping a run's disk usage within a budget
+//!
+//! Every job's checkout, and the build output in it, stays on disk until
+//! every job of its commit and toolchain is done, so a wide matrix on a big
+//! PR can fill the disk. With a budget, new jobs wait to start while the
+//! temporary checkouts and the target cache use more than it, and a build
+//! directory which no running job is using is deleted as soon as its last
+//! job finishes, rather than being kept for the next job to reuse. At
+//! least one job is always allowed to run, so that a budget which is too
+//! small slows the run down rather than stopping it.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::git::{dir_size, temp_disk_usage};
+use crate::say;
+
+/// How often to check disk usage while waiting for it to fall
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The budget, if there is one
+struct Budget {
+    bytes: u64,
+    target_cache: Option<PathBuf>,
+}
+
+/// The jobs which are running
+#[derive(Default)]
+struct Running {
+    jobs: usize,
+    /// The number of running jobs using each build directory
+    build_dirs: BTreeMap<PathBuf, usize>,
+}
+
+static BUDGET: Mutex<Option<Budget>> = Mutex::new(None);
+static RUNNING: Mutex<Option<Running>> = Mutex::new(None);
+
+/// Sets the number of bytes which the temporary checkouts, and the target
+/// cache if there is one, may use
+pub fn set_budget(bytes: u64, target_cache: Option<PathBuf>) {
+    *BUDGET.lock().unwrap() = Some(Budget {
+        bytes,
+        target_cache,
+    });
+}
+
+/// The number of bytes used, if there is a budget, and whether that is more
+/// than it
+fn usage() -> Option<(u64, bool)> {
+    let budget = BUDGET.lock().unwrap();
+    let budget = budget.as_ref()?;
+    let used = temp_disk_usage() + budget.target_cache.as_deref().map_or(0, dir_size);
+    Some((used, used > budget.bytes))
+}
+
+/// A job which has been allowed to start, which should be dropped once it
+/// finishes
+pub struct Job {
+    /// `None` if there is no budget, so the job was not counted
+    build_dirs: Option<Vec<PathBuf>>,
+}
+
+/// Waits until the disk usage is within the budget, or no other job is
+/// running, before a job starts
+///
+/// `build_dirs` are the directories which the job's build output goes in.
+/// Does nothing if there is no budget.
+pub fn start(build_dirs: Vec<PathBuf>) -> Job {
+    if BUDGET.lock().unwrap().is_none() {
+        return Job { build_dirs: None };
+    }
+    let mut waited = false;
+    loop {
+        let busy = RUNNING.lock().unwrap().as_ref().map_or(0, |r| r.jobs) > 0;
+        match usage() {
+            Some((used, true)) if busy => {
+                if !waited {
+                    say!(
+                        Verbose,
+                        "Waiting for disk space: {} MiB is in use",
+                        used >> 20
+                    );
+                    waited = true;
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+            _ => break,
+        }
+    }
+    let mut running = RUNNING.lock().unwrap();
+    let running = running.get_or_insert_with(Running::default);
+    running.jobs += 1;
+    for dir in &build_dirs {
+        *running.build_dirs.entry(dir.clone()).or_default() += 1;
+    }
+    Job {
+        build_dirs: Some(build_dirs),
+    }
+}
+
+impl Drop for Job {
+    fn drop(&mut self) {
+        let build_dirs = match self.build_dirs.take() {
+            Some(dirs) => dirs,
+            None => return,
+        };
+        // Held while deleting, so that no job starts using a directory
+        // which is being deleted
+        let mut running = RUNNING.lock().unwrap();
+        let running = running.get_or_insert_with(Running::default);
+        running.jobs -= 1;
+        let mut unused = vec![];
+        for dir in build_dirs {
+            let users = running.build_dirs.entry(dir.clone()).or_default();
+            *users -= 1;
+            if *users == 0 {
+                running.build_dirs.remove(&dir);
+                unused.push(dir);
+            }
+        }
+        if !matches!(usage(), Some((_, true))) {
+            return;
+        }
+        for dir in unused {
+            if dir.exists() {
+                say!(
+                    Verbose,
+                    "Over disk budget; deleting build directory {}",
+                    dir.to_string_lossy()
+                );
+                if let Err(e) = fs::remove_dir_all(&dir) {
+                    eprintln!(
+                        "WARNING: failed to delete build directory {}: {}",
+                        dir.to_string_lossy(),
+                        e
+                    );
+                }
+            }
+        }
+    }
+}
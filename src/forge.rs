@@ -0,0 +1,169 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+//! Forges which host repositories and their PRs
+//!
+//! GitHub, GitLab and Gitea (or Forgejo) each have their own API, and their
+//! own names for things, but are asked the same questions about PRs and
+//! told the same things about the results of a run. Each is configured by
+//! a section of the settings named after it.
+
+use std::fmt;
+
+use crate::output::{FinishedJob, Summary};
+
+/// What a forge says about a PR, beyond its commits
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PrInfo {
+    pub title: String,
+    /// Username of whoever opened the PR
+    pub author: String,
+    /// Date the PR was merged, as `YYYY-MM-DD`, if it has been
+    pub merged: Option<String>,
+    /// The commit which merged the PR, if it has been merged: a merge
+    /// commit, or the commit it was squashed into
+    pub merge_commit: Option<String>,
+}
+
+impl PrInfo {
+    /// Reads the description of a PR given by a forge's API, in which
+    /// `user` is the object naming its author
+    ///
+    /// The three forges agree on the names of the other fields, except that
+    /// GitLab gives the commit a merge request was squashed into separately
+    /// when it was not also merged with a merge commit.
+    pub(crate) fn from_json(pull: &serde_json::Value, user: &str) -> Option<Self> {
+        let merged = pull["merged_at"]
+            .as_str()
+            .map(|time| time.chars().take(10).collect());
+        // GitHub gives a test merge commit for PRs which are not merged
+        let merge_commit = if merged.is_some() {
+            pull["merge_commit_sha"]
+                .as_str()
+                .or_else(|| pull["squash_commit_sha"].as_str())
+                .map(str::to_owned)
+        } else {
+            None
+        };
+        Some(PrInfo {
+            title: pull["title"].as_str()?.to_owned(),
+            author: pull[user]["login"]
+                .as_str()
+                .or_else(|| pull[user]["username"].as_str())?
+                .to_owned(),
+            merged,
+            merge_commit,
+        })
+    }
+}
+
+impl fmt::Display for PrInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} by {}", self.title, self.author)?;
+        match self.merged {
+            Some(ref date) => write!(f, ", merged {}", date),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A forge hosting the repository being checked
+pub trait Forge {
+    /// Name of the forge, for messages
+    fn name(&self) -> &'static str;
+
+    /// The repository on the forge, as `owner/name`
+    fn repo(&self) -> &str;
+
+    /// Ref on the forge holding the tip of each PR, with `*` standing for
+    /// its number
+    fn pr_ref(&self) -> &'static str {
+        "refs/pull/*/head"
+    }
+
+    /// Lists the numbers of every open PR
+    fn open_prs(&self) -> anyhow::Result<Vec<usize>>;
+
+    /// Looks up the name of the branch a PR is to be merged into
+    fn pr_base(&self, pr: usize) -> anyhow::Result<String>;
+
+    /// Looks up the title, author and merge date of a PR
+    fn pr_info(&self, pr: usize) -> anyhow::Result<PrInfo>;
+
+    /// Whether results should be reported as statuses of each commit
+    fn wants_statuses(&self) -> bool;
+
+    /// Whether results should be posted as a comment on the PR
+    fn wants_comment(&self) -> bool;
+
+    /// Reports the outcome of every check on every commit
+    fn post_statuses(&self, summary: &Summary, jobs: &[FinishedJob]) -> anyhow::Result<()>;
+
+    /// Posts the summary table and failures as a comment on a PR, replacing
+    /// the one posted by an earlier run if there is one
+    fn post_comment(
+        &self,
+        pr: usize,
+        summary: &Summary,
+        jobs: &[FinishedJob],
+    ) -> anyhow::Result<()>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn pr_info() {
+        let pull = json!({
+            "title": "Fix \"the\" frobnicator",
+            "user": { "login": "alice" },
+            "merged_at": "2021-03-04T05:06:07Z",
+            "merge_commit_sha": "abcdef",
+        });
+        let info = PrInfo::from_json(&pull, "user").unwrap();
+        assert_eq!(info.merged.as_deref(), Some("2021-03-04"));
+        assert_eq!(info.merge_commit.as_deref(), Some("abcdef"));
+        assert_eq!(
+            info.to_string(),
+            r#""Fix \"the\" frobnicator" by alice, merged 2021-03-04"#
+        );
+
+        let merge_request = json!({
+            "title": "Open",
+            "author": { "username": "bob" },
+            "merged_at": null,
+            "merge_commit_sha": "abcdef",
+        });
+        let info = PrInfo::from_json(&merge_request, "author").unwrap();
+        assert_eq!(info.to_string(), r#""Open" by bob"#);
+        assert_eq!(info.merge_commit, None);
+
+        let squashed = json!({
+            "title": "Squashed",
+            "author": { "username": "bob" },
+            "merged_at": "2021-03-04T05:06:07Z",
+            "merge_commit_sha": null,
+            "squash_commit_sha": "123456",
+        });
+        let info = PrInfo::from_json(&squashed, "author").unwrap();
+        assert_eq!(info.merge_commit.as_deref(), Some("123456"));
+        assert!(PrInfo::from_json(&json!({ "title": "x" }), "user").is_none());
+    }
+}
@@ -0,0 +1,199 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+//! Cleaning up after runs which crashed or were killed
+//!
+//! A run removes its temporary worktrees and directories as it finishes
+//! with them, but one which crashes, or is killed with `SIGKILL`, leaves
+//! them behind. Temporary directories are named after the process which
+//! created them, so those of processes which no longer exist can be found
+//! and removed. Worktrees belong to a repository, so any which are left
+//! when no run holds the repository's lock are leaked.
+
+use anyhow::Context;
+use git2::Repository;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::{env, process};
+use tempfile::TempDir;
+
+/// Start of the names of temporary directories, which is followed by the
+/// process ID and a `-`
+const TEMP_PREFIX: &str = "check-pr-";
+
+/// Start of the names of temporary worktrees
+pub const WORKTREE_PREFIX: &str = "checkpr-temp-worktree-";
+
+/// Directory in which to create temporary directories, if not the system's
+static TEMP_ROOT: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Sets the directory in which to create temporary directories, creating it
+/// if need be
+pub fn set_temp_root(dir: PathBuf) -> anyhow::Result<()> {
+    fs::create_dir_all(&dir).with_context(|| {
+        format!(
+            "creating temporary directory root {}",
+            dir.to_string_lossy()
+        )
+    })?;
+    *TEMP_ROOT.lock().unwrap() = Some(dir);
+    Ok(())
+}
+
+/// The directory in which temporary directories are created
+fn temp_root() -> PathBuf {
+    TEMP_ROOT
+        .lock()
+        .unwrap()
+        .clone()
+        .unwrap_or_else(env::temp_dir)
+}
+
+/// Creates a temporary directory, named so that it can be cleaned up if this
+/// process dies without removing it
+pub fn temp_dir() -> std::io::Result<TempDir> {
+    tempfile::Builder::new()
+        .prefix(&format!("{}{}-", TEMP_PREFIX, process::id()))
+        .tempdir_in(temp_root())
+}
+
+/// Removes every temporary worktree of a repository, and the branch
+/// created for each, returning how many worktrees there were
+///
+/// This must only be called while holding the repository's
+/// [`RunLock`](crate::lock::RunLock), so that no run is using them.
+pub fn prune_worktrees(repo: &Repository) -> anyhow::Result<usize> {
+    let names = repo.worktrees().context("listing worktrees")?;
+    let mut count = 0;
+    for name in names.iter().flatten() {
+        if !name.starts_with(WORKTREE_PREFIX) {
+            continue;
+        }
+        let worktree = repo
+            .find_worktree(name)
+            .with_context(|| format!("opening worktree {}", name))?;
+        worktree
+            .prune(Some(
+                git2::WorktreePruneOptions::new()
+                    .valid(true)
+                    .locked(true)
+                    .working_tree(true),
+            ))
+            .with_context(|| format!("pruning worktree {}", name))?;
+        count += 1;
+    }
+    // Also delete the branches git-worktree created for them
+    let branches = repo
+        .branches(Some(git2::BranchType::Local))
+        .context("listing branches")?;
+    for branch in branches {
+        let (mut branch, _) = branch.context("listing branches")?;
+        let name = match branch.name() {
+            Ok(Some(name)) if name.starts_with(WORKTREE_PREFIX) => name.to_owned(),
+            _ => continue,
+        };
+        branch
+            .delete()
+            .with_context(|| format!("deleting branch {}", name))?;
+    }
+    Ok(count)
+}
+
+/// Removes every temporary directory left by a process which no longer
+/// exists, returning how many there were
+///
+/// Both the system's temporary directory and the one set with
+/// [`set_temp_root`], if any, are cleaned up.
+pub fn remove_temp_dirs() -> anyhow::Result<usize> {
+    let mut roots = vec![env::temp_dir()];
+    let root = temp_root();
+    if !roots.contains(&root) {
+        roots.push(root);
+    }
+    let mut count = 0;
+    for tmp in roots {
+        count += remove_temp_dirs_in(&tmp)?;
+    }
+    Ok(count)
+}
+
+/// Removes every temporary directory left in a directory by a process which
+/// no longer exists, returning how many there were
+fn remove_temp_dirs_in(tmp: &Path) -> anyhow::Result<usize> {
+    let entries =
+        fs::read_dir(tmp).with_context(|| format!("listing {}", tmp.to_string_lossy()))?;
+    let mut count = 0;
+    for entry in entries.filter_map(Result::ok) {
+        let name = entry.file_name();
+        let pid = match name.to_str().and_then(owner) {
+            Some(pid) => pid,
+            None => continue,
+        };
+        if process_exists(pid) || !entry.path().is_dir() {
+            continue;
+        }
+        let path = entry.path();
+        fs::remove_dir_all(&path)
+            .with_context(|| format!("removing {}", path.to_string_lossy()))?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// The process which created a temporary directory, going by its name
+fn owner(name: &str) -> Option<u32> {
+    let rest = name.strip_prefix(TEMP_PREFIX)?;
+    let (pid, _) = rest.split_once('-')?;
+    pid.parse().ok()
+}
+
+/// Whether a process exists
+#[cfg(unix)]
+fn process_exists(pid: u32) -> bool {
+    // Signal 0 only checks whether the signal could be sent
+    let ret = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    ret == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Whether a process exists
+///
+/// Elsewhere than Unix, this cannot be found out, so every process is
+/// assumed to exist, and no directories are removed.
+#[cfg(not(unix))]
+fn process_exists(_: u32) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn owners() {
+        assert_eq!(owner("check-pr-1234-abcdef"), Some(1234));
+        assert_eq!(owner("check-pr-1234"), None);
+        assert_eq!(owner("check-pr-x-abcdef"), None);
+        assert_eq!(owner(".tmpabcdef"), None);
+
+        let dir = temp_dir().unwrap();
+        let name = dir.path().file_name().unwrap().to_str().unwrap();
+        assert_eq!(owner(name), Some(process::id()));
+        assert!(process_exists(process::id()));
+    }
+}
@@ -0,0 +1,238 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+//! Gerrit integration
+//!
+//! Gerrit reviews commits rather than branches, each as a change named by
+//! the `Change-Id` trailer of its commits. Configured by a `[gerrit]`
+//! section of the settings, e.g.
+//!
+//! ```toml
+//! [gerrit]
+//! url = "https://review.example.org"
+//! project = "tools/rsgit"
+//! ```
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::env;
+
+use crate::forge::PrInfo;
+use crate::http::{basic_auth, percent_encode, Request};
+
+/// Trailer naming the change a commit belongs to
+pub const CHANGE_ID: &str = "Change-Id";
+
+/// Number of changes asked about in each query, keeping its URL short
+const CHANGES_PER_QUERY: usize = 50;
+
+/// Gerrit starts each JSON response with this, to stop it being run as a
+/// script
+const XSSI_PREFIX: &str = ")]}'";
+
+fn default_curl() -> String {
+    "curl".to_owned()
+}
+
+/// How to talk to Gerrit about a project
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Gerrit {
+    /// Base URL of the Gerrit instance
+    pub url: String,
+    /// The project, as Gerrit names it
+    pub project: String,
+    /// User to authenticate as; if not given, requests are anonymous
+    #[serde(default)]
+    username: Option<String>,
+    /// HTTP password of the user; if not given, `GERRIT_PASSWORD` is used
+    #[serde(default, skip_serializing)]
+    password: Option<String>,
+    /// Command to make HTTP requests with, which must accept curl's options
+    #[serde(default = "default_curl")]
+    curl: String,
+}
+
+/// A change under review on Gerrit
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Change {
+    /// The `Change-Id` of its commits
+    pub change_id: String,
+    pub number: usize,
+    /// Branch it is to be merged into
+    pub branch: String,
+    /// Its subject, owner, and date of submission if it was submitted
+    pub info: PrInfo,
+}
+
+impl Change {
+    /// Reads a change as described by the API, with detailed accounts
+    fn from_json(change: &serde_json::Value) -> Option<Self> {
+        let owner = &change["owner"];
+        let merged = if change["status"].as_str() == Some("MERGED") {
+            change["submitted"]
+                .as_str()
+                .map(|time| time.chars().take(10).collect())
+        } else {
+            None
+        };
+        Some(Change {
+            change_id: change["change_id"].as_str()?.to_owned(),
+            number: change["_number"].as_u64()? as usize,
+            branch: change["branch"].as_str()?.to_owned(),
+            info: PrInfo {
+                title: change["subject"].as_str()?.to_owned(),
+                author: owner["username"]
+                    .as_str()
+                    .or_else(|| owner["name"].as_str())
+                    .map(str::to_owned)
+                    .or_else(|| owner["_account_id"].as_u64().map(|id| id.to_string()))?,
+                merged,
+                merge_commit: None,
+            },
+        })
+    }
+}
+
+impl Gerrit {
+    /// Start of the URL of each change, to which its number is added
+    pub fn change_url_prefix(&self) -> String {
+        format!("{}/c/{}/+/", self.url.trim_end_matches('/'), self.project)
+    }
+
+    /// Makes a GET request to the API, returning the decoded response
+    fn request(&self, path: &str) -> anyhow::Result<serde_json::Value> {
+        let url = self.url.trim_end_matches('/');
+        let mut request = match self.username {
+            // Authenticated requests go under /a/
+            Some(ref username) => {
+                let password = match self.password {
+                    Some(ref password) => password.clone(),
+                    None => env::var("GERRIT_PASSWORD").map_err(|_| {
+                        anyhow::Error::msg(
+                            "no Gerrit password: set password in [gerrit], or GERRIT_PASSWORD",
+                        )
+                    })?,
+                };
+                Request::new(&self.curl, "GET", format!("{}/a{}", url, path))
+                    .secret_header(basic_auth(username, &password))
+            }
+            None => Request::new(&self.curl, "GET", format!("{}{}", url, path)),
+        };
+        request = request
+            .header("Accept: application/json")
+            .header("User-Agent: rsgit");
+        let response = request.send()?;
+        let response = response.trim_start().trim_start_matches(XSSI_PREFIX);
+        serde_json::from_str(response)
+            .with_context(|| format!("decoding Gerrit's response to GET {}", path))
+    }
+
+    /// Looks up the changes of the project with the given Change-Ids, of
+    /// which there may be several for each, on different branches
+    pub fn changes(&self, change_ids: &[&str]) -> anyhow::Result<Vec<Change>> {
+        let mut ret = vec![];
+        for batch in change_ids.chunks(CHANGES_PER_QUERY) {
+            let ids: Vec<_> = batch.iter().map(|id| format!("change:{}", id)).collect();
+            let query = format!("project:{} AND ({})", self.project, ids.join(" OR "));
+            let mut start = 0;
+            loop {
+                let changes = self.request(&format!(
+                    "/changes/?q={}&o=DETAILED_ACCOUNTS&S={}",
+                    percent_encode(&query),
+                    start
+                ))?;
+                let changes = changes.as_array().map(Vec::as_slice).unwrap_or(&[]);
+                ret.extend(changes.iter().filter_map(Change::from_json));
+                // Set on the last change when Gerrit stopped short
+                let more = changes
+                    .last()
+                    .and_then(|change| change["_more_changes"].as_bool())
+                    .unwrap_or(false);
+                if !more || changes.is_empty() {
+                    break;
+                }
+                start += changes.len();
+            }
+        }
+        Ok(ret)
+    }
+}
+
+/// The Change-Id of a commit, from its last `Change-Id` trailer
+pub fn change_id(message: &str) -> Option<String> {
+    let trailers = git2::message_trailers_strs(message).ok()?;
+    let ret = trailers
+        .iter()
+        .rev()
+        .find(|(key, _)| key.eq_ignore_ascii_case(CHANGE_ID))
+        .map(|(_, value)| value.trim().to_owned());
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn changes() {
+        let gerrit: Gerrit =
+            toml::from_str("url = \"https://review.example.org/\"\nproject = \"a/b\"").unwrap();
+        assert_eq!(
+            gerrit.change_url_prefix(),
+            "https://review.example.org/c/a/b/+/"
+        );
+
+        let change = json!({
+            "change_id": "I0123",
+            "_number": 45,
+            "branch": "master",
+            "subject": "Fix it",
+            "status": "MERGED",
+            "submitted": "2021-03-04 05:06:07.000000000",
+            "owner": { "_account_id": 1000, "name": "Some One", "username": "someone" },
+        });
+        let change = Change::from_json(&change).unwrap();
+        assert_eq!(change.number, 45);
+        assert_eq!(
+            change.info.to_string(),
+            "\"Fix it\" by someone, merged 2021-03-04"
+        );
+
+        let open = json!({
+            "change_id": "I0123",
+            "_number": 46,
+            "branch": "master",
+            "subject": "Fix it",
+            "status": "NEW",
+            "owner": { "_account_id": 1000 },
+        });
+        let open = Change::from_json(&open).unwrap();
+        assert_eq!(open.info.to_string(), "\"Fix it\" by 1000");
+
+        assert_eq!(
+            change_id("Fix it\n\nChange-Id: I0123\nSigned-off-by: X <x@y>\n").as_deref(),
+            Some("I0123")
+        );
+        assert_eq!(
+            change_id("Fix it\n\nChange-Id: I0123 is in the body\n\nMore\n"),
+            None
+        );
+    }
+}
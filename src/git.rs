@@ -19,14 +19,23 @@
 use anyhow::{self, Context};
 use git2::{self, Repository, Tree};
 use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use crate::gc;
+use crate::say;
 
 /// Marker structure used to ensure that a temp object stays alive
-pub struct RepoRef<'a>(&'a ());
+pub struct RepoRef<'a>(PhantomData<&'a ()>);
 
 impl<'a, T> From<&'a T> for RepoRef<'a> {
     fn from(_: &'a T) -> Self {
-        RepoRef(&())
+        RepoRef(PhantomData)
     }
 }
 
@@ -42,10 +51,10 @@ pub struct TempWorktree {
 impl TempWorktree {
     /// Creates a new temporary worktree in a given repository
     pub fn new(repo: &Repository, head: Option<&git2::Reference>) -> anyhow::Result<Self> {
-        let new_dir =
-            tempfile::tempdir().context("creating temporary directory for new worktree")?;
+        let new_dir = gc::temp_dir().context("creating temporary directory for new worktree")?;
         let name = format!(
-            "checkpr-temp-worktree-{}",
+            "{}{}",
+            gc::WORKTREE_PREFIX,
             new_dir
                 .path()
                 .file_name()
@@ -63,26 +72,27 @@ impl TempWorktree {
             .with_context(|| format!("creating new worktree {}", name))?;
 
         Ok(TempWorktree {
-            worktree: worktree,
+            worktree,
             dir: new_dir,
         })
     }
 
     /// Attempt to open the worktree as a repository
     pub fn repo(&self) -> anyhow::Result<Repository> {
-        Repository::open_from_worktree(&self.worktree)
-            .with_context(|| {
-                format!(
-                    "opening worktree at {} as repo",
-                    self.dir.path().to_string_lossy()
-                )
-            })
-            .map_err(anyhow::Error::from)
+        Repository::open_from_worktree(&self.worktree).with_context(|| {
+            format!(
+                "opening worktree at {} as repo",
+                self.dir.path().to_string_lossy()
+            )
+        })
     }
 }
 
 impl Drop for TempWorktree {
     fn drop(&mut self) {
+        let common = Repository::open_from_worktree(&self.worktree)
+            .map(|repo| common_dir(&repo))
+            .ok();
         // prune valid worktree .. it won't be valid soon when we delete it!
         if let Err(e) = self.worktree.prune(Some(
             &mut git2::WorktreePruneOptions::new().locked(true).valid(true),
@@ -92,10 +102,124 @@ impl Drop for TempWorktree {
                 self.dir.path().to_string_lossy(),
                 e,
             );
+            return;
+        }
+        // git-worktree created a branch named after the worktree, which
+        // would otherwise pile up in the repository
+        if let (Some(common), Some(name)) = (common, self.worktree.name()) {
+            let deleted = Repository::open(&common)
+                .and_then(|repo| repo.find_branch(name, git2::BranchType::Local)?.delete());
+            if let Err(e) = deleted {
+                eprintln!("WARNING: failed to delete branch {}: {}", name, e);
+            }
         }
     }
 }
 
+/// The directory a repository's config file lives in: its working tree,
+/// or for a bare repository its git directory
+pub fn repo_dir(repo: &Repository) -> &Path {
+    repo.workdir().unwrap_or_else(|| repo.path())
+}
+
+/// Adds a trailer to a commit message: to its trailer block if it ends in
+/// one, so that the trailers already there are still found, or else in a
+/// new paragraph
+pub fn add_trailer(message: &str, key: &str, value: &str) -> String {
+    let message = message.trim_end();
+    let has_trailers = git2::message_trailers_strs(message).is_ok_and(|t| t.len() > 0);
+    format!(
+        "{}{}{}: {}\n",
+        message,
+        if has_trailers { "\n" } else { "\n\n" },
+        key,
+        value
+    )
+}
+
+/// The branch PRs are normally based on, as found by [`default_branch`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DefaultBranch {
+    /// Name of the branch on the remote, e.g. `main`
+    pub name: String,
+    /// Name under which the branch can be looked up in the repository,
+    /// e.g. `origin/main` for a remote-tracking branch
+    pub local: String,
+}
+
+/// Finds the branch PRs are normally based on
+///
+/// This is the branch the remote's `HEAD` points to, as recorded by
+/// `git clone` or `git remote set-head`, or failing that a bare
+/// repository's own `HEAD` (as in a mirror), then `init.defaultBranch`,
+/// then `master`. Only a branch which exists in the repository, either as
+/// a remote-tracking branch or a local one, is returned; if there is none
+/// this fails, rather than guessing at a name which does not resolve.
+pub fn default_branch(repo: &Repository, remote: &str) -> anyhow::Result<DefaultBranch> {
+    let remote_prefix = format!("refs/remotes/{}/", remote);
+    let exists = |refname: &str| {
+        repo.find_reference(refname)
+            .and_then(|rf| rf.resolve())
+            .is_ok()
+    };
+    let remote_head = repo
+        .find_reference(&format!("{}HEAD", remote_prefix))
+        .ok()
+        .and_then(|rf| rf.symbolic_target().map(str::to_owned))
+        .filter(|target| exists(target))
+        .and_then(|target| target.strip_prefix(&remote_prefix).map(str::to_owned));
+    if let Some(name) = remote_head {
+        return Ok(DefaultBranch {
+            local: format!("{}/{}", remote, name),
+            name,
+        });
+    }
+    let bare_head = repo
+        .find_reference("HEAD")
+        .ok()
+        .filter(|_| repo.is_bare())
+        .and_then(|rf| rf.symbolic_target().map(str::to_owned))
+        .filter(|target| exists(target))
+        .and_then(|target| target.strip_prefix("refs/heads/").map(str::to_owned));
+    if let Some(name) = bare_head {
+        return Ok(DefaultBranch {
+            local: name.clone(),
+            name,
+        });
+    }
+    let mut names = vec![];
+    if let Ok(configured) = repo
+        .config()
+        .and_then(|config| config.get_string("init.defaultBranch"))
+    {
+        names.push(configured);
+    }
+    if !names.iter().any(|name| name == "master") {
+        names.push("master".to_owned());
+    }
+    for name in &names {
+        if exists(&format!("{}{}", remote_prefix, name)) {
+            return Ok(DefaultBranch {
+                local: format!("{}/{}", remote, name),
+                name: name.clone(),
+            });
+        }
+        if exists(&format!("refs/heads/{}", name)) {
+            return Ok(DefaultBranch {
+                local: name.clone(),
+                name: name.clone(),
+            });
+        }
+    }
+    Err(anyhow::Error::msg(format!(
+        "cannot find the default branch: {}/HEAD is not set, and there is no {} branch; \
+         run `git remote set-head {} --auto`, or give the branch explicitly",
+        remote,
+        names.join(" or "),
+        remote
+    )))
+}
+
 /// A structure representing a temporary repository. When
 /// it is dropped the repository will be deleted from disk.
 pub struct TempRepo {
@@ -105,6 +229,59 @@ pub struct TempRepo {
     pub dir: tempfile::TempDir,
 }
 
+/// Directories of every temporary repo which currently exists
+static LIVE_TEMP_REPOS: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// Total size, in bytes, of the files under a directory
+pub fn dir_size(path: &Path) -> u64 {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return 0, // deleted out from under us
+    };
+    entries
+        .filter_map(Result::ok)
+        .map(|entry| match entry.metadata() {
+            Ok(meta) if meta.is_dir() => dir_size(&entry.path()),
+            Ok(meta) => meta.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+/// Total size, in bytes, of the object files under a directory which are
+/// hardlinked from elsewhere, and so take up no space of their own
+#[cfg(unix)]
+fn linked_size(path: &Path) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    entries
+        .filter_map(Result::ok)
+        .map(|entry| match entry.metadata() {
+            Ok(meta) if meta.is_dir() => linked_size(&entry.path()),
+            Ok(meta) if meta.nlink() > 1 => meta.len(),
+            _ => 0,
+        })
+        .sum()
+}
+
+#[cfg(not(unix))]
+fn linked_size(_: &Path) -> u64 {
+    0
+}
+
+/// Total size, in bytes, of every temporary repo which currently exists,
+/// including any build output in them
+pub fn temp_disk_usage() -> u64 {
+    let dirs = LIVE_TEMP_REPOS.lock().unwrap().clone();
+    dirs.iter()
+        .map(|dir| dir_size(dir).saturating_sub(linked_size(&dir.join(".git").join("objects"))))
+        .sum()
+}
+
 /// Safe because I'm fairly confident that `git2::Repository` could actually be
 /// `Send`.
 unsafe impl Send for TempRepo {}
@@ -112,12 +289,15 @@ unsafe impl Send for TempRepo {}
 impl TempRepo {
     /// Creates a new temporary repo
     pub fn new() -> anyhow::Result<Self> {
-        let new_repo_dir =
-            tempfile::tempdir().context("creating temporary directory for new repo")?;
+        let new_repo_dir = gc::temp_dir().context("creating temporary directory for new repo")?;
         let path_str = new_repo_dir.path().to_string_lossy();
         let new_repo = Repository::init(new_repo_dir.path())
             .with_context(|| format!("initializing temporary repo in {}", path_str))?;
 
+        LIVE_TEMP_REPOS
+            .lock()
+            .unwrap()
+            .insert(new_repo_dir.path().to_path_buf());
         Ok(TempRepo {
             repo: new_repo,
             dir: new_repo_dir,
@@ -136,7 +316,7 @@ impl TempRepo {
         // Convert to an index to do the checkout
         let mut index = git2::Index::new().context("Creating in-memory index")?;
         index
-            .read_tree(&tree)
+            .read_tree(tree)
             .with_context(|| format!("reading tree {} into index", tree.id()))?;
 
         let new_id = index
@@ -151,14 +331,222 @@ impl TempRepo {
     ///
     /// If the underlying path has non-unicode characters they are
     /// replaced by `U+FFFD REPLACEMENT CHARACTER`
-    pub fn path(&self) -> Cow<str> {
+    pub fn path(&self) -> Cow<'_, str> {
         self.dir.path().to_string_lossy()
     }
 }
 
+impl Drop for TempRepo {
+    fn drop(&mut self) {
+        LIVE_TEMP_REPOS.lock().unwrap().remove(self.dir.path());
+    }
+}
+
 /// Creates a new temporary repo and copies the specified commit ID into it
-pub fn temp_repo<'src>(source: &'src Repository, commit_id: git2::Oid) -> anyhow::Result<TempRepo> {
-    // Create the reop
+pub fn temp_repo(source: &Repository, commit_id: git2::Oid) -> anyhow::Result<TempRepo> {
+    let mut new_repo = TempRepo::new()?;
+    let how = populate(
+        source,
+        &mut new_repo.repo,
+        commit_id,
+        &submodule_cache(source),
+    )?;
+
+    say!(
+        Verbose,
+        "Created new repo in {} with commit {} read into it{}",
+        new_repo.path(),
+        commit_id,
+        how,
+    );
+    Ok(new_repo)
+}
+
+/// Whether checkouts are kept for reuse once they are no longer in use
+static POOL_OPEN: AtomicBool = AtomicBool::new(false);
+
+/// Trees of which more than one commit is being checked, so that checkouts
+/// of them are worth keeping as they are
+static REUSED_TREES: Mutex<BTreeSet<git2::Oid>> = Mutex::new(BTreeSet::new());
+
+/// Checkouts which are not in use, by what they are for, with their trees
+static IDLE_CHECKOUTS: Mutex<BTreeMap<String, Vec<(git2::Oid, TempRepo)>>> =
+    Mutex::new(BTreeMap::new());
+
+/// Keeps checkouts made by [`pooled_repo`] once they are no longer in use,
+/// so that later commits can reuse them rather than creating and deleting a
+/// repo each
+///
+/// A commit with the same tree as an idle checkout, as after a rebase which
+/// dropped a change, reuses it along with its build output. Checkouts of
+/// `repeated_trees` are kept for such commits rather than cleaned for others.
+///
+/// Checkouts are kept until the returned guard is dropped, which deletes
+/// them.
+pub fn checkout_pool<I: IntoIterator<Item = git2::Oid>>(repeated_trees: I) -> PoolGuard {
+    REUSED_TREES.lock().unwrap().extend(repeated_trees);
+    POOL_OPEN.store(true, Ordering::Relaxed);
+    PoolGuard(())
+}
+
+/// Deletes the checkouts kept for reuse when dropped
+#[must_use]
+pub struct PoolGuard(());
+
+impl Drop for PoolGuard {
+    fn drop(&mut self) {
+        POOL_OPEN.store(false, Ordering::Relaxed);
+        REUSED_TREES.lock().unwrap().clear();
+        // Deleting them can take a while, so not under the lock
+        let idle = std::mem::take(&mut *IDLE_CHECKOUTS.lock().unwrap());
+        drop(idle);
+    }
+}
+
+/// A temporary repo which may have been used for another commit before, and
+/// which goes back to the pool once dropped, if there is one
+pub struct PooledRepo {
+    checkout: Option<TempRepo>,
+    tree_id: git2::Oid,
+    purpose: String,
+}
+
+impl Deref for PooledRepo {
+    type Target = TempRepo;
+
+    fn deref(&self) -> &TempRepo {
+        self.checkout.as_ref().unwrap()
+    }
+}
+
+impl Drop for PooledRepo {
+    fn drop(&mut self) {
+        if POOL_OPEN.load(Ordering::Relaxed) {
+            let repo = self.checkout.take().unwrap();
+            IDLE_CHECKOUTS
+                .lock()
+                .unwrap()
+                .entry(std::mem::take(&mut self.purpose))
+                .or_default()
+                .push((self.tree_id, repo));
+        }
+    }
+}
+
+/// Like [`temp_repo`], but takes an idle checkout made for the same
+/// `purpose` from the pool if there is one
+///
+/// One of the same tree is preferred, and has its HEAD moved to the commit
+/// with its files, and anything built in it, left as they are. Any other is
+/// cleaned, as with `git clean -ffdx`, and the commit checked out in it.
+pub fn pooled_repo(
+    source: &Repository,
+    commit_id: git2::Oid,
+    purpose: &str,
+) -> anyhow::Result<PooledRepo> {
+    let tree_id = source
+        .find_commit(commit_id)
+        .with_context(|| format!("finding commit {}", commit_id))?
+        .tree_id();
+
+    let idle = {
+        let reused_trees = REUSED_TREES.lock().unwrap();
+        let mut idle_checkouts = IDLE_CHECKOUTS.lock().unwrap();
+        idle_checkouts.get_mut(purpose).and_then(|idle| {
+            // Failing one of the same tree, take one whose build output no
+            // other commit can use: rebuilding costs more than a new repo
+            let index = idle
+                .iter()
+                .position(|&(tree, _)| tree == tree_id)
+                .or_else(|| {
+                    idle.iter()
+                        .position(|(tree, _)| !reused_trees.contains(tree))
+                })?;
+            Some(idle.swap_remove(index))
+        })
+    };
+
+    let repo = match idle {
+        Some((old_tree, repo)) if old_tree == tree_id => {
+            if repo.repo.find_commit(commit_id).is_err() {
+                copy_commit(source, &repo.repo, &source.find_commit(commit_id)?)?;
+            }
+            repo.repo.set_head_detached(commit_id)?;
+            say!(
+                Verbose,
+                "Reusing repo in {} for commit {}, which has the same tree {}",
+                repo.path(),
+                commit_id,
+                tree_id
+            );
+            repo
+        }
+        Some((_, mut repo)) => {
+            let how = reset(source, &mut repo, commit_id)
+                .with_context(|| format!("resetting {} to commit {}", repo.path(), commit_id))?;
+            say!(
+                Verbose,
+                "Reset repo in {} to commit {}{}",
+                repo.path(),
+                commit_id,
+                how
+            );
+            repo
+        }
+        None => temp_repo(source, commit_id)?,
+    };
+    Ok(PooledRepo {
+        checkout: Some(repo),
+        tree_id,
+        purpose: purpose.to_owned(),
+    })
+}
+
+/// Cleans a checkout of everything which is not in its commit, and checks
+/// out another commit in it
+fn reset(
+    source: &Repository,
+    repo: &mut TempRepo,
+    commit_id: git2::Oid,
+) -> anyhow::Result<&'static str> {
+    // Submodules may be at other commits, or gone, so are checked out afresh
+    let workdir = repo.dir.path();
+    for submodule in repo.repo.submodules().context("reading .gitmodules")? {
+        let path = workdir.join(submodule.path());
+        if path.exists() {
+            fs::remove_dir_all(&path).with_context(|| format!("removing {}", path.display()))?;
+        }
+    }
+
+    let capture = subprocess::Exec::cmd("git")
+        .arg("-C")
+        .arg(workdir)
+        .args(&["clean", "-ffdxq"])
+        .stdin(subprocess::NullFile)
+        .stdout(subprocess::NullFile)
+        .stderr(subprocess::Redirection::Pipe)
+        .capture()
+        .context("running git clean")?;
+    if !capture.success() {
+        return Err(anyhow::Error::msg(format!(
+            "git clean failed: {}",
+            capture.stderr_str().trim()
+        )));
+    }
+
+    populate(source, &mut repo.repo, commit_id, &submodule_cache(source))
+}
+
+/// Gives a repo the objects of a commit from another one, checks it out over
+/// whatever was checked out before, and does the same for its submodules
+///
+/// Returns a note on how the objects were provided.
+fn populate(
+    source: &Repository,
+    dest: &mut Repository,
+    commit_id: git2::Oid,
+    submodule_cache: &Path,
+) -> anyhow::Result<&'static str> {
     let commit = source
         .find_commit(commit_id)
         .with_context(|| format!("finding commit {}", commit_id))?;
@@ -166,26 +554,436 @@ pub fn temp_repo<'src>(source: &'src Repository, commit_id: git2::Oid) -> anyhow
         .tree()
         .with_context(|| format!("getting tree for {}", commit_id))?;
 
-    let new_repo = TempRepo::new()?;
-    new_repo
-        .copy_tree(&tree, source)
-        .with_context(|| format!("copying commit {}'s tree to {}", commit_id, new_repo.path()))?;
-    copy_commit(source, &new_repo.repo, &commit)?;
-    new_repo.repo.set_head_detached(commit.id())?;
-    new_repo.repo.checkout_head(None)?;
+    let dest_path = dest.path().to_path_buf();
+    let how = if SHARE_OBJECTS.load(Ordering::Relaxed) {
+        share_objects(source, dest)
+            .with_context(|| format!("sharing objects with {}", dest_path.display()))?;
+        // Reopen the repo so that it sees the alternates
+        *dest = Repository::open(&dest_path)
+            .with_context(|| format!("reopening repo {}", dest_path.display()))?;
+        " (sharing objects)"
+    } else if link_objects(source, dest) {
+        " (objects hardlinked)"
+    } else {
+        copy_tree(source, dest, &tree).with_context(|| {
+            format!(
+                "copying commit {}'s tree to {}",
+                commit_id,
+                dest_path.display()
+            )
+        })?;
+        copy_commit(source, dest, &commit)?;
+        ""
+    };
+    dest.set_head_detached(commit.id())?;
+    // Files of a commit checked out before, but not in this one, are removed
+    dest.checkout_head(Some(
+        git2::build::CheckoutBuilder::new()
+            .force()
+            .remove_untracked(true),
+    ))?;
+    verify_checkout(dest, &tree)?;
 
-    println!(
-        "Created new repo in {} with commit {} read into it",
-        new_repo.path(),
-        commit_id
+    if LFS.load(Ordering::Relaxed) {
+        smudge_lfs(source, dest)?;
+    }
+    checkout_submodules(source, dest, submodule_cache)?;
+    Ok(how)
+}
+
+/// Whether to replace Git LFS pointer files in checkouts by their contents
+static LFS: AtomicBool = AtomicBool::new(false);
+
+/// Directory in which to keep LFS objects, if not the default of the source
+/// repo's own LFS storage
+static LFS_CACHE: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Makes every temporary repo created from now on have the contents of its
+/// Git LFS files checked out, rather than pointers to them, keeping the
+/// objects in the given directory or else in the source repo's LFS storage
+pub fn set_lfs(cache: Option<PathBuf>) {
+    LFS.store(true, Ordering::Relaxed);
+    *LFS_CACHE.lock().unwrap() = cache;
+}
+
+/// Replaces the Git LFS pointer files of a fresh checkout by their contents
+///
+/// This needs `git-lfs`, and is only done if the top-level `.gitattributes`
+/// uses it. The checkout's repo is configured to keep LFS objects in the
+/// cache, and to fetch them from where its source does, so that they are
+/// only downloaded once and repos created from it do the same.
+fn smudge_lfs(source: &Repository, dest: &Repository) -> anyhow::Result<()> {
+    let workdir = match dest.workdir() {
+        Some(dir) => dir,
+        None => return Ok(()),
+    };
+    match fs::read_to_string(workdir.join(".gitattributes")) {
+        Ok(attrs) if attrs.contains("filter=lfs") => {}
+        _ => return Ok(()),
+    }
+
+    let src_config = source.config().context("reading config of source repo")?;
+    let storage = match LFS_CACHE.lock().unwrap().clone() {
+        Some(dir) => dir,
+        // Relative paths are relative to the git directory, like git-lfs does
+        None => match src_config.get_path("lfs.storage") {
+            Ok(dir) => common_dir(source).join(dir),
+            Err(_) => common_dir(source).join("lfs"),
+        },
+    };
+    let mut config = dest.config().context("opening config of temporary repo")?;
+    config
+        .set_str("lfs.storage", &storage.to_string_lossy())
+        .context("setting lfs.storage")?;
+    for key in &["lfs.url", "remote.origin.url"] {
+        if let Ok(value) = src_config.get_string(key) {
+            config
+                .set_str(key, &value)
+                .with_context(|| format!("setting {}", key))?;
+        }
+    }
+
+    let capture = subprocess::Exec::cmd("git")
+        .arg("-C")
+        .arg(workdir)
+        .args(&["lfs", "pull"])
+        .stdin(subprocess::NullFile)
+        .stdout(subprocess::NullFile)
+        .stderr(subprocess::Redirection::Pipe)
+        .capture()
+        .context("running git lfs pull")?;
+    if capture.success() {
+        say!(Verbose, "Pulled LFS files into {}", workdir.display());
+        Ok(())
+    } else {
+        Err(anyhow::Error::msg(format!(
+            "git lfs pull in {} failed: {}",
+            workdir.display(),
+            capture.stderr_str().trim()
+        )))
+    }
+}
+
+/// Directory in which to keep a bare clone of every submodule fetched, if
+/// not the default of `check-pr-submodules` in the source repo's git
+/// directory
+static SUBMODULE_CACHE: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Held while fetching into the submodule cache, so that fetches of the same
+/// submodule do not trip over each other
+static SUBMODULE_FETCH: Mutex<()> = Mutex::new(());
+
+/// The directory in which to keep a bare clone of every submodule of a repo
+fn submodule_cache(source: &Repository) -> PathBuf {
+    SUBMODULE_CACHE
+        .lock()
+        .unwrap()
+        .clone()
+        .unwrap_or_else(|| common_dir(source).join("check-pr-submodules"))
+}
+
+/// Sets the directory in which to keep a bare clone of every submodule which
+/// has to be fetched to check out a temporary repo
+pub fn set_submodule_cache(dir: PathBuf) {
+    *SUBMODULE_CACHE.lock().unwrap() = Some(dir);
+}
+
+/// Checks out every submodule of a freshly checked out repo, recursively
+fn checkout_submodules(
+    source: &Repository,
+    dest: &Repository,
+    submodule_cache: &Path,
+) -> anyhow::Result<()> {
+    let workdir = match dest.workdir() {
+        Some(dir) if dir.join(".gitmodules").exists() => dir,
+        _ => return Ok(()),
+    };
+    let config = source.config().context("reading config of source repo")?;
+    for submodule in dest.submodules().context("reading .gitmodules")? {
+        let name = submodule.name().unwrap_or("(non-UTF8 name)");
+        // Listed in .gitmodules but not in the tree
+        let commit_id = match submodule.head_id() {
+            Some(id) => id,
+            None => continue,
+        };
+        // As with git, the source repo's config overrides .gitmodules
+        let url = match config.get_string(&format!("submodule.{}.url", name)) {
+            Ok(url) => url,
+            Err(_) => submodule
+                .url()
+                .with_context(|| format!("submodule {} has no URL", name))?
+                .to_owned(),
+        };
+
+        let module = module_repo(
+            source,
+            name,
+            submodule.path(),
+            &url,
+            commit_id,
+            submodule_cache,
+        )?;
+        let path = workdir.join(submodule.path());
+        fs::create_dir_all(&path).with_context(|| format!("creating {}", path.display()))?;
+        let mut sub_repo = Repository::init(&path)
+            .with_context(|| format!("initializing submodule repo in {}", path.display()))?;
+        populate(&module, &mut sub_repo, commit_id, submodule_cache)
+            .with_context(|| format!("checking out submodule {} at {}", name, commit_id))?;
+        say!(
+            Verbose,
+            "Checked out submodule {} at {} in {}",
+            name,
+            commit_id,
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Finds a repo with the given commit of a submodule
+///
+/// This is the source repo's own clone of the submodule, either checked out
+/// in its working tree or absorbed into its git directory, if it has one
+/// with the commit. Otherwise it is a bare clone in the submodule cache,
+/// which is fetched from the submodule's URL if it lacks the commit.
+fn module_repo(
+    source: &Repository,
+    name: &str,
+    path: &Path,
+    url: &str,
+    commit_id: git2::Oid,
+    submodule_cache: &Path,
+) -> anyhow::Result<Repository> {
+    let mut own = vec![common_dir(source).join("modules").join(name)];
+    if let Some(workdir) = source.workdir() {
+        own.push(workdir.join(path));
+    }
+    for dir in own {
+        if let Ok(repo) = Repository::open(dir) {
+            if repo.find_commit(commit_id).is_ok() {
+                return Ok(repo);
+            }
+        }
+    }
+    if url.starts_with("./") || url.starts_with("../") {
+        return Err(anyhow::Error::msg(format!(
+            "submodule {} has relative URL {}; set submodule.{}.url in the repo's config \
+             to say where to fetch it from",
+            name, url, name
+        )));
+    }
+
+    let dir_name: String = url
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' => c,
+            _ => '_',
+        })
+        .collect();
+    let path = submodule_cache.join(dir_name);
+
+    let _guard = SUBMODULE_FETCH.lock().unwrap();
+    let has_commit = |repo: &Repository| repo.find_commit(commit_id).is_ok();
+    match Repository::open_bare(&path) {
+        Ok(repo) if has_commit(&repo) => return Ok(repo),
+        Ok(_) => {}
+        Err(_) => {
+            Repository::init_bare(&path)
+                .with_context(|| format!("creating submodule cache {}", path.display()))?;
+        }
+    }
+
+    say!(
+        Verbose,
+        "Fetching commit {} of submodule {} from {}",
+        commit_id,
+        name,
+        url
     );
-    Ok(new_repo)
+    // Not every server lets commits be fetched by ID, so fall back to
+    // fetching every branch and tag
+    if fetch(&path, url, &[&commit_id.to_string()]).is_err() {
+        fetch(
+            &path,
+            url,
+            &["+refs/heads/*:refs/heads/*", "+refs/tags/*:refs/tags/*"],
+        )?;
+    }
+    let repo = Repository::open_bare(&path)
+        .with_context(|| format!("opening submodule cache {}", path.display()))?;
+    if !has_commit(&repo) {
+        return Err(anyhow::Error::msg(format!(
+            "commit {} of submodule {} is not in {}",
+            commit_id, name, url
+        )));
+    }
+    Ok(repo)
+}
+
+/// Fetches refs from a URL into a bare repo with the git command line, which
+/// unlike `git2` here supports every transport
+fn fetch(path: &Path, url: &str, refspecs: &[&str]) -> anyhow::Result<()> {
+    let capture = subprocess::Exec::cmd("git")
+        .arg("--git-dir")
+        .arg(path)
+        .arg("fetch")
+        .arg("--quiet")
+        .arg(url)
+        .args(refspecs)
+        .stdin(subprocess::NullFile)
+        .stdout(subprocess::NullFile)
+        .stderr(subprocess::Redirection::Pipe)
+        .capture()
+        .with_context(|| format!("running git fetch from {}", url))?;
+    if capture.success() {
+        Ok(())
+    } else {
+        Err(anyhow::Error::msg(format!(
+            "git fetch from {} failed: {}",
+            url,
+            capture.stderr_str().trim()
+        )))
+    }
+}
+
+/// Checks that a fresh checkout matches the tree it is of, so that a file
+/// which was not written, lost its executable bit or was written in place of
+/// a symlink is caught before any job runs on it
+///
+/// Submodules, which are checked out separately, are not compared.
+fn verify_checkout(repo: &Repository, tree: &Tree) -> anyhow::Result<()> {
+    let mut opts = git2::DiffOptions::new();
+    opts.ignore_submodules(true).include_typechange(true);
+    let diff = repo
+        .diff_tree_to_workdir(Some(tree), Some(&mut opts))
+        .with_context(|| format!("comparing checkout of tree {} to it", tree.id()))?;
+    let paths: Vec<String> = diff
+        .deltas()
+        .map(|delta| {
+            let file = delta.new_file().path().or_else(|| delta.old_file().path());
+            file.map_or_else(|| "?".into(), |path| path.display().to_string())
+        })
+        .collect();
+    if paths.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow::Error::msg(format!(
+            "checkout of tree {} in {} does not match it: {}",
+            tree.id(),
+            repo_dir(repo).display(),
+            paths.join(", ")
+        )))
+    }
+}
+
+/// Whether temporary repos borrow their objects from their source through
+/// alternates, rather than having their own
+static SHARE_OBJECTS: AtomicBool = AtomicBool::new(false);
+
+/// Makes every temporary repo created from now on borrow its objects from
+/// the repo it is created from, rather than linking or copying them
+///
+/// Jobs which cannot see the source repo, such as those in containers, will
+/// then be unable to read any objects of their checkout's repo.
+pub fn set_share_objects(share: bool) {
+    SHARE_OBJECTS.store(share, Ordering::Relaxed);
+}
+
+/// The git directory of a repo, which for a worktree is that of the main
+/// repo
+fn common_dir(repo: &Repository) -> PathBuf {
+    match fs::read_to_string(repo.path().join("commondir")) {
+        Ok(dir) => repo.path().join(dir.trim()),
+        Err(_) => repo.path().to_path_buf(),
+    }
+}
+
+/// The object directory of a repo
+fn objects_dir(repo: &Repository) -> PathBuf {
+    common_dir(repo).join("objects")
+}
+
+/// Points one repo's object database at another's, read-only, through
+/// alternates
+///
+/// Any alternates of the source are carried over too, so that the new repo
+/// still finds every object if the source is itself a temporary repo which
+/// is deleted first.
+fn share_objects(source: &Repository, dest: &Repository) -> anyhow::Result<()> {
+    let src_objects = objects_dir(source);
+    let src_objects = src_objects
+        .canonicalize()
+        .with_context(|| format!("finding object directory {}", src_objects.display()))?;
+
+    let mut alternates = format!("{}\n", src_objects.display());
+    if let Ok(existing) = fs::read_to_string(src_objects.join("info").join("alternates")) {
+        for line in existing
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        {
+            // Relative alternates are relative to the object directory
+            alternates.push_str(&format!("{}\n", src_objects.join(line).display()));
+        }
+    }
+
+    let info_dir = dest.path().join("objects").join("info");
+    fs::create_dir_all(&info_dir).with_context(|| format!("creating {}", info_dir.display()))?;
+    fs::write(info_dir.join("alternates"), alternates)
+        .with_context(|| format!("writing alternates in {}", info_dir.display()))
+}
+
+/// Hardlinks every object file of one repo into another
+///
+/// Objects are never modified in place, so the two repos can safely share
+/// them. This is much faster than copying a tree through the ODB, but only
+/// works when both repos are on the same filesystem, and not when the source
+/// borrows objects from elsewhere through alternates. Returns whether it
+/// succeeded; if not, whatever was linked is harmless and the caller should
+/// copy the objects it needs instead.
+fn link_objects(source: &Repository, dest: &Repository) -> bool {
+    let src_objects = objects_dir(source);
+    if !src_objects.is_dir() || src_objects.join("info").join("alternates").exists() {
+        return false;
+    }
+
+    match link_dir(&src_objects, &dest.path().join("objects")) {
+        Ok(()) => true,
+        Err(e) => {
+            say!(
+                Verbose,
+                "Could not link objects of {} (will copy instead): {}",
+                source.path().display(),
+                e
+            );
+            false
+        }
+    }
+}
+
+/// Recursively hardlinks the files of one object directory into another,
+/// skipping the `info` directory which describes the repo rather than
+/// holding objects
+fn link_dir(src: &Path, dest: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(dest)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let dest_path = dest.join(&name);
+        if entry.file_type()?.is_dir() {
+            if name != "info" {
+                link_dir(&entry.path(), &dest_path)?;
+            }
+        } else if !dest_path.exists() {
+            fs::hard_link(entry.path(), dest_path)?;
+        }
+    }
+    Ok(())
 }
 
 /// Copy a tree from one repo into another
-fn copy_tree<'src, 'dst>(
+fn copy_tree<'src>(
     source: &'src Repository,
-    dest: &'dst Repository,
+    dest: &Repository,
     tree: &Tree<'src>,
 ) -> anyhow::Result<()> {
     let mut abort_err = Ok(());
@@ -193,6 +991,13 @@ fn copy_tree<'src, 'dst>(
     let dst_odb = dest.odb().context("getting odb for dest repo")?;
 
     tree.walk(git2::TreeWalkMode::PreOrder, |_, entry| {
+        // Submodules' commits live in their own repos, and are checked out
+        // separately. Every other entry, whatever its mode, is an object of
+        // ours; modes are recorded in the trees, so executable files and
+        // symlinks come out right on checkout.
+        if entry.filemode() == i32::from(git2::FileMode::Commit) {
+            return git2::TreeWalkResult::Ok;
+        }
         let obj = match src_odb.read(entry.id()) {
             Ok(obj) => obj,
             Err(e) => {
@@ -229,9 +1034,9 @@ fn copy_tree<'src, 'dst>(
 ///
 /// Does not copy the tree or parents or anything else. You will get an
 /// inconsistent repo if you are not very careful with this
-fn copy_commit<'src, 'dst>(
+fn copy_commit<'src>(
     source: &'src Repository,
-    dest: &'dst Repository,
+    dest: &Repository,
     commit: &git2::Commit<'src>,
 ) -> anyhow::Result<()> {
     let src_odb = source.odb().context("getting odb for source repo")?;
@@ -247,3 +1052,217 @@ fn copy_commit<'src, 'dst>(
 
     Ok(())
 }
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn add_trailer() {
+        let trailers = |msg: &str| -> Vec<(String, String)> {
+            git2::message_trailers_strs(msg)
+                .unwrap()
+                .iter()
+                .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                .collect()
+        };
+        let signed = super::add_trailer(
+            "Fix it\n\nSigned-off-by: A <a@b>\n",
+            "Cherry-picked-from",
+            "abc",
+        );
+        assert_eq!(
+            signed,
+            "Fix it\n\nSigned-off-by: A <a@b>\nCherry-picked-from: abc\n"
+        );
+        assert_eq!(trailers(&signed).len(), 2);
+        let plain = super::add_trailer("Fix it\n\nBecause.\n", "Cherry-picked-from", "abc");
+        assert_eq!(plain, "Fix it\n\nBecause.\n\nCherry-picked-from: abc\n");
+        assert_eq!(
+            trailers(&plain),
+            vec![("Cherry-picked-from".to_owned(), "abc".to_owned())]
+        );
+    }
+
+    #[test]
+    fn modes() {
+        let source_dir = tempfile::tempdir().unwrap();
+        let source = Repository::init(source_dir.path()).unwrap();
+        let blob = |data: &[u8]| source.blob(data).unwrap();
+        let mut builder = source.treebuilder(None).unwrap();
+        builder
+            .insert("autogen.sh", blob(b"#!/bin/sh\n"), 0o100755)
+            .unwrap();
+        builder
+            .insert("target.txt", blob(b"target\n"), 0o100644)
+            .unwrap();
+        builder
+            .insert("link", blob(b"target.txt"), 0o120000)
+            .unwrap();
+        // A submodule whose commit is not in this repo
+        builder
+            .insert(
+                "vendor",
+                git2::Oid::from_str(&"1".repeat(40)).unwrap(),
+                0o160000,
+            )
+            .unwrap();
+        let tree = source.find_tree(builder.write().unwrap()).unwrap();
+        let sig = git2::Signature::now("a", "a@b").unwrap();
+        let commit = source
+            .commit(None, &sig, &sig, "modes", &tree, &[])
+            .unwrap();
+
+        let check = |dir: &Path| {
+            let mode = fs::metadata(dir.join("autogen.sh"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o111, 0o111);
+            assert_eq!(
+                fs::read_link(dir.join("link")).unwrap(),
+                Path::new("target.txt")
+            );
+            assert!(dir.join("vendor").is_dir());
+        };
+
+        // Copying objects one by one
+        let copied = TempRepo::new().unwrap();
+        copy_tree(&source, &copied.repo, &tree).unwrap();
+        copy_commit(&source, &copied.repo, &source.find_commit(commit).unwrap()).unwrap();
+        copied.repo.set_head_detached(commit).unwrap();
+        copied.repo.checkout_head(None).unwrap();
+        verify_checkout(&copied.repo, &tree).unwrap();
+        check(copied.dir.path());
+
+        // Linking them
+        let linked = temp_repo(&source, commit).unwrap();
+        check(linked.dir.path());
+
+        // A checkout which differs from its tree is caught
+        let script = linked.dir.path().join("autogen.sh");
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o644)).unwrap();
+        let err = verify_checkout(&linked.repo, &tree).unwrap_err();
+        assert!(err.to_string().ends_with("does not match it: autogen.sh"));
+    }
+
+    #[test]
+    fn reuse() {
+        let source_dir = tempfile::tempdir().unwrap();
+        let source = Repository::init(source_dir.path()).unwrap();
+        let tree_id = source.treebuilder(None).unwrap().write().unwrap();
+        let tree = source.find_tree(tree_id).unwrap();
+        let sig = git2::Signature::now("a", "a@b").unwrap();
+        let first = source.commit(None, &sig, &sig, "1", &tree, &[]).unwrap();
+        let second = source.commit(None, &sig, &sig, "2", &tree, &[]).unwrap();
+
+        let guard = checkout_pool(vec![tree_id]);
+        let repo = pooled_repo(&source, first, "test").unwrap();
+        let path = repo.dir.path().to_path_buf();
+        drop(repo);
+        // Another purpose gets a checkout of its own
+        let other = pooled_repo(&source, second, "other").unwrap();
+        assert_ne!(other.dir.path(), path);
+        let repo = pooled_repo(&source, second, "test").unwrap();
+        assert_eq!(repo.dir.path(), path);
+        assert_eq!(repo.repo.head().unwrap().target(), Some(second));
+        drop(repo);
+
+        // Other trees do not take that one, but are reset for each other
+        let commit_with = |name: &str| {
+            let mut builder = source.treebuilder(None).unwrap();
+            builder
+                .insert(name, source.blob(b"x").unwrap(), 0o100644)
+                .unwrap();
+            let tree = source.find_tree(builder.write().unwrap()).unwrap();
+            source.commit(None, &sig, &sig, name, &tree, &[]).unwrap()
+        };
+        let repo = pooled_repo(&source, commit_with("a"), "test").unwrap();
+        let reset_path = repo.dir.path().to_path_buf();
+        assert_ne!(reset_path, path);
+        fs::write(reset_path.join("junk"), "junk").unwrap();
+        drop(repo);
+        let repo = pooled_repo(&source, commit_with("b"), "test").unwrap();
+        assert_eq!(repo.dir.path(), reset_path);
+        assert!(reset_path.join("b").exists());
+        assert!(!reset_path.join("a").exists());
+        assert!(!reset_path.join("junk").exists());
+
+        drop(repo);
+        drop(other);
+        drop(guard);
+        assert!(!path.exists());
+        assert!(!reset_path.exists());
+    }
+
+    #[test]
+    fn default_branch_lookup() {
+        let branch = |name: &str, local: &str| DefaultBranch {
+            name: name.to_owned(),
+            local: local.to_owned(),
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        repo.config()
+            .unwrap()
+            .set_str("init.defaultBranch", "trunk")
+            .unwrap();
+        // Nothing to resolve to, and no guessing
+        let err = default_branch(&repo, "origin").unwrap_err();
+        assert!(err.to_string().contains("no trunk or master branch"));
+
+        let sig = git2::Signature::now("a", "a@b").unwrap();
+        let tree_id = repo.treebuilder(None).unwrap().write().unwrap();
+        let tree = repo.find_tree(tree_id).unwrap();
+        let id = repo
+            .commit(None, &sig, &sig, "initial", &tree, &[])
+            .unwrap();
+        repo.reference("refs/heads/master", id, false, "").unwrap();
+        assert_eq!(
+            default_branch(&repo, "origin").unwrap(),
+            branch("master", "master")
+        );
+        repo.reference("refs/remotes/origin/trunk", id, false, "")
+            .unwrap();
+        assert_eq!(
+            default_branch(&repo, "origin").unwrap(),
+            branch("trunk", "origin/trunk")
+        );
+        // A remote HEAD pointing to a branch which was never fetched is ignored
+        repo.reference_symbolic(
+            "refs/remotes/origin/HEAD",
+            "refs/remotes/origin/main",
+            false,
+            "",
+        )
+        .unwrap();
+        assert_eq!(
+            default_branch(&repo, "origin").unwrap(),
+            branch("trunk", "origin/trunk")
+        );
+        repo.reference("refs/remotes/origin/main", id, false, "")
+            .unwrap();
+        assert_eq!(
+            default_branch(&repo, "origin").unwrap(),
+            branch("main", "origin/main")
+        );
+        assert_eq!(
+            default_branch(&repo, "upstream").unwrap(),
+            branch("master", "master")
+        );
+
+        let bare_dir = tempfile::tempdir().unwrap();
+        let bare = Repository::init_bare(bare_dir.path()).unwrap();
+        bare.set_head("refs/heads/develop").unwrap();
+        assert!(default_branch(&bare, "origin").is_err());
+        let tree_id = bare.treebuilder(None).unwrap().write().unwrap();
+        let tree = bare.find_tree(tree_id).unwrap();
+        bare.commit(Some("HEAD"), &sig, &sig, "initial", &tree, &[])
+            .unwrap();
+        assert_eq!(
+            default_branch(&bare, "origin").unwrap(),
+            branch("develop", "develop")
+        );
+    }
+}
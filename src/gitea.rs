@@ -0,0 +1,289 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+//! Gitea and Forgejo integration
+//!
+//! Configured by a `[gitea]` section of the settings, e.g.
+//!
+//! ```toml
+//! [gitea]
+//! repo = "rust-bitcoin/rust-bitcoin"
+//! api-url = "https://codeberg.org/api/v1"
+//! statuses = true
+//! comment = true
+//! ```
+//!
+//! Forgejo is a fork of Gitea with the same API, so is configured the same
+//! way.
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::env;
+
+use crate::forge::{Forge, PrInfo};
+use crate::http::Request;
+use crate::output::{FinishedJob, FormatDuration, Outcome, Summary};
+use crate::report::{self, COMMENT_MARKER};
+
+/// Length at which we cut comments short; Gitea's own limit is much higher,
+/// but such comments are already too long to read
+const MAX_COMMENT: usize = 65_000;
+
+fn default_curl() -> String {
+    "curl".to_owned()
+}
+
+/// How to talk to a Gitea or Forgejo instance about a repository
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Gitea {
+    /// The repository, as `owner/name`
+    pub repo: String,
+    /// Base URL of the API, e.g. `https://codeberg.org/api/v1`
+    api_url: String,
+    /// Token to authenticate with; if not given, `GITEA_TOKEN` is used
+    #[serde(default, skip_serializing)]
+    token: Option<String>,
+    /// Command to make HTTP requests with, which must accept curl's options
+    #[serde(default = "default_curl")]
+    curl: String,
+    /// Whether to report results as commit statuses
+    #[serde(default)]
+    pub statuses: bool,
+    /// Whether to post the summary table as a comment on the PR
+    #[serde(default)]
+    pub comment: bool,
+}
+
+impl Gitea {
+    /// The token to authenticate with
+    fn token(&self) -> anyhow::Result<String> {
+        match self.token {
+            Some(ref token) => Ok(token.clone()),
+            None => env::var("GITEA_TOKEN").map_err(|_| {
+                anyhow::Error::msg("no Gitea token: set token in [gitea], or GITEA_TOKEN")
+            }),
+        }
+    }
+
+    /// Makes a request to the API about the repository, returning the
+    /// decoded response
+    fn request(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<&serde_json::Value>,
+    ) -> anyhow::Result<serde_json::Value> {
+        let url = format!(
+            "{}/repos/{}{}",
+            self.api_url.trim_end_matches('/'),
+            self.repo,
+            path
+        );
+        let mut request = Request::new(&self.curl, method, url)
+            .header("Accept: application/json")
+            .header("User-Agent: rsgit")
+            .secret_header(format!("Authorization: token {}", self.token()?));
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+        let response = request.send()?;
+        if response.trim().is_empty() {
+            return Ok(serde_json::Value::Null);
+        }
+        serde_json::from_str(&response)
+            .with_context(|| format!("decoding Gitea's response to {} {}", method, path))
+    }
+
+    /// Finds the ID of the comment holding our results on a PR, if any
+    fn find_comment(&self, pr: usize) -> anyhow::Result<Option<u64>> {
+        // Gitea returns every comment on an issue at once
+        let comments = self.request("GET", &format!("/issues/{}/comments", pr), None)?;
+        Ok(comments
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|comment| {
+                comment["body"]
+                    .as_str()
+                    .is_some_and(|body| body.starts_with(COMMENT_MARKER))
+            })
+            .and_then(|comment| comment["id"].as_u64()))
+    }
+}
+
+impl Forge for Gitea {
+    fn name(&self) -> &'static str {
+        "Gitea"
+    }
+
+    fn repo(&self) -> &str {
+        &self.repo
+    }
+
+    fn open_prs(&self) -> anyhow::Result<Vec<usize>> {
+        let mut ret = vec![];
+        let mut page = 1;
+        loop {
+            let pulls = self.request(
+                "GET",
+                &format!("/pulls?state=open&limit=50&page={}", page),
+                None,
+            )?;
+            match pulls.as_array() {
+                Some(pulls) if !pulls.is_empty() => ret.extend(
+                    pulls
+                        .iter()
+                        .filter_map(|pull| pull["number"].as_u64())
+                        .map(|n| n as usize),
+                ),
+                _ => return Ok(ret),
+            }
+            page += 1;
+        }
+    }
+
+    fn pr_base(&self, pr: usize) -> anyhow::Result<String> {
+        let pull = self.request("GET", &format!("/pulls/{}", pr), None)?;
+        pull["base"]["ref"]
+            .as_str()
+            .map(str::to_owned)
+            .with_context(|| format!("Gitea did not give a base branch for PR #{}", pr))
+    }
+
+    fn pr_info(&self, pr: usize) -> anyhow::Result<PrInfo> {
+        let pull = self.request("GET", &format!("/pulls/{}", pr), None)?;
+        PrInfo::from_json(&pull, "user")
+            .with_context(|| format!("Gitea did not describe PR #{}", pr))
+    }
+
+    fn wants_statuses(&self) -> bool {
+        self.statuses
+    }
+
+    fn wants_comment(&self) -> bool {
+        self.comment
+    }
+
+    fn post_statuses(&self, summary: &Summary, jobs: &[FinishedJob]) -> anyhow::Result<()> {
+        for (commit, row) in summary.rows() {
+            for (column, outcome) in row.into_iter().enumerate() {
+                let outcome = match outcome {
+                    Some(outcome) => outcome,
+                    None => continue,
+                };
+                let jobs: Vec<&FinishedJob> = jobs
+                    .iter()
+                    .filter(|job| {
+                        job.commit == commit && summary.check_of_hash(&job.check) == Some(column)
+                    })
+                    .collect();
+                let check = &summary.checks()[column];
+                let status = match status(check, outcome, &jobs) {
+                    Some(status) => status,
+                    None => continue,
+                };
+                self.request("POST", &format!("/statuses/{}", commit), Some(&status))
+                    .with_context(|| {
+                        format!(
+                            "posting status of check {} on commit {} to Gitea",
+                            check, commit
+                        )
+                    })?;
+            }
+        }
+        Ok(())
+    }
+
+    fn post_comment(
+        &self,
+        pr: usize,
+        summary: &Summary,
+        jobs: &[FinishedJob],
+    ) -> anyhow::Result<()> {
+        let body = json!({ "body": report::comment(summary, jobs, MAX_COMMENT) });
+        match self
+            .find_comment(pr)
+            .with_context(|| format!("looking for earlier comment on PR #{}", pr))?
+        {
+            Some(id) => self
+                .request("PATCH", &format!("/issues/comments/{}", id), Some(&body))
+                .with_context(|| format!("updating comment {} on PR #{}", id, pr))?,
+            None => self
+                .request("POST", &format!("/issues/{}/comments", pr), Some(&body))
+                .with_context(|| format!("commenting on PR #{}", pr))?,
+        };
+        Ok(())
+    }
+}
+
+/// Request to set the status of a check on a commit. Gitea has no status
+/// for skipped checks, so none is posted for them.
+fn status(check: &str, outcome: Outcome, jobs: &[&FinishedJob]) -> Option<serde_json::Value> {
+    let failed = jobs
+        .iter()
+        .filter(|job| matches!(job.outcome, Outcome::Fail(..)))
+        .count();
+    let (state, description) = match outcome {
+        Outcome::Pass(time) => ("success", format!("Passed in {}", FormatDuration(time))),
+        Outcome::Fail(..) => (
+            "failure",
+            format!("{} of {} jobs failed", failed, jobs.len()),
+        ),
+        Outcome::Cached => ("success", "Passed in an earlier run".to_owned()),
+        Outcome::Skipped => return None,
+    };
+    Some(json!({
+        "state": state,
+        "context": format!("check-pr: {}", check),
+        "description": description,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn statuses() {
+        let gitea: Gitea = toml::from_str(
+            "repo = \"a/b\"\napi-url = \"https://codeberg.org/api/v1\"\ntoken = \"secret\"",
+        )
+        .unwrap();
+        assert!(!gitea.statuses);
+        assert_eq!(gitea.pr_ref(), "refs/pull/*/head");
+        assert!(!serde_json::to_string(&gitea).unwrap().contains("secret"));
+        assert!(toml::from_str::<Gitea>("repo = \"a/b\"").is_err());
+
+        let job = |outcome| FinishedJob::example("stable cargo build", outcome);
+        let time = Duration::from_secs(2);
+        let jobs = [job(Outcome::Pass(time)), job(Outcome::Fail(time))];
+        let jobs: Vec<&FinishedJob> = jobs.iter().collect();
+        assert_eq!(
+            status("{ rust }", Outcome::Fail(time), &jobs),
+            Some(json!({
+                "state": "failure",
+                "context": "check-pr: { rust }",
+                "description": "1 of 2 jobs failed",
+            })),
+        );
+        assert_eq!(status("{ rust }", Outcome::Skipped, &[]), None);
+    }
+}
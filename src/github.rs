@@ -0,0 +1,487 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+//! GitHub integration
+//!
+//! Configured by a `[github]` section of the settings, e.g.
+//!
+//! ```toml
+//! [github]
+//! repo = "rust-bitcoin/rust-bitcoin"
+//! checks = true
+//! comment = true
+//! ```
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeSet;
+use std::env;
+use std::fmt::Write as _;
+
+use crate::cargo::Annotation;
+use crate::forge::{Forge, PrInfo};
+use crate::http::Request;
+use crate::output::{FinishedJob, FormatDuration, Outcome, Summary};
+use crate::report::{self, code_block, COMMENT_MARKER};
+
+/// GitHub limits comments to 64k characters
+const MAX_COMMENT: usize = 65_000;
+
+/// GitHub accepts at most this many annotations in each request
+const ANNOTATIONS_PER_REQUEST: usize = 50;
+
+/// How many lines of each failed job's output to include in a check run
+const FAILURE_LINES: usize = 50;
+
+/// Length beyond which we stop adding failures to the text of a check run,
+/// which GitHub limits to 64k characters
+const MAX_TEXT: usize = 60_000;
+
+fn default_api_url() -> String {
+    "https://api.github.com".to_owned()
+}
+
+fn default_curl() -> String {
+    "curl".to_owned()
+}
+
+/// How to talk to GitHub about a repository
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct GitHub {
+    /// The repository, as `owner/name`
+    pub repo: String,
+    /// Token to authenticate with; if not given, `GITHUB_TOKEN` is used
+    #[serde(default, skip_serializing)]
+    token: Option<String>,
+    /// Base URL of the API, which is different for GitHub Enterprise
+    #[serde(default = "default_api_url")]
+    api_url: String,
+    /// Command to make HTTP requests with, which must accept curl's options
+    #[serde(default = "default_curl")]
+    curl: String,
+    /// Whether to report results as check runs
+    #[serde(default)]
+    pub checks: bool,
+    /// Whether to post the summary table as a comment on the PR
+    #[serde(default)]
+    pub comment: bool,
+}
+
+impl GitHub {
+    /// The token to authenticate with
+    fn token(&self) -> anyhow::Result<String> {
+        match self.token {
+            Some(ref token) => Ok(token.clone()),
+            None => env::var("GITHUB_TOKEN").map_err(|_| {
+                anyhow::Error::msg("no GitHub token: set token in [github], or GITHUB_TOKEN")
+            }),
+        }
+    }
+
+    /// Makes a request to the API, returning the decoded response
+    fn request(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<&serde_json::Value>,
+    ) -> anyhow::Result<serde_json::Value> {
+        let url = format!("{}{}", self.api_url.trim_end_matches('/'), path);
+        let mut request = Request::new(&self.curl, method, url)
+            .header("Accept: application/vnd.github+json")
+            .header("X-GitHub-Api-Version: 2022-11-28")
+            .header("User-Agent: rsgit")
+            .secret_header(format!("Authorization: Bearer {}", self.token()?));
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+        let response = request.send()?;
+        if response.trim().is_empty() {
+            return Ok(serde_json::Value::Null);
+        }
+        serde_json::from_str(&response)
+            .with_context(|| format!("decoding GitHub's response to {} {}", method, path))
+    }
+
+    /// Reports the outcome of every check on every commit as a check run,
+    /// with the compiler's warnings and errors as annotations
+    pub fn post_check_runs(&self, summary: &Summary, jobs: &[FinishedJob]) -> anyhow::Result<()> {
+        for (commit, row) in summary.rows() {
+            for (column, outcome) in row.into_iter().enumerate() {
+                let outcome = match outcome {
+                    Some(outcome) => outcome,
+                    None => continue,
+                };
+                let jobs: Vec<&FinishedJob> = jobs
+                    .iter()
+                    .filter(|job| {
+                        job.commit == commit && summary.check_of_hash(&job.check) == Some(column)
+                    })
+                    .collect();
+                let run = CheckRun::new(&summary.checks()[column], commit, outcome, &jobs);
+                self.post_check_run(&run).with_context(|| {
+                    format!(
+                        "posting check run for check {} on commit {}",
+                        column + 1,
+                        commit
+                    )
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates a check run, adding its annotations in batches if there are
+    /// too many to create it with
+    fn post_check_run(&self, run: &CheckRun) -> anyhow::Result<()> {
+        let mut batches = run.annotations.chunks(ANNOTATIONS_PER_REQUEST);
+        let first = batches.next().unwrap_or(&[]);
+        let created = self.request(
+            "POST",
+            &format!("/repos/{}/check-runs", self.repo),
+            Some(&run.to_json(first)),
+        )?;
+        let id = created["id"]
+            .as_u64()
+            .ok_or_else(|| anyhow::Error::msg("GitHub did not give the ID of the check run"))?;
+        for batch in batches {
+            self.request(
+                "PATCH",
+                &format!("/repos/{}/check-runs/{}", self.repo, id),
+                Some(&json!({ "output": run.output(batch) })),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Finds the ID of the comment holding our results on a PR, if any
+    fn find_comment(&self, pr: usize) -> anyhow::Result<Option<u64>> {
+        let mut page = 1;
+        loop {
+            let comments = self.request(
+                "GET",
+                &format!(
+                    "/repos/{}/issues/{}/comments?per_page=100&page={}",
+                    self.repo, pr, page
+                ),
+                None,
+            )?;
+            let comments = match comments.as_array() {
+                Some(comments) if !comments.is_empty() => comments,
+                _ => return Ok(None),
+            };
+            let ours = comments.iter().find(|comment| {
+                comment["body"]
+                    .as_str()
+                    .is_some_and(|body| body.starts_with(COMMENT_MARKER))
+            });
+            if let Some(comment) = ours {
+                return Ok(comment["id"].as_u64());
+            }
+            page += 1;
+        }
+    }
+}
+
+impl Forge for GitHub {
+    fn name(&self) -> &'static str {
+        "GitHub"
+    }
+
+    fn repo(&self) -> &str {
+        &self.repo
+    }
+
+    fn open_prs(&self) -> anyhow::Result<Vec<usize>> {
+        let mut ret = vec![];
+        let mut page = 1;
+        loop {
+            let pulls = self.request(
+                "GET",
+                &format!(
+                    "/repos/{}/pulls?state=open&per_page=100&page={}",
+                    self.repo, page
+                ),
+                None,
+            )?;
+            match pulls.as_array() {
+                Some(pulls) if !pulls.is_empty() => ret.extend(
+                    pulls
+                        .iter()
+                        .filter_map(|pull| pull["number"].as_u64())
+                        .map(|n| n as usize),
+                ),
+                _ => return Ok(ret),
+            }
+            page += 1;
+        }
+    }
+
+    fn pr_base(&self, pr: usize) -> anyhow::Result<String> {
+        let pull = self.request("GET", &format!("/repos/{}/pulls/{}", self.repo, pr), None)?;
+        pull["base"]["ref"]
+            .as_str()
+            .map(str::to_owned)
+            .with_context(|| format!("GitHub did not give a base branch for PR #{}", pr))
+    }
+
+    fn pr_info(&self, pr: usize) -> anyhow::Result<PrInfo> {
+        let pull = self.request("GET", &format!("/repos/{}/pulls/{}", self.repo, pr), None)?;
+        PrInfo::from_json(&pull, "user")
+            .with_context(|| format!("GitHub did not describe PR #{}", pr))
+    }
+
+    fn wants_statuses(&self) -> bool {
+        self.checks
+    }
+
+    fn wants_comment(&self) -> bool {
+        self.comment
+    }
+
+    fn post_statuses(&self, summary: &Summary, jobs: &[FinishedJob]) -> anyhow::Result<()> {
+        self.post_check_runs(summary, jobs)
+    }
+
+    fn post_comment(
+        &self,
+        pr: usize,
+        summary: &Summary,
+        jobs: &[FinishedJob],
+    ) -> anyhow::Result<()> {
+        let body = json!({ "body": comment_body(summary, jobs) });
+        match self
+            .find_comment(pr)
+            .with_context(|| format!("looking for earlier comment on PR #{}", pr))?
+        {
+            Some(id) => self
+                .request(
+                    "PATCH",
+                    &format!("/repos/{}/issues/comments/{}", self.repo, id),
+                    Some(&body),
+                )
+                .with_context(|| format!("updating comment {} on PR #{}", id, pr))?,
+            None => self
+                .request(
+                    "POST",
+                    &format!("/repos/{}/issues/{}/comments", self.repo, pr),
+                    Some(&body),
+                )
+                .with_context(|| format!("commenting on PR #{}", pr))?,
+        };
+        Ok(())
+    }
+}
+
+/// The text of a comment describing the results of a run
+fn comment_body(summary: &Summary, jobs: &[FinishedJob]) -> String {
+    report::comment(summary, jobs, MAX_COMMENT)
+}
+
+/// A check run describing the outcome of a check on a commit
+struct CheckRun {
+    name: String,
+    head_sha: String,
+    conclusion: &'static str,
+    title: String,
+    summary: String,
+    /// Output of the failed jobs
+    text: String,
+    /// Distinct annotations of every job, in order
+    annotations: Vec<Annotation>,
+}
+
+impl CheckRun {
+    fn new(check: &str, commit: git2::Oid, outcome: Outcome, jobs: &[&FinishedJob]) -> Self {
+        let failed = jobs
+            .iter()
+            .filter(|job| matches!(job.outcome, Outcome::Fail(..)))
+            .count();
+        let (conclusion, title) = match outcome {
+            Outcome::Pass(time) => ("success", format!("Passed in {}", FormatDuration(time))),
+            Outcome::Fail(..) => (
+                "failure",
+                format!("{} of {} jobs failed", failed, jobs.len()),
+            ),
+            Outcome::Cached => ("success", "Passed in an earlier run".to_owned()),
+            Outcome::Skipped => ("skipped", "Skipped".to_owned()),
+        };
+
+        let mut summary = format!("`{}`\n\n", check);
+        let mut text = String::new();
+        for job in jobs {
+            let _ = writeln!(summary, "- `{}`: {}", job.job, job.outcome);
+            if let Outcome::Fail(..) = job.outcome {
+                if text.len() < MAX_TEXT {
+                    let _ = writeln!(text, "### `{}`\n", job.job);
+                    code_block(&mut text, job.error.as_deref().unwrap_or(""), FAILURE_LINES);
+                    text.push('\n');
+                }
+            }
+        }
+
+        let annotations: BTreeSet<&Annotation> =
+            jobs.iter().flat_map(|job| &job.annotations).collect();
+        CheckRun {
+            name: format!("check-pr: {}", check),
+            head_sha: commit.to_string(),
+            conclusion,
+            title,
+            summary,
+            text,
+            annotations: annotations.into_iter().cloned().collect(),
+        }
+    }
+
+    /// The output of the check run, with the given annotations
+    fn output(&self, annotations: &[Annotation]) -> serde_json::Value {
+        let annotations: Vec<_> = annotations
+            .iter()
+            .map(|ann| {
+                json!({
+                    "path": ann.path,
+                    "start_line": ann.start_line,
+                    "end_line": ann.end_line,
+                    "annotation_level": if ann.level == "error" { "failure" } else { "warning" },
+                    "title": ann.message,
+                    "message": ann.rendered,
+                })
+            })
+            .collect();
+        let mut ret = json!({
+            "title": self.title,
+            "summary": self.summary,
+            "annotations": annotations,
+        });
+        if !self.text.is_empty() {
+            ret["text"] = json!(self.text);
+        }
+        ret
+    }
+
+    /// Request to create the check run, with the given annotations
+    fn to_json(&self, annotations: &[Annotation]) -> serde_json::Value {
+        json!({
+            "name": self.name,
+            "head_sha": self.head_sha,
+            "status": "completed",
+            "conclusion": self.conclusion,
+            "output": self.output(annotations),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn check_run() {
+        let github: GitHub = toml::from_str("repo = \"a/b\"\ntoken = \"secret\"").unwrap();
+        assert_eq!(github.api_url, "https://api.github.com");
+        assert!(!github.checks);
+        assert!(!serde_json::to_string(&github).unwrap().contains("secret"));
+
+        let commit = git2::Oid::from_str("aaaaaaa").unwrap();
+        let ann = |line, level: &str| Annotation {
+            path: "src/lib.rs".to_owned(),
+            start_line: line,
+            end_line: line,
+            level: level.to_owned(),
+            message: "oops".to_owned(),
+            rendered: format!("{}: oops", level),
+        };
+        let job = |job: &str, outcome, annotations| FinishedJob {
+            error: match outcome {
+                Outcome::Fail(..) => Some("error: oops".to_owned()),
+                _ => None,
+            },
+            annotations,
+            ..FinishedJob::example(job, outcome)
+        };
+        let time = Duration::from_secs(2);
+        let jobs = [
+            job(
+                "stable cargo build",
+                Outcome::Pass(time),
+                vec![ann(1, "warning")],
+            ),
+            job(
+                "stable cargo test",
+                Outcome::Fail(time),
+                vec![ann(1, "warning"), ann(3, "error")],
+            ),
+        ];
+        let jobs: Vec<&FinishedJob> = jobs.iter().collect();
+        let run = CheckRun::new("{ rust }", commit, Outcome::Fail(time), &jobs);
+        assert_eq!(run.annotations.len(), 2);
+        let batches: Vec<_> = run.annotations.chunks(1).collect();
+        assert_eq!(
+            run.to_json(batches[0]),
+            json!({
+                "name": "check-pr: { rust }",
+                "head_sha": commit.to_string(),
+                "status": "completed",
+                "conclusion": "failure",
+                "output": {
+                    "title": "1 of 2 jobs failed",
+                    "summary": "`{ rust }`\n\n\
+                                - `stable cargo build`: pass 2.0s\n\
+                                - `stable cargo test`: FAIL 2.0s\n",
+                    "text": "### `stable cargo test`\n\n```\nerror: oops\n```\n\n",
+                    "annotations": [{
+                        "path": "src/lib.rs",
+                        "start_line": 1,
+                        "end_line": 1,
+                        "annotation_level": "warning",
+                        "title": "oops",
+                        "message": "warning: oops",
+                    }],
+                },
+            }),
+        );
+        assert_eq!(
+            run.output(batches[1])["annotations"][0]["annotation_level"],
+            "failure"
+        );
+    }
+
+    #[test]
+    fn comment() {
+        let commit = git2::Oid::from_str("aaaaaaa").unwrap();
+        let mut summary = Summary::default();
+        summary.record(0, commit, "{ rust }", Outcome::Cached);
+        let body = comment_body(&summary, &[]);
+        assert!(body.starts_with(
+            "<!-- check-pr results -->\nResults of `check-pr` up to `aaaaaaa`:\n\n| Commit |"
+        ));
+
+        let error = "x".repeat(100) + "\n";
+        let jobs: Vec<FinishedJob> = (0..1000)
+            .map(|_| FinishedJob {
+                error: Some(error.repeat(10)),
+                ..FinishedJob::example("stable cargo build", Outcome::Fail(Duration::from_secs(1)))
+            })
+            .collect();
+        let body = comment_body(&summary, &jobs);
+        assert!(body.len() < 65_536);
+        assert!(body.ends_with("\n\n[truncated]\n"));
+    }
+}
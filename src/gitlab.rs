@@ -0,0 +1,308 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+//! GitLab integration
+//!
+//! Configured by a `[gitlab]` section of the settings, e.g.
+//!
+//! ```toml
+//! [gitlab]
+//! project = "rust-bitcoin/rust-bitcoin"
+//! statuses = true
+//! comment = true
+//! ```
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::env;
+
+use crate::forge::{Forge, PrInfo};
+use crate::http::{percent_encode, Request};
+use crate::output::{FinishedJob, FormatDuration, Outcome, Summary};
+use crate::report::{self, COMMENT_MARKER};
+
+/// GitLab limits notes to a million characters
+const MAX_COMMENT: usize = 1_000_000;
+
+fn default_api_url() -> String {
+    "https://gitlab.com/api/v4".to_owned()
+}
+
+fn default_curl() -> String {
+    "curl".to_owned()
+}
+
+/// How to talk to GitLab about a project
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct GitLab {
+    /// The project, as `namespace/name`
+    pub project: String,
+    /// Token to authenticate with; if not given, `GITLAB_TOKEN` is used
+    #[serde(default, skip_serializing)]
+    token: Option<String>,
+    /// Base URL of the API, which is different for self-hosted instances
+    #[serde(default = "default_api_url")]
+    api_url: String,
+    /// Command to make HTTP requests with, which must accept curl's options
+    #[serde(default = "default_curl")]
+    curl: String,
+    /// Whether to report results as commit statuses
+    #[serde(default)]
+    pub statuses: bool,
+    /// Whether to post the summary table as a comment on the merge request
+    #[serde(default)]
+    pub comment: bool,
+}
+
+impl GitLab {
+    /// The token to authenticate with
+    fn token(&self) -> anyhow::Result<String> {
+        match self.token {
+            Some(ref token) => Ok(token.clone()),
+            None => env::var("GITLAB_TOKEN").map_err(|_| {
+                anyhow::Error::msg("no GitLab token: set token in [gitlab], or GITLAB_TOKEN")
+            }),
+        }
+    }
+
+    /// Makes a request to the API about the project, returning the decoded
+    /// response
+    fn request(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<&serde_json::Value>,
+    ) -> anyhow::Result<serde_json::Value> {
+        let url = format!(
+            "{}/projects/{}{}",
+            self.api_url.trim_end_matches('/'),
+            percent_encode(&self.project),
+            path
+        );
+        let mut request = Request::new(&self.curl, method, url)
+            .header("User-Agent: rsgit")
+            .secret_header(format!("PRIVATE-TOKEN: {}", self.token()?));
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+        let response = request.send()?;
+        if response.trim().is_empty() {
+            return Ok(serde_json::Value::Null);
+        }
+        serde_json::from_str(&response)
+            .with_context(|| format!("decoding GitLab's response to {} {}", method, path))
+    }
+
+    /// Finds the ID of the note holding our results on a merge request, if
+    /// any
+    fn find_comment(&self, mr: usize) -> anyhow::Result<Option<u64>> {
+        let mut page = 1;
+        loop {
+            let notes = self.request(
+                "GET",
+                &format!("/merge_requests/{}/notes?per_page=100&page={}", mr, page),
+                None,
+            )?;
+            let notes = match notes.as_array() {
+                Some(notes) if !notes.is_empty() => notes,
+                _ => return Ok(None),
+            };
+            let ours = notes.iter().find(|note| {
+                note["body"]
+                    .as_str()
+                    .is_some_and(|body| body.starts_with(COMMENT_MARKER))
+            });
+            if let Some(note) = ours {
+                return Ok(note["id"].as_u64());
+            }
+            page += 1;
+        }
+    }
+}
+
+impl Forge for GitLab {
+    fn name(&self) -> &'static str {
+        "GitLab"
+    }
+
+    fn repo(&self) -> &str {
+        &self.project
+    }
+
+    fn pr_ref(&self) -> &'static str {
+        "refs/merge-requests/*/head"
+    }
+
+    fn open_prs(&self) -> anyhow::Result<Vec<usize>> {
+        let mut ret = vec![];
+        let mut page = 1;
+        loop {
+            let requests = self.request(
+                "GET",
+                &format!("/merge_requests?state=opened&per_page=100&page={}", page),
+                None,
+            )?;
+            match requests.as_array() {
+                Some(requests) if !requests.is_empty() => ret.extend(
+                    requests
+                        .iter()
+                        .filter_map(|request| request["iid"].as_u64())
+                        .map(|n| n as usize),
+                ),
+                _ => return Ok(ret),
+            }
+            page += 1;
+        }
+    }
+
+    fn pr_base(&self, mr: usize) -> anyhow::Result<String> {
+        let merge_request = self.request("GET", &format!("/merge_requests/{}", mr), None)?;
+        merge_request["target_branch"]
+            .as_str()
+            .map(str::to_owned)
+            .with_context(|| format!("GitLab did not give a target branch for MR !{}", mr))
+    }
+
+    fn pr_info(&self, mr: usize) -> anyhow::Result<PrInfo> {
+        let merge_request = self.request("GET", &format!("/merge_requests/{}", mr), None)?;
+        PrInfo::from_json(&merge_request, "author")
+            .with_context(|| format!("GitLab did not describe MR !{}", mr))
+    }
+
+    fn wants_statuses(&self) -> bool {
+        self.statuses
+    }
+
+    fn wants_comment(&self) -> bool {
+        self.comment
+    }
+
+    fn post_statuses(&self, summary: &Summary, jobs: &[FinishedJob]) -> anyhow::Result<()> {
+        for (commit, row) in summary.rows() {
+            for (column, outcome) in row.into_iter().enumerate() {
+                let outcome = match outcome {
+                    Some(outcome) => outcome,
+                    None => continue,
+                };
+                let jobs: Vec<&FinishedJob> = jobs
+                    .iter()
+                    .filter(|job| {
+                        job.commit == commit && summary.check_of_hash(&job.check) == Some(column)
+                    })
+                    .collect();
+                let check = &summary.checks()[column];
+                self.request(
+                    "POST",
+                    &format!("/statuses/{}", commit),
+                    Some(&status(check, outcome, &jobs)),
+                )
+                .with_context(|| {
+                    format!(
+                        "posting status of check {} on commit {} to GitLab",
+                        check, commit
+                    )
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    fn post_comment(
+        &self,
+        mr: usize,
+        summary: &Summary,
+        jobs: &[FinishedJob],
+    ) -> anyhow::Result<()> {
+        let body = json!({ "body": report::comment(summary, jobs, MAX_COMMENT) });
+        match self
+            .find_comment(mr)
+            .with_context(|| format!("looking for earlier comment on MR !{}", mr))?
+        {
+            Some(id) => self
+                .request(
+                    "PUT",
+                    &format!("/merge_requests/{}/notes/{}", mr, id),
+                    Some(&body),
+                )
+                .with_context(|| format!("updating comment {} on MR !{}", id, mr))?,
+            None => self
+                .request(
+                    "POST",
+                    &format!("/merge_requests/{}/notes", mr),
+                    Some(&body),
+                )
+                .with_context(|| format!("commenting on MR !{}", mr))?,
+        };
+        Ok(())
+    }
+}
+
+/// Request to set the status of a check on a commit
+fn status(check: &str, outcome: Outcome, jobs: &[&FinishedJob]) -> serde_json::Value {
+    let failed = jobs
+        .iter()
+        .filter(|job| matches!(job.outcome, Outcome::Fail(..)))
+        .count();
+    let (state, description) = match outcome {
+        Outcome::Pass(time) => ("success", format!("Passed in {}", FormatDuration(time))),
+        Outcome::Fail(..) => (
+            "failed",
+            format!("{} of {} jobs failed", failed, jobs.len()),
+        ),
+        Outcome::Cached => ("success", "Passed in an earlier run".to_owned()),
+        Outcome::Skipped => ("skipped", "Skipped".to_owned()),
+    };
+    json!({
+        "state": state,
+        "name": format!("check-pr: {}", check),
+        "description": description,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn statuses() {
+        let gitlab: GitLab = toml::from_str("project = \"a/b\"\ntoken = \"secret\"").unwrap();
+        assert_eq!(gitlab.api_url, "https://gitlab.com/api/v4");
+        assert!(!gitlab.statuses);
+        assert!(!serde_json::to_string(&gitlab).unwrap().contains("secret"));
+
+        let job = |outcome| FinishedJob::example("stable cargo build", outcome);
+        let time = Duration::from_secs(2);
+        let jobs = [job(Outcome::Pass(time)), job(Outcome::Fail(time))];
+        let jobs: Vec<&FinishedJob> = jobs.iter().collect();
+        assert_eq!(
+            status("{ rust }", Outcome::Fail(time), &jobs),
+            json!({
+                "state": "failed",
+                "name": "check-pr: { rust }",
+                "description": "1 of 2 jobs failed",
+            }),
+        );
+        assert_eq!(
+            status("{ rust }", Outcome::Cached, &jobs[..1])["state"],
+            "success"
+        );
+    }
+}
@@ -0,0 +1,249 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+//! Static HTML pages showing the results of checks
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use crate::notes::NoteEntry;
+use crate::output::{FinishedJob, Outcome};
+use crate::report::xml_escape;
+
+/// Style sheet included in every page
+const STYLE: &str = "
+body { font-family: sans-serif; }
+table { border-collapse: collapse; }
+th, td { border: 1px solid #ccc; padding: 2px 6px; white-space: nowrap; }
+th.job { writing-mode: vertical-rl; transform: rotate(180deg); text-align: left; }
+td.pass { background: #cfc; }
+td.fail { background: #fcc; }
+code { font-size: 90%; }
+";
+
+/// The result of a job on a commit, as shown in a matrix
+#[derive(Clone, Debug, PartialEq)]
+pub struct JobResult {
+    /// `Cached` if all that is known is that the job passed at some point
+    pub outcome: Outcome,
+    /// Number of compiler warnings, if recorded
+    pub warnings: Option<usize>,
+    /// Link to the job's output, if any
+    pub log: Option<String>,
+}
+
+/// A commit, and the results of the jobs run on it
+#[derive(Clone, Debug)]
+pub struct Row {
+    pub commit: git2::Oid,
+    pub summary: String,
+    pub results: BTreeMap<String, JobResult>,
+}
+
+/// Works out the result of each job on a commit, from the commit's notes
+/// and from the jobs recorded in the results database (oldest first)
+///
+/// The latest run of each job in the database is used, except that a job in
+/// the notes has passed, whatever the database says. A job the notes only
+/// record as failing has failed, if the database does not know of it. `log`
+/// gives the link to use for a job's log file, if there is one.
+pub fn commit_results(
+    notes: &[String],
+    jobs: &[FinishedJob],
+    log: impl Fn(&FinishedJob) -> Option<String>,
+) -> BTreeMap<String, JobResult> {
+    let mut ret = BTreeMap::new();
+    for job in jobs {
+        let result = JobResult {
+            outcome: job.outcome,
+            warnings: None,
+            log: log(job),
+        };
+        ret.insert(job.job.clone(), result);
+    }
+    for entry in notes.iter().filter_map(|note| NoteEntry::parse(note)) {
+        if !entry.passed() {
+            ret.entry(entry.job).or_insert(JobResult {
+                outcome: Outcome::Fail(entry.duration.unwrap_or_default()),
+                warnings: entry.warnings,
+                log: None,
+            });
+            continue;
+        }
+        // How long it took is only known from newer notes
+        let passed = entry.duration.map_or(Outcome::Cached, Outcome::Pass);
+        let result = ret.entry(entry.job).or_insert(JobResult {
+            outcome: passed,
+            warnings: None,
+            log: None,
+        });
+        if let Outcome::Fail(..) = result.outcome {
+            result.outcome = passed;
+        }
+        result.warnings = entry.warnings;
+    }
+    ret
+}
+
+/// Wraps the body of a page in the rest of an HTML document
+pub fn page(title: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n\
+         <style>{}</style>\n</head>\n<body>\n<h1>{}</h1>\n{}</body>\n</html>\n",
+        xml_escape(title),
+        STYLE,
+        xml_escape(title),
+        body,
+    )
+}
+
+/// Renders a list of links, given as (text, href) pairs
+pub fn link_list(links: &[(String, String)]) -> String {
+    let mut ret = String::from("<ul>\n");
+    for (text, href) in links {
+        let _ = writeln!(
+            ret,
+            "<li><a href=\"{}\">{}</a></li>",
+            xml_escape(href),
+            xml_escape(text)
+        );
+    }
+    ret.push_str("</ul>\n");
+    ret
+}
+
+/// Renders a table with a row for each commit and a column for each job
+pub fn matrix(rows: &[Row]) -> String {
+    let jobs: BTreeSet<&String> = rows.iter().flat_map(|row| row.results.keys()).collect();
+
+    let mut ret = String::from("<table>\n<tr><th>Commit</th>");
+    for job in &jobs {
+        let _ = write!(ret, "<th class=\"job\">{}</th>", xml_escape(job));
+    }
+    ret.push_str("</tr>\n");
+    for row in rows {
+        let _ = write!(
+            ret,
+            "<tr><td><code>{:.7}</code> {}</td>",
+            row.commit,
+            xml_escape(&row.summary)
+        );
+        for job in &jobs {
+            let result = match row.results.get(*job) {
+                Some(result) => result,
+                None => {
+                    ret.push_str("<td></td>");
+                    continue;
+                }
+            };
+            let (class, mut text) = match result.outcome {
+                Outcome::Pass(time) => ("pass", format!("{}s", time.as_secs())),
+                Outcome::Fail(time) => ("fail", format!("FAIL {}s", time.as_secs())),
+                Outcome::Cached | Outcome::Skipped => ("pass", "pass".to_owned()),
+            };
+            if let Some(n) = result.warnings.filter(|n| *n > 0) {
+                let _ = write!(text, " ({}w)", n);
+            }
+            let text = match result.log {
+                Some(ref href) => format!("<a href=\"{}\">{}</a>", xml_escape(href), text),
+                None => text,
+            };
+            let _ = write!(ret, "<td class=\"{}\">{}</td>", class, text);
+        }
+        ret.push_str("</tr>\n");
+    }
+    ret.push_str("</table>\n");
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notes::{NoteOutcome, SKIPPED_NOTE};
+    use std::time::Duration;
+
+    #[test]
+    fn results() {
+        let commit = git2::Oid::from_str("aaaaaaa").unwrap();
+        let job = |job: &str, outcome| FinishedJob {
+            log: Some(format!("/logs/{}.log", job.len()).into()),
+            ..FinishedJob::example(job, outcome)
+        };
+        let build = "stable cargo build '--features='";
+        let test = "stable cargo test '--features='";
+        let jobs = [
+            job(build, Outcome::Fail(Duration::from_secs(1))),
+            job(build, Outcome::Pass(Duration::from_secs(2))),
+            job(test, Outcome::Fail(Duration::from_secs(3))),
+        ];
+        let notes = [
+            "stable cargo build '--features=' # warnings 4".to_owned(),
+            SKIPPED_NOTE.to_owned(),
+        ];
+        let results = commit_results(&notes, &jobs, |job| {
+            job.log.as_ref().map(|l| l.to_string_lossy().into_owned())
+        });
+        assert_eq!(results.len(), 2);
+        assert_eq!(
+            results[build],
+            JobResult {
+                outcome: Outcome::Pass(Duration::from_secs(2)),
+                warnings: Some(4),
+                log: Some("/logs/32.log".to_owned()),
+            }
+        );
+        assert_eq!(results[test].outcome, Outcome::Fail(Duration::from_secs(3)));
+
+        let row = Row {
+            commit,
+            summary: "Fix <things>".to_owned(),
+            results,
+        };
+        assert_eq!(
+            matrix(&[row]),
+            "<table>\n<tr><th>Commit</th>\
+             <th class=\"job\">stable cargo build &apos;--features=&apos;</th>\
+             <th class=\"job\">stable cargo test &apos;--features=&apos;</th></tr>\n\
+             <tr><td><code>aaaaaaa</code> Fix &lt;things&gt;</td>\
+             <td class=\"pass\"><a href=\"/logs/32.log\">2s (4w)</a></td>\
+             <td class=\"fail\"><a href=\"/logs/31.log\">FAIL 3s</a></td></tr>\n</table>\n",
+        );
+
+        // Results known only from the notes
+        let clippy = "stable cargo clippy '--features='";
+        let fmt = "stable cargo fmt";
+        let failed = |job| NoteEntry::finished(job, NoteOutcome::Fail, Duration::from_secs(5));
+        let notes = [
+            failed(clippy).to_string(),
+            failed(test).to_string(),
+            "stable cargo build '--features='".to_owned(),
+            NoteEntry::finished(fmt, NoteOutcome::Pass, Duration::from_secs(7)).to_string(),
+        ];
+        let results = commit_results(&notes, &jobs, |_| None);
+        assert_eq!(
+            results[clippy].outcome,
+            Outcome::Fail(Duration::from_secs(5))
+        );
+        assert_eq!(results[fmt].outcome, Outcome::Pass(Duration::from_secs(7)));
+        assert_eq!(results[test].outcome, Outcome::Fail(Duration::from_secs(3)));
+        assert_eq!(
+            results[build].outcome,
+            Outcome::Pass(Duration::from_secs(2))
+        );
+    }
+}
@@ -0,0 +1,158 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+//! Committer identity used for the commits we create
+//!
+//! The name and email are looked up, in order, from the environment
+//! variables `RSGIT_COMMITTER_NAME`/`RSGIT_COMMITTER_EMAIL`, the git
+//! config keys `rsgit.name`/`rsgit.email`, the git config keys
+//! `user.name`/`user.email`, and finally a hardcoded default.
+//!
+//! The notes commits we create are signed with a GPG key if one is given by
+//! the environment variable `RSGIT_SIGNING_KEY` or the git config key
+//! `rsgit.signingKey`, using the program given by `gpg.program`, or `gpg`.
+
+use anyhow::Context;
+use git2::{Repository, Signature};
+use std::env;
+
+/// A committer identity
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Identity {
+    /// Name of the committer
+    pub name: String,
+    /// Email address of the committer
+    pub email: String,
+    /// GPG key with which to sign notes commits, if any
+    pub signing_key: Option<String>,
+}
+
+impl Identity {
+    /// Looks up the identity to use for a given repo
+    pub fn from_repo(repo: &Repository, default_name: &str, default_email: &str) -> Self {
+        let config = repo.config().ok();
+        let lookup_opt = |var: &str, keys: &[&str]| {
+            if let Ok(val) = env::var(var) {
+                return Some(val);
+            }
+            let config = config.as_ref()?;
+            keys.iter().find_map(|key| config.get_string(key).ok())
+        };
+        let lookup = |var: &str, keys: &[&str], default: &str| {
+            lookup_opt(var, keys).unwrap_or_else(|| default.to_owned())
+        };
+
+        Identity {
+            name: lookup(
+                "RSGIT_COMMITTER_NAME",
+                &["rsgit.name", "user.name"],
+                default_name,
+            ),
+            email: lookup(
+                "RSGIT_COMMITTER_EMAIL",
+                &["rsgit.email", "user.email"],
+                default_email,
+            ),
+            signing_key: lookup_opt("RSGIT_SIGNING_KEY", &["rsgit.signingKey"])
+                .filter(|key| !key.is_empty()),
+        }
+    }
+
+    /// Constructs a signature for this identity
+    ///
+    /// If no time is given, the current time is used.
+    pub fn signature(&self, time: Option<&git2::Time>) -> anyhow::Result<Signature<'static>> {
+        match time {
+            Some(time) => Signature::new(&self.name, &self.email, time),
+            None => Signature::now(&self.name, &self.email),
+        }
+        .with_context(|| format!("creating git signature for {} <{}>", self.name, self.email))
+    }
+
+    /// Checks that our signing key, if we have one, can sign, so that a run
+    /// can fail before any work is done rather than when recording it
+    pub fn check_signing_key(&self, repo: &Repository) -> anyhow::Result<()> {
+        match self.signing_key {
+            Some(ref key) => gpg_sign(repo, key, "").map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    /// Replaces the commit at the tip of a ref, as just created by us, with
+    /// the same commit signed with our signing key, if we have one, and
+    /// returns the ID of the ref's new tip
+    pub fn sign_ref(&self, repo: &Repository, refname: &str) -> anyhow::Result<git2::Oid> {
+        let commit = repo
+            .find_reference(refname)
+            .and_then(|reference| reference.peel_to_commit())
+            .with_context(|| format!("looking up tip of {}", refname))?;
+        let key = match self.signing_key {
+            Some(ref key) => key,
+            None => return Ok(commit.id()),
+        };
+        let parents: Vec<_> = commit.parents().collect();
+        let parent_refs: Vec<&_> = parents.iter().collect();
+        let buf = repo
+            .commit_create_buffer(
+                &commit.author(),
+                &commit.committer(),
+                commit.message().unwrap_or(""),
+                &commit.tree()?,
+                &parent_refs,
+            )
+            .with_context(|| format!("serializing commit {} to sign", commit.id()))?;
+        let buf = buf.as_str().context("serialized commit is not UTF-8")?;
+
+        let signature = gpg_sign(repo, key, buf)?;
+        let signed = repo
+            .commit_signed(buf, &signature, None)
+            .context("creating signed commit")?;
+        repo.reference_matching(
+            refname,
+            signed,
+            true,
+            commit.id(),
+            &format!("signed with {}", key),
+        )
+        .with_context(|| format!("updating {} to signed commit {}", refname, signed))?;
+        Ok(signed)
+    }
+}
+
+/// Makes an armored detached signature of some data with a GPG key
+fn gpg_sign(repo: &Repository, key: &str, data: &str) -> anyhow::Result<String> {
+    let program = repo
+        .config()
+        .and_then(|config| config.get_string("gpg.program"))
+        .unwrap_or_else(|_| "gpg".to_owned());
+    let capture = subprocess::Exec::cmd(&program)
+        .args(&["--batch", "--detach-sign", "--armor", "--local-user", key])
+        .stdin(data)
+        .stdout(subprocess::Redirection::Pipe)
+        .stderr(subprocess::Redirection::Pipe)
+        .capture()
+        .with_context(|| format!("running {}", program))?;
+    if !capture.success() {
+        return Err(anyhow::Error::msg(format!(
+            "signing with key {} failed: {}",
+            key,
+            capture.stderr_str().trim()
+        )));
+    }
+    Ok(capture.stdout_str())
+}
@@ -19,11 +19,169 @@
 //! Keeping track of processes
 
 use anyhow::Context;
-use rayon::ThreadPool;
-use std::io::Read;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, Read, Write};
 use std::panic;
+use std::path::Path;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+
+use crate::limits::Limits;
+use crate::say;
+
+/// Set once the run has been interrupted
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+/// Process IDs of every subprocess currently being waited on
+static RUNNING: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+/// Makes Ctrl-C (or SIGTERM) cancel the run, rather than killing us outright
+///
+/// On the first interrupt no more jobs are started and every running
+/// subprocess is killed, along with anything it started, so that jobs fail
+/// promptly and clean up after themselves. A second interrupt exits at once.
+pub fn cancel_on_interrupt() -> anyhow::Result<()> {
+    ctrlc::set_handler(|| {
+        if cancelled() {
+            std::process::exit(130);
+        }
+        eprintln!("Interrupted: stopping all jobs (interrupt again to exit at once)");
+        cancel();
+    })
+    .context("installing interrupt handler")
+}
+
+/// Cancels the run: no more jobs or subprocesses are started, and those
+/// running are killed
+pub fn cancel() {
+    CANCELLED.store(true, Ordering::SeqCst);
+    for &pid in RUNNING.lock().unwrap().iter() {
+        kill_tree(pid);
+    }
+}
+
+/// Whether the run has been cancelled
+pub fn cancelled() -> bool {
+    CANCELLED.load(Ordering::SeqCst)
+}
+
+/// Error returned by jobs which were cancelled rather than failing
+fn cancelled_error() -> anyhow::Error {
+    anyhow::Error::msg("cancelled")
+}
+
+/// Entry in `RUNNING`, removed when dropped
+struct Running(u32);
+
+impl Running {
+    /// Records that a subprocess is running; if the run has already been
+    /// cancelled, kills it instead
+    fn register(popen: &subprocess::Popen) -> Option<Self> {
+        let pid = popen.pid()?;
+        RUNNING.lock().unwrap().push(pid);
+        // `cancel` may have missed it if it came in just before
+        if cancelled() {
+            kill_tree(pid);
+        }
+        Some(Running(pid))
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        RUNNING.lock().unwrap().retain(|&pid| pid != self.0);
+    }
+}
+
+/// Sends SIGTERM to a process and all of its descendants
+///
+/// Descendants are found from /proc, so elsewhere than Linux only the
+/// process itself is signalled.
+fn kill_tree(root: u32) {
+    let mut children: HashMap<u32, Vec<u32>> = HashMap::new();
+    for entry in fs::read_dir("/proc").into_iter().flatten().flatten() {
+        let pid = match entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse().ok())
+        {
+            Some(pid) => pid,
+            None => continue,
+        };
+        // The parent is the second field after the command name, which is
+        // in parentheses and may itself contain spaces or parentheses
+        let ppid = fs::read_to_string(entry.path().join("stat"))
+            .ok()
+            .and_then(|stat| {
+                let (_, rest) = stat.rsplit_once(')')?;
+                rest.split_whitespace().nth(1)?.parse().ok()
+            });
+        if let Some(ppid) = ppid {
+            children.entry(ppid).or_default().push(pid);
+        }
+    }
+
+    // Find the whole tree before signalling anything, as children of a
+    // killed process are reparented
+    let mut tree = vec![root];
+    let mut i = 0;
+    while i < tree.len() {
+        if let Some(pids) = children.get(&tree[i]) {
+            tree.extend(pids);
+        }
+        i += 1;
+    }
+    for pid in tree {
+        // Ignore errors: the process may already have exited
+        unsafe {
+            libc::kill(pid as libc::pid_t, libc::SIGTERM);
+        }
+    }
+}
+
+/// How demanding a job is, which decides the pool it is run in
+#[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash)]
+pub enum JobClass {
+    /// Quick jobs, such as builds, which give fast feedback
+    Light,
+    /// Slow jobs, such as tests and fuzzing
+    Heavy,
+}
+
+/// Thread pools to run jobs in, one per class, so that a pile of heavy jobs
+/// cannot hold up the light ones
+pub struct BuildPools {
+    light: ThreadPool,
+    heavy: ThreadPool,
+}
+
+impl BuildPools {
+    /// Creates the pools, with `threads` threads in all, `light_threads` of
+    /// which are kept for light jobs (but at least one is left for heavy
+    /// ones)
+    pub fn new(threads: usize, light_threads: usize) -> anyhow::Result<Self> {
+        let heavy_threads = threads.saturating_sub(light_threads).max(1);
+        Ok(BuildPools {
+            light: ThreadPoolBuilder::new()
+                .num_threads(light_threads)
+                .build()
+                .context("setting up light job thread pool")?,
+            heavy: ThreadPoolBuilder::new()
+                .num_threads(heavy_threads)
+                .build()
+                .context("setting up heavy job thread pool")?,
+        })
+    }
+
+    /// The pool for jobs of the given class
+    pub fn pool(&self, class: JobClass) -> &ThreadPool {
+        match class {
+            JobClass::Light => &self.light,
+            JobClass::Heavy => &self.heavy,
+        }
+    }
+}
 
 /// Handle to construct/spawn an async job
 pub struct JobHandle<T> {
@@ -33,21 +191,28 @@ pub struct JobHandle<T> {
 }
 
 impl<T> JobHandle<T> {
-    /// Creates a new job and starts running it in the threadpool
-    pub fn spawn<F>(pool: &ThreadPool, ext_data: T, f: F) -> Self
+    /// Creates a new job and starts running it in the pool for its class
+    pub fn spawn<F>(pools: &BuildPools, class: JobClass, ext_data: T, f: F) -> Self
     where
         F: FnOnce() -> anyhow::Result<()> + Send + panic::UnwindSafe + 'static,
     {
         let (tx, rx) = mpsc::channel();
-        pool.spawn(move || match panic::catch_unwind(f) {
-            Ok(res) => tx.send(res).unwrap(),
-            Err(_) => tx
-                .send(Err(anyhow::Error::msg("a build job panicked")))
-                .unwrap(),
+        pools.pool(class).spawn(move || {
+            match panic::catch_unwind(|| {
+                if cancelled() {
+                    return Err(cancelled_error());
+                }
+                f()
+            }) {
+                Ok(res) => tx.send(res).unwrap(),
+                Err(_) => tx
+                    .send(Err(anyhow::Error::msg("a build job panicked")))
+                    .unwrap(),
+            }
         });
         JobHandle {
             data: ext_data,
-            rx: rx,
+            rx,
             joined: AtomicBool::new(false),
         }
     }
@@ -68,15 +233,63 @@ impl<T> Drop for JobHandle<T> {
     }
 }
 
+/// Counting semaphore used to limit how many of some resource are live
+#[derive(Clone)]
+pub struct Semaphore {
+    inner: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl Semaphore {
+    /// Creates a new semaphore with the given number of permits
+    pub fn new(permits: usize) -> Self {
+        Semaphore {
+            inner: Arc::new((Mutex::new(permits), Condvar::new())),
+        }
+    }
+
+    /// Blocks until a permit is available, and takes it
+    ///
+    /// The permit is returned when the returned guard is dropped.
+    pub fn acquire(&self) -> SemaphoreGuard {
+        let (ref lock, ref cvar) = *self.inner;
+        let mut permits = lock.lock().unwrap();
+        while *permits == 0 {
+            permits = cvar.wait(permits).unwrap();
+        }
+        *permits -= 1;
+        SemaphoreGuard {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+/// A permit taken from a `Semaphore`
+pub struct SemaphoreGuard {
+    inner: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl Drop for SemaphoreGuard {
+    fn drop(&mut self) {
+        let (ref lock, ref cvar) = *self.inner;
+        *lock.lock().unwrap() += 1;
+        cvar.notify_one();
+    }
+}
+
 /// Helper function to try to execute a command, putting
 /// stderr in the error return if it fails
 pub fn exec_or_stderr(e: subprocess::Exec) -> anyhow::Result<()> {
     let invocation = e.to_cmdline_lossy();
+    if cancelled() {
+        return Err(cancelled_error());
+    }
+    say!(Debug, "Running {}", invocation);
     let mut popen = e
         .stdout(subprocess::NullFile)
         .stderr(subprocess::Redirection::Pipe)
         .popen()
         .with_context(|| format!("constructing Exec: {}", invocation))?;
+    let _running = Running::register(&popen);
     let fail_msg = match popen
         .wait()
         .with_context(|| format!("waiting: {}", invocation))?
@@ -102,3 +315,137 @@ pub fn exec_or_stderr(e: subprocess::Exec) -> anyhow::Result<()> {
         None => Ok(()),
     }
 }
+
+/// Output of a process which has finished
+pub struct Captured {
+    /// The command line which was run
+    pub invocation: String,
+    /// How the process exited
+    pub status: subprocess::ExitStatus,
+    /// Everything the process wrote to stdout
+    pub stdout: String,
+    /// Everything the process wrote to stderr
+    pub stderr: String,
+    /// Which resource limit the process was killed for exceeding, if any
+    pub limit_exceeded: Option<String>,
+}
+
+impl Captured {
+    /// Whether the process exited successfully
+    pub fn success(&self) -> bool {
+        self.status.success()
+    }
+}
+
+/// What to do with a process's output, besides collecting it
+#[derive(Clone, Copy, Default)]
+pub struct CaptureOptions<'a> {
+    /// File to write the command line and every line of output to
+    pub log: Option<&'a Path>,
+    /// If set, every line of output is printed as soon as it is produced,
+    /// after this prefix
+    pub stream_prefix: Option<&'a str>,
+    /// Converts a line of output into the text to print when streaming;
+    /// lines mapped to `None` are not printed
+    pub display: Option<fn(&str) -> Option<String>>,
+    /// Limits on the resources the process may use
+    pub limits: Option<&'a Limits>,
+}
+
+/// Runs a command to completion, collecting its output
+///
+/// Each line of output is also logged and streamed, as soon as it is
+/// produced, according to `options`.
+pub fn run_captured(e: subprocess::Exec, options: CaptureOptions) -> anyhow::Result<Captured> {
+    let invocation = e.to_cmdline_lossy();
+    if cancelled() {
+        return Err(cancelled_error());
+    }
+    say!(Debug, "Running {}", invocation);
+    let log = match options.log {
+        Some(path) => {
+            let mut file = File::create(path)
+                .with_context(|| format!("creating log file {}", path.to_string_lossy()))?;
+            writeln!(file, "$ {}", invocation)
+                .with_context(|| format!("writing log file {}", path.to_string_lossy()))?;
+            Some(Mutex::new(file))
+        }
+        None => None,
+    };
+
+    let mut popen = e
+        .stdout(subprocess::Redirection::Pipe)
+        .stderr(subprocess::Redirection::Pipe)
+        .popen()
+        .with_context(|| format!("constructing Exec: {}", invocation))?;
+    let _running = Running::register(&popen);
+    // The process has no pid only if it has already exited
+    let cgroup = match (options.limits, popen.pid()) {
+        (Some(limits), Some(pid)) => match limits.apply(pid) {
+            Ok(cgroup) => cgroup,
+            Err(e) => {
+                let _ = popen.kill();
+                let _ = popen.wait();
+                return Err(e.context(format!("limiting resources of: {}", invocation)));
+            }
+        },
+        _ => None,
+    };
+    let stdout = popen.stdout.take().unwrap();
+    let stderr = popen.stderr.take().unwrap();
+    let (stdout, stderr) = std::thread::scope(|s| {
+        let stdout = s.spawn(|| read_lines(stdout, log.as_ref(), options));
+        let stderr = read_lines(stderr, log.as_ref(), options);
+        (stdout.join().expect("reader thread panicked"), stderr)
+    });
+    let status = popen
+        .wait()
+        .with_context(|| format!("waiting: {}", invocation))?;
+    let limit_exceeded = options
+        .limits
+        .and_then(|limits| limits.exceeded(status, cgroup.as_ref()));
+
+    Ok(Captured {
+        stdout: stdout.with_context(|| format!("reading stdout from: {}", invocation))?,
+        stderr: stderr.with_context(|| format!("reading stderr from: {}", invocation))?,
+        invocation,
+        status,
+        limit_exceeded,
+    })
+}
+
+/// Reads a process's output stream to the end, copying each line to the
+/// log file and the console as requested
+fn read_lines(
+    stream: File,
+    log: Option<&Mutex<File>>,
+    options: CaptureOptions,
+) -> io::Result<String> {
+    let mut reader = io::BufReader::new(stream);
+    let mut ret = String::new();
+    let mut line = vec![];
+    while reader.read_until(b'\n', &mut line)? > 0 {
+        let text = String::from_utf8_lossy(&line);
+        if let Some(log) = log {
+            log.lock().unwrap().write_all(text.as_bytes())?;
+        }
+        if let Some(prefix) = options.stream_prefix {
+            let trimmed = text.trim_end_matches(&['\r', '\n'][..]);
+            let shown = match options.display {
+                Some(display) => display(trimmed),
+                None => Some(trimmed.to_owned()),
+            };
+            if let Some(shown) = shown {
+                // Lock so that lines from different jobs are not interleaved
+                let stdout = io::stdout();
+                let mut stdout = stdout.lock();
+                for shown_line in shown.lines() {
+                    writeln!(stdout, "{} {}", prefix, shown_line)?;
+                }
+            }
+        }
+        ret.push_str(&text);
+        line.clear();
+    }
+    Ok(ret)
+}
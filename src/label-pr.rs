@@ -17,30 +17,111 @@
 // Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
 //
 
-mod pr;
-
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::usize;
 
 use anyhow::Context;
-use git2::{Repository, Signature};
+use git2::Repository;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use structopt::StructOpt;
 
-use self::pr::PullRequest;
+use git_utils::config::Config;
+use git_utils::forge::PrInfo;
+use git_utils::gerrit::{self, Change};
+use git_utils::identity::Identity;
+use git_utils::notes::{push_notes, update_notes, NotesMerge};
+use git_utils::pr::{BaseBranches, PullRequest};
+
+/// Default ref under which the labels are written
+const DEFAULT_NOTES_REF: &str = "refs/notes/label-pr";
+
+/// Name of the file, in the repository's git directory, recording which
+/// PRs were labelled at which tips under the default notes ref
+const STATE_FILE: &str = "label-pr-state.json";
+
+/// Number of PRs whose labels are collected before they are written
+const BATCH_SIZE: usize = 10_000;
+
+/// Marker appended to the labels of PRs whose refs have gone
+const CLOSED_MARK: &str = " [closed]";
+
+/// Field added to the JSON labels of PRs whose refs have gone
+const CLOSED_FIELD: &str = "\"closed\":true}";
 
 #[derive(StructOpt, Debug)]
 struct Opts {
     /// The repository to tag PRs in
     #[structopt(short = "r", long = "repo", default_value = ".")]
     repo: String,
+    /// GPG key with which to sign the notes commit, rather than any given
+    /// by the git config key rsgit.signingKey
+    #[structopt(long)]
+    signing_key: Option<String>,
+    /// Remote to push the notes to once they are written, keeping ours
+    /// over any pushed there by others in the meantime
+    #[structopt(long)]
+    push_notes: Option<String>,
+    /// Ref under which to write the labels, e.g. to keep those of PRs on
+    /// different forges apart
+    #[structopt(long, default_value = DEFAULT_NOTES_REF)]
+    notes_ref: String,
+    /// Label every PR afresh, rather than only those which are new or
+    /// whose tips have changed since the last run
+    #[structopt(long)]
+    full: bool,
+    /// What to do with the labels of PRs whose refs have gone since the
+    /// last run, on commits which never reached a master branch: `remove`
+    /// them, or `mark` them as closed
+    #[structopt(long, default_value = "remove")]
+    closed: Closed,
+    /// Look up the title, author and merge date of each PR on the forge
+    /// configured for the repository, and add them to its labels
+    #[structopt(long)]
+    metadata: bool,
+    /// Also label the commits of master branches which merged PRs: merge
+    /// commits whose other parent is the tip of a PR, and, with
+    /// `--metadata`, the commits the forge says PRs were merged or squashed
+    /// into
+    #[structopt(long)]
+    merges: bool,
+    /// Also label the commits merged by merge commits on master branches
+    /// whose messages name a PR, e.g. `Merge pull request #123`, unless the
+    /// PR's ref is there to label them from
+    #[structopt(long)]
+    merge_messages: bool,
+    /// Also label the commits of these master branches, separated by
+    /// commas, with the changes on the Gerrit instance configured for the
+    /// repository which their `Change-Id` trailers name
+    #[structopt(long)]
+    gerrit: Option<String>,
     /// Label structure to apply in the form pr_ref:master,branches:url_prefix
     #[structopt(name = "labels")]
     labels: Vec<Label>,
+    /// Label structure as for `labels`, but with each label written as a
+    /// JSON object on a line of its own
+    #[structopt(long = "json", number_of_values = 1)]
+    json_labels: Vec<Label>,
+}
+
+/// How labels are written in the notes
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Format {
+    /// `PR: <url> (<index>/<count>)`, for people
+    Text,
+    /// A JSON object, for other tools
+    Json,
 }
 
 #[derive(Debug)]
 struct Label {
+    /// The label as given on the command line, after `json:` for labels
+    /// written as JSON
+    spec: String,
+    /// How the labels are written
+    format: Format,
     /// The URL to use as a prefix when linking to PRs
     url_prefix: String,
     /// The prefix to search for PR refs under
@@ -66,6 +147,8 @@ impl FromStr for Label {
             None => return Err(format!("missing url_prefix field in {}", s)),
         };
         Ok(Label {
+            spec: s.to_owned(),
+            format: Format::Text,
             url_prefix,
             pr_ref,
             master,
@@ -73,144 +156,1029 @@ impl FromStr for Label {
     }
 }
 
+/// What happens to the labels of PRs whose refs have gone
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Closed {
+    Remove,
+    Mark,
+}
+
+impl FromStr for Closed {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "remove" => Ok(Closed::Remove),
+            "mark" => Ok(Closed::Mark),
+            _ => Err(format!("unknown action {} (use remove or mark)", s)),
+        }
+    }
+}
+
+/// Which PRs were labelled at which tips, so that later runs need only
+/// label the PRs which have changed
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct State {
+    /// The notes commit the labels were last written in. If the notes ref
+    /// has moved on since, the labels there may not be ours, so every PR
+    /// is labelled afresh.
+    notes_commit: Option<String>,
+    /// Tip of each PR when it was last labelled, by label as given on the
+    /// command line and PR number
+    tips: BTreeMap<String, BTreeMap<usize, String>>,
+    /// PRs labelled with what the forge said about them before they were
+    /// merged, which are labelled again by runs looking up the forge, in
+    /// case they have been merged since
+    #[serde(default)]
+    unmerged: BTreeMap<String, BTreeSet<usize>>,
+    /// Tip of each master branch when the merge commits in its history
+    /// were last labelled, by label and branch as given on the command
+    /// line
+    #[serde(default)]
+    merge_tips: BTreeMap<String, BTreeMap<String, String>>,
+    /// Tip of each master branch when the messages of the merge commits in
+    /// its history were last read, as for `merge_tips`
+    #[serde(default)]
+    message_tips: BTreeMap<String, BTreeMap<String, String>>,
+    /// Tip of each master branch given by `--gerrit` when the commits in
+    /// its history were last labelled with their changes
+    #[serde(default)]
+    gerrit_tips: BTreeMap<String, String>,
+}
+
+impl State {
+    /// Where the state is kept for a repository and notes ref
+    fn path(repo: &Repository, notes_ref: &str) -> PathBuf {
+        if notes_ref == DEFAULT_NOTES_REF {
+            repo.path().join(STATE_FILE)
+        } else {
+            let name = notes_ref
+                .trim_start_matches("refs/notes/")
+                .replace('/', "-");
+            repo.path().join(format!("label-pr-state-{}.json", name))
+        }
+    }
+
+    /// Reads the state left by the last run, if it still describes the
+    /// notes, or else starts afresh
+    fn load(repo: &Repository, notes_ref: &str) -> Self {
+        let notes_commit = repo.refname_to_id(notes_ref).ok().map(|id| id.to_string());
+        let state: State = match fs::read_to_string(State::path(repo, notes_ref)) {
+            Ok(data) => serde_json::from_str(&data).unwrap_or_default(),
+            Err(_) => State::default(),
+        };
+        if state.notes_commit.is_some() && state.notes_commit == notes_commit {
+            state
+        } else {
+            State::default()
+        }
+    }
+
+    /// Writes the state out, all at once so that being killed part-way
+    /// through cannot leave it corrupted
+    fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let tmp = path.with_extension("json.tmp");
+        let data = serde_json::to_string_pretty(self).expect("state can be serialized");
+        fs::write(&tmp, data).with_context(|| format!("writing {}", tmp.to_string_lossy()))?;
+        fs::rename(&tmp, path).with_context(|| format!("replacing {}", path.to_string_lossy()))
+    }
+}
+
 struct Note<'label> {
+    format: Format,
     url_prefix: &'label str,
     pr_num: usize,
-    commit_index: usize,
-    n_commits: usize,
+    /// Position of the commit in the PR, from 1, and the number of commits
+    /// in the PR, or `None` for the commit which merged the PR
+    position: Option<(usize, usize)>,
+    /// What the forge says about the PR, if it was asked
+    info: Option<PrInfo>,
+}
+
+/// A label written as JSON; `url` comes first, so that the labels of a PR
+/// can be found as for text labels, by the start of their line
+#[derive(Serialize)]
+struct JsonLabel<'a> {
+    url: String,
+    pr: usize,
+    /// Position of the commit in the PR, from 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    commit: Option<usize>,
+    /// Number of commits in the PR
+    #[serde(skip_serializing_if = "Option::is_none")]
+    commits: Option<usize>,
+    /// Whether the commit is the one which merged the PR
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    merge: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    author: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    merged: Option<&'a str>,
+}
+
+impl Note<'_> {
+    /// Start of the lines labelling commits with the PR
+    fn prefix(format: Format, url_prefix: &str, pr_num: usize) -> String {
+        let url = format!("{}{}", url_prefix, pr_num);
+        match format {
+            Format::Text => format!("PR: {} (", url),
+            Format::Json => format!(
+                "{{\"url\":{},",
+                serde_json::to_string(&url).expect("strings can be serialized")
+            ),
+        }
+    }
+
+    /// The start of a line labelling a commit with a PR, as given by
+    /// `prefix`, if it is such a line
+    fn prefix_of(line: &str) -> Option<&str> {
+        let end = if line.starts_with("PR: ") {
+            line.find(" (")? + 2
+        } else if line.starts_with("{\"url\":\"") {
+            line.find("\",")? + 2
+        } else {
+            return None;
+        };
+        Some(&line[..end])
+    }
+
+    /// The line labelling the commit
+    fn line(&self) -> String {
+        match self.format {
+            Format::Text => {
+                let mut ret = Note::prefix(self.format, self.url_prefix, self.pr_num);
+                match self.position {
+                    Some((index, n_commits)) => ret.push_str(&format!("{}/{})", index, n_commits)),
+                    None => ret.push_str("merge)"),
+                }
+                if let Some(ref info) = self.info {
+                    ret.push(' ');
+                    ret.push_str(&info.to_string());
+                }
+                ret
+            }
+            Format::Json => {
+                let info = self.info.as_ref();
+                let label = JsonLabel {
+                    url: format!("{}{}", self.url_prefix, self.pr_num),
+                    pr: self.pr_num,
+                    commit: self.position.map(|(index, _)| index),
+                    commits: self.position.map(|(_, n_commits)| n_commits),
+                    merge: self.position.is_none(),
+                    title: info.map(|info| &info.title[..]),
+                    author: info.map(|info| &info.author[..]),
+                    merged: info.and_then(|info| info.merged.as_deref()),
+                };
+                serde_json::to_string(&label).expect("labels can be serialized")
+            }
+        }
+    }
 }
 
 fn main() -> anyhow::Result<()> {
-    let opts = Opts::from_args();
+    let mut opts = Opts::from_args();
+    for label in &mut opts.json_labels {
+        label.format = Format::Json;
+        label.spec = format!("json:{}", label.spec);
+    }
     let repo = Repository::open_ext(&opts.repo, git2::RepositoryOpenFlags::empty(), Some("/"))
         .with_context(|| format!("Opening repo {}", opts.repo))?;
+    if !opts.notes_ref.starts_with("refs/notes/")
+        || !git2::Reference::is_valid_name(&opts.notes_ref)
+    {
+        return Err(anyhow::Error::msg(format!(
+            "--notes-ref {} must be a valid ref name under refs/notes/",
+            opts.notes_ref
+        )));
+    }
+    let mut identity = Identity::from_repo(&repo, "PR Labeller", "prlabel@wpsoftware.net");
+    if opts.signing_key.is_some() {
+        identity.signing_key = opts.signing_key.clone();
+    }
+    identity.check_signing_key(&repo)?;
 
-    for label in &opts.labels {
+    let old_state = if opts.full {
+        State::default()
+    } else {
+        State::load(&repo, &opts.notes_ref)
+    };
+    // With no state, the labels are written as a new tree from scratch
+    let mut writer = if old_state.notes_commit.is_some() {
+        println!("Labelling only the PRs which have changed since the last run");
+        Writer::Incremental
+    } else {
+        Writer::Full(repo.treebuilder(None).context("getting a treebuilder")?)
+    };
+    let mut new_state = State::default();
+    let config = if opts.metadata || opts.gerrit.is_some() {
+        Some(Config::load(&[], Some(git_utils::git::repo_dir(&repo)))?)
+    } else {
+        None
+    };
+
+    for label in opts.labels.iter().chain(&opts.json_labels) {
         // 1. Collect PRs
-        let mut prs = vec![];
         println!(
-            "Labeling {} from refs/remotes/{} (master branch {:?})",
-            label.url_prefix, label.pr_ref, label.master
+            "Labeling {} from refs/remotes/{} (master branch {:?}){}",
+            label.url_prefix,
+            label.pr_ref,
+            label.master,
+            if label.format == Format::Json {
+                " as JSON"
+            } else {
+                ""
+            },
         );
-        'ref_loop: for rf in repo.references().expect("get references") {
-            let rf = rf.expect("reference is legit");
-            if rf.is_remote() {
-                let name = rf.name().unwrap();
-                let mut segments = name.split('/');
-                if segments.next() != Some("refs") {
-                    continue;
-                }
-                if segments.next() != Some("remotes") {
-                    continue;
-                }
-                for seg in label.pr_ref.split('/') {
-                    if segments.next() != Some(seg) {
-                        continue 'ref_loop;
-                    }
-                }
-                let num = match segments.next().map(usize::from_str) {
-                    Some(Ok(n)) => n,
-                    _ => continue,
-                };
-                if segments.next() != Some("head") {
-                    continue;
-                }
-                prs.push(PullRequest {
-                    number: num,
-                    id: rf.target().expect("dereference pr ref"),
-                });
+        let forge = config.as_ref().and_then(|config| {
+            config
+                .settings()
+                .forges()
+                .into_iter()
+                .find(|forge| label.url_prefix.contains(forge.repo()))
+        });
+        if opts.metadata && forge.is_none() {
+            println!(
+                "No forge is configured for {}, so its PRs will not be looked up",
+                label.url_prefix
+            );
+        }
+
+        let prs = PullRequest::find_all(&repo, &label.pr_ref).expect("get references");
+        let old_tips = old_state.tips.get(&label.spec);
+        // Including those of PRs whose refs have gone, which may have been
+        // deleted once they were merged
+        let mut pr_tips: HashMap<git2::Oid, Vec<usize>> = HashMap::new();
+        let old_pr_tips = old_tips
+            .into_iter()
+            .flatten()
+            .filter_map(|(&number, tip)| Some((git2::Oid::from_str(tip).ok()?, number)));
+        for (tip, number) in prs.iter().map(|pr| (pr.id, pr.number)).chain(old_pr_tips) {
+            let numbers = pr_tips.entry(tip).or_default();
+            if !numbers.contains(&number) {
+                numbers.push(number);
             }
         }
-        println!("Found {} PRs", prs.len());
+        let old_unmerged = old_state.unmerged.get(&label.spec);
+        let tips = new_state.tips.entry(label.spec.clone()).or_default();
+        let unmerged = new_state.unmerged.entry(label.spec.clone()).or_default();
+        let prs: Vec<_> = prs
+            .into_iter()
+            .filter(|pr| {
+                tips.insert(pr.number, pr.id.to_string());
+                let was_unmerged = old_unmerged.is_some_and(|prs| prs.contains(&pr.number));
+                let changed =
+                    old_tips.and_then(|tips| tips.get(&pr.number)) != Some(&pr.id.to_string());
+                if changed || (was_unmerged && forge.is_some()) {
+                    true
+                } else {
+                    // Left for a later run which looks up the forge
+                    if was_unmerged {
+                        unmerged.insert(pr.number);
+                    }
+                    false
+                }
+            })
+            .collect();
+        println!("Found {} new or updated PRs", prs.len());
 
-        // 2. Check master tree
-        let mut parent_commits = HashSet::new();
+        // 2. Look up master branches
+        let mut tips = vec![];
         for master in &label.master {
             let rf = repo.revparse_single(master).expect("look up master ref");
-            let mut parent = repo.find_commit(rf.id());
-            while let Ok(parent_commit) = parent {
-                parent_commits.insert(parent_commit.id());
-                parent = parent_commit.parent(0);
+            tips.push(rf.id());
+        }
+        let masters = BaseBranches::new(tips.clone());
+
+        // With a full run the labels of closed PRs are simply not written
+        if let (Writer::Incremental, Some(old_tips)) = (&writer, old_tips) {
+            let tips = &new_state.tips[&label.spec];
+            let closed: Vec<_> = old_tips
+                .iter()
+                .filter(|(number, _)| !tips.contains_key(number))
+                .collect();
+            if !closed.is_empty() {
+                println!("Found {} closed PRs", closed.len());
+                close_prs(
+                    &repo,
+                    &identity,
+                    &opts.notes_ref,
+                    &masters,
+                    label,
+                    &closed,
+                    opts.closed,
+                )?;
+            }
+
+            // A full run would only label the commits now in each PR
+            let pushed: Vec<_> = prs
+                .iter()
+                .filter_map(|pr| {
+                    let old_tip = git2::Oid::from_str(old_tips.get(&pr.number)?).ok()?;
+                    Some((pr.number, old_tip, pr.id)).filter(|_| old_tip != pr.id)
+                })
+                .collect();
+            if !pushed.is_empty() {
+                drop_pushed_out(&repo, &identity, &opts.notes_ref, &masters, label, &pushed)?;
             }
         }
-        println!("Found {} parent commits", parent_commits.len());
 
         // 3. Build map of notes
-        let mut note_map = HashMap::new();
-        for (n, pr) in prs.iter().enumerate() {
-            pr.for_each_commit(&repo, &parent_commits, |id, index, n_commits| {
-                note_map.entry(id).or_insert(vec![]).push(Note {
-                    url_prefix: &label.url_prefix,
-                    pr_num: pr.number,
-                    commit_index: n_commits - index,
-                    n_commits: n_commits,
+        let mut n_done = 0;
+        for batch in prs.chunks(BATCH_SIZE) {
+            // One at a time, so as not to run into the forge's rate limits
+            let infos: Vec<_> = batch
+                .iter()
+                .map(|pr| {
+                    let info = forge.and_then(|forge| match forge.pr_info(pr.number) {
+                        Ok(info) => Some(info),
+                        Err(e) => {
+                            println!(
+                                "Could not look up PR #{} on {}: {:#}",
+                                pr.number,
+                                forge.name(),
+                                e
+                            );
+                            None
+                        }
+                    });
+                    if forge.is_some() && info.as_ref().is_none_or(|info| info.merged.is_none()) {
+                        new_state
+                            .unmerged
+                            .entry(label.spec.clone())
+                            .or_default()
+                            .insert(pr.number);
+                    }
+                    info
                 })
+                .collect();
+            let walks = par_map(&repo, batch.iter().collect(), |repo, pr| {
+                masters.branch_commits(repo, pr.id)
             });
 
-            if n % 10_000 == 9_999 || n == prs.len() - 1 {
+            let mut note_map = HashMap::new();
+            let mut relabelled = HashSet::new();
+            for ((pr, info), commits) in batch.iter().zip(infos).zip(walks) {
+                relabelled.insert(Note::prefix(label.format, &label.url_prefix, pr.number));
+                let commits = match commits? {
+                    Some(commits) => commits,
+                    None => {
+                        println!(
+                            "Skipping PR #{}: no history in common with master",
+                            pr.number
+                        );
+                        continue;
+                    }
+                };
+                // As for a squash merge, which no merge commit points to
+                let merge_commit = info
+                    .as_ref()
+                    .and_then(|info| info.merge_commit.as_deref())
+                    .and_then(|id| git2::Oid::from_str(id).ok())
+                    .filter(|&id| opts.merges && !commits.contains(&id))
+                    .filter(|&id| repo.find_commit(id).is_ok());
+                if let Some(id) = merge_commit {
+                    note_map.entry(id).or_insert(vec![]).push(Note {
+                        format: label.format,
+                        url_prefix: &label.url_prefix,
+                        pr_num: pr.number,
+                        position: None,
+                        info: info.clone(),
+                    });
+                }
+                let n_commits = commits.len();
+                for (index, id) in commits.into_iter().enumerate() {
+                    note_map.entry(id).or_insert(vec![]).push(Note {
+                        format: label.format,
+                        url_prefix: &label.url_prefix,
+                        pr_num: pr.number,
+                        position: Some((index + 1, n_commits)),
+                        info: info.clone(),
+                    })
+                }
+            }
+
+            n_done += batch.len();
+            println!(
+                "Labelling {} commits ({} / {} PRs)",
+                note_map.len(),
+                n_done,
+                prs.len()
+            );
+            writer.write(&repo, &identity, &opts.notes_ref, note_map, &relabelled)?;
+        }
+
+        // 4. Label merge commits
+        if opts.merges {
+            let (merges, merge_tips) = merge_commits(
+                &repo,
+                &label.master,
+                &tips,
+                old_state.merge_tips.get(&label.spec),
+            )?;
+            new_state.merge_tips.insert(label.spec.clone(), merge_tips);
+
+            // Nothing is relabelled, so that labels from the forge are kept
+            let mut note_map = HashMap::new();
+            for id in merges {
+                let commit = repo
+                    .find_commit(id)
+                    .context("looking up commit on master branch")?;
+                for parent in commit.parent_ids().skip(1) {
+                    for &number in pr_tips.get(&parent).into_iter().flatten() {
+                        note_map.entry(id).or_insert(vec![]).push(Note {
+                            format: label.format,
+                            url_prefix: &label.url_prefix,
+                            pr_num: number,
+                            position: None,
+                            info: None,
+                        });
+                    }
+                }
+            }
+            if !note_map.is_empty() {
+                println!("Labelling {} merge commits", note_map.len());
+                let relabelled = HashSet::new();
+                writer.write(&repo, &identity, &opts.notes_ref, note_map, &relabelled)?;
+            }
+        }
+
+        // 5. Label the commits of PRs named by the messages of merge commits
+        if opts.merge_messages {
+            let (merges, message_tips) = merge_commits(
+                &repo,
+                &label.master,
+                &tips,
+                old_state.message_tips.get(&label.spec),
+            )?;
+            new_state
+                .message_tips
+                .insert(label.spec.clone(), message_tips);
+
+            // PRs whose refs were labelled are left alone
+            let known: HashSet<usize> = pr_tips.values().flatten().copied().collect();
+            let mut found = vec![];
+            for id in merges {
+                let commit = repo
+                    .find_commit(id)
+                    .context("looking up commit on master branch")?;
+                let message = commit.message().unwrap_or("");
+                if let Some((pr_repo, number)) = PullRequest::number_from_merge_message(message) {
+                    if !known.contains(&number)
+                        && pr_repo.is_none_or(|pr_repo| label.url_prefix.contains(pr_repo))
+                    {
+                        found.push((id, number));
+                    }
+                }
+            }
+            println!("Found {} PRs in the messages of merge commits", found.len());
+
+            for batch in found.chunks(BATCH_SIZE) {
+                // The commits merged are those of the second parent which
+                // are not in the history of the first
+                let walks = par_map(&repo, batch.to_vec(), |repo, (id, _)| {
+                    let commit = repo.find_commit(id)?;
+                    BaseBranches::new(vec![commit.parent_id(0)?])
+                        .branch_commits(repo, commit.parent_id(1)?)
+                });
+
+                let mut note_map = HashMap::new();
+                let mut relabelled = HashSet::new();
+                for (&(id, number), commits) in batch.iter().zip(walks) {
+                    relabelled.insert(Note::prefix(label.format, &label.url_prefix, number));
+                    if opts.merges {
+                        note_map.entry(id).or_insert(vec![]).push(Note {
+                            format: label.format,
+                            url_prefix: &label.url_prefix,
+                            pr_num: number,
+                            position: None,
+                            info: None,
+                        });
+                    }
+                    let commits = commits?.unwrap_or_default();
+                    let n_commits = commits.len();
+                    for (index, commit) in commits.into_iter().enumerate() {
+                        note_map.entry(commit).or_insert(vec![]).push(Note {
+                            format: label.format,
+                            url_prefix: &label.url_prefix,
+                            pr_num: number,
+                            position: Some((index + 1, n_commits)),
+                            info: None,
+                        });
+                    }
+                }
                 println!(
-                    "Labelling {} commits ({} / {} PRs)",
+                    "Labelling {} commits merged by {} merge commits",
                     note_map.len(),
-                    n + 1,
-                    prs.len()
+                    batch.len()
                 );
-                create_notes(&repo, note_map)?;
-                note_map = HashMap::new();
+                writer.write(&repo, &identity, &opts.notes_ref, note_map, &relabelled)?;
             }
         }
     }
 
+    if let Some(ref branches) = opts.gerrit {
+        let gerrit = config
+            .as_ref()
+            .and_then(|config| config.settings().gerrit.clone())
+            .ok_or_else(|| {
+                anyhow::Error::msg("--gerrit needs a [gerrit] section in the configuration")
+            })?;
+        let masters: Vec<String> = branches.split(',').map(String::from).collect();
+        let url_prefix = gerrit.change_url_prefix();
+        println!("Labeling {} (master branch {:?})", url_prefix, masters);
+
+        // 1. Collect the commits added to the master branches with their
+        // Change-Ids
+        let mut walk = repo.revwalk().context("walking master branches")?;
+        for master in &masters {
+            let tip = repo
+                .revparse_single(master)
+                .with_context(|| format!("looking up master branch {}", master))?
+                .id();
+            walk.push(tip)?;
+            let old_tip = old_state
+                .gerrit_tips
+                .get(master)
+                .and_then(|tip| git2::Oid::from_str(tip).ok());
+            // The branch may have been force-pushed since
+            if let Some(old_tip) = old_tip.filter(|&id| repo.find_commit(id).is_ok()) {
+                walk.hide(old_tip)?;
+            }
+            new_state
+                .gerrit_tips
+                .insert(master.clone(), tip.to_string());
+        }
+        let mut found = vec![];
+        for id in walk {
+            let id = id.context("walking master branches")?;
+            let commit = repo
+                .find_commit(id)
+                .context("looking up commit on master branch")?;
+            if let Some(change_id) = gerrit::change_id(commit.message().unwrap_or("")) {
+                found.push((id, change_id));
+            }
+        }
+        println!("Found {} commits with a Change-Id", found.len());
+
+        // 2. Look up their changes, and label them
+        for batch in found.chunks(BATCH_SIZE) {
+            let ids: BTreeSet<&str> = batch.iter().map(|(_, id)| &id[..]).collect();
+            let ids: Vec<&str> = ids.into_iter().collect();
+            let changes = gerrit
+                .changes(&ids)
+                .context("looking up changes on Gerrit")?;
+
+            let mut note_map = HashMap::new();
+            let mut relabelled = HashSet::new();
+            for (id, change_id) in batch {
+                let change = match gerrit_change(&changes, change_id, &masters) {
+                    Some(change) => change,
+                    None => {
+                        println!("Skipping commit {}: no change {} on Gerrit", id, change_id);
+                        continue;
+                    }
+                };
+                relabelled.insert(Note::prefix(Format::Text, &url_prefix, change.number));
+                note_map.entry(*id).or_insert(vec![]).push(Note {
+                    format: Format::Text,
+                    url_prefix: &url_prefix,
+                    pr_num: change.number,
+                    position: Some((1, 1)),
+                    info: Some(change.info.clone()),
+                });
+            }
+            println!("Labelling {} commits with their changes", note_map.len());
+            writer.write(&repo, &identity, &opts.notes_ref, note_map, &relabelled)?;
+        }
+    }
+
+    if let Some(ref remote) = opts.push_notes {
+        push_notes(&repo, &identity, remote, &opts.notes_ref, NotesMerge::Ours)?;
+        println!("Pushed {} to {}", opts.notes_ref, remote);
+    }
+    // Written last, so that it describes the notes as pushed, where ours
+    // were kept over any others
+    new_state.notes_commit = repo
+        .refname_to_id(&opts.notes_ref)
+        .ok()
+        .map(|id| id.to_string());
+    new_state.save(&State::path(&repo, &opts.notes_ref))?;
     Ok(())
 }
 
-fn create_notes(
+/// The change with the given Change-Id which a commit on one of the master
+/// branches belongs to: that merged into one of them if there is one, as
+/// a change may be cherry-picked onto several branches, or else any
+fn gerrit_change<'c>(
+    changes: &'c [Change],
+    change_id: &str,
+    masters: &[String],
+) -> Option<&'c Change> {
+    let on_master = |change: &Change| {
+        masters.iter().any(|master| {
+            master == &change.branch || master.ends_with(&format!("/{}", change.branch))
+        })
+    };
+    let mut candidates = changes
+        .iter()
+        .filter(|change| change.change_id == change_id);
+    let first = candidates.clone().next()?;
+    Some(
+        candidates
+            .find(|change| change.info.merged.is_some() && on_master(change))
+            .unwrap_or(first),
+    )
+}
+
+/// Finds the merge commits in the first-parent history of each master
+/// branch, as given on the command line with its tip, back to its tip in
+/// `old_tips`, if any
+///
+/// Returns them, newest first, and the tips to pass as `old_tips` next time.
+fn merge_commits(
     repo: &Repository,
-    mut note_map: HashMap<git2::Oid, Vec<Note>>,
+    masters: &[String],
+    tips: &[git2::Oid],
+    old_tips: Option<&BTreeMap<String, String>>,
+) -> anyhow::Result<(Vec<git2::Oid>, BTreeMap<String, String>)> {
+    let mut walk = repo.revwalk().context("walking master branches")?;
+    walk.simplify_first_parent()?;
+    let mut new_tips = BTreeMap::new();
+    for (master, &tip) in masters.iter().zip(tips) {
+        walk.push(tip)?;
+        let old_tip = old_tips
+            .and_then(|tips| tips.get(master))
+            .and_then(|tip| git2::Oid::from_str(tip).ok());
+        // The branch may have been force-pushed since
+        if let Some(old_tip) = old_tip.filter(|&id| repo.find_commit(id).is_ok()) {
+            walk.hide(old_tip)?;
+        }
+        new_tips.insert(master.clone(), tip.to_string());
+    }
+
+    let mut ret = vec![];
+    for id in walk {
+        let id = id.context("walking master branches")?;
+        let commit = repo
+            .find_commit(id)
+            .context("looking up commit on master branch")?;
+        if commit.parent_count() > 1 {
+            ret.push(id);
+        }
+    }
+    Ok((ret, new_tips))
+}
+
+/// Removes or marks the labels of PRs whose refs have gone, given by number
+/// and the tip they were last labelled at, on their commits which are not
+/// in any master branch
+///
+/// Commits which have reached a master branch keep their labels, as the PR
+/// was presumably merged.
+fn close_prs(
+    repo: &Repository,
+    identity: &Identity,
+    notes_ref: &str,
+    masters: &BaseBranches,
+    label: &Label,
+    closed: &[(&usize, &String)],
+    action: Closed,
 ) -> anyhow::Result<()> {
-    // 4. Build note commit
-    let mut note_tree = repo.treebuilder(None).expect("getting a treebuilder");
-    for (id, notes) in &mut note_map {
-        let mut msg = String::new();
-        notes.sort_by_key(|note| (note.url_prefix, note.pr_num));
+    let mut closed_map: HashMap<git2::Oid, Vec<String>> = HashMap::new();
+    for &(&number, tip) in closed {
+        let commits = git2::Oid::from_str(tip)
+            .ok()
+            .filter(|&tip| repo.find_commit(tip).is_ok())
+            .map(|tip| masters.branch_commits(repo, tip))
+            .transpose()?
+            .flatten();
+        match commits {
+            Some(commits) => {
+                for id in commits {
+                    closed_map.entry(id).or_default().push(Note::prefix(
+                        label.format,
+                        &label.url_prefix,
+                        number,
+                    ));
+                }
+            }
+            None => println!(
+                "Leaving the labels of closed PR #{}: its commits are gone",
+                number
+            ),
+        }
+    }
+
+    let message = "Labels of closed PRs updated by label-pr utility";
+    let n_commits = remove_labels(
+        repo, identity, notes_ref, label, closed_map, action, message,
+    )?;
+    if n_commits > 0 {
+        println!(
+            "Done. {} the labels of closed PRs on {} commits",
+            match action {
+                Closed::Remove => "Removed",
+                Closed::Mark => "Marked",
+            },
+            n_commits
+        );
+    }
+    Ok(())
+}
 
-        for note in notes {
-            msg.push_str(&format!(
-                "PR: {}{} ({}/{})\n",
-                note.url_prefix, note.pr_num, note.commit_index, note.n_commits
+/// Removes the labels of PRs which have been force-pushed, given by number,
+/// the tip they were last labelled at and their new tip, from the commits
+/// which are no longer in them
+fn drop_pushed_out(
+    repo: &Repository,
+    identity: &Identity,
+    notes_ref: &str,
+    masters: &BaseBranches,
+    label: &Label,
+    pushed: &[(usize, git2::Oid, git2::Oid)],
+) -> anyhow::Result<()> {
+    let mut dropped_map: HashMap<git2::Oid, Vec<String>> = HashMap::new();
+    for &(number, old_tip, tip) in pushed {
+        if repo.find_commit(old_tip).is_err() {
+            continue;
+        }
+        let old_commits = masters.branch_commits(repo, old_tip)?.unwrap_or_default();
+        let commits: HashSet<_> = masters
+            .branch_commits(repo, tip)?
+            .into_iter()
+            .flatten()
+            .collect();
+        for id in old_commits.into_iter().filter(|id| !commits.contains(id)) {
+            dropped_map.entry(id).or_default().push(Note::prefix(
+                label.format,
+                &label.url_prefix,
+                number,
             ));
         }
-        let blob_id = repo.blob(msg.as_bytes()).expect("writing note blob");
-        note_tree
-            .insert(id.to_string(), blob_id, 33188)
-            .expect("putting note blob in tree");
-    }
-    let note_tree_id = note_tree.write().expect("writing new note tree");
-    let note_tree = repo
-        .find_tree(note_tree_id)
-        .expect("reading tree we just wrote");
-
-    // 5. Put notes into repo
-    let mut parents = vec![];
-    if let Ok(existing) = repo.find_reference("refs/notes/label-pr") {
-        parents.push(
-            existing
-                .peel_to_commit()
-                .expect("existing ref points to commit"),
+    }
+
+    let message = "Labels of force-pushed PRs updated by label-pr utility";
+    let n_commits = remove_labels(
+        repo,
+        identity,
+        notes_ref,
+        label,
+        dropped_map,
+        Closed::Remove,
+        message,
+    )?;
+    if n_commits > 0 {
+        println!(
+            "Done. Removed the labels of force-pushed PRs from {} commits",
+            n_commits
         );
     }
-    let parents_refs: Vec<&_> = parents.iter().collect(); // we need a slice of references for `commit()`
-    let sig = Signature::now("PR Labeller", "prlabel@wpsoftware.net").expect("create sig");
-    let comm_id = repo
-        .commit(
-            Some("refs/notes/label-pr"),
-            &sig,
-            &sig,
-            "Notes added by label-pr utility",
-            &note_tree,
-            &parents_refs,
+    Ok(())
+}
+
+/// Removes or marks the lines of commits' notes starting with the given
+/// prefixes, committing the result with the given message
+///
+/// Returns the number of commits whose notes changed.
+fn remove_labels(
+    repo: &Repository,
+    identity: &Identity,
+    notes_ref: &str,
+    label: &Label,
+    prefix_map: HashMap<git2::Oid, Vec<String>>,
+    action: Closed,
+    message: &str,
+) -> anyhow::Result<usize> {
+    let mut texts = vec![];
+    for (id, prefixes) in prefix_map {
+        let existing = match repo.find_note(Some(notes_ref), id) {
+            Ok(note) => note.message().unwrap_or("").to_owned(),
+            Err(_) => continue,
+        };
+        let mut msg = String::new();
+        for line in existing.lines() {
+            if !prefixes.iter().any(|prefix| line.starts_with(prefix)) {
+                msg.push_str(line);
+                msg.push('\n');
+            } else if action == Closed::Mark {
+                match label.format {
+                    Format::Text if !line.ends_with(CLOSED_MARK) => {
+                        msg.push_str(line);
+                        msg.push_str(CLOSED_MARK);
+                    }
+                    Format::Json if !line.ends_with(CLOSED_FIELD) => {
+                        msg.push_str(line.strip_suffix('}').unwrap_or(line));
+                        msg.push(',');
+                        msg.push_str(CLOSED_FIELD);
+                    }
+                    _ => msg.push_str(line),
+                }
+                msg.push('\n');
+            }
+        }
+        if msg != existing {
+            texts.push((id, Some(msg).filter(|msg| !msg.is_empty())));
+        }
+    }
+    if !texts.is_empty() {
+        update_notes(repo, identity, notes_ref, message, &texts)?;
+    }
+    Ok(texts.len())
+}
+
+/// How the labels are written
+enum Writer<'repo> {
+    /// Into a new tree of notes, committed after each batch
+    Full(git2::TreeBuilder<'repo>),
+    /// On top of the existing notes, replacing the labels of updated PRs
+    Incremental,
+}
+
+impl Writer<'_> {
+    /// Writes the labels of a batch of PRs, replacing any earlier labels
+    /// on the same commits starting with one of `relabelled`
+    fn write(
+        &mut self,
+        repo: &Repository,
+        identity: &Identity,
+        notes_ref: &str,
+        note_map: HashMap<git2::Oid, Vec<Note>>,
+        relabelled: &HashSet<String>,
+    ) -> anyhow::Result<()> {
+        // 4. Build note text, keeping the labels of other PRs, and
+        // 5. Put notes into repo
+        let message = "Notes added by label-pr utility";
+        let comm_id = match *self {
+            Writer::Full(ref mut builder) => {
+                let notes: Vec<_> = note_map
+                    .into_iter()
+                    .map(|(id, notes)| {
+                        let existing = builder
+                            .get(id.to_string())
+                            .ok()
+                            .flatten()
+                            .map(|entry| entry.id());
+                        (id, notes, existing)
+                    })
+                    .collect();
+                let blobs = par_map(repo, notes, |repo, (id, notes, existing)| {
+                    let existing = existing
+                        .and_then(|blob_id| repo.find_blob(blob_id).ok())
+                        .map(|blob| String::from_utf8_lossy(blob.content()).into_owned());
+                    let msg = note_text(existing.as_deref(), notes, relabelled);
+                    (id, repo.blob(msg.as_bytes()).expect("writing note blob"))
+                });
+                for (id, blob_id) in blobs {
+                    builder
+                        .insert(id.to_string(), blob_id, 33188)
+                        .expect("putting note blob in tree");
+                }
+                let note_tree_id = builder.write().expect("writing new note tree");
+                let note_tree = repo
+                    .find_tree(note_tree_id)
+                    .expect("reading tree we just wrote");
+
+                let mut parents = vec![];
+                if let Ok(existing) = repo.find_reference(notes_ref) {
+                    parents.push(
+                        existing
+                            .peel_to_commit()
+                            .expect("existing ref points to commit"),
+                    );
+                }
+                let parents_refs: Vec<&_> = parents.iter().collect(); // we need a slice of references for `commit()`
+                let sig = identity.signature(None)?;
+                repo.commit(
+                    Some(notes_ref),
+                    &sig,
+                    &sig,
+                    message,
+                    &note_tree,
+                    &parents_refs,
+                )
+                .expect("committing new notes");
+                identity
+                    .sign_ref(repo, notes_ref)
+                    .with_context(|| format!("signing {}", notes_ref))?
+            }
+            Writer::Incremental => {
+                let texts = par_map(repo, note_map.into_iter().collect(), |repo, (id, notes)| {
+                    let existing = repo
+                        .find_note(Some(notes_ref), id)
+                        .ok()
+                        .and_then(|note| note.message().map(str::to_owned));
+                    (id, Some(note_text(existing.as_deref(), notes, relabelled)))
+                });
+                // Which signs the notes commit itself
+                update_notes(repo, identity, notes_ref, message, &texts)?;
+                repo.refname_to_id(notes_ref)
+                    .with_context(|| format!("looking up {}", notes_ref))?
+            }
+        };
+
+        println!("Done. Added new notes as {}", comm_id);
+        Ok(())
+    }
+}
+
+/// The text of a commit's note, keeping the lines of its existing note other
+/// than those starting with one of `relabelled`, then adding those of its
+/// labels for PRs which no kept line is about
+fn note_text(existing: Option<&str>, mut notes: Vec<Note>, relabelled: &HashSet<String>) -> String {
+    let mut msg = String::new();
+    let mut kept = HashSet::new();
+    for line in existing.unwrap_or("").lines() {
+        let prefix = Note::prefix_of(line);
+        if !prefix.is_some_and(|prefix| relabelled.contains(prefix)) {
+            kept.extend(prefix);
+            msg.push_str(line);
+            msg.push('\n');
+        }
+    }
+    notes.sort_by_key(|note| (note.url_prefix, note.pr_num));
+    for note in notes {
+        if !kept.contains(&Note::prefix(note.format, note.url_prefix, note.pr_num)[..]) {
+            msg.push_str(&note.line());
+            msg.push('\n');
+        }
+    }
+    msg
+}
+
+/// Maps the items over rayon's threads, each with its own handle on the
+/// repository, as a `Repository` cannot be shared between threads
+fn par_map<T: Send, R: Send>(
+    repo: &Repository,
+    items: Vec<T>,
+    f: impl Fn(&Repository, T) -> R + Sync,
+) -> Vec<R> {
+    let path = repo.path().to_owned();
+    items
+        .into_par_iter()
+        .map_init(
+            || Repository::open(&path).expect("reopening repository"),
+            |repo, item| f(repo, item),
         )
-        .expect("committing new notes");
+        .collect()
+}
 
-    println!("Done. Added new notes as {}", comm_id);
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn force_push() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let sig = git2::Signature::now("A", "a@b").unwrap();
+        // Commits adding a file to the tree of `parent`
+        let commit = |parent: Option<git2::Oid>, file: &str| -> git2::Oid {
+            let parent = parent.map(|id| repo.find_commit(id).unwrap());
+            let mut builder = repo
+                .treebuilder(parent.as_ref().map(|p| p.tree().unwrap()).as_ref())
+                .unwrap();
+            let blob = repo.blob(file.as_bytes()).unwrap();
+            builder.insert(file, blob, 0o100644).unwrap();
+            let tree = repo.find_tree(builder.write().unwrap()).unwrap();
+            let parents: Vec<_> = parent.iter().collect();
+            repo.commit(None, &sig, &sig, file, &tree, &parents)
+                .unwrap()
+        };
+        let master = commit(None, "master");
+        let p1 = commit(Some(master), "p1");
+        let p2 = commit(Some(p1), "p2");
+        let p3 = commit(Some(p2), "p3");
+        let notes_ref = DEFAULT_NOTES_REF;
+        for (id, note) in &[
+            (p1, "PR: https://x/1 (1/3)\n"),
+            (p2, "PR: https://x/1 (2/3)\nPR: https://x/2 (1/1)\n"),
+            (p3, "PR: https://x/1 (3/3)\n"),
+        ] {
+            repo.note(&sig, &sig, Some(notes_ref), *id, note, false)
+                .unwrap();
+        }
+
+        // PR 1 is force-pushed to just its first commit
+        let label: Label = "pr:master:https://x/".parse().unwrap();
+        let identity = Identity::from_repo(&repo, "A", "a@b");
+        let masters = BaseBranches::new(vec![master]);
+        drop_pushed_out(
+            &repo,
+            &identity,
+            notes_ref,
+            &masters,
+            &label,
+            &[(1, p3, p1)],
+        )
+        .unwrap();
+        let note = |id| {
+            repo.find_note(Some(notes_ref), id)
+                .ok()
+                .and_then(|note| note.message().map(str::to_owned))
+        };
+        assert_eq!(note(p1).as_deref(), Some("PR: https://x/1 (1/3)\n"));
+        assert_eq!(note(p2).as_deref(), Some("PR: https://x/2 (1/1)\n"));
+        assert_eq!(note(p3), None);
+    }
 }
@@ -0,0 +1,57 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+//! Shared code for the git utilities
+
+pub mod cargo;
+pub mod checks;
+pub mod config;
+pub mod container;
+pub mod coverage;
+pub mod disk;
+pub mod forge;
+pub mod gc;
+pub mod gerrit;
+pub mod git;
+pub mod gitea;
+pub mod github;
+pub mod gitlab;
+pub mod html;
+pub mod http;
+pub mod identity;
+pub mod job;
+pub mod limits;
+pub mod lock;
+pub mod metrics;
+pub mod notes;
+pub mod notify;
+pub mod output;
+pub mod pr;
+pub mod protocol;
+#[cfg(feature = "sqlite")]
+pub mod queue;
+pub mod report;
+#[cfg(feature = "sqlite")]
+pub mod results;
+pub mod resume;
+pub mod runs;
+pub mod sandbox;
+pub mod serve;
+pub mod tui;
+pub mod webhook;
+pub mod worker;
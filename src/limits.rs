@@ -0,0 +1,303 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+//! Limiting the resources used by jobs
+//!
+//! Memory is limited with a cgroup (v2) for each job when one can be
+//! created, so that the limit covers every process the job starts, and if
+//! it is exceeded the kernel kills the job rather than some other process.
+//! Otherwise the address space of each process is limited with setrlimit.
+//! CPU time can only be limited with setrlimit, so applies to each process
+//! separately.
+
+use anyhow::Context;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Where the cgroup (v2) hierarchy is mounted
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Limits on the resources used by each job
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// Memory, in bytes
+    memory: Option<u64>,
+    /// CPU time of each process, in seconds
+    cpu_time: Option<u64>,
+    /// Cgroup under which to create one for each job, if memory is limited
+    /// that way
+    cgroup: Option<PathBuf>,
+}
+
+impl Limits {
+    /// Creates a set of limits
+    ///
+    /// If `cgroup` is given, each job's cgroup is created under it, and it
+    /// is an error if that cannot be done. Otherwise they are created under
+    /// check-pr's own cgroup if possible, and setrlimit is used if not.
+    pub fn new(
+        memory: Option<u64>,
+        cpu_time: Option<u64>,
+        cgroup: Option<PathBuf>,
+    ) -> anyhow::Result<Self> {
+        if !cfg!(target_os = "linux") && (memory.is_some() || cpu_time.is_some()) {
+            return Err(anyhow::Error::msg(
+                "memory and CPU time limits are only supported on Linux",
+            ));
+        }
+        let cgroup = match (memory, cgroup) {
+            (None, _) => None,
+            (Some(_), Some(dir)) => {
+                if !has_memory_controller(&dir) {
+                    return Err(anyhow::Error::msg(format!(
+                        "cgroup {} does not have the memory controller enabled for its \
+                         children (see its cgroup.subtree_control)",
+                        dir.to_string_lossy(),
+                    )));
+                }
+                Some(dir)
+            }
+            (Some(_), None) => own_cgroup().filter(|dir| has_memory_controller(dir)),
+        };
+        Ok(Limits {
+            memory,
+            cpu_time,
+            cgroup,
+        })
+    }
+
+    /// Applies the limits to a process which has just been started
+    ///
+    /// Anything the process started before this is not limited, so this
+    /// should be called as soon as possible. If the job was given its own
+    /// cgroup it is returned, and removed again when dropped.
+    pub fn apply(&self, pid: u32) -> anyhow::Result<Option<Cgroup>> {
+        if let Some(seconds) = self.cpu_time {
+            // The soft limit sends SIGXCPU, which says what happened; the
+            // hard limit sends SIGKILL in case that is caught
+            set_rlimit(pid, Resource::CpuTime, seconds, seconds + 1)
+                .context("limiting CPU time")?;
+        }
+        let memory = match self.memory {
+            Some(memory) => memory,
+            None => return Ok(None),
+        };
+        match self.cgroup {
+            Some(ref parent) => Cgroup::create(parent, pid, memory).map(Some),
+            None => {
+                set_rlimit(pid, Resource::AddressSpace, memory, memory)
+                    .context("limiting memory")?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// If a process was killed for exceeding a limit, says which
+    pub fn exceeded(
+        &self,
+        status: subprocess::ExitStatus,
+        cgroup: Option<&Cgroup>,
+    ) -> Option<String> {
+        if let Some(msg) = cgroup.and_then(Cgroup::exceeded) {
+            return Some(msg);
+        }
+        match (self.cpu_time, status) {
+            (Some(seconds), subprocess::ExitStatus::Signaled(sig))
+                if i32::from(sig) == libc::SIGXCPU =>
+            {
+                Some(format!("CPU time limit of {}s exceeded", seconds))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A cgroup created for a single job, which is removed when dropped
+pub struct Cgroup {
+    path: PathBuf,
+    memory: u64,
+}
+
+impl Cgroup {
+    /// Creates a cgroup with a memory limit, and moves a process into it
+    fn create(parent: &Path, pid: u32, memory: u64) -> anyhow::Result<Self> {
+        let path = parent.join(format!("check-pr-{}-{}", std::process::id(), pid));
+        fs::create_dir(&path)
+            .with_context(|| format!("creating cgroup {}", path.to_string_lossy()))?;
+        // Constructed straight away so that it is removed again on error
+        let ret = Cgroup { path, memory };
+        ret.write("memory.max", &memory.to_string())?;
+        // Swapping would only make a job which needs too much memory slower
+        // to fail; ignore errors as not every kernel accounts for swap
+        let _ = fs::write(ret.path.join("memory.swap.max"), "0");
+        ret.write("cgroup.procs", &pid.to_string())?;
+        Ok(ret)
+    }
+
+    /// Writes to one of the cgroup's files
+    fn write(&self, file: &str, value: &str) -> anyhow::Result<()> {
+        let path = self.path.join(file);
+        fs::write(&path, value)
+            .with_context(|| format!("writing {} to {}", value, path.to_string_lossy()))
+    }
+
+    /// If a process in the cgroup was killed for running out of memory,
+    /// says so
+    fn exceeded(&self) -> Option<String> {
+        let events = fs::read_to_string(self.path.join("memory.events")).ok()?;
+        let kills: u64 = events
+            .lines()
+            .find_map(|line| line.strip_prefix("oom_kill "))?
+            .trim()
+            .parse()
+            .ok()?;
+        if kills > 0 {
+            Some(format!(
+                "memory limit of {} exceeded",
+                format_size(self.memory)
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        // Ignore errors: this fails if the job left processes behind, in
+        // which case the cgroup keeps limiting them
+        let _ = fs::remove_dir(&self.path);
+    }
+}
+
+/// The cgroup (v2) check-pr is running in, if the hierarchy is mounted in
+/// the usual place
+fn own_cgroup() -> Option<PathBuf> {
+    let root = Path::new(CGROUP_ROOT);
+    if !root.join("cgroup.controllers").exists() {
+        return None;
+    }
+    let cgroups = fs::read_to_string("/proc/self/cgroup").ok()?;
+    let path = cgroups.lines().find_map(|line| line.strip_prefix("0::"))?;
+    Some(root.join(path.trim_start_matches('/')))
+}
+
+/// Whether a cgroup's children can be given memory limits
+fn has_memory_controller(cgroup: &Path) -> bool {
+    fs::read_to_string(cgroup.join("cgroup.subtree_control"))
+        .map(|controllers| controllers.split_whitespace().any(|c| c == "memory"))
+        .unwrap_or(false)
+}
+
+/// A resource which can be limited with setrlimit
+#[derive(Copy, Clone, Debug)]
+enum Resource {
+    CpuTime,
+    AddressSpace,
+}
+
+/// Sets a resource limit of another process
+#[cfg(target_os = "linux")]
+fn set_rlimit(pid: u32, resource: Resource, soft: u64, hard: u64) -> std::io::Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: soft as libc::rlim_t,
+        rlim_max: hard as libc::rlim_t,
+    };
+    let pid = pid as libc::pid_t;
+    let ret = unsafe {
+        match resource {
+            Resource::CpuTime => libc::prlimit(pid, libc::RLIMIT_CPU, &limit, std::ptr::null_mut()),
+            Resource::AddressSpace => {
+                libc::prlimit(pid, libc::RLIMIT_AS, &limit, std::ptr::null_mut())
+            }
+        }
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+/// Sets a resource limit of another process
+///
+/// Never called, as limits are refused when they are set up.
+#[cfg(not(target_os = "linux"))]
+fn set_rlimit(_: u32, _: Resource, _: u64, _: u64) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "resource limits are only supported on Linux",
+    ))
+}
+
+/// Parses an amount of memory, in bytes or with a binary suffix such as
+/// `512M` or `4GiB`
+pub fn parse_size(s: &str) -> anyhow::Result<u64> {
+    let s = s.trim();
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, suffix) = s.split_at(digits);
+    let number: u64 = number
+        .parse()
+        .with_context(|| format!("parsing size {:?}", s))?;
+    let shift = match suffix.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        "T" | "TB" | "TIB" => 40,
+        _ => {
+            return Err(anyhow::Error::msg(format!(
+                "unknown unit {:?} in size {:?}",
+                suffix, s
+            )))
+        }
+    };
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| anyhow::Error::msg(format!("size {:?} is too big", s)))
+}
+
+/// Formats an amount of memory in the largest unit it is a whole number of
+fn format_size(bytes: u64) -> String {
+    for (shift, unit) in [(40, "T"), (30, "G"), (20, "M"), (10, "K")] {
+        if bytes != 0 && bytes.trailing_zeros() >= shift {
+            return format!("{}{}", bytes >> shift, unit);
+        }
+    }
+    bytes.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("1234").unwrap(), 1234);
+        assert_eq!(parse_size("512M").unwrap(), 512 << 20);
+        assert_eq!(parse_size("4 GiB").unwrap(), 4 << 30);
+        assert_eq!(parse_size("2kb").unwrap(), 2048);
+        assert!(parse_size("4X").is_err());
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("99999999999T").is_err());
+
+        assert_eq!(format_size(4 << 30), "4G");
+        assert_eq!(format_size(1536 << 20), "1536M");
+        assert_eq!(format_size(1000), "1000");
+    }
+}
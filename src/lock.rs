@@ -0,0 +1,142 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+//! Making sure that only one check-pr runs on a repository at a time
+//!
+//! Two runs on the same repository would race to write notes, and to
+//! install toolchains. Each run therefore holds an advisory lock on a file
+//! in the repository's git directory for as long as it lasts; the lock is
+//! released by the kernel when the process exits, however it exits.
+
+use anyhow::Context;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use crate::say;
+
+/// Name of the lock file, in the repository's git directory
+const FILE_NAME: &str = "check-pr.lock";
+
+/// A held lock on a repository, released when dropped
+pub struct RunLock {
+    _file: File,
+}
+
+impl RunLock {
+    /// Where the lock for runs on a repository is kept
+    pub fn path(repo: &git2::Repository) -> PathBuf {
+        repo.path().join(FILE_NAME)
+    }
+
+    /// Takes the lock, or fails with a message saying who holds it, unless
+    /// `wait` is set, in which case this waits for them to finish
+    pub fn acquire(path: &Path, wait: bool) -> anyhow::Result<Self> {
+        // Not truncated until the lock is held, since it says who holds it
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("opening lock file {}", path.to_string_lossy()))?;
+        let locked =
+            lock(&file, false).with_context(|| format!("locking {}", path.to_string_lossy()))?;
+        if !locked {
+            let mut holder = String::new();
+            let _ = file.read_to_string(&mut holder);
+            let holder = match holder.trim() {
+                "" => "another check-pr",
+                holder => holder,
+            }
+            .to_owned();
+            if !wait {
+                return Err(anyhow::Error::msg(format!(
+                    "{} is already running on this repository (lock file {}); \
+                     pass --wait-for-lock to wait for it to finish",
+                    holder,
+                    path.to_string_lossy(),
+                )));
+            }
+            say!(Normal, "Waiting for {} to finish", holder);
+            lock(&file, true).with_context(|| format!("locking {}", path.to_string_lossy()))?;
+        }
+
+        file.set_len(0)
+            .and_then(|_| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| {
+                writeln!(
+                    file,
+                    "check-pr (pid {}, started {})",
+                    process::id(),
+                    time::now_utc().rfc3339(),
+                )
+            })
+            .with_context(|| format!("writing lock file {}", path.to_string_lossy()))?;
+        Ok(RunLock { _file: file })
+    }
+}
+
+/// Takes an exclusive lock on a file, returning whether it was taken, which
+/// it always is if `wait` is set
+#[cfg(unix)]
+fn lock(file: &File, wait: bool) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    let op = if wait {
+        libc::LOCK_EX
+    } else {
+        libc::LOCK_EX | libc::LOCK_NB
+    };
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), op) } == 0 {
+            return Ok(true);
+        }
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EWOULDBLOCK) => return Ok(false),
+            Some(libc::EINTR) => continue,
+            _ => return Err(err),
+        }
+    }
+}
+
+/// Takes an exclusive lock on a file
+///
+/// Locking is only supported on Unix, so elsewhere runs are not kept apart.
+#[cfg(not(unix))]
+fn lock(_: &File, _: bool) -> io::Result<bool> {
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exclusive() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(FILE_NAME);
+        let lock = RunLock::acquire(&path, false).unwrap();
+        let err = RunLock::acquire(&path, false).err().unwrap().to_string();
+        assert!(err.starts_with(&format!("check-pr (pid {}, ", process::id())));
+        drop(lock);
+        RunLock::acquire(&path, false).unwrap();
+    }
+}
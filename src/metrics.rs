@@ -0,0 +1,272 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+//! Metrics about the jobs being run, in the Prometheus text format
+//!
+//! Metrics are gathered from the events given to `update`, and served over
+//! HTTP by `serve` to whoever asks, whatever path they ask for.
+
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::Read;
+use std::net::TcpListener;
+use std::sync::Mutex;
+use std::thread;
+
+use crate::http::respond;
+use crate::output::{Event, Outcome};
+
+/// Upper bounds of the buckets of the job duration histograms, in seconds
+const DURATION_BUCKETS: [f64; 9] = [
+    10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0, 3600.0, 7200.0,
+];
+
+/// Metrics gathered since the program started
+static METRICS: Mutex<Metrics> = Mutex::new(Metrics::new());
+
+/// Counts of the durations of jobs
+#[derive(Clone, Debug, Default)]
+struct Histogram {
+    /// Count of durations no longer than each bucket's bound
+    buckets: [u64; DURATION_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, secs: f64) {
+        for (bound, bucket) in DURATION_BUCKETS.iter().zip(&mut self.buckets) {
+            if secs <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+}
+
+/// Everything we count
+#[derive(Clone, Debug)]
+struct Metrics {
+    queued: u64,
+    running: u64,
+    /// Completed jobs, keyed by outcome
+    completed: BTreeMap<&'static str, u64>,
+    /// Failed jobs, keyed by check hash
+    failures: BTreeMap<String, u64>,
+    /// Durations of jobs which were run, keyed by check hash
+    durations: BTreeMap<String, Histogram>,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            queued: 0,
+            running: 0,
+            completed: BTreeMap::new(),
+            failures: BTreeMap::new(),
+            durations: BTreeMap::new(),
+        }
+    }
+
+    fn update(&mut self, event: &Event) {
+        match *event {
+            Event::Queued { count, .. } => self.queued += count as u64,
+            Event::Started { .. } => self.running += 1,
+            Event::Finished { check, outcome, .. } => {
+                let (name, time) = match outcome {
+                    Outcome::Pass(time) => ("pass", Some(time)),
+                    Outcome::Fail(time) => ("fail", Some(time)),
+                    Outcome::Cached => ("cached", None),
+                    Outcome::Skipped => ("skipped", None),
+                };
+                *self.completed.entry(name).or_insert(0) += 1;
+                if let Some(time) = time {
+                    self.running = self.running.saturating_sub(1);
+                    self.durations
+                        .entry(check.to_owned())
+                        .or_default()
+                        .observe(time.as_secs_f64());
+                }
+                if name == "fail" {
+                    *self.failures.entry(check.to_owned()).or_insert(0) += 1;
+                }
+            }
+            Event::NoteWritten { .. } | Event::RunFinished { .. } => {}
+        }
+    }
+
+    /// Renders the metrics, plus the given disk usage, in the Prometheus
+    /// text exposition format
+    fn render(&self, temp_disk_bytes: u64) -> String {
+        let mut ret = String::new();
+        header(
+            &mut ret,
+            "rsgit_jobs_queued_total",
+            "counter",
+            "Jobs found which need to be run",
+        );
+        let _ = writeln!(ret, "rsgit_jobs_queued_total {}", self.queued);
+        header(
+            &mut ret,
+            "rsgit_jobs_running",
+            "gauge",
+            "Jobs currently running",
+        );
+        let _ = writeln!(ret, "rsgit_jobs_running {}", self.running);
+        header(
+            &mut ret,
+            "rsgit_jobs_completed_total",
+            "counter",
+            "Jobs completed, by outcome",
+        );
+        for (outcome, n) in &self.completed {
+            let _ = writeln!(
+                ret,
+                "rsgit_jobs_completed_total{{outcome=\"{}\"}} {}",
+                outcome, n
+            );
+        }
+        header(
+            &mut ret,
+            "rsgit_job_failures_total",
+            "counter",
+            "Jobs failed, by check config hash",
+        );
+        for (check, n) in &self.failures {
+            let _ = writeln!(ret, "rsgit_job_failures_total{{check=\"{}\"}} {}", check, n);
+        }
+        header(
+            &mut ret,
+            "rsgit_job_duration_seconds",
+            "histogram",
+            "Time taken by jobs, by check config hash",
+        );
+        for (check, hist) in &self.durations {
+            for (bound, n) in DURATION_BUCKETS.iter().zip(&hist.buckets) {
+                let _ = writeln!(
+                    ret,
+                    "rsgit_job_duration_seconds_bucket{{check=\"{}\",le=\"{}\"}} {}",
+                    check, bound, n
+                );
+            }
+            let _ = writeln!(
+                ret,
+                "rsgit_job_duration_seconds_bucket{{check=\"{}\",le=\"+Inf\"}} {}\n\
+                 rsgit_job_duration_seconds_sum{{check=\"{}\"}} {}\n\
+                 rsgit_job_duration_seconds_count{{check=\"{}\"}} {}",
+                check, hist.count, check, hist.sum, check, hist.count
+            );
+        }
+        header(
+            &mut ret,
+            "rsgit_temp_disk_bytes",
+            "gauge",
+            "Disk space used by temporary repositories",
+        );
+        let _ = writeln!(ret, "rsgit_temp_disk_bytes {}", temp_disk_bytes);
+        ret
+    }
+}
+
+/// Writes the help and type lines which introduce a metric
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+}
+
+/// Counts an event in the metrics
+pub fn update(event: &Event) {
+    METRICS.lock().unwrap().update(event);
+}
+
+/// Starts serving the metrics over HTTP on the given address, in the
+/// background
+pub fn serve(addr: &str) -> anyhow::Result<()> {
+    let listener =
+        TcpListener::bind(addr).with_context(|| format!("listening for metrics on {}", addr))?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            // We answer every request the same way, so only read enough of
+            // it to not reset the connection by closing it unread
+            let mut request = [0; 4096];
+            let _ = stream.read(&mut request);
+            let metrics = METRICS.lock().unwrap().clone();
+            let body = metrics.render(crate::git::temp_disk_usage());
+            let _ = respond(&mut stream, "200 OK", "text/plain; version=0.0.4", &body);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn render() {
+        let mut metrics = Metrics::new();
+        let commit = git2::Oid::zero();
+        let finished = |outcome| Event::Finished {
+            commit,
+            check: "abc",
+            toolchain: "stable",
+            job: "build",
+            outcome,
+            error: None,
+            log: None,
+            annotations: &[],
+        };
+        metrics.update(&Event::Queued {
+            commit,
+            toolchain: "stable",
+            count: 3,
+        });
+        for _ in 0..2 {
+            metrics.update(&Event::Started {
+                commit,
+                check: "abc",
+                toolchain: "stable",
+                job: "build",
+            });
+        }
+        metrics.update(&finished(Outcome::Fail(Duration::from_secs(45))));
+        metrics.update(&finished(Outcome::Cached));
+
+        let text = metrics.render(1234);
+        for line in &[
+            "rsgit_jobs_queued_total 3",
+            "rsgit_jobs_running 1",
+            "rsgit_jobs_completed_total{outcome=\"cached\"} 1",
+            "rsgit_jobs_completed_total{outcome=\"fail\"} 1",
+            "rsgit_job_failures_total{check=\"abc\"} 1",
+            "rsgit_job_duration_seconds_bucket{check=\"abc\",le=\"30\"} 0",
+            "rsgit_job_duration_seconds_bucket{check=\"abc\",le=\"60\"} 1",
+            "rsgit_job_duration_seconds_bucket{check=\"abc\",le=\"+Inf\"} 1",
+            "rsgit_job_duration_seconds_sum{check=\"abc\"} 45",
+            "rsgit_temp_disk_bytes 1234",
+        ] {
+            assert!(text.lines().any(|l| l == *line), "missing {}", line);
+        }
+    }
+}
@@ -0,0 +1,816 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+//! The entries in the notes which record the results of jobs on commits
+//!
+//! The notes on a commit are a line with a timestamp and the version of the
+//! format, followed by one entry per line. Each entry is the description of
+//! a job, followed by any number of fields of the form ` # <key> <value>`,
+//! e.g.
+//! ```text
+//! 2021-06-01T12:00:00Z # version 2
+//! stable cargo build '--features=' # warnings 2 # outcome pass # check 1f0e... # toolchain 8a3c... # duration 12.3 # at 2021-06-01T12:00:00Z # rsgit 0.1.0
+//! ```
+//! Fields are only recognized at the end of the line, with keys and values
+//! of the right form, so a job description which itself contains ` # `, as
+//! an example run with arguments can, is not mistaken for one. Entries
+//! written by older versions, with at most a warning count, are read as
+//! passes with nothing else known about them.
+//!
+//! Notes without a version are version 1, whose entries may lack fields
+//! which every entry has in version 2. Whenever a note is rewritten it is
+//! brought up to the current version, as `check-runs migrate-notes` does
+//! for every note at once, but notes in a version newer than this one
+//! knows about are never rewritten.
+
+use crate::identity::Identity;
+use anyhow::Context;
+use git2::Repository;
+use std::fmt;
+use std::time::Duration;
+
+/// Version of the format of the notes written by this version of rsgit
+pub const NOTES_VERSION: u32 = 2;
+
+/// Separates the timestamp and the version in the first line of a note
+const VERSION_MARKER: &str = " # version ";
+
+/// Notes entry recorded for commits skipped due to a skip marker
+pub const SKIPPED_NOTE: &str = "skipped: commit message contains skip marker";
+
+/// Starts the entries for failed jobs written before entries had an
+/// `outcome`, which were followed by the time and the job's description
+const LEGACY_FAILED_MARKER: &str = "failed ";
+
+/// Separates a job's description and the fields of its entry
+const FIELD_MARKER: &str = " # ";
+
+/// Format of the times in entries
+const TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%SZ";
+
+/// Number of times to try pushing a notes ref, merging in the remote's
+/// notes each time the push is rejected
+const PUSH_ATTEMPTS: usize = 3;
+
+/// Ref to which notes are written before they are committed in one go
+const SCRATCH_REF: &str = "refs/rsgit/notes-scratch";
+
+/// Whether a job recorded in the notes passed or failed
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum NoteOutcome {
+    Pass,
+    Fail,
+}
+
+/// A job's entry in the notes
+#[derive(Clone, Debug, PartialEq)]
+pub struct NoteEntry {
+    /// Description of the job, which identifies it among a commit's jobs
+    pub job: String,
+    pub outcome: NoteOutcome,
+    /// Number of compiler warnings, if known
+    pub warnings: Option<usize>,
+    /// Hash of the configuration of the check the job was part of
+    pub check: Option<String>,
+    /// Hash of the version of the toolchain the job was run with
+    pub toolchain: Option<String>,
+    /// How long the job took
+    pub duration: Option<Duration>,
+    /// When the job finished
+    pub at: Option<time::Timespec>,
+    /// Version of rsgit which ran the job
+    pub rsgit: Option<String>,
+}
+
+impl NoteEntry {
+    /// An entry for a job with the given outcome, with nothing else known
+    /// about it
+    pub fn new(job: &str, outcome: NoteOutcome) -> Self {
+        NoteEntry {
+            job: job.to_owned(),
+            outcome,
+            warnings: None,
+            check: None,
+            toolchain: None,
+            duration: None,
+            at: None,
+            rsgit: None,
+        }
+    }
+
+    /// An entry for a job which has just finished, run by this version of
+    /// rsgit
+    pub fn finished(job: &str, outcome: NoteOutcome, duration: Duration) -> Self {
+        NoteEntry {
+            duration: Some(duration),
+            at: Some(time::get_time()),
+            rsgit: Some(env!("CARGO_PKG_VERSION").to_owned()),
+            ..NoteEntry::new(job, outcome)
+        }
+    }
+
+    /// Reads an entry, or returns `None` if the line is not for a job
+    pub fn parse(line: &str) -> Option<Self> {
+        if line == SKIPPED_NOTE {
+            return None;
+        }
+        if let Some((at, job)) = line
+            .strip_prefix(LEGACY_FAILED_MARKER)
+            .and_then(|rest| rest.split_once(": "))
+        {
+            if let Some(at) = parse_time(at) {
+                return Some(NoteEntry {
+                    at: Some(at),
+                    ..NoteEntry::new(job, NoteOutcome::Fail)
+                });
+            }
+        }
+
+        let mut entry = NoteEntry::new("", NoteOutcome::Pass);
+        let mut job = line;
+        while let Some(idx) = job.rfind(FIELD_MARKER) {
+            if !entry.set_field(&job[idx + FIELD_MARKER.len()..]) {
+                break;
+            }
+            job = &job[..idx];
+        }
+        entry.job = job.to_owned();
+        Some(entry)
+    }
+
+    /// Sets the field given as `<key> <value>`, returning whether it is a
+    /// field at all
+    fn set_field(&mut self, field: &str) -> bool {
+        let (key, value) = match field.split_once(' ') {
+            Some(kv) => kv,
+            None => return false,
+        };
+        let is_hex = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_hexdigit());
+        match key {
+            "warnings" if self.warnings.is_none() => match value.parse() {
+                Ok(n) => self.warnings = Some(n),
+                Err(_) => return false,
+            },
+            "outcome" => match value {
+                "pass" => self.outcome = NoteOutcome::Pass,
+                "fail" => self.outcome = NoteOutcome::Fail,
+                _ => return false,
+            },
+            "check" if is_hex(value) => self.check = Some(value.to_owned()),
+            "toolchain" if is_hex(value) => self.toolchain = Some(value.to_owned()),
+            "duration" => match parse_duration(value) {
+                Some(duration) => self.duration = Some(duration),
+                None => return false,
+            },
+            "at" => match parse_time(value) {
+                Some(at) => self.at = Some(at),
+                None => return false,
+            },
+            "rsgit" if !value.is_empty() && !value.contains(char::is_whitespace) => {
+                self.rsgit = Some(value.to_owned())
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// Whether the job passed
+    pub fn passed(&self) -> bool {
+        self.outcome == NoteOutcome::Pass
+    }
+}
+
+impl fmt::Display for NoteEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.job)?;
+        if let Some(warnings) = self.warnings {
+            write!(f, "{}warnings {}", FIELD_MARKER, warnings)?;
+        }
+        let outcome = match self.outcome {
+            NoteOutcome::Pass => "pass",
+            NoteOutcome::Fail => "fail",
+        };
+        write!(f, "{}outcome {}", FIELD_MARKER, outcome)?;
+        if let Some(ref check) = self.check {
+            write!(f, "{}check {}", FIELD_MARKER, check)?;
+        }
+        if let Some(ref toolchain) = self.toolchain {
+            write!(f, "{}toolchain {}", FIELD_MARKER, toolchain)?;
+        }
+        if let Some(duration) = self.duration {
+            write!(f, "{}duration {:.1}", FIELD_MARKER, duration.as_secs_f64())?;
+        }
+        if let Some(at) = self.at {
+            write!(f, "{}at {}", FIELD_MARKER, time::at_utc(at).rfc3339())?;
+        }
+        if let Some(ref rsgit) = self.rsgit {
+            write!(f, "{}rsgit {}", FIELD_MARKER, rsgit)?;
+        }
+        Ok(())
+    }
+}
+
+/// The first line of a note
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NoteHeader {
+    /// When the note was written, in RFC 3339 format
+    pub timestamp: String,
+    /// Version of the format of the note
+    pub version: u32,
+}
+
+impl NoteHeader {
+    /// The header of a note written now
+    pub fn now() -> Self {
+        NoteHeader {
+            timestamp: time::now_utc().rfc3339().to_string(),
+            version: NOTES_VERSION,
+        }
+    }
+
+    /// Reads a header, taking one without a version to be version 1
+    pub fn parse(line: &str) -> Self {
+        match line
+            .rsplit_once(VERSION_MARKER)
+            .and_then(|(timestamp, version)| Some((timestamp, version.parse().ok()?)))
+        {
+            Some((timestamp, version)) => NoteHeader {
+                timestamp: timestamp.to_owned(),
+                version,
+            },
+            None => NoteHeader {
+                timestamp: line.to_owned(),
+                version: 1,
+            },
+        }
+    }
+
+    /// When the note was written, if the timestamp can be read
+    pub fn time(&self) -> Option<time::Timespec> {
+        parse_time(&self.timestamp)
+    }
+
+    /// Whether the note is in a version of the format we know, so may be
+    /// rewritten
+    pub fn is_known(&self) -> bool {
+        self.version <= NOTES_VERSION
+    }
+}
+
+impl fmt::Display for NoteHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}{}", self.timestamp, VERSION_MARKER, self.version)
+    }
+}
+
+/// Rewrites an entry in the current version of the format
+pub fn upgrade_entry(line: &str) -> String {
+    match NoteEntry::parse(line) {
+        Some(entry) => entry.to_string(),
+        None => line.to_owned(),
+    }
+}
+
+/// Splits the text of a note into its header and its entries, in the
+/// current version of the format unless the note is in a newer one
+fn split_note(text: &str) -> (NoteHeader, Vec<String>) {
+    let mut lines = text.lines();
+    let header = NoteHeader::parse(lines.next().unwrap_or(""));
+    let entries = if header.is_known() {
+        lines.map(upgrade_entry).collect()
+    } else {
+        lines.map(str::to_owned).collect()
+    };
+    (header, entries)
+}
+
+/// Reads a number of seconds, with up to nine decimal places, exactly
+fn parse_duration(s: &str) -> Option<Duration> {
+    let (secs, frac) = s.split_once('.').unwrap_or((s, "0"));
+    let is_digits =
+        |s: &str| !s.is_empty() && s.len() <= 9 && s.bytes().all(|b| b.is_ascii_digit());
+    if !is_digits(frac) || secs.is_empty() || !secs.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let nanos = format!("{:0<9}", frac).parse().ok()?;
+    Some(Duration::new(secs.parse().ok()?, nanos))
+}
+
+/// Reads a time as written in entries
+fn parse_time(s: &str) -> Option<time::Timespec> {
+    time::strptime(s, TIME_FORMAT)
+        .ok()
+        .map(|tm| tm.to_timespec())
+}
+
+/// Reads the entries of the notes on a commit, not including the timestamp
+pub fn read_notes(repo: &git2::Repository, notes_ref: &str, id: git2::Oid) -> Vec<String> {
+    repo.find_note(Some(notes_ref), id)
+        .ok()
+        .as_ref()
+        .and_then(|note| note.message())
+        .map(|text| text.lines().skip(1).map(str::to_owned).collect())
+        .unwrap_or_default()
+}
+
+/// Reads the header of the notes on a commit, if there are any
+pub fn read_header(repo: &git2::Repository, notes_ref: &str, id: git2::Oid) -> Option<NoteHeader> {
+    let note = repo.find_note(Some(notes_ref), id).ok()?;
+    Some(NoteHeader::parse(note.message()?.lines().next()?))
+}
+
+/// Reads the entries for jobs in the notes on a commit
+pub fn read_entries(repo: &git2::Repository, notes_ref: &str, id: git2::Oid) -> Vec<NoteEntry> {
+    read_notes(repo, notes_ref, id)
+        .iter()
+        .filter_map(|line| NoteEntry::parse(line))
+        .collect()
+}
+
+/// Adds entries to the entries of a note, replacing those for the same job
+/// unless they are for a pass more recent than the new entry
+pub fn merge_entries(entries: &mut Vec<String>, new: &[String]) {
+    for line in new {
+        if let Some(entry) = NoteEntry::parse(line) {
+            entries.retain(|old| {
+                NoteEntry::parse(old)
+                    .is_none_or(|old| old.job != entry.job || (old.passed() && old.at > entry.at))
+            });
+            // What is left for the job is a newer pass
+            let kept = entries
+                .iter()
+                .filter_map(|old| NoteEntry::parse(old))
+                .any(|old| old.job == entry.job);
+            if kept {
+                continue;
+            }
+        }
+        if !entries.contains(line) {
+            entries.push(line.clone());
+        }
+    }
+}
+
+/// The text of a note, from its header and entries
+pub fn note_text(header: &NoteHeader, entries: &[String]) -> String {
+    let mut text = format!("{}\n", header);
+    for entry in entries {
+        text.push_str(entry);
+        text.push('\n');
+    }
+    text
+}
+
+/// How to combine our notes with the notes a remote has which we do not
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum NotesMerge {
+    /// Merge the entries of each note, as written by `check-pr`, keeping
+    /// the later timestamp
+    Entries,
+    /// Keep our notes as they are, as `label-pr` writes all of them afresh
+    /// every time
+    Ours,
+}
+
+impl NotesMerge {
+    /// Merges the remote's note on an object into ours, if we have one
+    fn merge(self, ours: Option<&str>, theirs: &str) -> Option<String> {
+        let ours = match (self, ours) {
+            (NotesMerge::Ours, _) => return None,
+            (NotesMerge::Entries, None) => return Some(theirs.to_owned()),
+            (NotesMerge::Entries, Some(ours)) if ours == theirs => return None,
+            (NotesMerge::Entries, Some(ours)) => ours,
+        };
+        let (our_header, mut entries) = split_note(ours);
+        let (their_header, their_entries) = split_note(theirs);
+        // A note in a version we do not know is left as it is
+        if !our_header.is_known() {
+            return None;
+        }
+        if !their_header.is_known() {
+            return Some(theirs.to_owned());
+        }
+        merge_entries(&mut entries, &their_entries);
+        let header = NoteHeader {
+            // Timestamps are RFC 3339, all in UTC, so compare as strings
+            timestamp: our_header.timestamp.max(their_header.timestamp),
+            version: NOTES_VERSION,
+        };
+        Some(note_text(&header, &entries))
+    }
+}
+
+/// The ref to which a remote's notes are fetched
+fn tracking_ref(remote: &str, notes_ref: &str) -> String {
+    format!(
+        "refs/rsgit/notes/{}/{}",
+        remote,
+        notes_ref.trim_start_matches("refs/notes/")
+    )
+}
+
+/// Fetches a notes ref from a remote and merges its notes into ours,
+/// returning whether there was anything to merge
+pub fn fetch_notes(
+    repo: &Repository,
+    identity: &Identity,
+    remote: &str,
+    notes_ref: &str,
+) -> anyhow::Result<bool> {
+    let dir = crate::git::repo_dir(repo);
+    let tracking_ref = tracking_ref(remote, notes_ref);
+    // A remote nobody has pushed notes to yet has nothing to merge
+    let status = subprocess::Exec::cmd("git")
+        .arg("-C")
+        .arg(dir)
+        .args(&["ls-remote", "--quiet", "--exit-code", remote, notes_ref])
+        .stdin(subprocess::NullFile)
+        .stdout(subprocess::NullFile)
+        .join()
+        .with_context(|| format!("running git ls-remote for {}", notes_ref))?;
+    match status {
+        subprocess::ExitStatus::Exited(0) => {}
+        subprocess::ExitStatus::Exited(2) => return Ok(false),
+        status => {
+            return Err(anyhow::Error::msg(format!(
+                "looking up {} on {} exited with {:?}",
+                notes_ref, remote, status
+            )))
+        }
+    }
+    crate::serve::fetch(dir, remote, &[format!("+{}:{}", notes_ref, tracking_ref)])
+        .with_context(|| format!("fetching {} from {}", notes_ref, remote))?;
+    merge_notes(
+        repo,
+        identity,
+        notes_ref,
+        &tracking_ref,
+        NotesMerge::Entries,
+    )
+}
+
+/// Pushes a notes ref to a remote, first merging in the notes it has which
+/// we do not, if someone else has pushed to it since we last did
+pub fn push_notes(
+    repo: &Repository,
+    identity: &Identity,
+    remote: &str,
+    notes_ref: &str,
+    how: NotesMerge,
+) -> anyhow::Result<()> {
+    if repo.find_reference(notes_ref).is_err() {
+        return Ok(());
+    }
+    let dir = crate::git::repo_dir(repo);
+    let tracking_ref = tracking_ref(remote, notes_ref);
+    for _ in 0..PUSH_ATTEMPTS {
+        let capture = subprocess::Exec::cmd("git")
+            .arg("-C")
+            .arg(dir)
+            .args(&["push", "--quiet", remote])
+            .arg(format!("{}:{}", notes_ref, notes_ref))
+            .stdin(subprocess::NullFile)
+            .stdout(subprocess::NullFile)
+            .stderr(subprocess::Redirection::Pipe)
+            .capture()
+            .with_context(|| format!("running git push for {}", notes_ref))?;
+        if capture.success() {
+            return Ok(());
+        }
+
+        crate::serve::fetch(dir, remote, &[format!("+{}:{}", notes_ref, tracking_ref)])
+            .with_context(|| format!("fetching {} from {} to merge", notes_ref, remote))?;
+        if !merge_notes(repo, identity, notes_ref, &tracking_ref, how)? {
+            // Ours already has everything theirs does, so the push failed
+            // for some other reason
+            return Err(anyhow::Error::msg(format!(
+                "pushing {} to {} failed: {}",
+                notes_ref,
+                remote,
+                capture.stderr_str().trim()
+            )));
+        }
+    }
+    Err(anyhow::Error::msg(format!(
+        "pushing {} to {} was rejected {} times, as it kept moving",
+        notes_ref, remote, PUSH_ATTEMPTS
+    )))
+}
+
+/// Merges the notes under one ref into those under another, returning
+/// whether there was anything to merge
+fn merge_notes(
+    repo: &Repository,
+    identity: &Identity,
+    notes_ref: &str,
+    theirs_ref: &str,
+    how: NotesMerge,
+) -> anyhow::Result<bool> {
+    let theirs = repo.find_reference(theirs_ref)?.peel_to_commit()?;
+    let ours = match repo.find_reference(notes_ref) {
+        Ok(reference) => reference.peel_to_commit()?,
+        Err(_) => {
+            repo.reference(notes_ref, theirs.id(), false, "remote notes")?;
+            return Ok(true);
+        }
+    };
+    if ours.id() == theirs.id() || repo.graph_descendant_of(ours.id(), theirs.id())? {
+        return Ok(false);
+    }
+    if repo.graph_descendant_of(theirs.id(), ours.id())? {
+        repo.reference_matching(
+            notes_ref,
+            theirs.id(),
+            true,
+            ours.id(),
+            "fast-forward to remote notes",
+        )?;
+        return Ok(true);
+    }
+
+    let mut merged = vec![];
+    for note in repo.notes(Some(theirs_ref))? {
+        let (_, id) = note?;
+        let their_note = repo.find_note(Some(theirs_ref), id)?;
+        let their_text = their_note.message().unwrap_or("");
+        let our_note = repo.find_note(Some(notes_ref), id).ok();
+        let our_text = our_note.as_ref().and_then(|note| note.message());
+        if let Some(text) = how.merge(our_text, their_text) {
+            merged.push((id, Some(text)));
+        }
+    }
+    commit_notes(
+        repo,
+        identity,
+        notes_ref,
+        &ours,
+        &[&ours, &theirs],
+        "Merged remote notes",
+        &merged,
+    )
+    .with_context(|| format!("merging {} into {}", theirs_ref, notes_ref))?;
+    Ok(true)
+}
+
+/// Rewrites every note under a ref in an older version of the format in the
+/// current one, returning how many there were
+///
+/// Only notes written by `check-pr` should be migrated, not those written
+/// by `label-pr`, whose lines are not entries for jobs.
+pub fn migrate_notes(
+    repo: &Repository,
+    identity: &Identity,
+    notes_ref: &str,
+    dry_run: bool,
+) -> anyhow::Result<usize> {
+    let tip = match repo.find_reference(notes_ref) {
+        Ok(reference) => reference.peel_to_commit()?,
+        Err(_) => return Ok(0),
+    };
+    let mut migrated = vec![];
+    for note in repo.notes(Some(notes_ref))? {
+        let (_, id) = note?;
+        let note = repo.find_note(Some(notes_ref), id)?;
+        let (header, entries) = match note.message() {
+            Some(text) => split_note(text),
+            None => continue,
+        };
+        if header.version < NOTES_VERSION {
+            let header = NoteHeader {
+                version: NOTES_VERSION,
+                ..header
+            };
+            migrated.push((id, Some(note_text(&header, &entries))));
+        }
+    }
+    if !dry_run && !migrated.is_empty() {
+        let message = format!("Migrated notes to version {}", NOTES_VERSION);
+        commit_notes(
+            repo,
+            identity,
+            notes_ref,
+            &tip,
+            &[&tip],
+            &message,
+            &migrated,
+        )
+        .with_context(|| format!("migrating notes under {}", notes_ref))?;
+    }
+    Ok(migrated.len())
+}
+
+/// Removes every note under a ref which `keep` does not want to keep, given
+/// the object it is on and its text, returning how many were removed out of
+/// how many there were
+pub fn prune_notes<F>(
+    repo: &Repository,
+    identity: &Identity,
+    notes_ref: &str,
+    dry_run: bool,
+    mut keep: F,
+) -> anyhow::Result<(usize, usize)>
+where
+    F: FnMut(git2::Oid, &str) -> bool,
+{
+    let tip = match repo.find_reference(notes_ref) {
+        Ok(reference) => reference.peel_to_commit()?,
+        Err(_) => return Ok((0, 0)),
+    };
+    let mut removed = vec![];
+    let mut total = 0;
+    for note in repo.notes(Some(notes_ref))? {
+        let (_, id) = note?;
+        let note = repo.find_note(Some(notes_ref), id)?;
+        total += 1;
+        if !keep(id, note.message().unwrap_or("")) {
+            removed.push((id, None));
+        }
+    }
+    if !dry_run && !removed.is_empty() {
+        let message = format!("Removed {} notes", removed.len());
+        commit_notes(repo, identity, notes_ref, &tip, &[&tip], &message, &removed)
+            .with_context(|| format!("removing notes under {}", notes_ref))?;
+    }
+    Ok((removed.len(), total))
+}
+
+/// Writes notes on commits, or removes them where no text is given, in one
+/// new commit on top of `notes_ref`, which must already exist
+pub fn update_notes(
+    repo: &Repository,
+    identity: &Identity,
+    notes_ref: &str,
+    message: &str,
+    notes: &[(git2::Oid, Option<String>)],
+) -> anyhow::Result<()> {
+    let tip = repo
+        .find_reference(notes_ref)
+        .and_then(|rf| rf.peel_to_commit())
+        .with_context(|| format!("looking up {}", notes_ref))?;
+    commit_notes(repo, identity, notes_ref, &tip, &[&tip], message, notes)
+        .with_context(|| format!("writing notes under {}", notes_ref))
+}
+
+/// Writes notes on top of those of a notes commit, or removes them where no
+/// text is given, and replaces that commit at the tip of `notes_ref` with a
+/// single one of the result
+fn commit_notes(
+    repo: &Repository,
+    identity: &Identity,
+    notes_ref: &str,
+    base: &git2::Commit,
+    parents: &[&git2::Commit],
+    message: &str,
+    notes: &[(git2::Oid, Option<String>)],
+) -> anyhow::Result<()> {
+    let sig = identity.signature(None)?;
+    repo.reference(SCRATCH_REF, base.id(), true, "writing notes")?;
+    let commit = (|| -> anyhow::Result<git2::Oid> {
+        for (id, text) in notes {
+            match text {
+                Some(text) => {
+                    repo.note(&sig, &sig, Some(SCRATCH_REF), *id, text, true)?;
+                }
+                None => repo.note_delete(*id, Some(SCRATCH_REF), &sig, &sig)?,
+            }
+        }
+        let tree = repo.find_reference(SCRATCH_REF)?.peel_to_tree()?;
+        Ok(repo.commit(None, &sig, &sig, message, &tree, parents)?)
+    })();
+    repo.find_reference(SCRATCH_REF)?.delete()?;
+    repo.reference_matching(notes_ref, commit?, true, base.id(), message)?;
+    identity.sign_ref(repo, notes_ref)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn warning_notes() {
+        let job = "stable cargo build '--features='";
+        let note = NoteEntry {
+            warnings: Some(3),
+            ..NoteEntry::new(job, NoteOutcome::Pass)
+        }
+        .to_string();
+        assert_eq!(
+            note,
+            "stable cargo build '--features=' # warnings 3 # outcome pass"
+        );
+        let entry = NoteEntry::parse(&note).unwrap();
+        assert_eq!(entry.job, job);
+        assert_eq!(entry.warnings, Some(3));
+        let entry = NoteEntry::parse(job).unwrap();
+        assert_eq!(entry.job, job);
+        assert_eq!(entry.warnings, None);
+        assert!(entry.passed());
+
+        let example = "stable cargo run '--example x' -- '# warnings many' # env ''";
+        let entry = NoteEntry::parse(example).unwrap();
+        assert_eq!(entry.job, example);
+        assert_eq!(entry.warnings, None);
+
+        assert_eq!(NoteEntry::parse(SKIPPED_NOTE), None);
+    }
+
+    #[test]
+    fn fields() {
+        let job = "nightly cargo run '--example x' -- '# warnings many' # env ''";
+        let entry = NoteEntry {
+            warnings: Some(0),
+            check: Some("1f0e".repeat(10)),
+            toolchain: Some("8a3c5d2e01b4".to_owned()),
+            ..NoteEntry::finished(job, NoteOutcome::Fail, Duration::from_millis(12345))
+        };
+        let line = entry.to_string();
+        let parsed = NoteEntry::parse(&line).unwrap();
+        assert_eq!(parsed.job, job);
+        assert_eq!(parsed.outcome, NoteOutcome::Fail);
+        assert_eq!(parsed.warnings, Some(0));
+        assert_eq!(parsed.check, entry.check);
+        assert_eq!(parsed.toolchain, entry.toolchain);
+        assert_eq!(parsed.duration, Some(Duration::from_millis(12300)));
+        assert_eq!(parsed.at, entry.at.map(|at| time::Timespec::new(at.sec, 0)));
+        assert_eq!(parsed.rsgit.as_deref(), Some(env!("CARGO_PKG_VERSION")));
+        assert_eq!(parsed.to_string(), line);
+
+        // As the previous version wrote failures
+        let legacy = NoteEntry::parse("failed 2021-06-01T12:00:00Z: stable cargo build").unwrap();
+        assert_eq!(legacy.job, "stable cargo build");
+        assert!(!legacy.passed());
+        assert_eq!(legacy.at, parse_time("2021-06-01T12:00:00Z"));
+    }
+
+    #[test]
+    fn merge() {
+        let ours = "2021-06-02T00:00:00Z # version 2\n\
+                    a # warnings 0 # outcome pass\n\
+                    b # outcome fail # at 2021-06-01T00:00:00Z\n";
+        let theirs = "2021-06-03T00:00:00Z\n\
+                      b # outcome pass # at 2021-06-01T12:00:00Z\n\
+                      c\n";
+        assert_eq!(
+            NotesMerge::Entries.merge(Some(ours), theirs).unwrap(),
+            "2021-06-03T00:00:00Z # version 2\n\
+             a # warnings 0 # outcome pass\n\
+             b # outcome pass # at 2021-06-01T12:00:00Z\n\
+             c # outcome pass\n",
+        );
+        assert_eq!(NotesMerge::Entries.merge(Some(ours), ours), None);
+        assert_eq!(
+            NotesMerge::Entries.merge(None, theirs).as_deref(),
+            Some(theirs)
+        );
+        assert_eq!(NotesMerge::Ours.merge(Some(ours), theirs), None);
+        assert_eq!(NotesMerge::Ours.merge(None, theirs), None);
+
+        let newer = "2021-06-04T00:00:00Z # version 99\nd # flavour strawberry\n";
+        assert_eq!(
+            NotesMerge::Entries.merge(Some(ours), newer).as_deref(),
+            Some(newer)
+        );
+        assert_eq!(NotesMerge::Entries.merge(Some(newer), theirs), None);
+    }
+
+    #[test]
+    fn versions() {
+        let header = NoteHeader::parse("2021-06-01T12:00:00Z");
+        assert_eq!(header.timestamp, "2021-06-01T12:00:00Z");
+        assert_eq!(header.version, 1);
+        let header = NoteHeader::parse("2021-06-01T12:00:00Z # version 2");
+        assert_eq!(header.version, NOTES_VERSION);
+        assert_eq!(header.to_string(), "2021-06-01T12:00:00Z # version 2");
+        assert!(!NoteHeader::parse("2021-06-01T12:00:00Z # version 3").is_known());
+
+        let (header, entries) = split_note(
+            "2021-06-01T12:00:00Z\n\
+             stable cargo build '--features=' # warnings 1\n\
+             failed 2021-06-01T11:00:00Z: stable cargo test '--features='\n",
+        );
+        assert_eq!(header.version, 1);
+        assert_eq!(
+            entries,
+            [
+                "stable cargo build '--features=' # warnings 1 # outcome pass",
+                "stable cargo test '--features=' # outcome fail # at 2021-06-01T11:00:00Z",
+            ]
+        );
+    }
+}
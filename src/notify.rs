@@ -0,0 +1,537 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+//! Notifications sent when a run completes
+//!
+//! Notifiers are configured as a list in the settings, like checks, e.g.
+//!
+//! ```toml
+//! [[notify]]
+//! type = "email"
+//! to = ["me@example.com"]
+//!
+//! [[notify]]
+//! type = "irc"
+//! server = "irc.libera.chat:6667"
+//! channel = "#rust-bitcoin"
+//! ```
+//!
+//! Emails contain the full summary of the run, while chat notifiers just
+//! post a single line.
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::http::{percent_encode, Request};
+use crate::output::{FinishedJob, Outcome, Summary};
+use crate::pr::PullRequest;
+
+/// How many lines of each failed job's output to include in a notification
+const FAILURE_LINES: usize = 30;
+
+/// The outcome of a run, to notify people of
+pub struct RunReport<'a> {
+    /// Path of the repository checked
+    pub repo: &'a str,
+    /// The PR tip checked
+    pub tip: &'a str,
+    pub summary: &'a Summary,
+    pub jobs: &'a [FinishedJob],
+    /// The error the run failed with, if it did
+    pub error: Option<&'a str>,
+}
+
+impl RunReport<'_> {
+    /// One-line description of the outcome
+    pub fn subject(&self) -> String {
+        format!(
+            "check-pr {} on {} in {}",
+            if self.error.is_some() {
+                "FAILED"
+            } else {
+                "passed"
+            },
+            self.tip,
+            self.repo,
+        )
+    }
+
+    /// Short description of the outcome for chat, e.g.
+    /// `PR #123 tip abc1234: 14/14 checks passed`
+    pub fn one_line(&self) -> String {
+        // PR refs look like `pr/123/head`; otherwise just name the ref
+        let mut ret = match PullRequest::number_from_ref(self.tip) {
+            Some(num) => format!("PR #{}", num),
+            None => self.tip.to_owned(),
+        };
+        // Commits are ordered from the base, so the last one is the tip
+        if let Some((commit, _)) = self.summary.rows().last() {
+            let _ = write!(ret, " tip {:.7}", commit);
+        }
+        let (mut passed, mut total) = (0, 0);
+        for (_, outcomes) in self.summary.rows() {
+            for outcome in outcomes.into_iter().flatten() {
+                match outcome {
+                    Outcome::Pass(..) | Outcome::Cached => {
+                        passed += 1;
+                        total += 1;
+                    }
+                    Outcome::Fail(..) => total += 1,
+                    Outcome::Skipped => {}
+                }
+            }
+        }
+        let _ = write!(ret, ": {}/{} checks passed", passed, total);
+        if self.error.is_some() && passed == total {
+            ret.push_str(", but the run failed");
+        }
+        ret
+    }
+
+    /// Full description of the outcome: the summary table, then the end
+    /// of the output of each failed job
+    pub fn body(&self) -> String {
+        let mut ret = self.summary.render(false);
+        for job in self.jobs {
+            if let Outcome::Fail(..) = job.outcome {
+                let error = job.error.as_deref().unwrap_or("");
+                let lines: Vec<&str> = error.lines().collect();
+                let _ = writeln!(ret, "\nFailure: {:.7} {}", job.commit, job.job);
+                for line in &lines[lines.len().saturating_sub(FAILURE_LINES)..] {
+                    let _ = writeln!(ret, "    {}", line);
+                }
+            }
+        }
+        if let Some(error) = self.error {
+            let _ = write!(ret, "\n{}\n", error);
+        }
+        ret
+    }
+}
+
+/// Somewhere to send notifications
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", tag = "type")]
+pub enum Notifier {
+    Email(Email),
+    Irc(Irc),
+    Matrix(Matrix),
+}
+
+impl Notifier {
+    /// Sends a notification of the outcome of a run
+    pub fn send(&self, report: &RunReport) -> anyhow::Result<()> {
+        match *self {
+            Notifier::Email(ref email) => email.send(report),
+            Notifier::Irc(ref irc) => irc.send(&report.one_line()),
+            Notifier::Matrix(ref matrix) => matrix.send(&report.one_line()),
+        }
+    }
+}
+
+fn default_sendmail() -> String {
+    "sendmail".to_owned()
+}
+
+fn default_from() -> String {
+    "check-pr@localhost".to_owned()
+}
+
+/// Email notifications, sent with `sendmail` or directly to an SMTP server
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Email {
+    to: Vec<String>,
+    #[serde(default = "default_from")]
+    from: String,
+    /// Address of an SMTP server to send mail to, e.g. `localhost:25`. It
+    /// must accept mail without authentication or TLS, so this is only
+    /// really useful for a local relay; otherwise use `sendmail`.
+    #[serde(default)]
+    smtp: Option<String>,
+    /// Sendmail-compatible command to send mail with, if `smtp` is not set
+    #[serde(default = "default_sendmail")]
+    sendmail: String,
+}
+
+impl Email {
+    /// The full message, headers and all
+    fn message(&self, report: &RunReport) -> String {
+        let mut ret = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\n\
+             Content-Type: text/plain; charset=utf-8\r\n\r\n",
+            self.from,
+            self.to.join(", "),
+            report.subject(),
+            time::now().rfc822z(),
+        );
+        for line in report.body().lines() {
+            ret.push_str(line);
+            ret.push_str("\r\n");
+        }
+        ret
+    }
+
+    fn send(&self, report: &RunReport) -> anyhow::Result<()> {
+        let message = self.message(report);
+        match self.smtp {
+            Some(ref server) => smtp_send(server, &self.from, &self.to, &message),
+            None => {
+                // -t takes the recipients from the headers, -i stops a lone
+                // "." in the body from ending the message
+                let exec = subprocess::Exec::cmd(&self.sendmail).arg("-t").arg("-i");
+                run_with_input(exec, &self.sendmail, &message)
+            }
+        }
+    }
+}
+
+/// Runs a command with the given input, failing with whatever it printed
+/// if it does not succeed
+fn run_with_input(exec: subprocess::Exec, name: &str, input: &str) -> anyhow::Result<()> {
+    let output = exec
+        .stdin(input)
+        .stdout(subprocess::Redirection::Pipe)
+        .stderr(subprocess::Redirection::Merge)
+        .capture()
+        .with_context(|| format!("running {}", name))?;
+    if output.success() {
+        Ok(())
+    } else {
+        let mut msg = format!("{} exited with {:?}", name, output.exit_status);
+        let complaint = output.stdout_str();
+        if !complaint.trim().is_empty() {
+            msg.push_str(": ");
+            msg.push_str(complaint.trim_end());
+        }
+        Err(anyhow::Error::msg(msg))
+    }
+}
+
+fn default_nick() -> String {
+    "check-pr".to_owned()
+}
+
+/// IRC notifications, posted to a channel by a client which connects just
+/// long enough to do so
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Irc {
+    /// Address of the server, e.g. `irc.libera.chat:6667`. TLS is not
+    /// supported.
+    server: String,
+    channel: String,
+    #[serde(default = "default_nick")]
+    nick: String,
+    /// Server password, if one is needed
+    #[serde(default, skip_serializing)]
+    password: Option<String>,
+}
+
+impl Irc {
+    fn send(&self, line: &str) -> anyhow::Result<()> {
+        let stream = TcpStream::connect(&self.server)
+            .with_context(|| format!("connecting to IRC server {}", self.server))?;
+        stream
+            .set_read_timeout(Some(Duration::from_secs(60)))
+            .context("setting IRC read timeout")?;
+        let mut reader = BufReader::new(stream.try_clone().context("cloning IRC stream")?);
+        let mut writer = stream;
+        let server = &self.server;
+        let command = |writer: &mut TcpStream, line: &str| {
+            write!(writer, "{}\r\n", line)
+                .with_context(|| format!("sending {} to {}", redact_irc(line), server))
+        };
+
+        if let Some(ref password) = self.password {
+            command(&mut writer, &format!("PASS {}", password))?;
+        }
+        command(&mut writer, &format!("NICK {}", self.nick))?;
+        command(&mut writer, &format!("USER {} 0 * :check-pr", self.nick))?;
+        // Wait to be welcomed (numeric 001) before saying anything
+        loop {
+            let mut reply = String::new();
+            let n = reader
+                .read_line(&mut reply)
+                .with_context(|| format!("reading from IRC server {}", server))?;
+            if n == 0 {
+                return Err(anyhow::Error::msg(format!(
+                    "IRC server {} closed the connection",
+                    server
+                )));
+            }
+            let mut words = reply.split_whitespace();
+            let first = words.next().unwrap_or("");
+            if first == "PING" {
+                let token = reply.trim_end()["PING".len()..].trim_start();
+                command(&mut writer, &format!("PONG {}", token))?;
+                continue;
+            }
+            match words.next() {
+                Some("001") => break,
+                // Errors registering, e.g. 433 for the nick being taken
+                Some(code) if code.starts_with('4') => {
+                    return Err(anyhow::Error::msg(format!(
+                        "IRC server {} replied {}",
+                        server,
+                        reply.trim_end()
+                    )))
+                }
+                _ => {}
+            }
+        }
+        command(&mut writer, &format!("JOIN {}", self.channel))?;
+        command(&mut writer, &format!("PRIVMSG {} :{}", self.channel, line))?;
+        command(&mut writer, "QUIT")?;
+        // Let the server close the connection, so that it has seen
+        // everything before we go
+        let mut rest = vec![];
+        let _ = std::io::Read::read_to_end(&mut reader, &mut rest);
+        Ok(())
+    }
+}
+
+/// An IRC command as it may be shown in errors, without any password
+fn redact_irc(line: &str) -> &str {
+    if line.starts_with("PASS ") {
+        "PASS <password>"
+    } else {
+        line
+    }
+}
+
+fn default_curl() -> String {
+    "curl".to_owned()
+}
+
+/// Matrix notifications, posted to a room with the client-server API
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Matrix {
+    /// URL of the homeserver, e.g. `https://matrix.org`
+    homeserver: String,
+    /// ID of the room, e.g. `!abcdef:matrix.org`, which the user whose
+    /// access token is given must already have joined
+    room: String,
+    /// Access token of the user to post as; if not given,
+    /// `MATRIX_ACCESS_TOKEN` is used
+    #[serde(default, skip_serializing)]
+    access_token: Option<String>,
+    /// Command to make HTTP requests with, which must accept curl's options
+    #[serde(default = "default_curl")]
+    curl: String,
+}
+
+impl Matrix {
+    /// URL to send a message to, with the given transaction ID
+    fn url(&self, txn: &str) -> String {
+        format!(
+            "{}/_matrix/client/v3/rooms/{}/send/m.room.message/{}",
+            self.homeserver.trim_end_matches('/'),
+            percent_encode(&self.room),
+            percent_encode(txn),
+        )
+    }
+
+    /// The access token to authenticate with
+    fn access_token(&self) -> anyhow::Result<String> {
+        match self.access_token {
+            Some(ref token) => Ok(token.clone()),
+            None => env::var("MATRIX_ACCESS_TOKEN").map_err(|_| {
+                anyhow::Error::msg(
+                    "no Matrix access token: set access-token in [[notify]], \
+                     or MATRIX_ACCESS_TOKEN",
+                )
+            }),
+        }
+    }
+
+    fn send(&self, line: &str) -> anyhow::Result<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let txn = format!("check-pr-{}-{}", std::process::id(), now.as_nanos());
+        let body = serde_json::json!({ "msgtype": "m.notice", "body": line });
+        Request::new(&self.curl, "PUT", self.url(&txn))
+            .secret_header(format!("Authorization: Bearer {}", self.access_token()?))
+            .json(&body)
+            .send()
+            .with_context(|| format!("posting to Matrix room {}", self.room))?;
+        Ok(())
+    }
+}
+
+/// Escapes lines of a message starting with "." so that they do not end it
+/// early, as SMTP requires
+fn dot_stuff(message: &str) -> String {
+    let mut ret = String::with_capacity(message.len());
+    for line in message.split_inclusive("\r\n") {
+        if line.starts_with('.') {
+            ret.push('.');
+        }
+        ret.push_str(line);
+    }
+    ret
+}
+
+/// Sends a message to an SMTP server
+fn smtp_send(server: &str, from: &str, to: &[String], message: &str) -> anyhow::Result<()> {
+    let stream = TcpStream::connect(server)
+        .with_context(|| format!("connecting to SMTP server {}", server))?;
+    let mut reader = BufReader::new(stream.try_clone().context("cloning SMTP stream")?);
+    let mut writer = stream;
+
+    // Reads a (possibly multi-line) reply, checking its code
+    let mut expect = |code: &str| -> anyhow::Result<()> {
+        loop {
+            let mut line = String::new();
+            reader
+                .read_line(&mut line)
+                .with_context(|| format!("reading reply from {}", server))?;
+            if !line.starts_with(code) {
+                return Err(anyhow::Error::msg(format!(
+                    "SMTP server {} replied {} (expected {})",
+                    server,
+                    line.trim_end(),
+                    code
+                )));
+            }
+            // "250-..." is followed by more lines, "250 ..." is the last
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok(());
+            }
+        }
+    };
+    let command = |writer: &mut TcpStream, line: &str| {
+        write!(writer, "{}\r\n", line).with_context(|| format!("sending {} to {}", line, server))
+    };
+
+    expect("220")?;
+    command(&mut writer, "HELO localhost")?;
+    expect("250")?;
+    command(&mut writer, &format!("MAIL FROM:<{}>", from))?;
+    expect("250")?;
+    for addr in to {
+        command(&mut writer, &format!("RCPT TO:<{}>", addr))?;
+        expect("250")?;
+    }
+    command(&mut writer, "DATA")?;
+    expect("354")?;
+    write!(writer, "{}.\r\n", dot_stuff(message))
+        .with_context(|| format!("sending message to {}", server))?;
+    expect("250")?;
+    command(&mut writer, "QUIT")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn email() {
+        let email: Email = toml::from_str("to = [\"me@example.com\"]").unwrap();
+        assert_eq!(email.sendmail, "sendmail");
+        assert_eq!(email.smtp, None);
+
+        let commit = git2::Oid::from_str("aaaaaaa").unwrap();
+        let mut summary = Summary::default();
+        summary.record(0, commit, "{ rust }", Outcome::Fail(Duration::from_secs(2)));
+        let jobs = [FinishedJob {
+            error: Some("error[E0308]: mismatched types\n.".to_owned()),
+            ..FinishedJob::example("stable cargo build", Outcome::Fail(Duration::from_secs(2)))
+        }];
+        let report = RunReport {
+            repo: "/repo",
+            tip: "pr/1/head",
+            summary: &summary,
+            jobs: &jobs,
+            error: Some("failed"),
+        };
+        let message = email.message(&report);
+        assert!(message.starts_with(
+            "From: check-pr@localhost\r\nTo: me@example.com\r\n\
+             Subject: check-pr FAILED on pr/1/head in /repo\r\n"
+        ));
+        assert!(message.ends_with(
+            "\r\n\r\n         check 1\r\naaaaaaa  FAIL 2.0s\r\ncheck 1: { rust }\r\n\r\n\
+             Failure: aaaaaaa stable cargo build\r\n    error[E0308]: mismatched types\r\n    .\r\n\
+             \r\nfailed\r\n"
+        ));
+        assert_eq!(dot_stuff("a\r\n.b\r\n"), "a\r\n..b\r\n");
+    }
+
+    #[test]
+    fn chat() {
+        let notifiers: toml::Value = toml::from_str(
+            "[[notify]]\ntype = \"irc\"\nserver = \"irc.libera.chat:6667\"\n\
+             channel = \"#rsgit\"\npassword = \"secret\"\n\
+             [[notify]]\ntype = \"matrix\"\nhomeserver = \"https://matrix.org/\"\n\
+             room = \"!abc:matrix.org\"\naccess-token = \"secret\"\n",
+        )
+        .unwrap();
+        let notifiers: Vec<Notifier> = notifiers["notify"].clone().try_into().unwrap();
+        assert!(!serde_json::to_string(&notifiers)
+            .unwrap()
+            .contains("secret"));
+        assert_eq!(redact_irc("PASS secret"), "PASS <password>");
+        assert_eq!(redact_irc("NICK check-pr"), "NICK check-pr");
+        match notifiers[0] {
+            Notifier::Irc(ref irc) => assert_eq!(irc.nick, "check-pr"),
+            ref x => panic!("expected IRC notifier, got {:?}", x),
+        }
+        match notifiers[1] {
+            Notifier::Matrix(ref matrix) => assert_eq!(
+                matrix.url("t 1"),
+                "https://matrix.org/_matrix/client/v3/rooms/%21abc%3Amatrix.org\
+                 /send/m.room.message/t%201"
+            ),
+            ref x => panic!("expected Matrix notifier, got {:?}", x),
+        }
+
+        let base = git2::Oid::from_str("aaaaaaa").unwrap();
+        let tip = git2::Oid::from_str("bbbbbbb").unwrap();
+        let pass = Outcome::Pass(Duration::from_secs(2));
+        let mut summary = Summary::default();
+        summary.record(0, base, "a", pass);
+        summary.record(0, base, "b", Outcome::Cached);
+        summary.record(1, tip, "a", pass);
+        summary.record(1, tip, "b", Outcome::Fail(Duration::from_secs(2)));
+        let mut report = RunReport {
+            repo: "/repo",
+            tip: "pr/123/head",
+            summary: &summary,
+            jobs: &[],
+            error: Some("failed"),
+        };
+        assert_eq!(report.one_line(), "PR #123 tip bbbbbbb: 3/4 checks passed");
+        let empty = Summary::default();
+        report.tip = "HEAD";
+        report.summary = &empty;
+        assert_eq!(
+            report.one_line(),
+            "HEAD: 0/0 checks passed, but the run failed"
+        );
+    }
+}
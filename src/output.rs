@@ -0,0 +1,580 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+//! Controlling what is printed
+//!
+//! Progress messages are printed with the `say!` macro, which only prints
+//! them if the verbosity is at least the level they are given. The progress
+//! of individual jobs is announced with `report`, for the benefit of the
+//! dashboard. Results are gathered into a `Summary`, which is printed as a
+//! table at the end.
+//!
+//! With the `json-lines` output format, nothing is printed for people to read.
+//! Instead every event, and the summary, is printed as a line of JSON.
+
+use std::collections::BTreeMap;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use std::{env, fmt, io};
+
+use serde_json::json;
+
+use crate::cargo::Annotation;
+
+/// How much to print
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// Only the final results
+    Quiet,
+    /// Progress through the PR, and each job as it is started
+    Normal,
+    /// Also every temporary repo created and every dependency pinned
+    Verbose,
+    /// Also every command run
+    Debug,
+}
+
+static VERBOSITY: AtomicUsize = AtomicUsize::new(Verbosity::Normal as usize);
+
+impl Verbosity {
+    /// Works out the verbosity from the number of `-q` and `-v` flags given
+    pub fn from_flags(quiet: bool, verbose: u64) -> Self {
+        match (quiet, verbose) {
+            (true, _) => Verbosity::Quiet,
+            (false, 0) => Verbosity::Normal,
+            (false, 1) => Verbosity::Verbose,
+            (false, _) => Verbosity::Debug,
+        }
+    }
+
+    /// Sets the verbosity for the rest of the program
+    pub fn set(self) {
+        VERBOSITY.store(self as usize, Ordering::Relaxed);
+    }
+
+    /// Whether messages at this level should be printed
+    pub fn shown(self) -> bool {
+        self as usize <= VERBOSITY.load(Ordering::Relaxed) && Format::current() == Format::Human
+    }
+}
+
+/// What form to print output in
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Messages for people to read
+    Human,
+    /// One JSON object per line for each event, for other programs to read
+    JsonLines,
+}
+
+static JSON_LINES: AtomicBool = AtomicBool::new(false);
+
+impl Format {
+    /// Sets the output format for the rest of the program
+    pub fn set(self) {
+        JSON_LINES.store(self == Format::JsonLines, Ordering::Relaxed);
+    }
+
+    /// The output format in use
+    pub fn current() -> Self {
+        if JSON_LINES.load(Ordering::Relaxed) {
+            Format::JsonLines
+        } else {
+            Format::Human
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "human" => Ok(Format::Human),
+            "json-lines" => Ok(Format::JsonLines),
+            x => Err(anyhow::Error::msg(format!(
+                "unknown output format {} (expected human or json-lines)",
+                x
+            ))),
+        }
+    }
+}
+
+/// Prints a message if the verbosity is at least the given level, e.g.
+/// `say!(Verbose, "pinning {}", dep)`
+#[macro_export]
+macro_rules! say {
+    ($level:ident, $($arg:tt)*) => {
+        if $crate::output::Verbosity::$level.shown() {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Something which happened to a job
+#[derive(Copy, Clone, Debug)]
+pub enum Event<'a> {
+    /// Some jobs are waiting to be run on a commit with a toolchain
+    Queued {
+        commit: git2::Oid,
+        toolchain: &'a str,
+        count: usize,
+    },
+    /// A job of the check with the given config hash has started
+    Started {
+        commit: git2::Oid,
+        check: &'a str,
+        toolchain: &'a str,
+        job: &'a str,
+    },
+    /// A job has finished, with the given error if it failed, and its
+    /// output saved in the given log file if there is one
+    Finished {
+        commit: git2::Oid,
+        check: &'a str,
+        toolchain: &'a str,
+        job: &'a str,
+        outcome: Outcome,
+        error: Option<&'a str>,
+        log: Option<&'a Path>,
+        /// Compiler warnings and errors pointing into the crate's source
+        annotations: &'a [Annotation],
+    },
+    /// Notes were recorded on a commit, in the given notes commit
+    NoteWritten {
+        commit: git2::Oid,
+        notes_ref: &'a str,
+        note: git2::Oid,
+        lines: &'a [String],
+    },
+    /// The run is over, with the given error if it failed
+    RunFinished {
+        summary: &'a Summary,
+        error: Option<&'a str>,
+    },
+}
+
+impl Event<'_> {
+    /// Encodes the event as a JSON object, with its type in `event`
+    pub fn to_json(&self) -> serde_json::Value {
+        match *self {
+            Event::Queued {
+                commit,
+                toolchain,
+                count,
+            } => json!({
+                "event": "queued",
+                "commit": commit.to_string(),
+                "toolchain": toolchain,
+                "count": count,
+            }),
+            Event::Started {
+                commit,
+                check,
+                toolchain,
+                job,
+            } => json!({
+                "event": "started",
+                "commit": commit.to_string(),
+                "check": check,
+                "toolchain": toolchain,
+                "job": job,
+            }),
+            Event::Finished {
+                commit,
+                check,
+                toolchain,
+                job,
+                outcome,
+                error,
+                log,
+                annotations,
+            } => {
+                let annotations: Vec<_> = annotations
+                    .iter()
+                    .map(|ann| {
+                        json!({
+                            "path": ann.path,
+                            "start-line": ann.start_line,
+                            "end-line": ann.end_line,
+                            "level": ann.level,
+                            "message": ann.message,
+                        })
+                    })
+                    .collect();
+                let mut ret = json!({
+                    "event": "finished",
+                    "commit": commit.to_string(),
+                    "check": check,
+                    "toolchain": toolchain,
+                    "job": job,
+                    "error": error,
+                    "log": log,
+                    "annotations": annotations,
+                });
+                outcome.add_to_json(&mut ret);
+                ret
+            }
+            Event::NoteWritten {
+                commit,
+                notes_ref,
+                note,
+                lines,
+            } => json!({
+                "event": "note-written",
+                "commit": commit.to_string(),
+                "notes-ref": notes_ref,
+                "note": note.to_string(),
+                "lines": lines,
+            }),
+            Event::RunFinished { summary, error } => json!({
+                "event": "run-finished",
+                "success": error.is_none(),
+                "error": error,
+                "results": summary.to_json(),
+            }),
+        }
+    }
+}
+
+/// A job which has finished, as recorded by `report`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FinishedJob {
+    pub commit: git2::Oid,
+    /// Hash of the configuration of the check the job is part of
+    pub check: String,
+    pub toolchain: String,
+    pub job: String,
+    pub outcome: Outcome,
+    pub error: Option<String>,
+    pub log: Option<PathBuf>,
+    pub annotations: Vec<Annotation>,
+}
+
+#[cfg(test)]
+impl FinishedJob {
+    /// A job of the `stable` toolchain on commit `aaaaaaa`, for tests
+    pub fn example(job: &str, outcome: Outcome) -> Self {
+        FinishedJob {
+            commit: git2::Oid::from_str("aaaaaaa").unwrap(),
+            check: "0123abc".to_owned(),
+            toolchain: "stable".to_owned(),
+            job: job.to_owned(),
+            outcome,
+            error: None,
+            log: None,
+            annotations: vec![],
+        }
+    }
+}
+
+/// Every job which has finished, in the order they finished
+static FINISHED_JOBS: Mutex<Vec<FinishedJob>> = Mutex::new(vec![]);
+
+/// Announces the progress of a job
+pub fn report(event: Event) {
+    if Format::current() == Format::JsonLines {
+        println!("{}", event.to_json());
+    }
+    if let Event::Finished {
+        commit,
+        check,
+        toolchain,
+        job,
+        outcome,
+        error,
+        log,
+        annotations,
+    } = event
+    {
+        FINISHED_JOBS.lock().unwrap().push(FinishedJob {
+            commit,
+            check: check.to_owned(),
+            toolchain: toolchain.to_owned(),
+            job: job.to_owned(),
+            outcome,
+            error: error.map(str::to_owned),
+            log: log.map(Path::to_path_buf),
+            annotations: annotations.to_vec(),
+        });
+    }
+    crate::metrics::update(&event);
+    crate::tui::update(&event);
+}
+
+/// Returns every job which has finished so far
+pub fn finished_jobs() -> Vec<FinishedJob> {
+    FINISHED_JOBS.lock().unwrap().clone()
+}
+
+/// Whether to color output: only if stdout is a terminal, and the user has
+/// not asked for no color by setting `NO_COLOR`
+pub fn use_color() -> bool {
+    io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none()
+}
+
+/// What happened when a check was run on a commit
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// All jobs succeeded, taking the given time
+    Pass(Duration),
+    /// Some job failed, after the given time
+    Fail(Duration),
+    /// All jobs had already succeeded in an earlier run
+    Cached,
+    /// The check was not run on this commit
+    Skipped,
+}
+
+impl Outcome {
+    /// ANSI color code to display the outcome in
+    fn color(self) -> &'static str {
+        match self {
+            Outcome::Pass(..) => "32",
+            Outcome::Fail(..) => "1;31",
+            Outcome::Cached => "36",
+            Outcome::Skipped => "2",
+        }
+    }
+}
+
+impl Outcome {
+    /// Adds the outcome, and the time taken if known, to a JSON object
+    fn add_to_json(self, obj: &mut serde_json::Value) {
+        let (name, time) = match self {
+            Outcome::Pass(time) => ("pass", Some(time)),
+            Outcome::Fail(time) => ("fail", Some(time)),
+            Outcome::Cached => ("cached", None),
+            Outcome::Skipped => ("skipped", None),
+        };
+        obj["outcome"] = json!(name);
+        if let Some(time) = time {
+            obj["seconds"] = json!(time.as_secs_f64());
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Outcome::Pass(time) => write!(f, "pass {}", FormatDuration(time)),
+            Outcome::Fail(time) => write!(f, "FAIL {}", FormatDuration(time)),
+            Outcome::Cached => f.write_str("cached"),
+            Outcome::Skipped => f.write_str("skipped"),
+        }
+    }
+}
+
+/// Displays a duration to a sensible precision, e.g. `4.2s` or `3m07s`
+pub struct FormatDuration(pub Duration);
+
+impl fmt::Display for FormatDuration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let secs = self.0.as_secs();
+        if secs < 60 {
+            write!(f, "{:.1}s", self.0.as_secs_f64())
+        } else if secs < 3600 {
+            write!(f, "{}m{:02}s", secs / 60, secs % 60)
+        } else {
+            write!(f, "{}h{:02}m", secs / 3600, secs % 3600 / 60)
+        }
+    }
+}
+
+/// Table of the outcome of every check on every commit
+#[derive(Clone, Debug, Default)]
+pub struct Summary {
+    /// Descriptions of the checks, in the order they were first seen
+    checks: Vec<String>,
+    /// ID and outcomes of each commit, keyed by its position in the PR
+    commits: BTreeMap<usize, (git2::Oid, BTreeMap<usize, Outcome>)>,
+    /// Column of each check, keyed by the configuration hash of the check
+    /// as actually run (which trailers may have changed)
+    hashes: BTreeMap<String, usize>,
+}
+
+impl Summary {
+    /// Column of the check with the given description, adding it if needed
+    fn column(&mut self, check: &str) -> usize {
+        match self.checks.iter().position(|c| c == check) {
+            Some(idx) => idx,
+            None => {
+                self.checks.push(check.to_owned());
+                self.checks.len() - 1
+            }
+        }
+    }
+
+    /// Records the outcome of a check on a commit
+    ///
+    /// Commits are shown in order of their `position` in the PR.
+    pub fn record(&mut self, position: usize, commit: git2::Oid, check: &str, outcome: Outcome) {
+        let column = self.column(check);
+        self.commits
+            .entry(position)
+            .or_insert_with(|| (commit, BTreeMap::new()))
+            .1
+            .insert(column, outcome);
+    }
+
+    /// Adds a column for the check with the given description, if there is
+    /// not one already, so that columns can be put in order before any
+    /// outcome is known
+    pub fn add_check(&mut self, check: &str) {
+        self.column(check);
+    }
+
+    /// Records that jobs reported with the given configuration hash belong
+    /// to the check with the given description
+    pub fn record_hash(&mut self, check: &str, hash: &str) {
+        let column = self.column(check);
+        self.hashes.insert(hash.to_owned(), column);
+    }
+
+    /// Index into `checks` of the check which jobs with the given
+    /// configuration hash belong to, if known
+    pub fn check_of_hash(&self, hash: &str) -> Option<usize> {
+        self.hashes.get(hash).copied()
+    }
+
+    /// Whether nothing has been recorded
+    pub fn is_empty(&self) -> bool {
+        self.commits.is_empty()
+    }
+
+    /// Descriptions of the checks, in the order of the table's columns
+    pub fn checks(&self) -> &[String] {
+        &self.checks
+    }
+
+    /// Each commit, in order, with the outcome of each check (if any)
+    pub fn rows(&self) -> impl Iterator<Item = (git2::Oid, Vec<Option<Outcome>>)> + '_ {
+        self.commits.values().map(move |(commit, outcomes)| {
+            let row = (0..self.checks.len())
+                .map(|col| outcomes.get(&col).copied())
+                .collect();
+            (*commit, row)
+        })
+    }
+
+    /// Encodes the summary as a JSON list with an object for each cell
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut ret = vec![];
+        for (commit, outcomes) in self.commits.values() {
+            for (col, outcome) in outcomes {
+                let mut cell = json!({
+                    "commit": commit.to_string(),
+                    "check": self.checks[*col],
+                });
+                outcome.add_to_json(&mut cell);
+                ret.push(cell);
+            }
+        }
+        serde_json::Value::Array(ret)
+    }
+
+    /// Renders the summary as a table, with a legend of the checks below it
+    pub fn render(&self, color: bool) -> String {
+        let headers: Vec<String> = (1..=self.checks.len())
+            .map(|n| format!("check {}", n))
+            .collect();
+        let rows: Vec<_> = self
+            .rows()
+            .map(|(commit, row)| {
+                let row: Vec<_> = row
+                    .into_iter()
+                    .map(|out| match out {
+                        Some(out) => (out.to_string(), Some(out)),
+                        None => ("-".to_owned(), None),
+                    })
+                    .collect();
+                (format!("{:.7}", commit), row)
+            })
+            .collect();
+
+        let label_width = rows.iter().map(|(l, _)| l.len()).max().unwrap_or(0);
+        let widths: Vec<usize> = headers
+            .iter()
+            .enumerate()
+            .map(|(col, header)| {
+                rows.iter()
+                    .map(|(_, row)| row[col].0.len())
+                    .chain(Some(header.len()))
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect();
+
+        let mut ret = format!("{:1$}", "", label_width);
+        for (header, width) in headers.iter().zip(&widths) {
+            ret.push_str(&format!("  {:1$}", header, width));
+        }
+        ret.truncate(ret.trim_end().len());
+        ret.push('\n');
+        for (label, row) in &rows {
+            ret.push_str(&format!("{:1$}", label, label_width));
+            for ((text, outcome), width) in row.iter().zip(&widths) {
+                // Pad before coloring, since escape codes have no width
+                let text = format!("{:1$}", text, width);
+                match outcome {
+                    Some(outcome) if color => {
+                        ret.push_str(&format!("  \x1b[{}m{}\x1b[0m", outcome.color(), text))
+                    }
+                    _ => ret.push_str(&format!("  {}", text)),
+                }
+            }
+            ret.truncate(ret.trim_end().len());
+            ret.push('\n');
+        }
+        for (n, check) in self.checks.iter().enumerate() {
+            ret.push_str(&format!("check {}: {}\n", n + 1, check));
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary() {
+        let mut summary = Summary::default();
+        let secs = Duration::from_secs;
+        let commit = |s| git2::Oid::from_str(s).unwrap();
+        let (a, b, c) = (commit("aaaaaaa"), commit("bbbbbbb"), commit("ccccccc"));
+        summary.record(1, b, "build", Outcome::Fail(secs(200)));
+        summary.record(0, a, "build", Outcome::Pass(secs(2)));
+        summary.record(0, a, "fuzz", Outcome::Cached);
+        summary.record(2, c, "fuzz", Outcome::Skipped);
+        assert_eq!(
+            summary.render(false),
+            "         check 1     check 2
+aaaaaaa  pass 2.0s   cached
+bbbbbbb  FAIL 3m20s  -
+ccccccc  -           skipped
+check 1: build
+check 2: fuzz
+",
+        );
+    }
+}
@@ -17,8 +17,24 @@
 //
 
 use git2::{Oid, Repository};
+use std::cmp::Reverse;
 use std::collections::hash_map::Entry;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+/// The order in which `PullRequest::for_each_commit` numbers commits
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CommitOrder {
+    /// In the order they are found walking back from the tip, which is
+    /// index 0. A merge's parents may be numbered before or after each
+    /// other's ancestors.
+    Discovery,
+    /// Parents before children, from the oldest commit at index 0. Commits
+    /// which could go in either order are ordered by commit time and then
+    /// ID, so the numbering is the same every time.
+    Topological,
+}
 
 /// Pull request branch
 pub struct PullRequest {
@@ -29,11 +45,101 @@ pub struct PullRequest {
 }
 
 impl PullRequest {
+    /// Guesses the number of the PR whose branch a ref names, e.g. 123 for
+    /// `pr/123/head` or `refs/remotes/origin/pull/123/head`
+    pub fn number_from_ref(name: &str) -> Option<usize> {
+        name.split('/').find_map(|seg| usize::from_str(seg).ok())
+    }
+
+    /// Finds which PR a merge commit merged from its message, as written by
+    /// GitHub (`Merge pull request #123 from ...`), by merge bots and scripts
+    /// (`Merge #123: ...` or `Merge owner/repo#123: ...`) or by GitLab
+    /// (`See merge request group/project!123`)
+    ///
+    /// Returns the number of the PR, and the repository the message names
+    /// it in, if any.
+    pub fn number_from_merge_message(message: &str) -> Option<(Option<&str>, usize)> {
+        // The number at the start of a string, and what follows it
+        fn number(s: &str) -> Option<(usize, &str)> {
+            let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+            Some((s[..end].parse().ok()?, &s[end..]))
+        }
+
+        let first = message.lines().next().unwrap_or("");
+        if let Some(rest) = first.strip_prefix("Merge pull request #") {
+            return number(rest).map(|(n, _)| (None, n));
+        }
+        if let Some(rest) = first.strip_prefix("Merge ") {
+            let word = rest.split(' ').next().unwrap_or("");
+            if let Some((repo, rest)) = word.split_once('#') {
+                if let Some((n, ":")) = number(rest) {
+                    return Some((Some(repo).filter(|repo| !repo.is_empty()), n));
+                }
+            }
+        }
+        for line in message.lines() {
+            if let Some(rest) = line.trim().strip_prefix("See merge request ") {
+                if let Some((repo, rest)) = rest.rsplit_once('!') {
+                    if let Some((n, "")) = number(rest) {
+                        return Some((Some(repo).filter(|repo| !repo.is_empty()), n));
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Finds every PR branch fetched to `refs/remotes/<pr_ref>/<number>/head`
+    pub fn find_all(repo: &Repository, pr_ref: &str) -> Result<Vec<Self>, git2::Error> {
+        let mut prs = vec![];
+        'ref_loop: for rf in repo.references()? {
+            let rf = rf?;
+            if rf.is_remote() {
+                let name = match rf.name() {
+                    Some(name) => name,
+                    None => continue,
+                };
+                let mut segments = name.split('/');
+                if segments.next() != Some("refs") {
+                    continue;
+                }
+                if segments.next() != Some("remotes") {
+                    continue;
+                }
+                for seg in pr_ref.split('/') {
+                    if segments.next() != Some(seg) {
+                        continue 'ref_loop;
+                    }
+                }
+                let num = match segments.next().map(usize::from_str) {
+                    Some(Ok(n)) => n,
+                    _ => continue,
+                };
+                if segments.next() != Some("head") {
+                    continue;
+                }
+                if let Some(id) = rf.target() {
+                    prs.push(PullRequest { number: num, id });
+                }
+            }
+        }
+        Ok(prs)
+    }
+
     /// Scan through the commits in a PR branch, running some action on each one
-    pub fn for_each_commit<'label, F: FnMut(Oid, usize, usize)>(
+    ///
+    /// The PR's commits are taken to be those not in `master_commits`,
+    /// which should be the first-parent history of the branches the PR may
+    /// be based on. `BaseBranches::branch_commits` is better at this, and
+    /// only falls back to this.
+    ///
+    /// The action is given each commit's ID, its index in `order` and the
+    /// number of commits, and is run on the commits in index order.
+    pub fn for_each_commit<F: FnMut(Oid, usize, usize)>(
         &self,
         repo: &Repository,
         master_commits: &HashSet<Oid>,
+        order: CommitOrder,
         mut action: F,
     ) {
         let mut pr_map = HashMap::new();
@@ -61,8 +167,206 @@ impl PullRequest {
         }
 
         let n_commits = pr_map.len();
-        for (id, index) in pr_map {
+        let mut commits: Vec<_> = pr_map.into_iter().map(|(id, index)| (index, id)).collect();
+        commits.sort();
+        if order == CommitOrder::Topological {
+            commits = topological(repo, commits.into_iter().map(|(_, id)| id))
+                .into_iter()
+                .enumerate()
+                .collect();
+        }
+        for (index, id) in commits {
             action(id, index, n_commits);
         }
     }
 }
+
+/// Sorts a set of commits so that each comes after its parents in the set,
+/// breaking ties by commit time and then ID
+fn topological<I: Iterator<Item = Oid>>(repo: &Repository, ids: I) -> Vec<Oid> {
+    // For each commit, its time and how many of its parents are yet to be
+    // sorted, and the commits it is a parent of
+    let mut pending = HashMap::new();
+    let mut children: HashMap<Oid, Vec<Oid>> = HashMap::new();
+    let mut commits = vec![];
+    for id in ids {
+        let commit = repo.find_commit(id).expect("look up commit");
+        pending.insert(id, (commit.time().seconds(), 0));
+        commits.push(commit);
+    }
+    for commit in &commits {
+        for parent in commit.parent_ids() {
+            if pending.contains_key(&parent) {
+                children.entry(parent).or_default().push(commit.id());
+                pending.get_mut(&commit.id()).unwrap().1 += 1;
+            }
+        }
+    }
+
+    let mut ready: BinaryHeap<_> = pending
+        .iter()
+        .filter(|(_, &(_, n_parents))| n_parents == 0)
+        .map(|(&id, &(time, _))| Reverse((time, id)))
+        .collect();
+    let mut sorted = Vec::with_capacity(commits.len());
+    while let Some(Reverse((_, id))) = ready.pop() {
+        sorted.push(id);
+        for child in children.remove(&id).unwrap_or_default() {
+            let (time, n_parents) = pending.get_mut(&child).unwrap();
+            *n_parents -= 1;
+            if *n_parents == 0 {
+                ready.push(Reverse((*time, child)));
+            }
+        }
+    }
+    sorted
+}
+
+/// The branches which PRs are based on, for finding which commits belong to
+/// a PR
+pub struct BaseBranches {
+    tips: Vec<Oid>,
+    /// The first-parent history of every branch, only walked if merge
+    /// bases cannot be looked up, by whichever thread first needs it
+    history: OnceLock<HashSet<Oid>>,
+}
+
+impl BaseBranches {
+    /// Takes the branches to be those with the given tips
+    pub fn new(tips: Vec<Oid>) -> Self {
+        BaseBranches {
+            tips,
+            history: OnceLock::new(),
+        }
+    }
+
+    /// Finds the commits of a branch which are not in the history of any
+    /// of the base branches, as `git rev-list <tip> ^<base>...` would, in
+    /// the same order as `CommitOrder::Topological`
+    ///
+    /// Returns `None` if the branch has no history in common with any of
+    /// the base branches, e.g. because it has another root commit, rather
+    /// than every commit in its history.
+    pub fn branch_commits(
+        &self,
+        repo: &Repository,
+        tip: Oid,
+    ) -> Result<Option<Vec<Oid>>, git2::Error> {
+        let mut related = false;
+        for &base in &self.tips {
+            match repo.merge_base(tip, base) {
+                Ok(_) => related = true,
+                Err(e) if e.code() == git2::ErrorCode::NotFound => {}
+                // e.g. a shallow clone, missing the history to search
+                Err(_) => return Ok(Some(self.fallback_commits(repo, tip))),
+            }
+        }
+        if !related {
+            return Ok(None);
+        }
+
+        let mut walk = repo.revwalk()?;
+        walk.push(tip)?;
+        for &base in &self.tips {
+            walk.hide(base)?;
+        }
+        let commits = walk.collect::<Result<Vec<_>, _>>()?;
+        Ok(Some(topological(repo, commits.into_iter())))
+    }
+
+    /// Finds the commits of a branch which are not in the first-parent
+    /// history of any of the base branches, from the oldest
+    fn fallback_commits(&self, repo: &Repository, tip: Oid) -> Vec<Oid> {
+        let history = self.history.get_or_init(|| {
+            let mut history = HashSet::new();
+            for &base in &self.tips {
+                let mut parent = repo.find_commit(base);
+                while let Ok(parent_commit) = parent {
+                    history.insert(parent_commit.id());
+                    parent = parent_commit.parent(0);
+                }
+            }
+            history
+        });
+
+        let mut commits = vec![];
+        PullRequest { number: 0, id: tip }.for_each_commit(
+            repo,
+            history,
+            CommitOrder::Topological,
+            |id, _, _| commits.push(id),
+        );
+        commits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn number_from_merge_message() {
+        let parse = PullRequest::number_from_merge_message;
+        assert_eq!(
+            parse("Merge pull request #1234 from someone/branch\n\nFix it"),
+            Some((None, 1234))
+        );
+        assert_eq!(parse("Merge #123: Fix it\n"), Some((None, 123)));
+        assert_eq!(
+            parse("Merge bitcoin/bitcoin#123: Fix it"),
+            Some((Some("bitcoin/bitcoin"), 123))
+        );
+        assert_eq!(
+            parse("Merge branch 'fix' into 'master'\n\nFix it\n\nSee merge request group/project!45\n"),
+            Some((Some("group/project"), 45))
+        );
+        assert_eq!(parse("Merge branch 'master' into fix"), None);
+        assert_eq!(parse("Merge #123 and #124"), None);
+        assert_eq!(parse("Merge pull request #x"), None);
+        assert_eq!(parse("Fix #123: something"), None);
+    }
+
+    #[test]
+    fn topological_order() {
+        let dir = tempfile::tempdir().expect("creating tempdir");
+        let repo = Repository::init(dir.path()).expect("creating repo");
+        let tree = {
+            let mut index = repo.index().unwrap();
+            repo.find_tree(index.write_tree().unwrap()).unwrap()
+        };
+        let commit = |msg: &str, time: i64, parents: &[Oid]| {
+            let sig = git2::Signature::new("x", "x@y", &git2::Time::new(time, 0)).unwrap();
+            let parents: Vec<_> = parents
+                .iter()
+                .map(|&id| repo.find_commit(id).unwrap())
+                .collect();
+            let parents: Vec<_> = parents.iter().collect();
+            repo.commit(None, &sig, &sig, msg, &tree, &parents).unwrap()
+        };
+        // base <- a <- b <- merge <- tip, with c forking off a and merged.
+        // c is newer than b, so goes after it
+        let base = commit("base", 100, &[]);
+        let a = commit("a", 200, &[base]);
+        let c = commit("c", 400, &[a]);
+        let b = commit("b", 300, &[a]);
+        let merge = commit("merge", 500, &[b, c]);
+        let tip = commit("tip", 600, &[merge]);
+
+        let pr = PullRequest { number: 1, id: tip };
+        let master: HashSet<_> = [base].iter().copied().collect();
+        let mut topo = vec![];
+        pr.for_each_commit(&repo, &master, CommitOrder::Topological, |id, index, n| {
+            assert_eq!(n, 5);
+            topo.push((index, id));
+        });
+        assert_eq!(topo, vec![(0, a), (1, b), (2, c), (3, merge), (4, tip)]);
+
+        let mut discovery = vec![];
+        pr.for_each_commit(&repo, &master, CommitOrder::Discovery, |id, index, _| {
+            discovery.push((index, id));
+        });
+        assert_eq!(discovery[0], (0, tip));
+        assert_eq!(discovery[1], (1, merge));
+        assert_eq!(discovery.len(), 5);
+    }
+}
@@ -0,0 +1,391 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+//! The job protocol spoken by `rsgit-worker`
+//!
+//! check-pr runs the jobs it hands to `[[worker]]`s through this, by way of
+//! `rsgit-worker --run-job` on this machine, which is the client, and
+//! `rsgit-worker` on the worker, run over ssh.
+//!
+//! A client sends the worker requests, and the worker sends back responses,
+//! over stdin/stdout (e.g. through ssh) or a TCP connection. Each message
+//! is a JSON object, preceded by its length in bytes as a 4-byte big-endian
+//! integer. Jobs are run one at a time: for each [`Request::Job`], the
+//! worker sends a [`Response::Log`] for every line of the job's output, as
+//! it is produced, then a single [`Response::Done`] or [`Response::Error`].
+//!
+//! Since the worker only runs what it is sent, it can equally be put inside
+//! a container or sandbox, and driven from outside it.
+
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use tempfile::TempDir;
+
+/// Largest message which will be read, to catch garbage on the connection
+const MAX_MESSAGE_LEN: u32 = 64 * 1024 * 1024;
+
+/// A message from the client to the worker
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Request {
+    /// Run a job and report on it
+    Job(Job),
+}
+
+/// A command to run, and where to run it
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Job {
+    /// The tree to run the command in
+    pub source: Source,
+    /// The directory to run it in, relative to the tree
+    #[serde(default)]
+    pub cwd: Option<String>,
+    /// The program and its arguments
+    pub argv: Vec<String>,
+    /// Variables to add to the worker's environment
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+}
+
+/// Where the tree which a job runs in comes from
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum Source {
+    /// A directory on the worker, e.g. one which a checkout was copied to
+    Dir { path: String },
+    /// A commit, which the worker fetches into a temporary checkout, and
+    /// deletes again after the job
+    Fetch { repo: String, commit: String },
+}
+
+/// Which of a job's output streams a line came from
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// A message from the worker to the client
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Response {
+    /// A line of the job's output, including its newline if it had one
+    Log { stream: Stream, data: String },
+    /// The job's command has exited, with a code or because of a signal
+    Done {
+        #[serde(default)]
+        code: Option<u32>,
+        #[serde(default)]
+        signal: Option<u8>,
+    },
+    /// The job could not be run at all
+    Error { message: String },
+}
+
+/// Writes a message
+pub fn write_message<W: Write, T: Serialize>(w: &mut W, message: &T) -> anyhow::Result<()> {
+    let data = serde_json::to_vec(message).context("encoding message")?;
+    let len = u32::try_from(data.len())
+        .ok()
+        .filter(|&len| len <= MAX_MESSAGE_LEN)
+        .ok_or_else(|| {
+            anyhow::Error::msg(format!("message of {} bytes is too long", data.len()))
+        })?;
+    w.write_all(&len.to_be_bytes())
+        .and_then(|_| w.write_all(&data))
+        .and_then(|_| w.flush())
+        .context("writing message")
+}
+
+/// Reads a message, or returns `None` if the connection was closed cleanly
+/// before it
+pub fn read_message<R: Read, T: DeserializeOwned>(r: &mut R) -> anyhow::Result<Option<T>> {
+    let mut len = [0; 4];
+    match r.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e).context("reading message length"),
+    }
+    let len = u32::from_be_bytes(len);
+    if len > MAX_MESSAGE_LEN {
+        return Err(anyhow::Error::msg(format!(
+            "message of {} bytes is too long",
+            len
+        )));
+    }
+    let mut data = vec![0; len as usize];
+    r.read_exact(&mut data).context("reading message")?;
+    serde_json::from_slice(&data)
+        .map(Some)
+        .context("decoding message")
+}
+
+/// Runs a job on a worker, passing each line of its output to `on_log` as
+/// it comes, and returns how its command exited, as in [`Response::Done`]
+pub fn run<R: Read, W: Write>(
+    mut from_worker: R,
+    mut to_worker: W,
+    job: Job,
+    mut on_log: impl FnMut(Stream, &str) -> anyhow::Result<()>,
+) -> anyhow::Result<(Option<u32>, Option<u8>)> {
+    write_message(&mut to_worker, &Request::Job(job))?;
+    loop {
+        match read_message(&mut from_worker)? {
+            Some(Response::Log { stream, data }) => on_log(stream, &data)?,
+            Some(Response::Done { code, signal }) => return Ok((code, signal)),
+            Some(Response::Error { message }) => {
+                return Err(anyhow::Error::msg(message).context("worker could not run the job"))
+            }
+            None => {
+                return Err(anyhow::Error::msg(
+                    "worker closed the connection before the job finished",
+                ))
+            }
+        }
+    }
+}
+
+/// Answers requests until the client closes the connection
+///
+/// Fetched checkouts are put in `work_dir`, or the system's temporary
+/// directory if it is `None`.
+pub fn serve<R: Read, W: Write>(
+    mut input: R,
+    mut output: W,
+    work_dir: Option<&Path>,
+) -> anyhow::Result<()> {
+    while let Some(request) = read_message(&mut input)? {
+        match request {
+            Request::Job(job) => {
+                if let Err(e) = run_job(&job, &mut output, work_dir) {
+                    let message = format!("{:#}", e);
+                    write_message(&mut output, &Response::Error { message })?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs a job, sending its output as it goes, then its exit status
+///
+/// Errors are only returned if the job could not be started; once it has
+/// been, failing to send its output kills it, since nobody is listening.
+fn run_job<W: Write>(job: &Job, output: &mut W, work_dir: Option<&Path>) -> anyhow::Result<()> {
+    let (program, args) = job
+        .argv
+        .split_first()
+        .ok_or_else(|| anyhow::Error::msg("job has no command"))?;
+    // Kept until the job is done
+    let checkout: Option<TempDir>;
+    let mut cwd = match job.source {
+        Source::Dir { ref path } => {
+            checkout = None;
+            PathBuf::from(path)
+        }
+        Source::Fetch {
+            ref repo,
+            ref commit,
+        } => {
+            let dir = fetch(repo, commit, work_dir)?;
+            let path = dir.path().to_path_buf();
+            checkout = Some(dir);
+            path
+        }
+    };
+    if let Some(ref sub) = job.cwd {
+        cwd.push(sub);
+    }
+    if !cwd.is_dir() {
+        return Err(anyhow::Error::msg(format!(
+            "directory {} does not exist",
+            cwd.to_string_lossy()
+        )));
+    }
+
+    let mut popen = subprocess::Exec::cmd(program)
+        .args(args)
+        .env_extend(&job.env.iter().collect::<Vec<_>>())
+        .cwd(&cwd)
+        .stdin(subprocess::NullFile)
+        .stdout(subprocess::Redirection::Pipe)
+        .stderr(subprocess::Redirection::Pipe)
+        .popen()
+        .with_context(|| format!("running {}", program))?;
+
+    let (tx, rx) = mpsc::channel();
+    let stdout = popen.stdout.take().map(|f| (Stream::Stdout, f));
+    let stderr = popen.stderr.take().map(|f| (Stream::Stderr, f));
+    for (stream, file) in stdout.into_iter().chain(stderr) {
+        let tx = tx.clone();
+        thread::spawn(move || {
+            let mut reader = BufReader::new(file);
+            let mut line = vec![];
+            while let Ok(n) = reader.read_until(b'\n', &mut line) {
+                if n == 0 {
+                    break;
+                }
+                let data = String::from_utf8_lossy(&line).into_owned();
+                if tx.send(Response::Log { stream, data }).is_err() {
+                    break;
+                }
+                line.clear();
+            }
+        });
+    }
+    drop(tx);
+    for response in rx {
+        if let Err(e) = write_message(output, &response) {
+            let _ = popen.kill();
+            let _ = popen.wait();
+            return Err(e);
+        }
+    }
+
+    let status = popen.wait().context("waiting for job")?;
+    drop(checkout);
+    let (code, signal) = match status {
+        subprocess::ExitStatus::Exited(code) => (Some(code), None),
+        subprocess::ExitStatus::Signaled(signal) => (None, Some(signal)),
+        _ => (None, None),
+    };
+    write_message(output, &Response::Done { code, signal })
+}
+
+/// Fetches a commit into a new temporary checkout
+fn fetch(repo: &str, commit: &str, work_dir: Option<&Path>) -> anyhow::Result<TempDir> {
+    let dir = match work_dir {
+        Some(dir) => tempfile::tempdir_in(dir),
+        None => tempfile::tempdir(),
+    }
+    .context("creating checkout directory")?;
+    let git = |args: &[&str]| -> anyhow::Result<()> {
+        let capture = subprocess::Exec::cmd("git")
+            .arg("-C")
+            .arg(dir.path())
+            .args(args)
+            .stdin(subprocess::NullFile)
+            .stdout(subprocess::NullFile)
+            .stderr(subprocess::Redirection::Pipe)
+            .capture()
+            .with_context(|| format!("running git {}", args[0]))?;
+        if capture.success() {
+            Ok(())
+        } else {
+            Err(anyhow::Error::msg(format!(
+                "git {} exited with {:?}\nstderr:\n{}",
+                args[0],
+                capture.exit_status,
+                capture.stderr_str(),
+            )))
+        }
+    };
+    git(&["init", "--quiet"])?;
+    git(&["fetch", "--quiet", "--depth=1", repo, commit])
+        .with_context(|| format!("fetching {} from {}", commit, repo))?;
+    git(&["checkout", "--quiet", "--detach", "FETCH_HEAD"])?;
+    Ok(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn job() {
+        let mut input = vec![];
+        let job = Job {
+            source: Source::Dir {
+                path: "/".to_owned(),
+            },
+            cwd: Some("tmp".to_owned()),
+            argv: vec![
+                "sh".to_owned(),
+                "-c".to_owned(),
+                "pwd; echo \"$GREETING\" >&2; exit 3".to_owned(),
+            ],
+            env: vec![("GREETING".to_owned(), "hello".to_owned())]
+                .into_iter()
+                .collect(),
+        };
+        write_message(&mut input, &Request::Job(job.clone())).unwrap();
+        let mut output = vec![];
+        serve(&input[..], &mut output, None).unwrap();
+
+        // What the client makes of the worker's responses
+        let mut sent = vec![];
+        let mut logs = vec![];
+        let exit = run(&output[..], &mut sent, job, |stream, data| {
+            logs.push((stream, data.to_owned()));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(sent, input);
+        assert_eq!(exit, (Some(3), None));
+        assert_eq!(logs.len(), 2);
+        assert!(logs.contains(&(Stream::Stderr, "hello\n".to_owned())));
+
+        let mut output = &output[..];
+        let mut responses = vec![];
+        while let Some(response) = read_message::<_, Response>(&mut output).unwrap() {
+            responses.push(response);
+        }
+        assert_eq!(responses.len(), 3);
+        assert!(responses.contains(&Response::Log {
+            stream: Stream::Stdout,
+            data: "/tmp\n".to_owned(),
+        }));
+        assert!(responses.contains(&Response::Log {
+            stream: Stream::Stderr,
+            data: "hello\n".to_owned(),
+        }));
+        assert_eq!(
+            responses[2],
+            Response::Done {
+                code: Some(3),
+                signal: None,
+            }
+        );
+
+        let error = Response::Error {
+            message: "directory /nonexistent does not exist".to_owned(),
+        };
+        let mut output = vec![];
+        write_message(&mut output, &error).unwrap();
+        let job = Job {
+            source: Source::Dir {
+                path: "/nonexistent".to_owned(),
+            },
+            cwd: None,
+            argv: vec!["true".to_owned()],
+            env: BTreeMap::new(),
+        };
+        let err = run(&output[..], vec![], job.clone(), |_, _| Ok(())).unwrap_err();
+        assert!(format!("{:#}", err).contains("does not exist"));
+        assert!(run(&b""[..], vec![], job, |_, _| Ok(())).is_err());
+    }
+}
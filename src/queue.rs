@@ -0,0 +1,173 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+//! Database of the PRs waiting to be checked by `check-serve`
+//!
+//! Keeping the queue on disk means that PRs which were queued, or being
+//! checked, when `check-serve` stopped are checked once it is restarted.
+//! It also lets other processes, such as `check-serve --poll-once` run from
+//! cron, queue PRs for a running `check-serve` to check; SQLite takes care
+//! of them writing to the database at the same time.
+
+use anyhow::Context;
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::serve::PrUpdate;
+
+/// Creates the table, if it does not already exist
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS queue (
+    id INTEGER PRIMARY KEY,
+    repo TEXT NOT NULL,
+    number INTEGER NOT NULL,
+    head_ref TEXT NOT NULL,
+    base TEXT NOT NULL,
+    -- 1 once check-serve has started checking the PR
+    started INTEGER NOT NULL DEFAULT 0
+);
+";
+
+/// How long to wait for another process to finish writing to the database
+const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// An open queue database
+pub struct QueueDb {
+    conn: Connection,
+}
+
+impl QueueDb {
+    /// Opens the database at the given path, creating it if need be
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("creating directory {}", dir.to_string_lossy()))?;
+        }
+        let conn = Connection::open(path)
+            .with_context(|| format!("opening queue database {}", path.to_string_lossy()))?;
+        conn.busy_timeout(BUSY_TIMEOUT)
+            .context("setting queue database timeout")?;
+        conn.execute_batch(SCHEMA)
+            .context("creating queue database table")?;
+        Ok(QueueDb { conn })
+    }
+
+    /// Puts back any PRs which were being checked, e.g. when check-serve was
+    /// killed, so that they are checked again
+    ///
+    /// This must only be called by the process which checks PRs, before it
+    /// starts doing so.
+    pub fn requeue_started(&mut self) -> anyhow::Result<usize> {
+        let tx = self
+            .conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .context("starting queue transaction")?;
+        // A PR which was queued again while being checked only needs one
+        // more check
+        tx.execute(
+            "DELETE FROM queue WHERE started = 1 AND EXISTS (
+                 SELECT 1 FROM queue AS waiting
+                 WHERE waiting.started = 0
+                 AND waiting.repo = queue.repo AND waiting.number = queue.number
+             )",
+            [],
+        )
+        .context("dropping duplicate PRs")?;
+        let requeued = tx
+            .execute("UPDATE queue SET started = 0 WHERE started = 1", [])
+            .context("requeueing started PRs")?;
+        tx.commit().context("committing queue")?;
+        Ok(requeued)
+    }
+
+    /// Adds a PR to the end of the queue, unless it is already waiting,
+    /// returning whether it was added
+    pub fn push(&mut self, update: &PrUpdate) -> anyhow::Result<bool> {
+        let tx = self
+            .conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .context("starting queue transaction")?;
+        let waiting: Option<i64> = tx
+            .query_row(
+                "SELECT id FROM queue WHERE started = 0 AND repo = ?1 AND number = ?2",
+                params![update.repo, update.number],
+                |row| row.get(0),
+            )
+            .optional()
+            .context("looking for queued PR")?;
+        if waiting.is_some() {
+            return Ok(false);
+        }
+        tx.execute(
+            "INSERT INTO queue (repo, number, head_ref, base) VALUES (?1, ?2, ?3, ?4)",
+            params![update.repo, update.number, update.head_ref, update.base],
+        )
+        .context("queueing PR")?;
+        tx.commit().context("committing queue")?;
+        Ok(true)
+    }
+
+    /// Takes the PR at the front of the queue, if there is one, marking it
+    /// as started
+    pub fn pop(&mut self) -> anyhow::Result<Option<PrUpdate>> {
+        let tx = self
+            .conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .context("starting queue transaction")?;
+        let front = tx
+            .query_row(
+                "SELECT id, repo, number, head_ref, base FROM queue
+                 WHERE started = 0 ORDER BY id LIMIT 1",
+                [],
+                |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        PrUpdate {
+                            repo: row.get(1)?,
+                            number: row.get(2)?,
+                            head_ref: row.get(3)?,
+                            base: row.get(4)?,
+                        },
+                    ))
+                },
+            )
+            .optional()
+            .context("reading queue")?;
+        let (id, update) = match front {
+            Some(front) => front,
+            None => return Ok(None),
+        };
+        tx.execute("UPDATE queue SET started = 1 WHERE id = ?1", params![id])
+            .context("marking PR as started")?;
+        tx.commit().context("committing queue")?;
+        Ok(Some(update))
+    }
+
+    /// Removes a PR which has been checked from the queue
+    pub fn done(&mut self, update: &PrUpdate) -> anyhow::Result<()> {
+        self.conn
+            .execute(
+                "DELETE FROM queue WHERE started = 1 AND repo = ?1 AND number = ?2",
+                params![update.repo, update.number],
+            )
+            .context("removing checked PR from queue")?;
+        Ok(())
+    }
+}
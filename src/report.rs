@@ -0,0 +1,280 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+//! Reports of a run, written for other tools or for PR comments
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::output::{FinishedJob, Outcome, Summary};
+
+/// How many lines of a failed job's output to include in a Markdown summary,
+/// since comments on GitHub are limited to 64k characters
+const MARKDOWN_FAILURE_LINES: usize = 100;
+
+/// Marks the comment holding our results on a PR, so that it can be found
+/// to be updated rather than posting another one
+pub(crate) const COMMENT_MARKER: &str = "<!-- check-pr results -->";
+
+/// Escapes text for use in XML (or HTML) attributes and content
+pub(crate) fn xml_escape(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    for ch in s.chars() {
+        match ch {
+            '&' => ret.push_str("&amp;"),
+            '<' => ret.push_str("&lt;"),
+            '>' => ret.push_str("&gt;"),
+            '"' => ret.push_str("&quot;"),
+            '\'' => ret.push_str("&apos;"),
+            // Control characters (e.g. from colored compiler output) are
+            // not allowed in XML at all
+            '\n' | '\t' | '\r' => ret.push(ch),
+            ch if ch.is_control() => {}
+            ch => ret.push(ch),
+        }
+    }
+    ret
+}
+
+/// Renders a JUnit XML report, with a test suite for each commit and a test
+/// case for each job run on it
+///
+/// Jobs which had already passed in an earlier run are marked as skipped.
+pub fn junit(jobs: &[FinishedJob]) -> String {
+    let mut suites: BTreeMap<String, Vec<&FinishedJob>> = BTreeMap::new();
+    for job in jobs {
+        suites.entry(job.commit.to_string()).or_default().push(job);
+    }
+
+    let mut ret = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites>\n");
+    for (commit, jobs) in &suites {
+        let count = |f: fn(&Outcome) -> bool| jobs.iter().filter(|job| f(&job.outcome)).count();
+        let time: f64 = jobs.iter().map(|job| seconds(job.outcome)).sum();
+        let _ = writeln!(
+            ret,
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{:.3}\">",
+            commit,
+            jobs.len(),
+            count(|o| matches!(o, Outcome::Fail(..))),
+            count(|o| matches!(o, Outcome::Cached | Outcome::Skipped)),
+            time,
+        );
+        for job in jobs {
+            let _ = write!(
+                ret,
+                "    <testcase classname=\"{}\" name=\"{}\" time=\"{:.3}\"",
+                commit,
+                xml_escape(&job.job),
+                seconds(job.outcome),
+            );
+            match job.outcome {
+                Outcome::Pass(..) => ret.push_str("/>\n"),
+                Outcome::Fail(..) => {
+                    let error = job.error.as_deref().unwrap_or("");
+                    let _ = writeln!(
+                        ret,
+                        ">\n      <failure message=\"{}\">{}</failure>\n    </testcase>",
+                        xml_escape(error.lines().next().unwrap_or("failed")),
+                        xml_escape(error),
+                    );
+                }
+                Outcome::Cached => {
+                    ret.push_str(">\n      <skipped message=\"passed in an earlier run\"/>\n");
+                    ret.push_str("    </testcase>\n");
+                }
+                Outcome::Skipped => {
+                    ret.push_str(">\n      <skipped/>\n    </testcase>\n");
+                }
+            }
+        }
+        ret.push_str("  </testsuite>\n");
+    }
+    ret.push_str("</testsuites>\n");
+    ret
+}
+
+/// Renders a Markdown table of the result of every check on every commit,
+/// followed by the output of every failed job in a collapsed section
+pub fn markdown(summary: &Summary, jobs: &[FinishedJob]) -> String {
+    let mut ret = String::from("| Commit |");
+    for n in 1..=summary.checks().len() {
+        let _ = write!(ret, " Check {} |", n);
+    }
+    ret.push_str("\n|---|");
+    ret.push_str(&"---|".repeat(summary.checks().len()));
+    ret.push('\n');
+    for (commit, row) in summary.rows() {
+        let _ = write!(ret, "| `{:.7}` |", commit);
+        for outcome in row {
+            let _ = match outcome {
+                Some(outcome @ Outcome::Fail(..)) => write!(ret, " **{}** |", outcome),
+                Some(outcome @ Outcome::Pass(..)) => write!(ret, " {} |", outcome),
+                Some(outcome) => write!(ret, " _{}_ |", outcome),
+                None => write!(ret, " - |"),
+            };
+        }
+        ret.push('\n');
+    }
+    ret.push('\n');
+    for (n, check) in summary.checks().iter().enumerate() {
+        let _ = writeln!(ret, "- Check {}: `{}`", n + 1, check);
+    }
+
+    for job in jobs {
+        if let Outcome::Fail(..) = job.outcome {
+            let _ = writeln!(
+                ret,
+                "\n<details><summary>Failure: <code>{:.7}</code> <code>{}</code></summary>\n",
+                job.commit,
+                html_escape(&job.job),
+            );
+            code_block(
+                &mut ret,
+                job.error.as_deref().unwrap_or(""),
+                MARKDOWN_FAILURE_LINES,
+            );
+            ret.push_str("\n</details>\n");
+        }
+    }
+    ret
+}
+
+/// The text of a comment describing the results of a run, cut short at
+/// about `max_len` bytes
+pub(crate) fn comment(summary: &Summary, jobs: &[FinishedJob], max_len: usize) -> String {
+    let mut ret = format!("{}\n", COMMENT_MARKER);
+    // Commits are ordered from the base, so the last one is the tip
+    if let Some((commit, _)) = summary.rows().last() {
+        let _ = write!(ret, "Results of `check-pr` up to `{:.7}`:\n\n", commit);
+    }
+    ret.push_str(&markdown(summary, jobs));
+    if ret.len() > max_len {
+        let mut end = max_len;
+        while !ret.is_char_boundary(end) {
+            end -= 1;
+        }
+        ret.truncate(end);
+        ret.push_str("\n\n[truncated]\n");
+    }
+    ret
+}
+
+/// Adds the last `max_lines` lines of some text to a Markdown document, as
+/// a code block
+pub(crate) fn code_block(ret: &mut String, text: &str, max_lines: usize) {
+    let lines: Vec<&str> = text.lines().collect();
+    let start = lines.len().saturating_sub(max_lines);
+    // Use a longer fence than any run of backticks in the output
+    let fence = "`".repeat(3.max(longest_backtick_run(text) + 1));
+    let _ = writeln!(ret, "{}", fence);
+    if start > 0 {
+        let _ = writeln!(ret, "[{} lines omitted]", start);
+    }
+    for line in &lines[start..] {
+        let _ = writeln!(ret, "{}", line);
+    }
+    let _ = writeln!(ret, "{}", fence);
+}
+
+/// Escapes text for use in HTML in a Markdown document
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Length of the longest run of backticks in some text
+fn longest_backtick_run(s: &str) -> usize {
+    s.split(|c| c != '`').map(str::len).max().unwrap_or(0)
+}
+
+/// Time a job took, in seconds, or zero if it was not run
+fn seconds(outcome: Outcome) -> f64 {
+    match outcome {
+        Outcome::Pass(time) | Outcome::Fail(time) => time.as_secs_f64(),
+        Outcome::Cached | Outcome::Skipped => 0.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn reports() {
+        let commit = git2::Oid::from_str("aaaaaaa").unwrap();
+        let job = |job: &str, outcome, error: Option<&str>| FinishedJob {
+            error: error.map(str::to_owned),
+            ..FinishedJob::example(job, outcome)
+        };
+        let jobs = [
+            job(
+                "stable cargo build '--features='",
+                Outcome::Pass(Duration::from_millis(1500)),
+                None,
+            ),
+            job(
+                "stable cargo test '--features='",
+                Outcome::Fail(Duration::from_secs(2)),
+                Some("cargo test: exited\nexpected `u32`, found `&str`"),
+            ),
+            job("stable cargo fuzz run 'a'", Outcome::Cached, None),
+        ];
+        assert_eq!(
+            junit(&jobs[..]),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites>
+  <testsuite name="aaaaaaa000000000000000000000000000000000" tests="3" failures="1" skipped="1" time="3.500">
+    <testcase classname="aaaaaaa000000000000000000000000000000000" name="stable cargo build &apos;--features=&apos;" time="1.500"/>
+    <testcase classname="aaaaaaa000000000000000000000000000000000" name="stable cargo test &apos;--features=&apos;" time="2.000">
+      <failure message="cargo test: exited">cargo test: exited
+expected `u32`, found `&amp;str`</failure>
+    </testcase>
+    <testcase classname="aaaaaaa000000000000000000000000000000000" name="stable cargo fuzz run &apos;a&apos;" time="0.000">
+      <skipped message="passed in an earlier run"/>
+    </testcase>
+  </testsuite>
+</testsuites>
+"#,
+        );
+
+        let mut summary = Summary::default();
+        summary.record(0, commit, "{ rust }", Outcome::Fail(Duration::from_secs(2)));
+        summary.record(0, commit, "{ fuzz }", Outcome::Cached);
+        assert_eq!(
+            markdown(&summary, &jobs),
+            r#"| Commit | Check 1 | Check 2 |
+|---|---|---|
+| `aaaaaaa` | **FAIL 2.0s** | _cached_ |
+
+- Check 1: `{ rust }`
+- Check 2: `{ fuzz }`
+
+<details><summary>Failure: <code>aaaaaaa</code> <code>stable cargo test '--features='</code></summary>
+
+```
+cargo test: exited
+expected `u32`, found `&str`
+```
+
+</details>
+"#,
+        );
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+//! Database of the result of every job ever run
+//!
+//! Notes only record which jobs have passed, which is all that is needed to
+//! decide what to run. The database also records failures, how long each
+//! job took and where its output was saved, so that history can be queried
+//! with `sqlite3`.
+
+use anyhow::Context;
+use rusqlite::{params, Connection};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::output::{FinishedJob, Outcome};
+
+/// Creates the tables, if they do not already exist
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS jobs (
+    id INTEGER PRIMARY KEY,
+    -- When the run which ran the job started, in RFC 3339 format
+    run_time TEXT NOT NULL,
+    repo TEXT NOT NULL,
+    commit_id TEXT NOT NULL,
+    -- Git blob ID of the JSON configuration of the job's check
+    check_hash TEXT NOT NULL,
+    toolchain TEXT NOT NULL,
+    job TEXT NOT NULL,
+    -- One of 'pass', 'fail' or 'cached'
+    outcome TEXT NOT NULL,
+    -- NULL for cached jobs
+    seconds REAL,
+    log_path TEXT
+);
+CREATE INDEX IF NOT EXISTS jobs_commit ON jobs (commit_id);
+";
+
+/// An open results database
+pub struct ResultsDb {
+    conn: Connection,
+}
+
+impl ResultsDb {
+    /// Opens the database at the given path, creating it if need be
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("creating directory {}", dir.to_string_lossy()))?;
+        }
+        let conn = Connection::open(path)
+            .with_context(|| format!("opening results database {}", path.to_string_lossy()))?;
+        conn.execute_batch(SCHEMA)
+            .context("creating results database tables")?;
+        Ok(ResultsDb { conn })
+    }
+
+    /// Records the jobs of a run, which started at `run_time`, on `repo`
+    pub fn record(
+        &mut self,
+        run_time: &str,
+        repo: &str,
+        jobs: &[FinishedJob],
+    ) -> anyhow::Result<()> {
+        let tx = self
+            .conn
+            .transaction()
+            .context("starting results transaction")?;
+        {
+            let mut insert = tx
+                .prepare(
+                    "INSERT INTO jobs (run_time, repo, commit_id, check_hash, toolchain, job,
+                                       outcome, seconds, log_path)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                )
+                .context("preparing results insert")?;
+            for job in jobs {
+                let (outcome, seconds) = match job.outcome {
+                    Outcome::Pass(time) => ("pass", Some(time.as_secs_f64())),
+                    Outcome::Fail(time) => ("fail", Some(time.as_secs_f64())),
+                    Outcome::Cached => ("cached", None),
+                    Outcome::Skipped => ("skipped", None),
+                };
+                insert
+                    .execute(params![
+                        run_time,
+                        repo,
+                        job.commit.to_string(),
+                        job.check,
+                        job.toolchain,
+                        job.job,
+                        outcome,
+                        seconds,
+                        job.log.as_ref().map(|log| log.to_string_lossy()),
+                    ])
+                    .with_context(|| format!("recording result of {}", job.job))?;
+            }
+        }
+        tx.commit().context("committing results")
+    }
+
+    /// Returns every job which was actually run (rather than cached) on a
+    /// commit, oldest first
+    pub fn jobs_for_commit(&self, commit: git2::Oid) -> anyhow::Result<Vec<FinishedJob>> {
+        let mut query = self
+            .conn
+            .prepare(
+                "SELECT check_hash, toolchain, job, outcome, seconds, log_path FROM jobs
+                 WHERE commit_id = ?1 AND outcome IN ('pass', 'fail') ORDER BY id",
+            )
+            .context("preparing results query")?;
+        let rows = query
+            .query_map(params![commit.to_string()], |row| {
+                let time = Duration::from_secs_f64(row.get::<_, Option<f64>>(4)?.unwrap_or(0.0));
+                Ok(FinishedJob {
+                    commit,
+                    check: row.get(0)?,
+                    toolchain: row.get(1)?,
+                    job: row.get(2)?,
+                    outcome: if row.get::<_, String>(3)? == "pass" {
+                        Outcome::Pass(time)
+                    } else {
+                        Outcome::Fail(time)
+                    },
+                    error: None,
+                    log: row.get::<_, Option<String>>(5)?.map(PathBuf::from),
+                    annotations: vec![],
+                })
+            })
+            .with_context(|| format!("looking up results of {}", commit))?;
+        rows.collect::<Result<_, _>>()
+            .with_context(|| format!("reading results of {}", commit))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn record() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("sub/results.sqlite");
+        let jobs = [
+            FinishedJob::example("build", Outcome::Pass(Duration::from_secs(3))),
+            FinishedJob::example("test", Outcome::Fail(Duration::from_secs(5))),
+            FinishedJob::example("fuzz", Outcome::Cached),
+        ];
+        ResultsDb::open(&path)
+            .unwrap()
+            .record("2021-01-01T00:00:00Z", "/repo", &jobs)
+            .unwrap();
+        // Reopening must not clobber what is already there
+        let mut db = ResultsDb::open(&path).unwrap();
+        db.record("2021-01-02T00:00:00Z", "/repo", &jobs[..1])
+            .unwrap();
+
+        let found = db.jobs_for_commit(jobs[0].commit).unwrap();
+        assert_eq!(found.len(), 3);
+        assert_eq!(found[1], jobs[1]);
+
+        let (count, total): (i64, f64) = db
+            .conn
+            .query_row(
+                "SELECT COUNT(*), SUM(seconds) FROM jobs WHERE job = 'build'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((count, total), (2, 6.0));
+    }
+}
@@ -0,0 +1,255 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+//! Saving the progress of a run, so that it can be resumed if interrupted
+//!
+//! Notes are only written once a check has passed on a commit, so a run
+//! which is cut short loses every job of a check which had not finished.
+//! The journal records each job as soon as it passes, along with how the
+//! run was started, so that `check-pr --resume` can start it again without
+//! redoing any of them.
+//!
+//! The journal is kept as JSON lines: the first says how the run was
+//! started, and each after it records one job which passed, appended as it
+//! does so.
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Name of the journal file, in the repository's git directory
+const FILE_NAME: &str = "check-pr-resume.jsonl";
+
+/// Everything needed to resume a run
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Journal {
+    /// Directory the run was started in, which relative paths in its
+    /// arguments are relative to
+    pub cwd: PathBuf,
+    /// Command-line arguments the run was started with
+    pub args: Vec<String>,
+    /// Notes of every job which has passed, by commit
+    pub passed: BTreeMap<String, Vec<String>>,
+}
+
+/// The first line of a saved journal
+#[derive(Serialize, Deserialize)]
+struct Header {
+    cwd: PathBuf,
+    args: Vec<String>,
+}
+
+/// Any later line of a saved journal, for a job which passed
+#[derive(Serialize, Deserialize)]
+struct Passed {
+    commit: String,
+    note: String,
+}
+
+impl Passed {
+    /// The line recording the job, with its newline
+    fn line(&self) -> String {
+        let mut line = serde_json::to_string(self).expect("journal lines can be serialized");
+        line.push('\n');
+        line
+    }
+}
+
+/// The journal of the current run, where it is saved, and the file
+/// opened for appending to it
+static ACTIVE: Mutex<Option<(PathBuf, Journal, fs::File)>> = Mutex::new(None);
+
+impl Journal {
+    /// Creates an empty journal for a run started with the given arguments
+    pub fn new(cwd: PathBuf, args: Vec<String>) -> Self {
+        Journal {
+            cwd,
+            args,
+            passed: BTreeMap::new(),
+        }
+    }
+
+    /// Where the journal for runs on a repository is kept
+    pub fn path(repo: &git2::Repository) -> PathBuf {
+        repo.path().join(FILE_NAME)
+    }
+
+    /// Reads a saved journal
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let data = fs::read_to_string(path)
+            .with_context(|| format!("reading journal {}", path.to_string_lossy()))?;
+        // Being killed part-way through appending a line can only leave the
+        // last one cut short, without its newline, and its job is just run
+        // again
+        let complete = &data[..data.rfind('\n').map_or(0, |end| end + 1)];
+        let mut lines = complete.lines();
+        let header: Header = serde_json::from_str(lines.next().unwrap_or_default())
+            .with_context(|| format!("parsing journal {}", path.to_string_lossy()))?;
+        let mut journal = Journal::new(header.cwd, header.args);
+        for (n, line) in lines.enumerate() {
+            let passed: Passed = serde_json::from_str(line).with_context(|| {
+                format!(
+                    "parsing line {} of journal {}",
+                    n + 2,
+                    path.to_string_lossy()
+                )
+            })?;
+            journal
+                .passed
+                .entry(passed.commit)
+                .or_default()
+                .push(passed.note);
+        }
+        Ok(journal)
+    }
+
+    /// Writes the journal out, replacing any saved copy all at once so that
+    /// being killed part-way through cannot leave it corrupted, and returns
+    /// the file opened for appending jobs to
+    fn save(&self, path: &Path) -> anyhow::Result<fs::File> {
+        let tmp = path.with_extension("jsonl.tmp");
+        let header = Header {
+            cwd: self.cwd.clone(),
+            args: self.args.clone(),
+        };
+        let mut data = serde_json::to_string(&header).expect("journals can be serialized");
+        data.push('\n');
+        for (commit, notes) in &self.passed {
+            for note in notes {
+                data += &Passed {
+                    commit: commit.clone(),
+                    note: note.clone(),
+                }
+                .line();
+            }
+        }
+        fs::write(&tmp, data)
+            .with_context(|| format!("writing journal {}", tmp.to_string_lossy()))?;
+        fs::rename(&tmp, path)
+            .with_context(|| format!("replacing journal {}", path.to_string_lossy()))?;
+        fs::OpenOptions::new()
+            .append(true)
+            .open(path)
+            .with_context(|| format!("opening journal {}", path.to_string_lossy()))
+    }
+}
+
+/// Appends the line for a job which passed to a saved journal
+///
+/// It is written all at once, so that runs are never interleaved.
+fn append(file: &mut fs::File, commit: &str, note: &str) -> anyhow::Result<()> {
+    let line = Passed {
+        commit: commit.to_owned(),
+        note: note.to_owned(),
+    }
+    .line();
+    file.write_all(line.as_bytes())
+        .context("appending to journal")
+}
+
+/// Starts keeping the given journal up to date at the given path, for the
+/// rest of the program
+pub fn start(path: PathBuf, journal: Journal) -> anyhow::Result<()> {
+    let file = journal.save(&path)?;
+    *ACTIVE.lock().unwrap() = Some((path, journal, file));
+    Ok(())
+}
+
+/// Records that the job with the given note has passed on a commit
+///
+/// Does nothing if no journal has been started.
+pub fn record(commit: git2::Oid, note: &str) {
+    let mut active = ACTIVE.lock().unwrap();
+    if let Some((_, ref mut journal, ref mut file)) = *active {
+        let commit = commit.to_string();
+        // Losing track of a job only means running it again on resuming,
+        // which is no reason to fail it
+        if let Err(e) = append(file, &commit, note) {
+            eprintln!("Failed to update journal: {:?}", e);
+        }
+        journal
+            .passed
+            .entry(commit)
+            .or_default()
+            .push(note.to_owned());
+    }
+}
+
+/// Notes of the jobs recorded as passed on a commit
+pub fn notes(commit: git2::Oid) -> Vec<String> {
+    match *ACTIVE.lock().unwrap() {
+        Some((_, ref journal, _)) => journal
+            .passed
+            .get(&commit.to_string())
+            .cloned()
+            .unwrap_or_default(),
+        None => vec![],
+    }
+}
+
+/// Deletes the journal, once the run has finished and there is nothing
+/// left to resume
+pub fn finish() -> anyhow::Result<()> {
+    if let Some((path, _, _)) = ACTIVE.lock().unwrap().take() {
+        fs::remove_file(&path)
+            .with_context(|| format!("removing journal {}", path.to_string_lossy()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_load() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join(FILE_NAME);
+        let mut journal = Journal::new("/src".into(), vec!["check-pr".into(), "-t".into()]);
+        journal.passed.insert(
+            "aaaaaaa".into(),
+            vec!["stable cargo build # warnings 2".into()],
+        );
+        let mut file = journal.save(&path).unwrap();
+        assert_eq!(Journal::load(&path).unwrap(), journal);
+        assert!(!path.with_extension("jsonl.tmp").exists());
+
+        append(&mut file, "aaaaaaa", "stable cargo test").unwrap();
+        append(&mut file, "bbbbbbb", "stable cargo build # warnings 2").unwrap();
+        journal
+            .passed
+            .get_mut("aaaaaaa")
+            .unwrap()
+            .push("stable cargo test".into());
+        journal.passed.insert(
+            "bbbbbbb".into(),
+            vec!["stable cargo build # warnings 2".into()],
+        );
+        assert_eq!(Journal::load(&path).unwrap(), journal);
+
+        // A line cut short by being killed is ignored, but not a garbled one
+        file.write_all(br#"{"commit":"ccccccc","no"#).unwrap();
+        assert_eq!(Journal::load(&path).unwrap(), journal);
+        file.write_all(b"\n").unwrap();
+        assert!(Journal::load(&path).is_err());
+    }
+}
@@ -0,0 +1,141 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+use std::ffi::OsString;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::{process, thread};
+
+use anyhow::Context;
+use structopt::StructOpt;
+
+use git_utils::protocol::{self, Job, Stream};
+
+#[derive(StructOpt, Debug)]
+struct Opts {
+    /// Address to accept connections on, e.g. 127.0.0.1:7878, rather than
+    /// using stdin and stdout. There is no authentication, so anyone who can
+    /// connect can run anything: only listen where nobody else can reach.
+    #[structopt(short, long)]
+    listen: Option<String>,
+    /// Directory to fetch commits into, rather than the system's temporary
+    /// directory
+    #[structopt(long, parse(from_os_str))]
+    work_dir: Option<PathBuf>,
+    /// Rather than running jobs, send this one, as JSON, to the worker run
+    /// by COMMAND (e.g. `ssh host rsgit-worker`), printing its output as
+    /// our own and exiting as it does
+    #[structopt(long, requires = "command", conflicts_with = "listen")]
+    run_job: Option<String>,
+    /// Command which runs the worker, for --run-job
+    #[structopt(name = "command", last = true, parse(from_os_str))]
+    command: Vec<OsString>,
+}
+
+/// Exit status for when the job could not be run at all, as ssh uses for
+/// its own errors
+const ERROR_EXIT: i32 = 255;
+
+/// Runs a job on the worker run by `command`, relaying its output, and
+/// returns the status to exit with
+fn run_job(job: &str, command: &[OsString]) -> anyhow::Result<i32> {
+    let job: Job = serde_json::from_str(job).context("decoding --run-job")?;
+    let mut popen = subprocess::Exec::cmd(&command[0])
+        .args(&command[1..])
+        .stdin(subprocess::Redirection::Pipe)
+        .stdout(subprocess::Redirection::Pipe)
+        .popen()
+        .with_context(|| format!("running {}", command[0].to_string_lossy()))?;
+    let to_worker = popen.stdin.take().expect("stdin is piped");
+    let from_worker = BufReader::new(popen.stdout.take().expect("stdout is piped"));
+    let (stdout, stderr) = (io::stdout(), io::stderr());
+    let exit = protocol::run(from_worker, to_worker, job, |stream, data| {
+        match stream {
+            Stream::Stdout => stdout.lock().write_all(data.as_bytes()),
+            Stream::Stderr => stderr.lock().write_all(data.as_bytes()),
+        }
+        .context("relaying job output")
+    });
+    // Having closed its stdin, so that it stops
+    let _ = popen.wait();
+    Ok(match exit? {
+        (Some(code), _) => code as i32,
+        (None, Some(signal)) => 128 + i32::from(signal),
+        (None, None) => ERROR_EXIT,
+    })
+}
+
+fn main() -> anyhow::Result<()> {
+    let opts = Opts::from_args();
+    if let Some(ref job) = opts.run_job {
+        let code = run_job(job, &opts.command).unwrap_or_else(|e| {
+            eprintln!("Error: {:?}", e);
+            ERROR_EXIT
+        });
+        process::exit(code);
+    }
+    let listen = match opts.listen {
+        Some(ref listen) => listen,
+        None => {
+            let stdin = io::stdin();
+            let stdout = io::stdout();
+            return protocol::serve(
+                stdin.lock(),
+                BufWriter::new(stdout.lock()),
+                opts.work_dir.as_deref(),
+            );
+        }
+    };
+
+    let listener =
+        TcpListener::bind(listen).with_context(|| format!("listening for jobs on {}", listen))?;
+    eprintln!("Listening for jobs on {}", listen);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Error accepting connection: {}", e);
+                continue;
+            }
+        };
+        let work_dir = opts.work_dir.clone();
+        // Each connection gets its own thread, so several clients can run
+        // jobs at once
+        thread::spawn(move || {
+            let peer = stream
+                .peer_addr()
+                .map(|addr| addr.to_string())
+                .unwrap_or_default();
+            let result = stream
+                .try_clone()
+                .context("duplicating connection")
+                .and_then(|writer| {
+                    protocol::serve(
+                        BufReader::new(stream),
+                        BufWriter::new(writer),
+                        work_dir.as_deref(),
+                    )
+                });
+            if let Err(e) = result {
+                eprintln!("Error serving {}: {:#}", peer, e);
+            }
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,188 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+//! Record of the check configuration used by each check-pr run
+//!
+//! Each run is a commit on `RUNS_REF` whose tree contains the fully
+//! expanded check configuration, so that configurations can be compared
+//! across runs with `check-runs diff` (or plain `git diff`). It also lists
+//! the tips of the PRs the run checked, so that a PR which has been
+//! force-pushed since it was last checked can be noticed.
+
+use anyhow::Context;
+use git2::{Oid, Repository};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::str::FromStr;
+
+use crate::checks::Check;
+use crate::identity::Identity;
+
+/// Reference under which runs are recorded
+pub const RUNS_REF: &str = "refs/rsgit/runs";
+
+/// Name of the expanded check configuration within each run's tree
+pub const CONFIG_FILE: &str = "checks.json";
+
+/// Name of the list of PR tips within each run's tree, one `<oid> <tip>`
+/// line per PR, as named on the check-pr command line
+pub const TIPS_FILE: &str = "tips";
+
+/// Records a new run with the given configuration, PR tips and description
+pub fn record_run(
+    repo: &Repository,
+    identity: &Identity,
+    checks: &[Check],
+    tips: &[(String, Oid)],
+    description: &str,
+) -> anyhow::Result<Oid> {
+    let json = serde_json::to_string_pretty(checks).context("serializing check config")?;
+    let blob = repo
+        .blob(format!("{}\n", json).as_bytes())
+        .context("writing check config blob")?;
+    let mut builder = repo.treebuilder(None).context("getting a treebuilder")?;
+    builder
+        .insert(CONFIG_FILE, blob, 0o100644)
+        .context("putting check config in tree")?;
+    if !tips.is_empty() {
+        let mut list = String::new();
+        for (name, id) in tips {
+            writeln!(list, "{} {}", id, name).unwrap();
+        }
+        let blob = repo.blob(list.as_bytes()).context("writing PR tips blob")?;
+        builder
+            .insert(TIPS_FILE, blob, 0o100644)
+            .context("putting PR tips in tree")?;
+    }
+    let tree_id = builder.write().context("writing run tree")?;
+    let tree = repo
+        .find_tree(tree_id)
+        .context("reading tree we just wrote")?;
+
+    let parent = match repo.find_reference(RUNS_REF) {
+        Ok(existing) => Some(
+            existing
+                .peel_to_commit()
+                .with_context(|| format!("reading previous run from {}", RUNS_REF))?,
+        ),
+        Err(_) => None,
+    };
+    let parents: Vec<&_> = parent.iter().collect();
+    let sig = identity.signature(None)?;
+    repo.commit(Some(RUNS_REF), &sig, &sig, description, &tree, &parents)
+        .with_context(|| format!("recording run in {}", RUNS_REF))
+}
+
+/// Looks up a recorded run
+///
+/// Runs may be given as any git revision, or as a plain number `n`,
+/// meaning the `n`th most recent run (`0` being the latest).
+pub fn find_run<'repo>(repo: &'repo Repository, run: &str) -> anyhow::Result<git2::Commit<'repo>> {
+    let rev = match usize::from_str(run) {
+        Ok(n) => format!("{}~{}", RUNS_REF, n),
+        Err(_) => run.to_owned(),
+    };
+    repo.revparse_single(&rev)
+        .and_then(|obj| obj.peel_to_commit())
+        .with_context(|| format!("looking up run {}", rev))
+}
+
+/// Reads the PR tips recorded for a run, which are none for runs on a range
+/// or recorded before tips were
+pub fn run_tips(repo: &Repository, run: &git2::Commit) -> Vec<(String, Oid)> {
+    let blob = match run
+        .tree()
+        .ok()
+        .and_then(|tree| tree.get_name(TIPS_FILE).map(|entry| entry.id()))
+        .and_then(|id| repo.find_blob(id).ok())
+    {
+        Some(blob) => blob,
+        None => return vec![],
+    };
+    let content = String::from_utf8_lossy(blob.content());
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, ' ');
+            let id = Oid::from_str(parts.next()?).ok()?;
+            Some((parts.next()?.to_owned(), id))
+        })
+        .collect()
+}
+
+/// Finds the tip each PR had when it was last checked, by the most recent
+/// run to check it
+pub fn last_tips(repo: &Repository) -> HashMap<String, Oid> {
+    let mut tips = HashMap::new();
+    let mut run = match repo.find_reference(RUNS_REF) {
+        Ok(rf) => rf.peel_to_commit().ok(),
+        Err(_) => None,
+    };
+    while let Some(commit) = run {
+        for (name, id) in run_tips(repo, &commit) {
+            tips.entry(name).or_insert(id);
+        }
+        run = commit.parent(0).ok();
+    }
+    tips
+}
+
+/// Finds the commits of a PR's old tip which were dropped when it was
+/// force-pushed to its new tip, which are none if the new tip just adds
+/// commits to the old one
+pub fn superseded_commits(repo: &Repository, old: Oid, new: Oid) -> anyhow::Result<Vec<Oid>> {
+    if old == new
+        || repo
+            .graph_descendant_of(new, old)
+            .with_context(|| format!("comparing {} with {}", new, old))?
+    {
+        return Ok(vec![]);
+    }
+    let mut walk = repo.revwalk().context("walking history")?;
+    walk.push(old)
+        .with_context(|| format!("looking up old tip {}", old))?;
+    walk.hide(new)
+        .with_context(|| format!("looking up new tip {}", new))?;
+    walk.collect::<Result<_, _>>()
+        .with_context(|| format!("walking history of {}", old))
+}
+
+/// Produces a unified diff of the check configurations of two runs
+pub fn diff_runs(repo: &Repository, old: &str, new: &str) -> anyhow::Result<String> {
+    let old_tree = find_run(repo, old)?
+        .tree()
+        .context("getting old run tree")?;
+    let new_tree = find_run(repo, new)?
+        .tree()
+        .context("getting new run tree")?;
+    let diff = repo
+        .diff_tree_to_tree(Some(&old_tree), Some(&new_tree), None)
+        .with_context(|| format!("diffing runs {} and {}", old, new))?;
+
+    let mut ret = String::new();
+    diff.print(git2::DiffFormat::Patch, |_, _, line| {
+        let content = String::from_utf8_lossy(line.content());
+        match line.origin() {
+            '+' | '-' | ' ' => write!(ret, "{}{}", line.origin(), content),
+            _ => write!(ret, "{}", content),
+        }
+        .is_ok()
+    })
+    .context("formatting diff")?;
+    Ok(ret)
+}
@@ -0,0 +1,278 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+//! Running jobs in a lightweight sandbox
+//!
+//! For machines without a container engine, a `[sandbox]` section runs
+//! every build, test, example and fuzz job under bubblewrap or
+//! systemd-nspawn instead. The job can see the host's system directories,
+//! read-only, but not the rest of its filesystem: its home directory is
+//! empty apart from the rustup toolchains and cargo's downloaded
+//! dependencies, which are shared for speed. The toolchains are read-only,
+//! and the dependencies are overlaid with a throwaway layer, so that cargo
+//! can unpack crates without writing to the host's copy. As with
+//! containers, git operations and cargo commands which do not run the
+//! crate's code are still run on the host.
+
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+/// System directories which jobs can see, read-only
+const SYSTEM_DIRS: &[&str] = &["/usr", "/bin", "/sbin", "/lib", "/lib32", "/lib64", "/etc"];
+
+/// The program which sandboxes jobs
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Tool {
+    /// `bwrap`, which needs no privileges but at least version 0.9
+    #[default]
+    Bubblewrap,
+    /// `systemd-nspawn`, which must be run as root
+    SystemdNspawn,
+}
+
+/// How to sandbox jobs
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Sandbox {
+    /// The program to sandbox jobs with
+    #[serde(default)]
+    pub tool: Tool,
+    /// Extra options to it
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+/// The host directories shared with every job
+struct Shared {
+    home: PathBuf,
+    rustup_home: PathBuf,
+    cargo_home: PathBuf,
+}
+
+impl Shared {
+    /// Finds the directories, as rustup and cargo would
+    fn find() -> Self {
+        let home = env::var_os("HOME").map(PathBuf::from).unwrap_or_default();
+        let rustup_home = env::var_os("RUSTUP_HOME")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| home.join(".rustup"));
+        let cargo_home = env::var_os("CARGO_HOME")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| home.join(".cargo"));
+        Shared {
+            home,
+            rustup_home,
+            cargo_home,
+        }
+    }
+
+    /// The parts of the cargo home which are shared, and whether each is
+    /// overlaid rather than read-only
+    fn cargo_dirs(&self) -> Vec<(PathBuf, bool)> {
+        [
+            ("bin", false),
+            ("config", false),
+            ("config.toml", false),
+            ("registry", true),
+            ("git", true),
+        ]
+        .iter()
+        .map(|&(name, overlay)| (self.cargo_home.join(name), overlay))
+        .filter(|(path, _)| path.exists())
+        .collect()
+    }
+}
+
+impl Sandbox {
+    /// Constructs the command to run a program in the sandbox
+    ///
+    /// Each of `mounts` is writable by the job, and unless `env` sets
+    /// `CARGO_HOME`, the host's cargo home is shared as described above.
+    /// The environment is not inherited from the host, apart from `PATH`;
+    /// only `env` is set. The job only has network access if `network` is
+    /// set.
+    pub fn exec(
+        &self,
+        argv: &[OsString],
+        env: &[(OsString, OsString)],
+        cwd: &Path,
+        mounts: &[PathBuf],
+        network: bool,
+    ) -> subprocess::Exec {
+        let shared = Shared::find();
+        let own_cargo_home = env.iter().any(|(key, _)| key == "CARGO_HOME");
+        let mut vars = vec![
+            (OsString::from("HOME"), shared.home.clone().into_os_string()),
+            ("RUSTUP_HOME".into(), shared.rustup_home.clone().into()),
+        ];
+        if let Some(path) = env::var_os("PATH") {
+            vars.push(("PATH".into(), path));
+        }
+        if !own_cargo_home {
+            vars.push(("CARGO_HOME".into(), shared.cargo_home.clone().into()));
+        }
+        vars.extend(env.iter().cloned());
+
+        let exec = match self.tool {
+            Tool::Bubblewrap => bubblewrap(&shared, own_cargo_home, &vars, cwd, mounts, network),
+            Tool::SystemdNspawn => nspawn(&shared, own_cargo_home, &vars, cwd, mounts, network),
+        };
+        exec.args(&self.args).args(argv).stdin(subprocess::NullFile)
+    }
+}
+
+/// Joins an option and its value, for tools which take them as one argument
+fn opt(name: &str, value: impl AsRef<OsStr>) -> OsString {
+    let mut ret = OsString::from(name);
+    ret.push(value);
+    ret
+}
+
+/// Starts a `bwrap` command to sandbox a job
+fn bubblewrap(
+    shared: &Shared,
+    own_cargo_home: bool,
+    vars: &[(OsString, OsString)],
+    cwd: &Path,
+    mounts: &[PathBuf],
+    network: bool,
+) -> subprocess::Exec {
+    let mut exec = subprocess::Exec::cmd("bwrap")
+        .arg("--unshare-all")
+        .arg("--die-with-parent")
+        .arg("--clearenv");
+    if network {
+        exec = exec.arg("--share-net");
+    }
+    for dir in SYSTEM_DIRS {
+        exec = exec.args(&["--ro-bind-try", dir, dir]);
+    }
+    exec = exec
+        .args(&["--dev", "/dev", "--proc", "/proc", "--tmpfs", "/tmp"])
+        .arg("--tmpfs")
+        .arg(&shared.home)
+        .arg("--ro-bind-try")
+        .arg(&shared.rustup_home)
+        .arg(&shared.rustup_home);
+    if !own_cargo_home {
+        exec = exec.arg("--tmpfs").arg(&shared.cargo_home);
+        for (dir, overlay) in shared.cargo_dirs() {
+            exec = if overlay {
+                exec.arg("--overlay-src")
+                    .arg(&dir)
+                    .arg("--tmp-overlay")
+                    .arg(&dir)
+            } else {
+                exec.arg("--ro-bind").arg(&dir).arg(&dir)
+            };
+        }
+    }
+    for dir in mounts {
+        exec = exec.arg("--bind").arg(dir).arg(dir);
+    }
+    exec = exec.arg("--chdir").arg(cwd);
+    for (key, val) in vars {
+        exec = exec.arg("--setenv").arg(key).arg(val);
+    }
+    exec
+}
+
+/// Starts a `systemd-nspawn` command to sandbox a job
+///
+/// The host's root directory is used as the container's, read-only and
+/// with a fresh `/var`.
+fn nspawn(
+    shared: &Shared,
+    own_cargo_home: bool,
+    vars: &[(OsString, OsString)],
+    cwd: &Path,
+    mounts: &[PathBuf],
+    network: bool,
+) -> subprocess::Exec {
+    let mut exec = subprocess::Exec::cmd("systemd-nspawn")
+        .arg("--quiet")
+        .arg("--register=no")
+        .arg("--directory=/")
+        .arg("--volatile=state")
+        .arg("--as-pid2")
+        .arg("--console=pipe");
+    if !network {
+        exec = exec.arg("--private-network");
+    }
+    exec = exec
+        .arg("--tmpfs=/tmp")
+        .arg(opt("--tmpfs=", &shared.home))
+        .arg(opt("--bind-ro=", &shared.rustup_home));
+    if !own_cargo_home {
+        exec = exec.arg(opt("--tmpfs=", &shared.cargo_home));
+        for (dir, overlay) in shared.cargo_dirs() {
+            exec = if overlay {
+                // An empty upper directory is a temporary one
+                let mut spec = dir.clone().into_os_string();
+                spec.push("::");
+                spec.push(&dir);
+                exec.arg(opt("--overlay=", spec))
+            } else {
+                exec.arg(opt("--bind-ro=", &dir))
+            };
+        }
+    }
+    for dir in mounts {
+        exec = exec.arg(opt("--bind=", dir));
+    }
+    exec = exec.arg(opt("--chdir=", cwd));
+    for (key, val) in vars {
+        let mut var = key.clone();
+        var.push("=");
+        var.push(val);
+        exec = exec.arg(opt("--setenv=", var));
+    }
+    exec
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bwrap() {
+        let shared = Shared {
+            home: PathBuf::from("/nonexistent/home"),
+            rustup_home: PathBuf::from("/nonexistent/home/.rustup"),
+            cargo_home: PathBuf::from("/nonexistent/home/.cargo"),
+        };
+        let exec = bubblewrap(
+            &shared,
+            false,
+            &[("CARGO_NET_OFFLINE".into(), "true".into())],
+            Path::new("/tmp/repo"),
+            &[PathBuf::from("/tmp/repo")],
+            false,
+        );
+        let cmdline = exec.to_cmdline_lossy();
+        assert!(cmdline.starts_with("bwrap --unshare-all --die-with-parent --clearenv "));
+        assert!(!cmdline.contains("--share-net"));
+        assert!(cmdline.ends_with(
+            " --tmpfs /nonexistent/home/.cargo --bind /tmp/repo /tmp/repo \
+             --chdir /tmp/repo --setenv CARGO_NET_OFFLINE true"
+        ));
+    }
+}
@@ -0,0 +1,387 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+//! Checking PRs as they are updated, for `check-serve`
+//!
+//! PRs are found to need checking either by a webhook delivery, or by
+//! polling: fetching every PR's head ref, and seeing which have changed.
+//! Each PR to be checked is fetched into the local clone of its repository,
+//! to `refs/remotes/pr/<number>/head` as for `label-pr`, and then checked by
+//! running `check-pr` on it, with whatever checks are configured for that
+//! repository. PRs are checked one at a time, in the order they were queued.
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use crate::config::expand_home;
+use crate::pr::PullRequest;
+#[cfg(feature = "sqlite")]
+use crate::queue::QueueDb;
+
+fn default_remote() -> String {
+    "origin".to_owned()
+}
+
+fn default_pr_ref() -> String {
+    "refs/pull/*/head".to_owned()
+}
+
+/// A repository whose PRs are checked
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ServedRepo {
+    /// Name of the repository on its forge, e.g. `owner/name`
+    pub name: String,
+    /// Path of a local clone
+    pub path: String,
+    /// Secret which webhook deliveries for the repository are signed with
+    #[serde(default, skip_serializing)]
+    pub secret: Option<String>,
+    /// Remote to fetch PRs from
+    #[serde(default = "default_remote")]
+    pub remote: String,
+    /// Refs on the remote holding the tips of PRs, with `*` for the PR
+    /// number, e.g. `refs/merge-requests/*/head` for GitLab
+    #[serde(default = "default_pr_ref")]
+    pub pr_ref: String,
+    /// Branch which polled PRs are taken to be merged into, since only
+    /// webhook deliveries say which branch that really is. By default,
+    /// the one the remote's HEAD points to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base: Option<String>,
+    /// Extra arguments to give check-pr, e.g. `["--log-dir", "/srv/logs"]`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub check_pr_args: Vec<String>,
+}
+
+impl ServedRepo {
+    /// Path of the local clone
+    pub fn path(&self) -> PathBuf {
+        expand_home(&self.path)
+    }
+
+    /// Fetches the heads of every PR, returning those which are new or
+    /// have changed since they were last fetched
+    pub fn poll(&self) -> anyhow::Result<Vec<PrUpdate>> {
+        self.fetch_prs(true)
+    }
+
+    /// Fetches the heads of every PR, returning all of them, for `check-pr`
+    /// to run whichever of their checks are outstanding
+    ///
+    /// This includes PRs whose checks could not be run before, e.g. because
+    /// of an error or a crash, or which were fetched by something else.
+    pub fn all_prs(&self) -> anyhow::Result<Vec<PrUpdate>> {
+        self.fetch_prs(false)
+    }
+
+    /// Fetches the heads of every PR, returning those which have changed
+    /// since they were last fetched if `only_changed` is set, or else all
+    fn fetch_prs(&self, only_changed: bool) -> anyhow::Result<Vec<PrUpdate>> {
+        let path = self.path();
+        let repo = git2::Repository::open(&path)
+            .with_context(|| format!("opening repository {}", path.to_string_lossy()))?;
+        let tips = |repo: &git2::Repository| -> anyhow::Result<BTreeMap<usize, git2::Oid>> {
+            let prs = PullRequest::find_all(repo, "pr").context("listing PR refs")?;
+            Ok(prs.into_iter().map(|pr| (pr.number, pr.id)).collect())
+        };
+        let before = tips(&repo)?;
+        fetch(
+            &path,
+            &self.remote,
+            &[format!("+{}:refs/remotes/pr/*/head", self.pr_ref)],
+        )?;
+        let after = tips(&repo)?;
+        let base = match self.base {
+            Some(ref base) => base.clone(),
+            None => {
+                crate::git::default_branch(&repo, &self.remote)
+                    .with_context(|| format!("finding the base branch of {}", self.name))?
+                    .name
+            }
+        };
+
+        Ok(after
+            .into_iter()
+            .filter(|(number, id)| !only_changed || before.get(number) != Some(id))
+            .map(|(number, _)| PrUpdate {
+                repo: self.name.clone(),
+                number,
+                head_ref: self.pr_ref.replace('*', &number.to_string()),
+                base: base.clone(),
+            })
+            .collect())
+    }
+}
+
+/// Fetches the given refspecs from a remote
+pub fn fetch(path: &Path, remote: &str, refspecs: &[String]) -> anyhow::Result<()> {
+    let status = subprocess::Exec::cmd("git")
+        .arg("-C")
+        .arg(path)
+        .arg("fetch")
+        .arg("--quiet")
+        .arg(remote)
+        .args(refspecs)
+        .join()
+        .with_context(|| format!("running git fetch in {}", path.to_string_lossy()))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(anyhow::Error::msg(format!(
+            "fetching {} from {} exited with {:?}",
+            refspecs.join(" "),
+            remote,
+            status
+        )))
+    }
+}
+
+/// A PR to be checked
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PrUpdate {
+    /// Name of the repository on its forge
+    pub repo: String,
+    pub number: usize,
+    /// Ref on the remote holding the tip of the PR, e.g. `refs/pull/1/head`
+    pub head_ref: String,
+    /// Branch the PR is to be merged into
+    pub base: String,
+}
+
+impl PrUpdate {
+    /// Fetches the PR from `remote` to `refs/remotes/pr/<number>/head`, and
+    /// its base branch to `refs/remotes/<remote>/<base>`, returning the
+    /// names under which `check-pr` should look for them
+    pub fn fetch(&self, path: &Path, remote: &str) -> anyhow::Result<(String, String)> {
+        fetch(
+            path,
+            remote,
+            &[
+                format!("+{}:refs/remotes/pr/{}/head", self.head_ref, self.number),
+                format!(
+                    "+refs/heads/{}:refs/remotes/{}/{}",
+                    self.base, remote, self.base
+                ),
+            ],
+        )
+        .with_context(|| format!("fetching PR #{}", self.number))?;
+        Ok((
+            format!("pr/{}/head", self.number),
+            format!("{}/{}", remote, self.base),
+        ))
+    }
+
+    /// Fetches the PR and its base branch, then runs `check-pr` on it,
+    /// returning whether every check passed
+    pub fn check(&self, repo: &ServedRepo, check_pr: &Path) -> anyhow::Result<bool> {
+        let path = repo.path();
+        let (tip, master) = self.fetch(&path, &repo.remote)?;
+        let status = subprocess::Exec::cmd(check_pr)
+            .arg("--repo")
+            .arg(&path)
+            .arg("--tip")
+            .arg(tip)
+            .arg("--master")
+            .arg(master)
+            // Rather than failing if check-all, say, is running on it
+            .arg("--wait-for-lock")
+            .args(&repo.check_pr_args)
+            .join()
+            .with_context(|| format!("running {}", check_pr.to_string_lossy()))?;
+        Ok(status.success())
+    }
+}
+
+/// How often a queue kept on disk is checked for PRs queued by other
+/// processes, while waiting for one
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Where the queue is kept
+enum Store {
+    Memory(VecDeque<PrUpdate>),
+    #[cfg(feature = "sqlite")]
+    Db(QueueDb),
+}
+
+/// PRs waiting to be checked
+pub struct Queue {
+    store: Mutex<Store>,
+    ready: Condvar,
+}
+
+impl Default for Queue {
+    fn default() -> Self {
+        Queue {
+            store: Mutex::new(Store::Memory(VecDeque::new())),
+            ready: Condvar::new(),
+        }
+    }
+}
+
+impl Queue {
+    /// Creates an empty queue, kept in memory
+    pub fn new() -> Self {
+        Queue::default()
+    }
+
+    /// Opens a queue kept in a database, creating it if need be
+    #[cfg(feature = "sqlite")]
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        Ok(Queue {
+            store: Mutex::new(Store::Db(QueueDb::open(path)?)),
+            ready: Condvar::new(),
+        })
+    }
+
+    /// Puts back any PRs which were being checked when the process checking
+    /// them stopped, returning how many there were
+    ///
+    /// This must only be called by the process which checks PRs, before it
+    /// starts doing so.
+    pub fn requeue_started(&self) -> anyhow::Result<usize> {
+        match *self.store.lock().unwrap() {
+            Store::Memory(_) => Ok(0),
+            #[cfg(feature = "sqlite")]
+            Store::Db(ref mut db) => db.requeue_started(),
+        }
+    }
+
+    /// Adds a PR to the end of the queue, unless it is already waiting,
+    /// returning whether it was added
+    ///
+    /// A PR which is already waiting will be fetched afresh when it is
+    /// checked, so needs no second run.
+    pub fn push(&self, update: PrUpdate) -> anyhow::Result<bool> {
+        let added = match *self.store.lock().unwrap() {
+            Store::Memory(ref mut updates) => {
+                let waiting = updates
+                    .iter()
+                    .any(|u| u.repo == update.repo && u.number == update.number);
+                if !waiting {
+                    updates.push_back(update);
+                }
+                !waiting
+            }
+            #[cfg(feature = "sqlite")]
+            Store::Db(ref mut db) => db.push(&update)?,
+        };
+        if added {
+            self.ready.notify_one();
+        }
+        Ok(added)
+    }
+
+    /// Takes the PR at the front of the queue, waiting for one if needed
+    ///
+    /// Once it has been checked, [`Queue::done`] should be called.
+    pub fn pop(&self) -> anyhow::Result<PrUpdate> {
+        let mut store = self.store.lock().unwrap();
+        loop {
+            let front = match *store {
+                Store::Memory(ref mut updates) => updates.pop_front(),
+                #[cfg(feature = "sqlite")]
+                Store::Db(ref mut db) => db.pop()?,
+            };
+            if let Some(update) = front {
+                return Ok(update);
+            }
+            store = self
+                .ready
+                .wait_timeout(store, QUEUE_POLL_INTERVAL)
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// Records that a PR taken from the queue has been checked
+    #[cfg_attr(not(feature = "sqlite"), allow(unused_variables))]
+    pub fn done(&self, update: &PrUpdate) -> anyhow::Result<()> {
+        match *self.store.lock().unwrap() {
+            Store::Memory(_) => Ok(()),
+            #[cfg(feature = "sqlite")]
+            Store::Db(ref mut db) => db.done(update),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queue() {
+        let update = |repo: &str, number| PrUpdate {
+            repo: repo.to_owned(),
+            number,
+            head_ref: format!("refs/pull/{}/head", number),
+            base: "master".to_owned(),
+        };
+        let queue = Queue::new();
+        assert!(queue.push(update("a/b", 1)).unwrap());
+        assert!(queue.push(update("a/b", 2)).unwrap());
+        assert!(!queue.push(update("a/b", 1)).unwrap());
+        assert!(queue.push(update("a/c", 1)).unwrap());
+        assert_eq!(queue.pop().unwrap(), update("a/b", 1));
+        assert!(queue.push(update("a/b", 1)).unwrap());
+        assert_eq!(queue.pop().unwrap(), update("a/b", 2));
+        assert_eq!(queue.pop().unwrap(), update("a/c", 1));
+        assert_eq!(queue.pop().unwrap(), update("a/b", 1));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn queue_db() {
+        let update = |number| PrUpdate {
+            repo: "a/b".to_owned(),
+            number,
+            head_ref: format!("refs/pull/{}/head", number),
+            base: "master".to_owned(),
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("queue.sqlite");
+        {
+            let queue = Queue::open(&path).unwrap();
+            assert!(queue.push(update(1)).unwrap());
+            assert!(queue.push(update(2)).unwrap());
+            assert!(queue.push(update(3)).unwrap());
+            assert_eq!(queue.pop().unwrap(), update(1));
+            queue.done(&update(1)).unwrap();
+            // Killed while checking #2, which is queued again meanwhile
+            assert_eq!(queue.pop().unwrap(), update(2));
+            assert!(queue.push(update(2)).unwrap());
+        }
+        // Another process queueing a PR
+        assert!(Queue::open(&path).unwrap().push(update(4)).unwrap());
+
+        let queue = Queue::open(&path).unwrap();
+        assert_eq!(queue.requeue_started().unwrap(), 0);
+        assert_eq!(queue.pop().unwrap(), update(3));
+        assert_eq!(queue.pop().unwrap(), update(2));
+        assert_eq!(queue.pop().unwrap(), update(4));
+        drop(queue);
+        let queue = Queue::open(&path).unwrap();
+        assert_eq!(queue.requeue_started().unwrap(), 3);
+        assert!(!queue.push(update(4)).unwrap());
+        assert_eq!(queue.pop().unwrap(), update(3));
+    }
+}
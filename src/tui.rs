@@ -0,0 +1,232 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+//! Live dashboard of the jobs being run
+//!
+//! The dashboard takes over the terminal, using its alternate screen so
+//! that the normal scrollback is left alone, and is redrawn a few times a
+//! second from the events given to `update`.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::output::{Event, Outcome};
+
+/// How often the dashboard is redrawn
+const REDRAW_INTERVAL: Duration = Duration::from_millis(250);
+/// Width of the progress bars, in characters
+const BAR_WIDTH: usize = 30;
+/// How many lines of the most recent failure to show
+const FAILURE_LINES: usize = 15;
+
+/// State of the dashboard, if there is one
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+
+/// Progress of the jobs on a single commit with a single toolchain
+#[derive(Default)]
+struct Progress {
+    queued: usize,
+    passed: usize,
+    failed: usize,
+}
+
+/// Everything displayed on the dashboard
+struct State {
+    start: Instant,
+    /// Progress keyed by commit and toolchain, in the order first seen
+    progress: Vec<((git2::Oid, String), Progress)>,
+    /// Running jobs, keyed by commit, toolchain and job, with their start time
+    running: BTreeMap<(git2::Oid, String, String), Instant>,
+    /// Description and error message of the latest job to fail
+    last_failure: Option<(String, String)>,
+}
+
+impl State {
+    fn progress(&mut self, commit: git2::Oid, toolchain: &str) -> &mut Progress {
+        let idx = match self
+            .progress
+            .iter()
+            .position(|((c, t), _)| *c == commit && t == toolchain)
+        {
+            Some(idx) => idx,
+            None => {
+                let key = (commit, toolchain.to_owned());
+                self.progress.push((key, Progress::default()));
+                self.progress.len() - 1
+            }
+        };
+        &mut self.progress[idx].1
+    }
+
+    fn update(&mut self, event: &Event) {
+        match *event {
+            Event::Queued {
+                commit,
+                toolchain,
+                count,
+            } => self.progress(commit, toolchain).queued += count,
+            Event::Started {
+                commit,
+                toolchain,
+                job,
+                ..
+            } => {
+                let key = (commit, toolchain.to_owned(), job.to_owned());
+                self.running.insert(key, Instant::now());
+            }
+            Event::Finished {
+                commit,
+                toolchain,
+                job,
+                outcome,
+                error,
+                ..
+            } => {
+                let key = (commit, toolchain.to_owned(), job.to_owned());
+                self.running.remove(&key);
+                let progress = self.progress(commit, toolchain);
+                match outcome {
+                    Outcome::Fail(..) => progress.failed += 1,
+                    _ => progress.passed += 1,
+                }
+                if let Some(error) = error {
+                    let desc = format!("{:.7} {}", commit, job);
+                    self.last_failure = Some((desc, error.to_owned()));
+                }
+            }
+            Event::NoteWritten { .. } | Event::RunFinished { .. } => {}
+        }
+    }
+
+    fn render(&self) -> String {
+        let mut ret = String::new();
+        let now = Instant::now();
+        let _ = writeln!(
+            ret,
+            "check-pr: {}s elapsed, {} jobs running",
+            now.duration_since(self.start).as_secs(),
+            self.running.len()
+        );
+
+        ret.push_str("\n\x1b[1mProgress\x1b[0m\n");
+        for ((commit, toolchain), progress) in &self.progress {
+            let done = progress.passed + progress.failed;
+            let filled = (BAR_WIDTH * done).checked_div(progress.queued).unwrap_or(0);
+            let color = if progress.failed > 0 { "31" } else { "32" };
+            let _ = writeln!(
+                ret,
+                "  {:.7} {:<12} \x1b[{}m{}\x1b[0m{} {}/{}{}",
+                commit,
+                toolchain,
+                color,
+                "#".repeat(filled.min(BAR_WIDTH)),
+                "-".repeat(BAR_WIDTH - filled.min(BAR_WIDTH)),
+                done,
+                progress.queued,
+                if progress.failed > 0 {
+                    format!(" ({} failed)", progress.failed)
+                } else {
+                    String::new()
+                },
+            );
+        }
+
+        ret.push_str("\n\x1b[1mRunning\x1b[0m\n");
+        for ((commit, _, job), start) in &self.running {
+            let _ = writeln!(
+                ret,
+                "  {:>5}s  {:.7} {}",
+                now.duration_since(*start).as_secs(),
+                commit,
+                job
+            );
+        }
+
+        if let Some((ref desc, ref error)) = self.last_failure {
+            let _ = writeln!(ret, "\n\x1b[1;31mLast failure\x1b[0m: {}", desc);
+            let lines: Vec<&str> = error.lines().collect();
+            for line in &lines[lines.len().saturating_sub(FAILURE_LINES)..] {
+                let _ = writeln!(ret, "  {}", line);
+            }
+        }
+        ret
+    }
+}
+
+/// Passes an event on to the dashboard, if it is running
+pub fn update(event: &Event) {
+    if let Some(ref mut state) = *STATE.lock().unwrap() {
+        state.update(event);
+    }
+}
+
+/// Handle to the running dashboard, which restores the terminal when dropped
+pub struct Dashboard {
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Dashboard {
+    /// Switches the terminal to the dashboard and starts redrawing it
+    pub fn start() -> Self {
+        *STATE.lock().unwrap() = Some(State {
+            start: Instant::now(),
+            progress: vec![],
+            running: BTreeMap::new(),
+            last_failure: None,
+        });
+        // Switch to the alternate screen, hide the cursor and turn off line
+        // wrapping, so that long lines are cut off rather than scrolling
+        print!("\x1b[?1049h\x1b[?25l\x1b[?7l");
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = thread::spawn(move || {
+            while !thread_stop.load(Ordering::Relaxed) {
+                if let Some(ref state) = *STATE.lock().unwrap() {
+                    let stdout = io::stdout();
+                    let mut stdout = stdout.lock();
+                    let _ = write!(stdout, "\x1b[H\x1b[2J{}", state.render());
+                    let _ = stdout.flush();
+                }
+                thread::sleep(REDRAW_INTERVAL);
+            }
+        });
+        Dashboard {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Dashboard {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        *STATE.lock().unwrap() = None;
+        print!("\x1b[?7h\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+    }
+}
@@ -0,0 +1,263 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+//! Webhook deliveries from GitHub and GitLab
+//!
+//! GitHub signs each delivery with an HMAC of its body, keyed by the secret
+//! set for the webhook, while GitLab simply sends the secret along with it.
+//! Either way, a delivery is only acted on if it checks out against the
+//! secret configured for the repository it claims to be about.
+
+use anyhow::Context;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::http::Incoming;
+use crate::serve::{PrUpdate, ServedRepo};
+
+/// What to do about a webhook delivery
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Delivery {
+    /// Check a PR which was opened or updated
+    Check(PrUpdate),
+    /// Nothing, e.g. because a PR was closed, for the given reason
+    Ignore(String),
+}
+
+/// Works out what to do about a webhook delivery, having verified it with
+/// the secret of the repository it is about
+pub fn parse(request: &Incoming, repos: &[ServedRepo]) -> anyhow::Result<Delivery> {
+    let payload: serde_json::Value =
+        serde_json::from_slice(&request.body).context("decoding webhook payload")?;
+    let find_repo = |name: Option<&str>| -> anyhow::Result<&ServedRepo> {
+        let name = name.ok_or_else(|| anyhow::Error::msg("payload does not name a repository"))?;
+        repos
+            .iter()
+            .find(|repo| repo.name == name)
+            .ok_or_else(|| anyhow::Error::msg(format!("repository {} is not served", name)))
+    };
+    let secret = |repo: &ServedRepo| -> anyhow::Result<String> {
+        repo.secret.clone().ok_or_else(|| {
+            anyhow::Error::msg(format!("no webhook secret is set for {}", repo.name))
+        })
+    };
+
+    if let Some(event) = request.header("X-GitHub-Event") {
+        let repo = find_repo(payload["repository"]["full_name"].as_str())?;
+        let signature = request
+            .header("X-Hub-Signature-256")
+            .ok_or_else(|| anyhow::Error::msg("delivery is not signed"))?;
+        if !github_signature_valid(&secret(repo)?, &request.body, signature) {
+            return Err(anyhow::Error::msg(format!(
+                "bad signature for {}",
+                repo.name
+            )));
+        }
+        if event != "pull_request" {
+            return Ok(Delivery::Ignore(format!("{} event", event)));
+        }
+        let action = payload["action"].as_str().unwrap_or("");
+        if !["opened", "reopened", "synchronize"].contains(&action) {
+            return Ok(Delivery::Ignore(format!("PR {}", action)));
+        }
+        let number = number(&payload["number"])?;
+        Ok(Delivery::Check(PrUpdate {
+            repo: repo.name.clone(),
+            number,
+            head_ref: format!("refs/pull/{}/head", number),
+            base: string(&payload["pull_request"]["base"]["ref"])?,
+        }))
+    } else if let Some(event) = request.header("X-Gitlab-Event") {
+        let repo = find_repo(payload["project"]["path_with_namespace"].as_str())?;
+        let token = request.header("X-Gitlab-Token").unwrap_or("");
+        if !constant_time_eq(secret(repo)?.as_bytes(), token.as_bytes()) {
+            return Err(anyhow::Error::msg(format!("bad token for {}", repo.name)));
+        }
+        if event != "Merge Request Hook" {
+            return Ok(Delivery::Ignore(event.to_owned()));
+        }
+        let attrs = &payload["object_attributes"];
+        let action = attrs["action"].as_str().unwrap_or("");
+        // Merge requests are also "updated" by e.g. editing their title,
+        // but only have an `oldrev` if they were pushed to
+        let pushed = action == "update" && attrs.get("oldrev").is_some();
+        if !(action == "open" || action == "reopen" || pushed) {
+            return Ok(Delivery::Ignore(format!("merge request {}", action)));
+        }
+        let number = number(&attrs["iid"])?;
+        Ok(Delivery::Check(PrUpdate {
+            repo: repo.name.clone(),
+            number,
+            head_ref: format!("refs/merge-requests/{}/head", number),
+            base: string(&attrs["target_branch"])?,
+        }))
+    } else {
+        Err(anyhow::Error::msg(
+            "not a GitHub or GitLab webhook delivery",
+        ))
+    }
+}
+
+/// Reads a PR number from a payload
+fn number(value: &serde_json::Value) -> anyhow::Result<usize> {
+    value
+        .as_u64()
+        .map(|n| n as usize)
+        .ok_or_else(|| anyhow::Error::msg(format!("bad PR number {}", value)))
+}
+
+/// Reads a string from a payload
+fn string(value: &serde_json::Value) -> anyhow::Result<String> {
+    value
+        .as_str()
+        .map(str::to_owned)
+        .ok_or_else(|| anyhow::Error::msg(format!("expected a string, got {}", value)))
+}
+
+/// Checks a `X-Hub-Signature-256` header, `sha256=<hex HMAC of the body>`
+fn github_signature_valid(secret: &str, body: &[u8], signature: &str) -> bool {
+    let hex = match signature.strip_prefix("sha256=") {
+        Some(hex) => hex,
+        None => return false,
+    };
+    let expected = match hex_decode(hex) {
+        Some(expected) => expected,
+        None => return false,
+    };
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(body);
+    mac.verify_slice(&expected).is_ok()
+}
+
+/// Decodes a hex string
+fn hex_decode(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Compares two secrets without leaking, through timing, how much of them
+/// matches
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &str, body: &str) -> Incoming {
+        let raw = format!(
+            "POST / HTTP/1.1\r\n{}Content-Length: {}\r\n\r\n{}",
+            headers,
+            body.len(),
+            body
+        );
+        Incoming::read(&mut raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn deliveries() {
+        let repos = vec![ServedRepo {
+            name: "a/b".to_owned(),
+            path: "/srv/b".to_owned(),
+            secret: Some("It's a Secret to Everybody".to_owned()),
+            remote: "origin".to_owned(),
+            pr_ref: "refs/pull/*/head".to_owned(),
+            base: Some("master".to_owned()),
+            check_pr_args: vec![],
+        }];
+
+        // Example from GitHub's documentation
+        assert!(github_signature_valid(
+            "It's a Secret to Everybody",
+            b"Hello, World!",
+            "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17",
+        ));
+
+        let body = r#"{"action":"synchronize","number":12,"repository":{"full_name":"a/b"},
+                       "pull_request":{"base":{"ref":"master"}}}"#;
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"It's a Secret to Everybody").unwrap();
+        mac.update(body.as_bytes());
+        let signature: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let github = |event: &str, signature: &str, body: &str| {
+            let headers = format!(
+                "X-GitHub-Event: {}\r\nX-Hub-Signature-256: sha256={}\r\n",
+                event, signature
+            );
+            parse(&request(&headers, body), &repos)
+        };
+        assert_eq!(
+            github("pull_request", &signature, body).unwrap(),
+            Delivery::Check(PrUpdate {
+                repo: "a/b".to_owned(),
+                number: 12,
+                head_ref: "refs/pull/12/head".to_owned(),
+                base: "master".to_owned(),
+            }),
+        );
+        assert_eq!(
+            github("push", &signature, body).unwrap(),
+            Delivery::Ignore("push event".to_owned()),
+        );
+        assert!(github("pull_request", &"0".repeat(64), body).is_err());
+        assert!(github("pull_request", &signature, &body.replace("a/b", "a/c")).is_err());
+
+        let body = r#"{"object_kind":"merge_request","project":{"path_with_namespace":"a/b"},
+                       "object_attributes":{"action":"update","iid":3,"target_branch":"main",
+                                            "oldrev":"abc"}}"#;
+        let gitlab = |token: &str, body: &str| {
+            let headers = format!(
+                "X-Gitlab-Event: Merge Request Hook\r\nX-Gitlab-Token: {}\r\n",
+                token
+            );
+            parse(&request(&headers, body), &repos)
+        };
+        assert_eq!(
+            gitlab("It's a Secret to Everybody", body).unwrap(),
+            Delivery::Check(PrUpdate {
+                repo: "a/b".to_owned(),
+                number: 3,
+                head_ref: "refs/merge-requests/3/head".to_owned(),
+                base: "main".to_owned(),
+            }),
+        );
+        assert_eq!(
+            gitlab(
+                "It's a Secret to Everybody",
+                &body.replace(
+                    ",\n                                            \"oldrev\":\"abc\"",
+                    ""
+                )
+            )
+            .unwrap(),
+            Delivery::Ignore("merge request update".to_owned()),
+        );
+        assert!(gitlab("guess", body).is_err());
+    }
+}
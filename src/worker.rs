@@ -0,0 +1,332 @@
+// Copyright (c) 2021
+//      Andrew Poelstra <rsgit@wpsoftware.net>
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software
+// Foundation, Inc., 675 Mass Ave, Cambridge, MA 02139, USA.
+//
+
+//! Running jobs on other machines, over SSH
+//!
+//! One machine cannot always keep up with the fuzz, miri and MSRV jobs of a
+//! large PR, so check-pr can hand some of them to workers listed in
+//! `[[worker]]` sections. Each job runs either on this machine or in one of
+//! a worker's slots, whichever is free. For a job on a worker, the checkout
+//! is copied to a temporary directory there, and cargo is run in it by
+//! `rsgit-worker`, reached over SSH and sent the job with the protocol of
+//! [`crate::protocol`]; its output comes back over the connection, so logs,
+//! notes and results are all kept here, as for any other job. Workers need
+//! `rsgit-worker`, the toolchains, and any cargo subcommands, which their
+//! jobs use, and they download dependencies into their own cargo home.
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::{Condvar, Mutex};
+
+use crate::protocol::{self, Job};
+
+/// A machine to run jobs on
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Worker {
+    /// Where to connect to, as given to ssh, e.g. `user@host`
+    pub host: String,
+    /// Number of jobs to run on it at once
+    #[serde(default = "default_slots")]
+    pub slots: usize,
+    /// The directory on it to put checkouts in
+    #[serde(default = "default_dir")]
+    pub dir: String,
+    /// Extra options to ssh
+    #[serde(default)]
+    pub ssh_args: Vec<String>,
+    /// Command which runs `rsgit-worker` on it
+    #[serde(default = "default_command")]
+    pub command: String,
+}
+
+fn default_slots() -> usize {
+    1
+}
+
+fn default_dir() -> String {
+    "/tmp".to_owned()
+}
+
+fn default_command() -> String {
+    "rsgit-worker".to_owned()
+}
+
+impl Worker {
+    /// Starts an ssh command to run a shell command on the worker
+    ///
+    /// ssh is never allowed to prompt for a password, since there is nobody
+    /// to answer it.
+    fn ssh(&self, command: &str) -> subprocess::Exec {
+        let argv = self.ssh_argv(command);
+        subprocess::Exec::cmd(&argv[0]).args(&argv[1..])
+    }
+
+    /// The program and arguments of `ssh`
+    fn ssh_argv(&self, command: &str) -> Vec<OsString> {
+        let mut argv: Vec<OsString> = vec!["ssh".into(), "-o".into(), "BatchMode=yes".into()];
+        argv.extend(self.ssh_args.iter().map(OsString::from));
+        argv.push(self.host.clone().into());
+        argv.push(command.into());
+        argv
+    }
+}
+
+/// The free slots on this machine and on each worker
+struct Slots {
+    local: usize,
+    workers: Vec<(Worker, usize)>,
+}
+
+static SLOTS: Mutex<Option<Slots>> = Mutex::new(None);
+static FREED: Condvar = Condvar::new();
+
+/// Sets up the slots which jobs are shared out between
+///
+/// Does nothing if there are no workers, in which case every job runs on
+/// this machine, as many at once as the thread pools allow.
+pub fn start(local: usize, workers: &[Worker]) {
+    if workers.is_empty() {
+        return;
+    }
+    *SLOTS.lock().unwrap() = Some(Slots {
+        local,
+        workers: workers.iter().map(|w| (w.clone(), w.slots)).collect(),
+    });
+}
+
+/// A slot which a job is running in, freed when dropped
+pub struct Slot {
+    /// The index of the worker, or `None` for this machine
+    index: Option<usize>,
+    worker: Option<Worker>,
+}
+
+impl Slot {
+    /// The worker the slot is on, or `None` if it is on this machine
+    pub fn worker(&self) -> Option<&Worker> {
+        self.worker.as_ref()
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut slots = SLOTS.lock().unwrap();
+        if let Some(ref mut slots) = *slots {
+            match self.index {
+                Some(index) => slots.workers[index].1 += 1,
+                None => slots.local += 1,
+            }
+        }
+        FREED.notify_one();
+    }
+}
+
+/// Waits for a free slot to run a job in, preferring this machine's
+///
+/// Returns `None` if there are no workers.
+pub fn acquire() -> Option<Slot> {
+    let mut guard = SLOTS.lock().unwrap();
+    loop {
+        let slots = guard.as_mut()?;
+        if slots.local > 0 {
+            slots.local -= 1;
+            return Some(Slot {
+                index: None,
+                worker: None,
+            });
+        }
+        if let Some(index) = slots.workers.iter().position(|&(_, free)| free > 0) {
+            slots.workers[index].1 -= 1;
+            return Some(Slot {
+                index: Some(index),
+                worker: Some(slots.workers[index].0.clone()),
+            });
+        }
+        guard = FREED.wait(guard).unwrap();
+    }
+}
+
+/// A copy of a checkout on a worker, deleted when dropped
+pub struct Remote {
+    worker: Worker,
+    dir: String,
+}
+
+impl Remote {
+    /// Copies a checkout to a new temporary directory on a worker
+    ///
+    /// Build directories are not copied, since other jobs may be writing to
+    /// them. These are recognized by the `CACHEDIR.TAG` files which cargo
+    /// puts in them, but older versions of cargo do not do this, so they
+    /// should also be given in `excludes`, relative to the checkout.
+    pub fn ship(worker: &Worker, checkout: &Path, excludes: &[String]) -> anyhow::Result<Self> {
+        let mut tar = subprocess::Exec::cmd("tar")
+            .arg("-C")
+            .arg(checkout)
+            .arg("--exclude-caches-all")
+            .arg("--anchored");
+        for path in excludes {
+            tar = tar.arg(format!("--exclude=./{}", path));
+        }
+        let script = format!(
+            "mkdir -p {dir} && d=$(mktemp -d {dir}/check-pr.XXXXXX) && echo \"$d\" && tar -C \"$d\" -xf -",
+            dir = quote(OsStr::new(&worker.dir)),
+        );
+        // A pipeline's stderr can only go to a file
+        let mut stderr = tempfile::tempfile().context("creating temporary file")?;
+        let pipeline = tar.args(&["-cf", "-", "."]) | worker.ssh(&script);
+        let capture = pipeline
+            .stderr_to(stderr.try_clone().context("duplicating temporary file")?)
+            .capture()
+            .with_context(|| format!("copying checkout to worker {}", worker.host))?;
+        let dir = capture.stdout_str().trim().to_owned();
+        if !capture.success() || dir.is_empty() {
+            let mut errors = String::new();
+            stderr
+                .seek(SeekFrom::Start(0))
+                .and_then(|_| stderr.read_to_string(&mut errors))
+                .context("reading temporary file")?;
+            return Err(anyhow::Error::msg(format!(
+                "copying checkout to worker {}: exited with {:?}\nstderr:\n{}",
+                worker.host, capture.exit_status, errors,
+            )));
+        }
+        Ok(Remote {
+            worker: worker.clone(),
+            dir,
+        })
+    }
+
+    /// Constructs the command to run a program in the copied checkout
+    ///
+    /// `cwd` is relative to the checkout. The worker's environment is used,
+    /// with `env` added to it. The command is `rsgit-worker --run-job` on
+    /// this machine, which sends the job to `rsgit-worker` on the worker,
+    /// and prints its output and exits as it does.
+    pub fn exec(
+        &self,
+        argv: &[OsString],
+        env: &[(OsString, OsString)],
+        cwd: &Path,
+    ) -> subprocess::Exec {
+        let job = serde_json::to_string(&self.job(argv, env, cwd)).expect("jobs can be serialized");
+        // The one installed alongside check-pr, if it can be found
+        let client = env::current_exe()
+            .map(|exe| exe.with_file_name("rsgit-worker"))
+            .ok()
+            .filter(|path| path.is_file())
+            .map_or_else(|| "rsgit-worker".into(), OsString::from);
+        subprocess::Exec::cmd(client)
+            .arg("--run-job")
+            .arg(job)
+            .arg("--")
+            .args(&self.worker.ssh_argv(&self.worker.command))
+            .stdin(subprocess::NullFile)
+    }
+
+    /// The job to run a program in the copied checkout, as for `exec`
+    ///
+    /// The protocol only carries strings, so any arguments or variables
+    /// which are not UTF-8 are converted lossily.
+    fn job(&self, argv: &[OsString], env: &[(OsString, OsString)], cwd: &Path) -> Job {
+        let lossy = |s: &OsStr| s.to_string_lossy().into_owned();
+        Job {
+            source: protocol::Source::Dir {
+                path: self.dir.clone(),
+            },
+            cwd: Some(lossy(cwd.as_os_str())).filter(|cwd| !cwd.is_empty()),
+            argv: argv.iter().map(|arg| lossy(arg)).collect(),
+            env: env
+                .iter()
+                .map(|(key, val)| (lossy(key), lossy(val)))
+                .collect(),
+        }
+    }
+}
+
+impl Drop for Remote {
+    fn drop(&mut self) {
+        let command = format!("rm -rf {}", quote(OsStr::new(&self.dir)));
+        let result = self
+            .worker
+            .ssh(&command)
+            .stdin(subprocess::NullFile)
+            .stdout(subprocess::NullFile)
+            .stderr(subprocess::NullFile)
+            .join();
+        if !matches!(result, Ok(status) if status.success()) {
+            eprintln!(
+                "Failed to remove {} from worker {}",
+                self.dir, self.worker.host
+            );
+        }
+    }
+}
+
+/// Quotes a word for the shell on a worker
+fn quote(word: &OsStr) -> String {
+    let word = word.to_string_lossy();
+    if !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+-./:=@_,".contains(c))
+    {
+        word.into_owned()
+    } else {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn job() {
+        let worker: Worker = toml::from_str("host = \"ci@builder\"").unwrap();
+        assert_eq!(worker.command, "rsgit-worker");
+        let remote = Remote {
+            worker,
+            dir: "/tmp/check-pr.abc123".to_owned(),
+        };
+        let job = remote.job(
+            &["cargo".into(), "+1.41.0".into(), "test".into()],
+            &[("CARGO_NET_OFFLINE".into(), "true".into())],
+            Path::new("sub crate"),
+        );
+        assert_eq!(
+            serde_json::to_value(&job).unwrap(),
+            serde_json::json!({
+                "source": { "kind": "dir", "path": "/tmp/check-pr.abc123" },
+                "cwd": "sub crate",
+                "argv": ["cargo", "+1.41.0", "test"],
+                "env": { "CARGO_NET_OFFLINE": "true" },
+            }),
+        );
+        assert_eq!(remote.job(&[], &[], Path::new("")).cwd, None);
+        // Not removing a checkout which was never made
+        std::mem::forget(remote);
+
+        assert_eq!(quote(OsStr::new("it's")), "'it'\\''s'");
+    }
+}